@@ -33,6 +33,12 @@
 //!
 //! Totally empty inputs produce no blocks at all.
 //!
+//! Blocks are always hashed and passed around as cleartext here. Compression
+//! is the server layer's concern: each block is gzipped (at the configured
+//! level) just before it is encrypted and inflated just after it is
+//! decrypted. This keeps block HMACs, and therefore deduplication and file
+//! identity, independent of the compression setting.
+//!
 //! Separating files into blocks, each of which is stored separately in the
 //! server, has a number of benefits:
 //!
@@ -59,13 +65,26 @@
 //! This module does not handle encryption itself; the blocks it passes through
 //! are still in cleartext.
 
-use std::io;
-use std::sync::Arc;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Seek};
+use std::ops::Range;
+use std::path::Path;
+#[cfg(test)]
+use std::sync::atomic::AtomicBool;
+#[cfg(test)]
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use blake3;
+use tempfile::NamedTempFile;
 use tiny_keccak::Keccak;
 
 use crate::defs::*;
 use crate::errors::*;
+use crate::server::Storage;
 
 /// The representation of a list of blocks into which an input stream was
 /// split.
@@ -81,19 +100,284 @@ pub struct BlockList {
     pub blocks: Vec<HashId>,
     /// The total number of bytes that were read from the stream.
     pub size: FileSize,
+    /// The size of each block in `blocks`, if known.
+    ///
+    /// This is populated by `stream_to_blocks` and allows `read_range_with` to
+    /// find the blocks covering a byte range without reading the ones before
+    /// it. It is not stored on the server; block lists obtained from there
+    /// have it reconstructed by `uniform_block_sizes`.
+    pub block_sizes: Option<Vec<FileSize>>,
+    /// Statistics gathered while blocking the stream, if known.
+    ///
+    /// This is populated by `stream_to_blocks` and is not stored on the
+    /// server.
+    pub stats: Option<BlockStats>,
+}
+
+/// Statistics on the blocks produced from a stream.
+///
+/// These only describe the stream itself; whether any of the blocks were
+/// already present on the server is up to the caller to track.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    /// The number of blocks produced.
+    pub blocks: u64,
+    /// The total number of bytes in all blocks.
+    pub bytes: FileSize,
+    /// The number of blocks consisting entirely of zero bytes, i.e., which
+    /// are likely sparse areas of the file.
+    pub zero_blocks: u64,
+    /// The total number of bytes in blocks counted by `zero_blocks`.
+    pub zero_bytes: FileSize,
+}
+
+impl BlockStats {
+    /// Accounts for one block containing `data`.
+    pub fn add_block(&mut self, data: &[u8]) {
+        self.blocks += 1;
+        self.bytes += data.len() as FileSize;
+        if data.iter().all(|&b| 0 == b) {
+            self.zero_blocks += 1;
+            self.zero_bytes += data.len() as FileSize;
+        }
+    }
+
+    /// Adds the counts from `other` into `self`, e.g., to total the
+    /// statistics for several files.
+    pub fn merge(&mut self, other: &BlockStats) {
+        self.blocks += other.blocks;
+        self.bytes += other.bytes;
+        self.zero_blocks += other.zero_blocks;
+        self.zero_bytes += other.zero_bytes;
+    }
+}
+
+impl BlockList {
+    /// Checks that the fields of this block list agree with each other.
+    ///
+    /// If `block_sizes` is known, it must have one entry per block and sum to
+    /// `size`. Nothing is checked otherwise; in particular, this does not
+    /// verify `total` (see `blocks_to_stream`) or that the blocks actually
+    /// contain `size` bytes (see `blocks_to_stream_checked_with`).
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref sizes) = self.block_sizes {
+            if sizes.len() != self.blocks.len() {
+                return Err(ErrorKind::BlockCountMismatch(
+                    self.blocks.len(),
+                    sizes.len(),
+                )
+                .into());
+            }
+
+            let sum: FileSize = sizes.iter().sum();
+            if sum != self.size {
+                return Err(
+                    ErrorKind::StreamSizeMismatch(self.size, sum).into()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the indices into `blocks` of the blocks overlapping the
+    /// `len` bytes starting at `offset`, and the offset of the first such
+    /// block within the stream.
+    ///
+    /// Returns `None` if `block_sizes` is not known.
+    pub fn blocks_in_range(
+        &self,
+        offset: FileSize,
+        len: FileSize,
+    ) -> Option<(Range<usize>, FileSize)> {
+        let sizes = self.block_sizes.as_ref()?;
+        let end = offset.saturating_add(len);
+
+        let mut first = sizes.len();
+        let mut first_offset = 0;
+        let mut last = sizes.len();
+        let mut block_offset: FileSize = 0;
+        for (ix, &size) in sizes.iter().enumerate() {
+            let block_end = block_offset + size;
+            if first == sizes.len() && block_end > offset {
+                first = ix;
+                first_offset = block_offset;
+            }
+            if block_end >= end {
+                last = ix + 1;
+                break;
+            }
+            block_offset = block_end;
+        }
+
+        if first >= last || 0 == len {
+            Some((first..first, first_offset))
+        } else {
+            Some((first..last, first_offset))
+        }
+    }
+}
+
+/// The hash function used for block and stream ids.
+///
+/// Every client of a store must use the same variant, since it determines the
+/// ids under which blocks are stored. The variant in use is recorded
+/// store-wide (see `keymgmt::set_hash_variant()`); stores which do not record
+/// one use `Sha3_256`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashVariant {
+    /// Standard FIPS 202 SHA3-256.
+    Sha3_256,
+    /// The original Keccak-256 submission, with the pre-standardisation
+    /// padding. This is what some other tools call "SHA3".
+    Keccak256,
+    /// BLAKE3 with 256-bit output, which is considerably faster than either
+    /// of the above on large transfers.
+    Blake3,
+}
+
+/// Code recorded for `HashVariant::Sha3_256`.
+pub const HASH_VARIANT_SHA3_256: u32 = 0;
+/// Code recorded for `HashVariant::Keccak256`.
+pub const HASH_VARIANT_KECCAK_256: u32 = 1;
+/// Code recorded for `HashVariant::Blake3`.
+pub const HASH_VARIANT_BLAKE3: u32 = 2;
+
+/// The BLAKE3 key derivation context used to turn a block secret into the key
+/// for `HashVariant::Blake3`.
+const BLAKE3_KEY_CONTEXT: &str = "ensync 2021 block hash key";
+
+impl Default for HashVariant {
+    fn default() -> Self {
+        HashVariant::Sha3_256
+    }
+}
+
+impl HashVariant {
+    /// Returns the variant with the given recorded code, if known.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            HASH_VARIANT_SHA3_256 => Some(HashVariant::Sha3_256),
+            HASH_VARIANT_KECCAK_256 => Some(HashVariant::Keccak256),
+            HASH_VARIANT_BLAKE3 => Some(HashVariant::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Returns the code under which this variant is recorded.
+    pub fn code(self) -> u32 {
+        match self {
+            HashVariant::Sha3_256 => HASH_VARIANT_SHA3_256,
+            HashVariant::Keccak256 => HASH_VARIANT_KECCAK_256,
+            HashVariant::Blake3 => HASH_VARIANT_BLAKE3,
+        }
+    }
+
+    /// Returns a fresh hasher for this variant, keyed with `secret`.
+    ///
+    /// The Keccak-based variants are keyed by prefixing `secret` to the data.
+    /// BLAKE3 has a proper keyed mode, which is used with a 32-byte key
+    /// derived from `secret`.
+    pub fn hasher(self, secret: &[u8]) -> BlockHasher {
+        let keccak = |mut kc: Keccak| {
+            kc.update(secret);
+            BlockHasher::Keccak(kc)
+        };
+        match self {
+            HashVariant::Sha3_256 => keccak(Keccak::new_sha3_256()),
+            HashVariant::Keccak256 => keccak(Keccak::new_keccak256()),
+            HashVariant::Blake3 => {
+                BlockHasher::Blake3(blake3::Hasher::new_keyed(
+                    &blake3::derive_key(BLAKE3_KEY_CONTEXT, secret),
+                ))
+            }
+        }
+    }
+}
+
+/// An in-progress hash for one of the `HashVariant`s, as returned by
+/// `HashVariant::hasher()`.
+pub enum BlockHasher {
+    Keccak(Keccak),
+    Blake3(blake3::Hasher),
+}
+
+impl BlockHasher {
+    /// Feeds `data` into the hash.
+    pub fn update(&mut self, data: &[u8]) {
+        match *self {
+            BlockHasher::Keccak(ref mut kc) => kc.update(data),
+            BlockHasher::Blake3(ref mut hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Consumes the hasher, writing the final hash into `out`.
+    pub fn finalize(self, out: &mut HashId) {
+        match self {
+            BlockHasher::Keccak(kc) => kc.finalize(out),
+            BlockHasher::Blake3(hasher) => *out = *hasher.finalize().as_bytes(),
+        }
+    }
 }
 
 /// Computes the hash of the given block using the same method used internally
 /// in the block transfer system.
 pub fn hash_block(secret: &[u8], block: &[u8]) -> HashId {
-    let mut kc = Keccak::new_sha3_256();
-    kc.update(secret);
+    hash_block_with(HashVariant::default(), secret, block)
+}
+
+/// Like `hash_block`, but with an explicit hash variant.
+pub fn hash_block_with(
+    variant: HashVariant,
+    secret: &[u8],
+    block: &[u8],
+) -> HashId {
+    let mut kc = variant.hasher(secret);
     kc.update(block);
     let mut hash = [0; 32];
     kc.finalize(&mut hash);
     hash
 }
 
+/// Returns the number of blocks `stream_to_blocks` will produce for an input of
+/// exactly `size` bytes with the given `block_size`.
+///
+/// This is exact for the fixed-size chunking done here, since every block but
+/// the last is exactly `block_size` bytes. (A content-defined chunker could
+/// only ever produce an estimate.) Note that if the source changes size while
+/// being read, the actual count will of course differ.
+pub fn expected_block_count(size: FileSize, block_size: usize) -> u64 {
+    assert!(block_size > 0, "block_size must be non-zero");
+
+    let block_size = block_size as u64;
+    size / block_size + if 0 == size % block_size { 0 } else { 1 }
+}
+
+/// Reconstructs the `block_sizes` of a block list with `count` blocks which
+/// `stream_to_blocks` produced from `size` bytes with the given `block_size`.
+///
+/// Every block but the last is exactly `block_size` bytes, so the sizes need
+/// not be stored alongside the list. Returns `None` if `count` is not what
+/// `expected_block_count` gives, i.e., the list was not produced that way.
+pub fn uniform_block_sizes(
+    size: FileSize,
+    block_size: usize,
+    count: usize,
+) -> Option<Vec<FileSize>> {
+    if 0 == block_size || expected_block_count(size, block_size) != count as u64
+    {
+        return None;
+    }
+
+    let mut sizes = vec![block_size as FileSize; count];
+    if let Some(last) = sizes.last_mut() {
+        *last = size - (count as FileSize - 1) * block_size as FileSize;
+    }
+    Some(sizes)
+}
+
 /// Breaks the input byte stream `input` into non-empty byte blocks up to size
 /// `block_size`.
 ///
@@ -116,16 +400,37 @@ pub fn hash_block(secret: &[u8], block: &[u8]) -> HashId {
 /// implementation could be based on that, and then this function would
 /// transitively provide a coherence guarantee as well.
 pub fn stream_to_blocks<F: FnMut(&HashId, &[u8]) -> Result<()>, R: io::Read>(
+    input: R,
+    block_size: usize,
+    secret: &[u8],
+    block_out: F,
+) -> Result<BlockList> {
+    stream_to_blocks_with(
+        HashVariant::default(),
+        input,
+        block_size,
+        secret,
+        block_out,
+    )
+}
+
+/// Like `stream_to_blocks`, but with an explicit hash variant.
+pub fn stream_to_blocks_with<
+    F: FnMut(&HashId, &[u8]) -> Result<()>,
+    R: io::Read,
+>(
+    variant: HashVariant,
     mut input: R,
     block_size: usize,
     secret: &[u8],
     mut block_out: F,
 ) -> Result<BlockList> {
     let mut blocks = Vec::new();
+    let mut block_sizes = Vec::new();
+    let mut stats = BlockStats::default();
     let mut hash = [0u8; 32];
     let mut size: FileSize = 0;
-    let mut total_kc = Keccak::new_sha3_256();
-    total_kc.update(secret);
+    let mut total_kc = variant.hasher(secret);
 
     // Allocate in a vector so we don't blow 1MB of stack space
     let mut block_data: Vec<u8> = Vec::new();
@@ -133,29 +438,20 @@ pub fn stream_to_blocks<F: FnMut(&HashId, &[u8]) -> Result<()>, R: io::Read>(
 
     // Read blocks until we read an empty block.
     loop {
-        let mut off = 0;
-        // Fill the data for this block up to the maximum size or EOF.
-        while off < block_size {
-            match input.read(&mut block_data[off..]) {
-                Ok(0) => break,
-                Ok(nread) => off += nread,
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
-                    continue
-                }
-                Err(e) => return Err(e.into()),
-            };
-        }
+        let off = fill_block(&mut input, &mut block_data)?;
 
         // Empty block == EOF
         if 0 == off {
             break;
         }
 
-        hash = hash_block(secret, &block_data[0..off]);
+        hash = hash_block_with(variant, secret, &block_data[0..off]);
 
         block_out(&hash, &block_data[0..off])?;
+        stats.add_block(&block_data[0..off]);
         total_kc.update(&hash);
         blocks.push(hash);
+        block_sizes.push(off as FileSize);
         size += off as FileSize;
     }
 
@@ -164,9 +460,188 @@ pub fn stream_to_blocks<F: FnMut(&HashId, &[u8]) -> Result<()>, R: io::Read>(
         total: hash,
         blocks: blocks,
         size: size,
+        block_sizes: Some(block_sizes),
+        stats: Some(stats),
+    })
+}
+
+/// The most block data `stream_to_blocks_parallel_with` holds in memory at
+/// once, however many threads its pool has.
+pub const PARALLEL_BUFFER_LIMIT: usize = 64 * 1024 * 1024;
+
+type HashJob = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of worker threads on which `stream_to_blocks_parallel_with`
+/// computes block HMACs.
+///
+/// The threads are started the first time the pool is used and are then
+/// shared by every stream hashed through it, until the pool is dropped.
+pub struct HashPool {
+    threads: usize,
+    jobs: Mutex<Option<mpsc::Sender<HashJob>>>,
+    workers: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+impl HashPool {
+    /// Creates a pool of `threads` worker threads. A `threads` of 0 is treated
+    /// as 1.
+    pub fn new(threads: usize) -> Self {
+        HashPool {
+            threads: cmp::max(1, threads),
+            jobs: Mutex::new(None),
+            workers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the number of worker threads in this pool.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    fn submit(&self, job: HashJob) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.is_none() {
+            let (tx, rx) = mpsc::channel::<HashJob>();
+            let rx = Arc::new(Mutex::new(rx));
+            let mut workers = self.workers.lock().unwrap();
+            for _ in 0..self.threads {
+                let rx = rx.clone();
+                workers.push(thread::spawn(move || loop {
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                }));
+            }
+            *jobs = Some(tx);
+        }
+
+        jobs.as_ref()
+            .unwrap()
+            .send(job)
+            .expect("HashPool workers exited");
+    }
+}
+
+impl Drop for HashPool {
+    fn drop(&mut self) {
+        // Closing the channel makes each worker exit once it is idle.
+        *self.jobs.get_mut().unwrap() = None;
+        for worker in self.workers.get_mut().unwrap().drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Like `stream_to_blocks_with`, but computes the HMACs of several blocks at
+/// once on the threads of `pool`.
+///
+/// Blocks are still read from `input` sequentially, and `block_out` is still
+/// called on the calling thread with each block in stream order, so the result
+/// is identical to that of `stream_to_blocks_with`. One block per thread is
+/// held in memory at once, up to `PARALLEL_BUFFER_LIMIT` bytes in total (but
+/// always at least one block).
+pub fn stream_to_blocks_parallel_with<
+    F: FnMut(&HashId, &[u8]) -> Result<()>,
+    R: io::Read,
+>(
+    variant: HashVariant,
+    mut input: R,
+    block_size: usize,
+    secret: &[u8],
+    pool: &HashPool,
+    mut block_out: F,
+) -> Result<BlockList> {
+    let in_flight = parallel_in_flight(pool, block_size);
+    // The jobs outlive this call if it fails part-way, so they need their own
+    // copy of the secret.
+    let job_secret = Arc::new(Passphrase::from(secret.to_vec()));
+
+    let mut blocks = Vec::new();
+    let mut block_sizes = Vec::new();
+    let mut stats = BlockStats::default();
+    let mut hash = [0u8; 32];
+    let mut size: FileSize = 0;
+    let mut total_kc = variant.hasher(secret);
+
+    // Buffers of blocks already passed to `block_out`, for reuse.
+    let mut free: Vec<Vec<u8>> = Vec::new();
+    // The result of each block submitted to the pool, in stream order. Only
+    // the job holds the sending side, so a job which dies without a result
+    // shows up as a closed channel rather than blocking forever.
+    let mut pending = VecDeque::new();
+    let mut eof = false;
+
+    loop {
+        while !eof && (pending.len() as u64) < in_flight {
+            let mut buf = free.pop().unwrap_or_else(|| vec![0u8; block_size]);
+            let len = fill_block(&mut input, &mut buf)?;
+            if 0 == len {
+                eof = true;
+                break;
+            }
+
+            let secret = job_secret.clone();
+            let (done_tx, done_rx) = mpsc::channel();
+            pool.submit(Box::new(move || {
+                let hash = hash_block_with(variant, &secret, &buf[..len]);
+                let _ = done_tx.send((hash, buf, len));
+            }));
+            pending.push_back(done_rx);
+        }
+
+        let (hash, buf, len) = match pending.pop_front() {
+            None => break,
+            Some(done_rx) => done_rx.recv().map_err(|_| {
+                Error::from("Block hashing job exited without a result")
+            })?,
+        };
+
+        let data = &buf[..len];
+        block_out(&hash, data)?;
+        stats.add_block(data);
+        total_kc.update(&hash);
+        blocks.push(hash);
+        block_sizes.push(len as FileSize);
+        size += len as FileSize;
+        free.push(buf);
+    }
+
+    total_kc.finalize(&mut hash);
+    Ok(BlockList {
+        total: hash,
+        blocks: blocks,
+        size: size,
+        block_sizes: Some(block_sizes),
+        stats: Some(stats),
     })
 }
 
+/// Returns how many blocks of `block_size` bytes
+/// `stream_to_blocks_parallel_with` may hold in memory at once with `pool`.
+fn parallel_in_flight(pool: &HashPool, block_size: usize) -> u64 {
+    cmp::max(
+        1,
+        cmp::min(pool.threads(), PARALLEL_BUFFER_LIMIT / block_size),
+    ) as u64
+}
+
+/// Reads from `input` until `buf` is full or EOF is reached, returning the
+/// number of bytes read.
+fn fill_block<R: io::Read>(input: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut off = 0;
+    while off < buf.len() {
+        match input.read(&mut buf[off..]) {
+            Ok(0) => break,
+            Ok(nread) => off += nread,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+    }
+    Ok(off)
+}
+
 /// Fetches the constituent blocks of a file, verifies them, and writes them to
 /// a byte stream.
 ///
@@ -186,18 +661,99 @@ pub fn blocks_to_stream<
     F: FnMut(&HashId) -> Result<R>,
     W: io::Write,
 >(
+    input: &BlockList,
+    output: W,
+    secret: &[u8],
+    block_fetch: F,
+) -> Result<()> {
+    blocks_to_stream_with(
+        HashVariant::default(),
+        input,
+        output,
+        secret,
+        block_fetch,
+    )
+}
+
+/// Like `blocks_to_stream`, but with an explicit hash variant.
+pub fn blocks_to_stream_with<
+    R: io::Read,
+    F: FnMut(&HashId) -> Result<R>,
+    W: io::Write,
+>(
+    variant: HashVariant,
+    input: &BlockList,
+    output: W,
+    secret: &[u8],
+    block_fetch: F,
+) -> Result<()> {
+    blocks_to_stream_impl(variant, input, output, secret, block_fetch, false)
+}
+
+/// Like `blocks_to_stream_with`, but additionally verifies that the blocks
+/// produce exactly `input.size` bytes, failing with `StreamSizeMismatch`
+/// otherwise.
+///
+/// `input` is also checked with `BlockList::validate` before any block is
+/// fetched. Output stops as soon as the stream is known to be too long, but
+/// as with the HMAC checks, anything already written to `output` must be
+/// considered corrupt on failure.
+pub fn blocks_to_stream_checked_with<
+    R: io::Read,
+    F: FnMut(&HashId) -> Result<R>,
+    W: io::Write,
+>(
+    variant: HashVariant,
+    input: &BlockList,
+    output: W,
+    secret: &[u8],
+    block_fetch: F,
+) -> Result<()> {
+    input.validate()?;
+    blocks_to_stream_impl(variant, input, output, secret, block_fetch, true)
+}
+
+/// Performs the same checks as `blocks_to_stream_with`, but discards the
+/// content instead of writing it anywhere.
+///
+/// Each block is fetched exactly once, in order. Returns the error for the
+/// first mismatch found, which is `HmacMismatch` if the content of a block or
+/// the block list itself has been corrupted.
+pub fn verify_blocks_with<R: io::Read, F: FnMut(&HashId) -> Result<R>>(
+    variant: HashVariant,
+    input: &BlockList,
+    block_fetch: F,
+    secret: &[u8],
+) -> Result<()> {
+    blocks_to_stream_impl(
+        variant,
+        input,
+        io::sink(),
+        secret,
+        block_fetch,
+        false,
+    )
+}
+
+fn blocks_to_stream_impl<
+    R: io::Read,
+    F: FnMut(&HashId) -> Result<R>,
+    W: io::Write,
+>(
+    variant: HashVariant,
     input: &BlockList,
     mut output: W,
     secret: &[u8],
     mut block_fetch: F,
+    check_size: bool,
 ) -> Result<()> {
     let mut hash = [0u8; 32];
+    let mut written: FileSize = 0;
     let mut buf = [0u8; 4096];
 
     // Sanity check the BlockList
     {
-        let mut kc = Keccak::new_sha3_256();
-        kc.update(secret);
+        let mut kc = variant.hasher(secret);
         for h in &input.blocks {
             kc.update(h);
         }
@@ -212,14 +768,20 @@ pub fn blocks_to_stream<
 
     for id in &input.blocks {
         let mut reader = block_fetch(id)?;
-        let mut kc = Keccak::new_sha3_256();
-        kc.update(secret);
+        let mut kc = variant.hasher(secret);
 
         loop {
             match reader.read(&mut buf) {
                 Ok(0) => break,
                 Ok(nread) => {
                     kc.update(&buf[0..nread]);
+                    written += nread as FileSize;
+                    if check_size && written > input.size {
+                        return Err(ErrorKind::StreamSizeMismatch(
+                            input.size, written,
+                        )
+                        .into());
+                    }
                     output
                         .write_all(&buf[0..nread])
                         .chain_err(|| "Error writing to output stream")?;
@@ -237,59 +799,491 @@ pub fn blocks_to_stream<
         }
     }
 
-    return Ok(());
-}
+    if check_size && written != input.size {
+        return Err(ErrorKind::StreamSizeMismatch(input.size, written).into());
+    }
 
-/// A file data source (eg, for `Replica::TransferIn` or
-/// `Replica::TransferOut`) representing a backing store which is not
-/// content-addressable but instead presents files as linear byte streams.
-pub trait StreamSource: io::Read {
-    /// Resets the stream to the beginning of the file.
-    fn reset(&mut self) -> Result<()>;
-    /// Notifies the source of the final computed block list that was read from
-    /// the source (once hitting EOF) so that it can update its own data
-    /// structures accordinly.
-    fn finish(&mut self, blocks: &BlockList) -> Result<()>;
+    return Ok(());
 }
 
-/// A file data source representing a content-addressable backing store which
-/// does not support inherent byte streams.
-#[derive(Clone)]
-pub struct ContentAddressableSource {
-    /// The block list of the file being transferred.
-    pub blocks: BlockList,
-    /// The block size used for this object
-    pub block_size: usize,
-    /// Object from which to read actual block content.
-    pub fetch: Arc<dyn BlockFetch>,
+/// Reconstructs the stream described by `input` into the file at `dst`,
+/// replacing it atomically, using the hash variant `variant`.
+///
+/// The data is first written to a temporary file in the same directory as
+/// `dst`, as with `blocks_to_stream_checked_with`. Only once the total sum,
+/// every block and the final size (against `input.size`) have been verified
+/// is the temporary file renamed over `dst`. On failure, the temporary file is
+/// removed and `dst` is left untouched.
+///
+/// Only tests write straight to the final path; the replica goes through
+/// `blocks_to_temp_file_with` so it can set the mode first.
+#[cfg(test)]
+pub fn blocks_to_file_with<R: io::Read, F: FnMut(&HashId) -> Result<R>>(
+    variant: HashVariant,
+    input: &BlockList,
+    dst: &Path,
+    secret: &[u8],
+    block_fetch: F,
+) -> Result<()> {
+    let dir = match dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // Dropping `tmpfile` on any of the error paths below deletes it.
+    let mut tmpfile = NamedTempFile::new_in(dir)?;
+    blocks_to_temp_file_with(
+        variant,
+        input,
+        &mut tmpfile,
+        secret,
+        block_fetch,
+    )?;
+    tmpfile.persist(dst)?;
+    Ok(())
 }
 
-/// Trait for fetching blocks found in a `ContentAddressableSource`.
-pub trait BlockFetch {
-    /// Fetches the block identified by `block`, returning a stream that can be
-    /// used to obtain the data within.
-    fn fetch(&self, block: &HashId) -> Result<Box<dyn io::Read>>;
+/// The part of `blocks_to_file_with` before the rename, for callers which
+/// create the temporary file themselves and need to do more with it (e.g.,
+/// setting its mode) before putting it into place.
+///
+/// `tmpfile` is truncated before anything is written to it. On success, its
+/// content has been fully verified and synced to disk.
+pub fn blocks_to_temp_file_with<R: io::Read, F: FnMut(&HashId) -> Result<R>>(
+    variant: HashVariant,
+    input: &BlockList,
+    tmpfile: &mut NamedTempFile,
+    secret: &[u8],
+    block_fetch: F,
+) -> Result<()> {
+    let file = tmpfile.as_file_mut();
+    file.seek(io::SeekFrom::Start(0))?;
+    file.set_len(0)?;
+    blocks_to_stream_checked_with(
+        variant,
+        input,
+        &mut *file,
+        secret,
+        block_fetch,
+    )?;
+    file.sync_all()?;
+    Ok(())
 }
 
-#[cfg(test)]
-mod test {
-    use std::collections::HashMap;
-
-    use super::*;
+/// Writes the `len` bytes of the stream described by `input` starting at
+/// `offset` to `output`, fetching only the blocks which overlap that range.
+///
+/// This requires `input.block_sizes`, and fails with `BlockSizesUnknown`
+/// without it. The range is truncated to the end of the stream. Each block
+/// fetched is verified in full before any of it is written to `output`, as
+/// is the total sum of `input`.
+pub fn read_range_with<
+    R: io::Read,
+    F: FnMut(&HashId) -> Result<R>,
+    W: io::Write,
+>(
+    variant: HashVariant,
+    input: &BlockList,
+    offset: FileSize,
+    len: FileSize,
+    mut output: W,
+    secret: &[u8],
+    mut block_fetch: F,
+) -> Result<()> {
+    let (range, mut block_offset) = input
+        .blocks_in_range(offset, len)
+        .ok_or(ErrorKind::BlockSizesUnknown)?;
 
-    fn to_blocklist(
-        text: &[u8],
-        secret: &[u8],
-    ) -> (BlockList, HashMap<HashId, Vec<u8>>) {
-        let mut blocks = HashMap::new();
+    let mut kc = variant.hasher(secret);
+    for h in &input.blocks {
+        kc.update(h);
+    }
+    let mut hash = [0u8; 32];
+    kc.finalize(&mut hash);
+    if hash != input.total {
+        return Err(ErrorKind::HmacMismatch("total", input.total, hash).into());
+    }
 
-        let blocklist = stream_to_blocks(text, 4, secret, |&id, data| {
-            blocks.insert(id, data.to_vec());
-            Ok(())
-        })
-        .unwrap();
+    let end = offset.saturating_add(len);
+    let mut data = Vec::new();
+    for id in &input.blocks[range] {
+        data.clear();
+        block_fetch(id)?.read_to_end(&mut data)?;
+        let hash = hash_block_with(variant, secret, &data);
+        if hash != *id {
+            return Err(ErrorKind::HmacMismatch("block", *id, hash).into());
+        }
 
-        (blocklist, blocks)
+        let block_end = block_offset + data.len() as FileSize;
+        let from = offset.max(block_offset) - block_offset;
+        let to = end.min(block_end).max(block_offset) - block_offset;
+        if from < to {
+            output
+                .write_all(&data[from as usize..to as usize])
+                .chain_err(|| "Error writing to output stream")?;
+        }
+        block_offset = block_end;
+    }
+
+    Ok(())
+}
+
+/// Shared flags through which another thread can pause or cancel transfers.
+///
+/// The transfer functions only consult these between blocks, so a block in
+/// progress always completes first.
+///
+/// Nothing outside the tests drives these yet; interrupting a sync only takes
+/// effect between files.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct TransferControl {
+    paused: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+#[cfg(test)]
+impl TransferControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Causes transfers to block before their next block until `resume()` or
+    /// `cancel()` is called.
+    pub fn pause(&self) {
+        self.paused.store(true, SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, SeqCst);
+    }
+
+    /// Causes transfers to fail with `TransferCancelled` before their next
+    /// block, including ones currently paused.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(SeqCst)
+    }
+
+    /// Blocks for as long as this control is paused, then fails if it has
+    /// been cancelled.
+    pub fn checkpoint(&self) -> Result<()> {
+        loop {
+            if self.is_cancelled() {
+                return Err(ErrorKind::TransferCancelled.into());
+            }
+            if !self.is_paused() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Like `stream_to_blocks`, but calls `control.checkpoint()` before emitting
+/// each block.
+#[cfg(test)]
+pub fn stream_to_blocks_controlled<
+    F: FnMut(&HashId, &[u8]) -> Result<()>,
+    R: io::Read,
+>(
+    input: R,
+    block_size: usize,
+    secret: &[u8],
+    control: &TransferControl,
+    mut block_out: F,
+) -> Result<BlockList> {
+    stream_to_blocks(input, block_size, secret, |id, data| {
+        control.checkpoint()?;
+        block_out(id, data)
+    })
+}
+
+/// Like `blocks_to_stream`, but calls `control.checkpoint()` before fetching
+/// each block.
+#[cfg(test)]
+pub fn blocks_to_stream_controlled<
+    R: io::Read,
+    F: FnMut(&HashId) -> Result<R>,
+    W: io::Write,
+>(
+    input: &BlockList,
+    output: W,
+    secret: &[u8],
+    control: &TransferControl,
+    mut block_fetch: F,
+) -> Result<()> {
+    blocks_to_stream(input, output, secret, |id| {
+        control.checkpoint()?;
+        block_fetch(id)
+    })
+}
+
+/// Tracks the time of the last completed block of a transfer, so that a
+/// transfer which stops making progress can be aborted.
+///
+/// The transfer functions can only notice a stall once the stuck callback
+/// returns.
+#[derive(Debug)]
+pub struct TransferWatchdog {
+    window: Duration,
+    last_progress: Mutex<Instant>,
+}
+
+impl TransferWatchdog {
+    /// Creates a watchdog which considers a transfer stalled if no block
+    /// completes within `window`. The window starts now.
+    pub fn new(window: Duration) -> Self {
+        TransferWatchdog {
+            window: window,
+            last_progress: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Fails with `StalledTransfer` if the transfer has stalled, and otherwise
+    /// records progress as of now.
+    pub fn tick(&self) -> Result<()> {
+        let mut last_progress = self.last_progress.lock().unwrap();
+        if last_progress.elapsed() > self.window {
+            return Err(ErrorKind::StalledTransfer.into());
+        }
+        *last_progress = Instant::now();
+        Ok(())
+    }
+
+    /// Runs `f`, which is expected to complete a block, failing with
+    /// `StalledTransfer` if the transfer stalled before or while it ran.
+    ///
+    /// This is intended to wrap the `block_fetch` callback of the
+    /// `blocks_to_*` functions; the previous block has been fully written by
+    /// the time the next one is fetched.
+    pub fn watch<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<T> {
+        self.tick()?;
+        let ret = f()?;
+        self.tick()?;
+        Ok(ret)
+    }
+}
+
+/// Returns whether `storage` already holds the object identified by `id`,
+/// without fetching its content.
+///
+/// This is intended for the `block_out` callback of `stream_to_blocks`, to
+/// skip uploading blocks the server already has. Note that `id` is the id of
+/// the object in the storage, which need not be the block HMAC itself.
+pub fn block_present<S: Storage + ?Sized>(
+    storage: &S,
+    id: &HashId,
+) -> Result<bool> {
+    storage.hasobj(id)
+}
+
+/// A file data source (eg, for `Replica::TransferIn` or
+/// `Replica::TransferOut`) representing a backing store which is not
+/// content-addressable but instead presents files as linear byte streams.
+pub trait StreamSource: io::Read {
+    /// Resets the stream to the beginning of the file.
+    fn reset(&mut self) -> Result<()>;
+    /// Notifies the source of the final computed block list that was read from
+    /// the source (once hitting EOF) so that it can update its own data
+    /// structures accordinly.
+    fn finish(&mut self, blocks: &BlockList) -> Result<()>;
+    /// Returns the block size the destination should use for this stream
+    /// instead of its own default, if any.
+    ///
+    /// The default returns `None`.
+    fn block_size(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// A file data source representing a content-addressable backing store which
+/// does not support inherent byte streams.
+#[derive(Clone)]
+pub struct ContentAddressableSource {
+    /// The block list of the file being transferred.
+    pub blocks: BlockList,
+    /// The block size used for this object
+    pub block_size: usize,
+    /// Object from which to read actual block content.
+    pub fetch: Arc<dyn BlockFetch>,
+}
+
+impl ContentAddressableSource {
+    /// Returns a copy of this source which reports the number of bytes read
+    /// from fetched blocks to `progress`, out of `total`.
+    pub fn with_progress(self, total: FileSize, progress: ProgressFn) -> Self {
+        let progress =
+            Arc::new(Mutex::new(TransferProgress::new(total, progress)));
+        ContentAddressableSource {
+            fetch: Arc::new(ProgressBlockFetch {
+                inner: self.fetch,
+                progress: progress,
+            }),
+            ..self
+        }
+    }
+}
+
+/// Trait for fetching blocks found in a `ContentAddressableSource`.
+pub trait BlockFetch {
+    /// Fetches the block identified by `block`, returning a stream that can be
+    /// used to obtain the data within.
+    fn fetch(&self, block: &HashId) -> Result<Box<dyn io::Read>>;
+}
+
+struct ProgressBlockFetch {
+    inner: Arc<dyn BlockFetch>,
+    progress: Arc<Mutex<TransferProgress>>,
+}
+
+impl BlockFetch for ProgressBlockFetch {
+    fn fetch(&self, block: &HashId) -> Result<Box<dyn io::Read>> {
+        Ok(Box::new(ProgressReader::new(
+            self.inner.fetch(block)?,
+            self.progress.clone(),
+        )))
+    }
+}
+
+/// Callback receiving the number of bytes transferred so far and the total
+/// number of bytes in a transfer.
+pub type ProgressFn = Arc<dyn Fn(FileSize, FileSize) + Send + Sync>;
+
+/// Callback invoked once before a file is reconstructed from its blocks, with
+/// the path of the file, the number of blocks and the total size.
+pub type PreflightFn = Arc<dyn Fn(&Path, usize, FileSize) + Send + Sync>;
+
+/// The minimum time between two calls to the callback of a
+/// `TransferProgress`, other than for the final report.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Tracks how much of a single transfer has completed, passing the count on
+/// to a `ProgressFn` at a limited rate.
+///
+/// The callback is invoked on the first advance, on every advance which
+/// reaches the total, and otherwise at most once per interval.
+pub struct TransferProgress {
+    total: FileSize,
+    done: FileSize,
+    interval: Duration,
+    last_report: Option<Instant>,
+    report: ProgressFn,
+}
+
+impl TransferProgress {
+    /// Creates a tracker for a transfer of `total` bytes which reports at most
+    /// once per `PROGRESS_INTERVAL`.
+    pub fn new(total: FileSize, report: ProgressFn) -> Self {
+        TransferProgress::with_interval(total, PROGRESS_INTERVAL, report)
+    }
+
+    /// Like `new()`, but with an explicit minimum interval between reports.
+    pub fn with_interval(
+        total: FileSize,
+        interval: Duration,
+        report: ProgressFn,
+    ) -> Self {
+        TransferProgress {
+            total: total,
+            done: 0,
+            interval: interval,
+            last_report: None,
+            report: report,
+        }
+    }
+
+    /// Restarts the count from zero, eg, because the stream was rewound.
+    pub fn reset(&mut self) {
+        self.done = 0;
+    }
+
+    /// Records that another `n` bytes have been transferred.
+    pub fn advance(&mut self, n: usize) {
+        self.done += n as FileSize;
+
+        let now = Instant::now();
+        let due = self
+            .last_report
+            .map_or(true, |last| now.duration_since(last) >= self.interval);
+        if due || self.done >= self.total {
+            self.last_report = Some(now);
+            (self.report)(self.done, self.total);
+        }
+    }
+}
+
+/// Wraps a reader so that every byte read from it is counted toward a
+/// `TransferProgress`.
+///
+/// When wrapping a `StreamSource`, resetting the stream also resets the
+/// progress.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Arc<Mutex<TransferProgress>>,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, progress: Arc<Mutex<TransferProgress>>) -> Self {
+        ProgressReader {
+            inner: inner,
+            progress: progress,
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.progress.lock().unwrap().advance(n);
+        }
+        Ok(n)
+    }
+}
+
+impl StreamSource for ProgressReader<Box<dyn StreamSource>> {
+    fn reset(&mut self) -> Result<()> {
+        self.progress.lock().unwrap().reset();
+        self.inner.reset()
+    }
+
+    fn finish(&mut self, blocks: &BlockList) -> Result<()> {
+        self.inner.finish(blocks)
+    }
+
+    fn block_size(&self) -> Option<usize> {
+        self.inner.block_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use super::*;
+    use crate::server::storage::Tx;
+
+    fn to_blocklist(
+        text: &[u8],
+        secret: &[u8],
+    ) -> (BlockList, HashMap<HashId, Vec<u8>>) {
+        let mut blocks = HashMap::new();
+
+        let blocklist = stream_to_blocks(text, 4, secret, |&id, data| {
+            blocks.insert(id, data.to_vec());
+            Ok(())
+        })
+        .unwrap();
+
+        (blocklist, blocks)
     }
 
     fn to_stream(
@@ -304,6 +1298,244 @@ mod test {
         Ok(output)
     }
 
+    /// A minimal in-memory `Storage`. Writes take effect immediately rather
+    /// than on commit, and any access to `failing` fails as a lost connection
+    /// would.
+    #[derive(Default)]
+    struct MemStorage {
+        dirs: Mutex<HashMap<HashId, (HashId, Vec<u8>)>>,
+        objs: Mutex<HashMap<HashId, Vec<u8>>>,
+        failing: HashId,
+    }
+
+    impl MemStorage {
+        fn check(&self, id: &HashId) -> Result<()> {
+            if *id == self.failing {
+                Err(ErrorKind::ServerConnectionClosed.into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl Storage for MemStorage {
+        fn getdir(&self, id: &HashId) -> Result<Option<(HashId, Vec<u8>)>> {
+            self.check(id)?;
+            Ok(self.dirs.lock().unwrap().get(id).cloned())
+        }
+        fn getobj(&self, id: &HashId) -> Result<Option<Vec<u8>>> {
+            self.check(id)?;
+            Ok(self.objs.lock().unwrap().get(id).cloned())
+        }
+        fn hasobj(&self, id: &HashId) -> Result<bool> {
+            self.check(id)?;
+            Ok(self.objs.lock().unwrap().contains_key(id))
+        }
+        fn check_dir_dirty(
+            &self,
+            _: &HashId,
+            _: &HashId,
+            _: u32,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn for_dirty_dir(
+            &self,
+            _: &mut dyn FnMut(&HashId) -> Result<()>,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn start_tx(&self, _: Tx) -> Result<()> {
+            Ok(())
+        }
+        fn commit(&self, _: Tx) -> Result<bool> {
+            Ok(true)
+        }
+        fn abort(&self, _: Tx) -> Result<()> {
+            Ok(())
+        }
+        fn mkdir(
+            &self,
+            _: Tx,
+            id: &HashId,
+            ver: &HashId,
+            _: &HashId,
+            data: &[u8],
+        ) -> Result<()> {
+            self.check(id)?;
+            self.dirs.lock().unwrap().insert(*id, (*ver, data.to_vec()));
+            Ok(())
+        }
+        fn updir(
+            &self,
+            _: Tx,
+            id: &HashId,
+            _: &HashId,
+            _: u32,
+            data: &[u8],
+        ) -> Result<()> {
+            self.check(id)?;
+            if let Some(dir) = self.dirs.lock().unwrap().get_mut(id) {
+                dir.1.extend_from_slice(data);
+            }
+            Ok(())
+        }
+        fn rmdir(&self, _: Tx, id: &HashId, _: &HashId, _: u32) -> Result<()> {
+            self.check(id)?;
+            self.dirs.lock().unwrap().remove(id);
+            Ok(())
+        }
+        fn linkobj(&self, _: Tx, id: &HashId, _: &HashId) -> Result<bool> {
+            self.hasobj(id)
+        }
+        fn putobj(
+            &self,
+            _: Tx,
+            id: &HashId,
+            _: &HashId,
+            data: &[u8],
+        ) -> Result<()> {
+            self.check(id)?;
+            self.objs.lock().unwrap().insert(*id, data.to_vec());
+            Ok(())
+        }
+        fn unlinkobj(&self, _: Tx, id: &HashId, _: &HashId) -> Result<()> {
+            self.check(id)?;
+            self.objs.lock().unwrap().remove(id);
+            Ok(())
+        }
+        fn watch(
+            &mut self,
+            _: Box<dyn FnMut(Option<&HashId>) + Send>,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn watchdir(&self, _: &HashId, _: &HashId, _: u32) -> Result<()> {
+            Ok(())
+        }
+        fn clean_up(&self) {}
+    }
+
+    #[test]
+    fn paused_transfer_waits_for_resume() {
+        use std::time::Instant;
+
+        let control = Arc::new(TransferControl::new());
+        control.pause();
+
+        let control2 = control.clone();
+        let resumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            control2.resume();
+        });
+
+        let start = Instant::now();
+        let mut nblocks = 0;
+        stream_to_blocks_controlled(
+            &b"hello world"[..],
+            4,
+            b"secret",
+            &control,
+            |_, _| {
+                nblocks += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+        resumer.join().unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(3, nblocks);
+    }
+
+    #[test]
+    fn cancelled_transfer_fails_cleanly() {
+        let control = TransferControl::new();
+        let (blocklist, blocks) = to_blocklist(b"hello world", b"secret");
+
+        let mut nblocks = 0;
+        let r = stream_to_blocks_controlled(
+            &b"hello world"[..],
+            4,
+            b"secret",
+            &control,
+            |_, _| {
+                nblocks += 1;
+                if 2 == nblocks {
+                    control.cancel();
+                }
+                Ok(())
+            },
+        );
+        match r {
+            Err(Error(ErrorKind::TransferCancelled, _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(2, nblocks);
+
+        let mut output = Vec::new();
+        match blocks_to_stream_controlled(
+            &blocklist,
+            &mut output,
+            b"secret",
+            &control,
+            |h| Ok(&blocks[h][..]),
+        ) {
+            Err(Error(ErrorKind::TransferCancelled, _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn stalled_block_fetch_is_detected() {
+        let (blocklist, blocks) = to_blocklist(b"hello world", b"secret");
+
+        let watchdog = TransferWatchdog::new(Duration::from_millis(100));
+        let mut output = Vec::new();
+        let mut nfetched = 0;
+        match blocks_to_stream(&blocklist, &mut output, b"secret", |h| {
+            watchdog.watch(|| {
+                nfetched += 1;
+                if 2 == nfetched {
+                    thread::sleep(Duration::from_millis(300));
+                }
+                Ok(&blocks[h][..])
+            })
+        }) {
+            Err(Error(ErrorKind::StalledTransfer, _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(2, nfetched);
+        assert!(watchdog.tick().is_err());
+
+        // A transfer which keeps making progress is unaffected.
+        let watchdog = TransferWatchdog::new(Duration::from_secs(30));
+        let mut output = Vec::new();
+        blocks_to_stream(&blocklist, &mut output, b"secret", |h| {
+            watchdog.watch(|| Ok(&blocks[h][..]))
+        })
+        .unwrap();
+        assert_eq!(&b"hello world"[..], &output[..]);
+        watchdog.tick().unwrap();
+    }
+
+    #[test]
+    fn block_present_maps_storage_results() {
+        let storage = MemStorage {
+            failing: [2; 32],
+            ..MemStorage::default()
+        };
+        storage.putobj(1, &[1; 32], &[1; 32], b"data").unwrap();
+
+        assert!(block_present(&storage, &[1; 32]).unwrap());
+        assert!(!block_present(&storage, &[3; 32]).unwrap());
+        match block_present(&storage, &[2; 32]) {
+            Err(Error(ErrorKind::ServerConnectionClosed, _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
     #[test]
     fn text_blocked_and_deblocked_correctly() {
         let text = &b"hello world"[..];
@@ -321,6 +1553,241 @@ mod test {
         assert_eq!(text, &output[..]);
     }
 
+    #[test]
+    fn read_range_fetches_only_overlapping_blocks() {
+        let text = &b"hello world"[..];
+        let (blocklist, blocks) = to_blocklist(text, &b"secret"[..]);
+        assert_eq!(Some(vec![4, 4, 3]), blocklist.block_sizes);
+
+        let read = |offset, len| {
+            let mut fetched = Vec::new();
+            let mut output = Vec::new();
+            read_range_with(
+                HashVariant::default(),
+                &blocklist,
+                offset,
+                len,
+                &mut output,
+                b"secret",
+                |h| {
+                    fetched.push(
+                        blocklist.blocks.iter().position(|b| b == h).unwrap(),
+                    );
+                    Ok(&blocks[h][..])
+                },
+            )
+            .unwrap();
+            (output, fetched)
+        };
+
+        assert_eq!((b" wo".to_vec(), vec![1]), read(5, 3));
+        assert_eq!((b"lo wor".to_vec(), vec![0, 1, 2]), read(3, 6));
+        assert_eq!((b"lo w".to_vec(), vec![0, 1]), read(3, 4));
+        assert_eq!((b"ld".to_vec(), vec![2]), read(9, 100));
+        assert_eq!((vec![], vec![]), read(4, 0));
+        assert_eq!((vec![], vec![]), read(11, 5));
+
+        let mut unsized_blocklist = blocklist.clone();
+        unsized_blocklist.block_sizes = None;
+        match read_range_with(
+            HashVariant::default(),
+            &unsized_blocklist,
+            0,
+            1,
+            io::sink(),
+            b"secret",
+            |h| Ok(&blocks[h][..]),
+        ) {
+            Ok(_) => panic!("Read range without block sizes"),
+            Err(e) => match *e.kind() {
+                ErrorKind::BlockSizesUnknown => (),
+                _ => panic!("Unexpected error: {}", e),
+            },
+        }
+    }
+
+    fn assert_size_mismatch<T>(r: Result<T>) {
+        match r {
+            Ok(_) => panic!("Size check didn't fail!"),
+            Err(e) => match *e.kind() {
+                ErrorKind::StreamSizeMismatch(..) => (),
+                _ => panic!("Unexpected error: {}", e),
+            },
+        }
+    }
+
+    #[test]
+    fn tampered_size_detected_during_deblock() {
+        let secret = &b"secret"[..];
+        let text = &b"hello world"[..];
+        let (mut blocklist, blocks) = to_blocklist(text, secret);
+
+        let deblock = |blocklist: &BlockList| {
+            let mut output = Vec::new();
+            blocks_to_stream_checked_with(
+                HashVariant::default(),
+                blocklist,
+                &mut output,
+                secret,
+                |h| Ok(&blocks[h][..]),
+            )
+            .map(|_| output)
+        };
+        assert_eq!(text, &deblock(&blocklist).unwrap()[..]);
+
+        // Without the size index, only the bytes actually produced can catch
+        // the tampering.
+        blocklist.block_sizes = None;
+        for &size in &[10, 12, 0] {
+            blocklist.size = size;
+            assert!(blocklist.validate().is_ok());
+            assert_size_mismatch(deblock(&blocklist));
+            // The HMACs alone don't notice.
+            to_stream(&blocklist, &blocks, secret).unwrap();
+        }
+
+        // With it, the inconsistency is caught before fetching anything.
+        let (mut blocklist, _) = to_blocklist(text, secret);
+        blocklist.size = 12;
+        assert_size_mismatch(blocklist.validate());
+        assert_size_mismatch(blocks_to_stream_checked_with(
+            HashVariant::default(),
+            &blocklist,
+            io::sink(),
+            secret,
+            |_| -> Result<&'static [u8]> { panic!("Block fetched") },
+        ));
+
+        blocklist.size = 11;
+        blocklist.block_sizes.as_mut().unwrap().pop();
+        match blocklist.validate() {
+            Err(Error(ErrorKind::BlockCountMismatch(3, 2), _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn blocks_to_file_leaves_dst_untouched_on_failure() {
+        let secret = &b"secret"[..];
+        let text = &b"hello world, this is a test"[..];
+        let (blocklist, mut blocks) = to_blocklist(text, secret);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("dst");
+        fs::write(&dst, b"original").unwrap();
+
+        let corrupt = blocklist.blocks[blocklist.blocks.len() / 2];
+        blocks.get_mut(&corrupt).unwrap()[0] ^= 1;
+        assert_hmac_mismatch(blocks_to_file_with(
+            HashVariant::default(),
+            &blocklist,
+            &dst,
+            secret,
+            |h| Ok(&blocks[h][..]),
+        ));
+        assert_eq!(b"original", &fs::read(&dst).unwrap()[..]);
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+
+        blocks.get_mut(&corrupt).unwrap()[0] ^= 1;
+        blocks_to_file_with(
+            HashVariant::default(),
+            &blocklist,
+            &dst,
+            secret,
+            |h| Ok(&blocks[h][..]),
+        )
+        .unwrap();
+        assert_eq!(text, &fs::read(&dst).unwrap()[..]);
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn blocks_to_file_rejects_wrong_size() {
+        let secret = &b"secret"[..];
+        let text = &b"hello world"[..];
+        let (mut blocklist, blocks) = to_blocklist(text, secret);
+        blocklist.block_sizes = None;
+        blocklist.size = 10;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("dst");
+        match blocks_to_file_with(
+            HashVariant::default(),
+            &blocklist,
+            &dst,
+            secret,
+            |h| Ok(&blocks[h][..]),
+        ) {
+            Err(Error(ErrorKind::StreamSizeMismatch(10, 11), _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn stats_count_zero_blocks() {
+        let mut text = vec![0u8; 11];
+        text[5] = 1;
+        let (blocklist, _) = to_blocklist(&text, &b"secret"[..]);
+        assert_eq!(
+            Some(BlockStats {
+                blocks: 3,
+                bytes: 11,
+                zero_blocks: 2,
+                zero_bytes: 7,
+            }),
+            blocklist.stats
+        );
+
+        // Zero blocks must hash like any other block.
+        let (plain, _) = to_blocklist(&text[..4], &b"secret"[..]);
+        assert_eq!(hash_block(b"secret", &text[..4]), plain.blocks[0]);
+
+        let (empty, _) = to_blocklist(b"", &b"secret"[..]);
+        assert_eq!(Some(BlockStats::default()), empty.stats);
+
+        let mut total = BlockStats::default();
+        total.merge(blocklist.stats.as_ref().unwrap());
+        total.merge(plain.stats.as_ref().unwrap());
+        assert_eq!(4, total.blocks);
+        assert_eq!(15, total.bytes);
+        assert_eq!(3, total.zero_blocks);
+        assert_eq!(11, total.zero_bytes);
+    }
+
+    #[test]
+    fn expected_block_count_matches_blocking() {
+        assert_eq!(0, expected_block_count(0, 4));
+        assert_eq!(1, expected_block_count(3, 4));
+        assert_eq!(1, expected_block_count(4, 4));
+        assert_eq!(2, expected_block_count(8, 4));
+        assert_eq!(3, expected_block_count(11, 4));
+
+        for &len in &[0usize, 4, 11] {
+            let text = vec![b'x'; len];
+            let (blocklist, _) = to_blocklist(&text, &b"secret"[..]);
+            assert_eq!(
+                blocklist.blocks.len() as u64,
+                expected_block_count(len as FileSize, 4)
+            );
+        }
+    }
+
+    #[test]
+    fn uniform_block_sizes_matches_blocking() {
+        for &len in &[0usize, 4, 11] {
+            let text = vec![b'x'; len];
+            let (blocklist, _) = to_blocklist(&text, &b"secret"[..]);
+            assert_eq!(
+                blocklist.block_sizes,
+                uniform_block_sizes(len as FileSize, 4, blocklist.blocks.len())
+            );
+        }
+
+        assert_eq!(None, uniform_block_sizes(11, 4, 2));
+        assert_eq!(None, uniform_block_sizes(11, 0, 3));
+    }
+
     fn assert_hmac_mismatch<T>(r: Result<T>) {
         match r {
             Ok(_) => panic!("HMAC didn't fail!"),
@@ -362,4 +1829,244 @@ mod test {
 
         assert_hmac_mismatch(to_stream(&blocklist, &blocks, &b"secret"[..]));
     }
+
+    #[test]
+    fn hash_variants_are_distinct() {
+        let sha3 = hash_block_with(HashVariant::Sha3_256, b"secret", b"data");
+        let keccak =
+            hash_block_with(HashVariant::Keccak256, b"secret", b"data");
+        assert_ne!(sha3, keccak);
+
+        // The default must remain standard SHA3-256, or every existing store
+        // would become unreadable.
+        let mut expected = [0u8; 32];
+        let mut kc = Keccak::new_sha3_256();
+        kc.update(b"secretdata");
+        kc.finalize(&mut expected);
+        assert_eq!(HashVariant::Sha3_256, HashVariant::default());
+        assert_eq!(expected, hash_block(b"secret", b"data"));
+        assert_eq!(expected, sha3);
+
+        // A stream blocked with one variant can only be read back with the
+        // same one.
+        let text = &b"hello world"[..];
+        let mut blocks = HashMap::new();
+        let blocklist = stream_to_blocks_with(
+            HashVariant::Keccak256,
+            text,
+            4,
+            b"secret",
+            |&id, data| {
+                blocks.insert(id, data.to_vec());
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_hmac_mismatch(to_stream(&blocklist, &blocks, &b"secret"[..]));
+
+        let mut output = Vec::new();
+        blocks_to_stream_with(
+            HashVariant::Keccak256,
+            &blocklist,
+            &mut output,
+            b"secret",
+            |h| Ok(&blocks[h][..]),
+        )
+        .unwrap();
+        assert_eq!(text, &output[..]);
+
+        for &variant in &[
+            HashVariant::Sha3_256,
+            HashVariant::Keccak256,
+            HashVariant::Blake3,
+        ] {
+            assert_eq!(Some(variant), HashVariant::from_code(variant.code()));
+        }
+        assert_eq!(None, HashVariant::from_code(42));
+    }
+
+    #[test]
+    fn blake3_variant_round_trips_and_verifies() {
+        let key = blake3::derive_key(BLAKE3_KEY_CONTEXT, b"secret");
+        assert_eq!(
+            *blake3::keyed_hash(&key, b"data").as_bytes(),
+            hash_block_with(HashVariant::Blake3, b"secret", b"data")
+        );
+
+        let text = &b"hello world"[..];
+        let mut blocks = HashMap::new();
+        let blocklist = stream_to_blocks_with(
+            HashVariant::Blake3,
+            text,
+            4,
+            b"secret",
+            |&id, data| {
+                blocks.insert(id, data.to_vec());
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let deblock = |variant, secret: &[u8]| {
+            let mut output = Vec::new();
+            blocks_to_stream_with(
+                variant,
+                &blocklist,
+                &mut output,
+                secret,
+                |h| Ok(&blocks[h][..]),
+            )
+            .map(|_| output)
+        };
+        assert_eq!(text, &deblock(HashVariant::Blake3, b"secret").unwrap()[..]);
+        assert_hmac_mismatch(deblock(HashVariant::Blake3, b"geheimniss"));
+        assert_hmac_mismatch(deblock(HashVariant::Sha3_256, b"secret"));
+        assert_hmac_mismatch(deblock(HashVariant::Keccak256, b"secret"));
+    }
+
+    #[test]
+    fn verify_blocks_fetches_each_block_once() {
+        let (blocklist, mut blocks) = to_blocklist(b"hello world", b"secret");
+
+        let mut fetched = Vec::new();
+        verify_blocks_with(
+            HashVariant::default(),
+            &blocklist,
+            |id| {
+                fetched.push(*id);
+                Ok(&blocks[id][..])
+            },
+            b"secret",
+        )
+        .unwrap();
+        assert_eq!(blocklist.blocks, fetched);
+
+        let bad = blocklist.blocks[1];
+        blocks.insert(bad, b"XXXX".to_vec());
+        match verify_blocks_with(
+            HashVariant::default(),
+            &blocklist,
+            |id| Ok(&blocks[id][..]),
+            b"secret",
+        ) {
+            Err(Error(ErrorKind::HmacMismatch("block", id, _), _)) => {
+                assert_eq!(bad, id)
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        let mut tampered = blocklist.clone();
+        tampered.blocks.swap(0, 1);
+        match verify_blocks_with(
+            HashVariant::default(),
+            &tampered,
+            |id| Ok(&blocks[id][..]),
+            b"secret",
+        ) {
+            Err(Error(ErrorKind::HmacMismatch("total", ..), _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn progress_is_rate_limited_but_reports_completion() {
+        let text = b"0123456789abcdefghijklmnopqrstuvwxyz";
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let progress = {
+            let reports = reports.clone();
+            TransferProgress::with_interval(
+                text.len() as FileSize,
+                Duration::from_secs(3600),
+                Arc::new(move |done: FileSize, total: FileSize| {
+                    reports.lock().unwrap().push((done, total))
+                }),
+            )
+        };
+        let progress = Arc::new(Mutex::new(progress));
+
+        // Read through a reader which returns at most 4 bytes at a time, so
+        // that the progress is advanced many times.
+        let input = ProgressReader::new(
+            io::Read::chain(&text[..4], &text[4..]),
+            progress.clone(),
+        );
+        let blocklist =
+            stream_to_blocks(input, 4, b"secret", |_, _| Ok(())).unwrap();
+        assert_eq!(text.len() as FileSize, blocklist.size);
+
+        let total = text.len() as FileSize;
+        assert_eq!(vec![(4, total), (total, total)], *reports.lock().unwrap());
+
+        progress.lock().unwrap().reset();
+        progress.lock().unwrap().advance(1);
+        assert_eq!(
+            vec![(4, total), (total, total)],
+            *reports.lock().unwrap(),
+            "Report within the interval was not suppressed"
+        );
+    }
+
+    #[test]
+    fn parallel_blocking_matches_serial() {
+        let text: Vec<u8> =
+            (0..10000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        let mut serial_blocks = Vec::new();
+        let serial = stream_to_blocks(&text[..], 1024, b"secret", |&id, _| {
+            serial_blocks.push(id);
+            Ok(())
+        })
+        .unwrap();
+
+        for &threads in &[0, 1, 3, 10, 16] {
+            let pool = HashPool::new(threads);
+            // Hash twice to check that the pool's threads can be reused.
+            for _ in 0..2 {
+                let mut parallel_blocks = Vec::new();
+                let mut output = Vec::new();
+                let parallel = stream_to_blocks_parallel_with(
+                    HashVariant::default(),
+                    &text[..],
+                    1024,
+                    b"secret",
+                    &pool,
+                    |&id, data| {
+                        parallel_blocks.push(id);
+                        output.extend_from_slice(data);
+                        Ok(())
+                    },
+                )
+                .unwrap();
+
+                assert_eq!(serial.total, parallel.total);
+                assert_eq!(serial.blocks, parallel.blocks);
+                assert_eq!(serial.size, parallel.size);
+                assert_eq!(serial.block_sizes, parallel.block_sizes);
+                assert_eq!(serial.stats, parallel.stats);
+                assert_eq!(serial_blocks, parallel_blocks);
+                assert_eq!(text, output);
+            }
+        }
+
+        let empty = stream_to_blocks_parallel_with(
+            HashVariant::default(),
+            &b""[..],
+            1024,
+            b"secret",
+            &HashPool::new(4),
+            |_, _| panic!(),
+        )
+        .unwrap();
+        assert!(empty.blocks.is_empty());
+    }
+
+    #[test]
+    fn parallel_blocking_buffers_at_most_limit() {
+        let pool = HashPool::new(16);
+        assert_eq!(16, parallel_in_flight(&pool, 1024));
+        assert_eq!(4, parallel_in_flight(&pool, PARALLEL_BUFFER_LIMIT / 4));
+        // At least one block is always buffered, however large.
+        assert_eq!(1, parallel_in_flight(&pool, PARALLEL_BUFFER_LIMIT * 2));
+        assert_eq!(1, parallel_in_flight(&HashPool::new(0), 1024));
+    }
 }
@@ -16,33 +16,74 @@
 // You should have received a copy of the GNU General Public License along with
 // Ensync. If not, see <http://www.gnu.org/licenses/>.
 
+use std::fs;
 use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
+use libc::isatty;
 
+use crate::block_xfer::HashVariant;
 use crate::cli::config::*;
+use crate::cli::open_server::open_server_replica;
 use crate::errors::*;
 use crate::server::*;
 
 macro_rules! root_prompt {
-    ($root:expr) => {
-        || $root.read_passphrase("passphrase in `root` group", false)
-    };
+    ($root:expr) => {{
+        let mut root = $root.read_once("passphrase in `root` group", false);
+        move || root.get().map(|passphrase| passphrase.clone())
+    }};
 }
 
 pub fn init_keys(
     config: &Config,
     storage: &dyn Storage,
     name: &str,
+    algorithm: &str,
+    comment: Option<&str>,
+    record_key_use: bool,
 ) -> Result<()> {
-    keymgmt::init_keys(
+    let passphrase =
+        config.passphrase.read_passphrase("new passphrase", true)?;
+    warn_if_weak(&passphrase[..]);
+    keymgmt::init_keys_with(
         storage,
-        &config.passphrase.read_passphrase("new passphrase", true)?[..],
+        &passphrase[..],
         name,
+        algorithm,
+        comment,
+        false,
+        record_key_use,
     )
     .map(|_| ())
 }
 
+/// Prints a warning to stderr if `passphrase` is weak according to
+/// `keymgmt::assess_passphrase`.
+///
+/// Empty passphrases are left for the key store to reject.
+fn warn_if_weak(passphrase: &[u8]) {
+    match keymgmt::assess_passphrase(passphrase) {
+        keymgmt::PassphraseStrength::TooShort => {
+            let _ = writeln!(
+                io::stderr(),
+                "Warning: passphrase is shorter than {} characters",
+                keymgmt::MIN_PASSPHRASE_LEN
+            );
+        }
+        keymgmt::PassphraseStrength::LowEntropy => {
+            let _ = writeln!(
+                io::stderr(),
+                "Warning: passphrase looks easy to guess"
+            );
+        }
+        keymgmt::PassphraseStrength::Empty
+        | keymgmt::PassphraseStrength::Acceptable => (),
+    }
+}
+
 pub fn add_key(
     storage: &dyn Storage,
     old: &PassphraseConfig,
@@ -52,10 +93,15 @@ pub fn add_key(
 ) -> Result<()> {
     let old_pass = old.read_passphrase("old passphrase", false)?;
     let new_pass = new.read_passphrase("new passphrase", true)?;
+    warn_if_weak(&new_pass[..]);
     keymgmt::add_key(storage, &old_pass, &new_pass, name, root_prompt!(root))
 }
 
-pub fn list_keys(storage: &dyn Storage) -> Result<()> {
+pub fn list_keys(
+    storage: &dyn Storage,
+    sort: &str,
+    reverse: bool,
+) -> Result<()> {
     fn format_date(date: Option<&DateTime<Utc>>) -> String {
         if let Some(date) = date {
             super::format_date::format_date(date)
@@ -64,21 +110,192 @@ pub fn list_keys(storage: &dyn Storage) -> Result<()> {
         }
     }
 
-    let keys = keymgmt::list_keys(storage)?;
+    let keys =
+        keymgmt::list_keys_sorted(storage, parse_key_sort(sort)?, reverse)?;
     for key in keys {
         print!("{}:", key.name);
         for group in &key.groups {
             print!(" {}", group);
         }
         println!("");
+        if let Some(ref comment) = key.comment {
+            println!("  comment:      {}", comment);
+        }
         println!("  algorithm:    {}", key.algorithm);
         println!("  created:      {}", format_date(Some(&key.created)));
         println!("  last changed: {}", format_date(key.updated.as_ref()));
+        println!("  last used:    {}", format_date(key.used.as_ref()));
+        if key.has_clock_anomaly() {
+            println!(
+                "  warning:      these times are inconsistent; \
+                 a clock was probably wrong when one was recorded"
+            );
+        }
         println!("");
     }
     Ok(())
 }
 
+pub fn list_algorithms(storage: &dyn Storage) -> Result<()> {
+    for (algorithm, count) in keymgmt::algorithm_histogram(storage)? {
+        println!("{}: {}", algorithm, count);
+    }
+    Ok(())
+}
+
+pub fn probe(storage: &dyn Storage, key: &PassphraseConfig) -> Result<()> {
+    let pass = key.read_passphrase("passphrase", false)?;
+    let result = keymgmt::probe(storage, &pass)?;
+
+    println!(
+        "Key store:  {} ({} ms)",
+        if result.initialised {
+            "reachable"
+        } else {
+            "not initialised"
+        },
+        result.fetch_time.as_millis()
+    );
+    if !result.initialised {
+        return Err(ErrorKind::KdfListNotExists.into());
+    }
+
+    println!(
+        "Passphrase: {} ({} ms)",
+        if result.authenticated {
+            "accepted"
+        } else {
+            "rejected"
+        },
+        result.derive_time.as_millis()
+    );
+    if !result.authenticated {
+        return Err(ErrorKind::PassphraseNotInKdfList.into());
+    }
+
+    Ok(())
+}
+
+pub fn access(storage: &dyn Storage, key: &PassphraseConfig) -> Result<()> {
+    let pass = key.read_passphrase("passphrase", false)?;
+    let groups = keymgmt::groups_for_passphrase(storage, &pass)?;
+    let missing = keymgmt::missing_groups(storage, &pass)?;
+
+    println!("Groups:         {}", groups.join(", "));
+    if missing.is_empty() {
+        println!("Missing groups: none");
+    } else {
+        println!("Missing groups: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+pub fn check_keys(storage: &dyn Storage, names: Vec<String>) -> Result<()> {
+    let names = if names.is_empty() {
+        keymgmt::list_keys(storage)?
+            .into_iter()
+            .map(|key| key.name)
+            .collect()
+    } else {
+        names
+    };
+
+    let mut passphrases = Vec::with_capacity(names.len());
+    for name in &names {
+        let pass = PassphraseConfig::Prompt.read_passphrase(
+            &format!("passphrase for key `{}`", name),
+            false,
+        )?;
+        passphrases.push(pass);
+    }
+
+    let pairs: Vec<(&str, &[u8])> = names
+        .iter()
+        .zip(&passphrases)
+        .map(|(name, pass)| (name.as_str(), &pass[..]))
+        .collect();
+    keymgmt::full_consistency_check(storage, &pairs)?;
+    println!("{} key(s) are consistent", pairs.len());
+    Ok(())
+}
+
+pub fn rewrap_keys(
+    storage: &dyn Storage,
+    root: &PassphraseConfig,
+) -> Result<()> {
+    keymgmt::rewrap_keys(
+        storage,
+        |name| {
+            PassphraseConfig::Prompt.read_passphrase(
+                &format!("passphrase for key `{}`", name),
+                false,
+            )
+        },
+        root_prompt!(root),
+    )
+}
+
+pub fn backup_keys(storage: &dyn Storage, file: &Path) -> Result<()> {
+    let data = keymgmt::export_kdflist(storage)?;
+    fs::write(file, &data).chain_err(|| {
+        format!("Failed to write key store backup to {}", file.display())
+    })
+}
+
+pub fn restore_keys(
+    storage: &dyn Storage,
+    file: &Path,
+    force: bool,
+    merge: bool,
+    root: &PassphraseConfig,
+) -> Result<()> {
+    let data = fs::read(file).chain_err(|| {
+        format!("Failed to read key store backup from {}", file.display())
+    })?;
+    if merge {
+        keymgmt::import_kdflist(storage, &data, true, root_prompt!(root))
+    } else {
+        keymgmt::restore_kdflist(storage, &data, force, root_prompt!(root))
+    }
+}
+
+pub fn export_key(
+    storage: &dyn Storage,
+    name: &str,
+    file: &Path,
+) -> Result<()> {
+    let data = keymgmt::export_key(storage, name)?;
+    fs::write(file, &data).chain_err(|| {
+        format!("Failed to write key `{}` to {}", name, file.display())
+    })
+}
+
+pub fn import_key(
+    storage: &dyn Storage,
+    file: &Path,
+    root: &PassphraseConfig,
+) -> Result<()> {
+    let data = fs::read(file).chain_err(|| {
+        format!("Failed to read exported key from {}", file.display())
+    })?;
+    let pass = PassphraseConfig::Prompt
+        .read_passphrase("passphrase of imported key", false)?;
+    keymgmt::import_key(storage, &data, &pass, root_prompt!(root))
+}
+
+pub fn audit_log(storage: &dyn Storage, root: &PassphraseConfig) -> Result<()> {
+    let pass = root.read_passphrase("passphrase in `root` group", false)?;
+    for entry in keymgmt::read_audit_log(storage, &pass)? {
+        println!(
+            "{}  {} {}",
+            super::format_date::format_date(&entry.timestamp),
+            entry.operation,
+            entry.names.join(", ")
+        );
+    }
+    Ok(())
+}
+
 pub fn change_key(
     config: &Config,
     storage: &dyn Storage,
@@ -87,17 +304,42 @@ pub fn change_key(
     root: &PassphraseConfig,
     name: Option<&str>,
     allow_change_via_other_passphrase: bool,
+    algorithm: Option<&str>,
 ) -> Result<()> {
     let old_pass = old.read_passphrase("old passphrase", false)?;
     let new_pass = new.read_passphrase("new passphrase", true)?;
-    keymgmt::change_key(
-        storage,
-        &old_pass,
-        &new_pass,
-        name,
-        allow_change_via_other_passphrase,
-        root_prompt!(root),
-    )?;
+    warn_if_weak(&new_pass[..]);
+    if name.is_none() && 1 == unsafe { isatty(0) } {
+        keymgmt::change_key_resolving(
+            storage,
+            &old_pass,
+            &new_pass,
+            name,
+            allow_change_via_other_passphrase,
+            algorithm,
+            Some(&mut choose_key),
+            root_prompt!(root),
+        )?;
+    } else if algorithm.is_some() {
+        keymgmt::change_key_with_algorithm(
+            storage,
+            &old_pass,
+            &new_pass,
+            name,
+            allow_change_via_other_passphrase,
+            algorithm,
+            root_prompt!(root),
+        )?;
+    } else {
+        keymgmt::change_key(
+            storage,
+            &old_pass,
+            &new_pass,
+            name,
+            allow_change_via_other_passphrase,
+            root_prompt!(root),
+        )?;
+    }
 
     if config.passphrase == *old && config.passphrase != *new {
         println!(
@@ -109,6 +351,29 @@ pub fn change_key(
     Ok(())
 }
 
+/// Asks on the terminal which of `keys` to operate on, by number or name.
+fn choose_key(keys: &[keymgmt::KeyInfo]) -> Result<String> {
+    println!("There is more than one key. Which one should be changed?");
+    for (ix, key) in keys.iter().enumerate() {
+        match key.comment {
+            Some(ref comment) => {
+                println!("  {}) {} ({})", ix + 1, key.name, comment)
+            }
+            None => println!("  {}) {}", ix + 1, key.name),
+        }
+    }
+    print!("Key: ");
+    let _ = io::stdout().flush();
+
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice)?;
+    let choice = choice.trim();
+    match choice.parse::<usize>() {
+        Ok(n) if n >= 1 && n <= keys.len() => Ok(keys[n - 1].name.clone()),
+        _ => Ok(choice.to_owned()),
+    }
+}
+
 pub fn del_key(
     storage: &dyn Storage,
     name: &str,
@@ -117,39 +382,111 @@ pub fn del_key(
     keymgmt::del_key(storage, name, root_prompt!(root))
 }
 
+pub fn rename_key(
+    storage: &dyn Storage,
+    old_name: &str,
+    new_name: &str,
+    root: &PassphraseConfig,
+) -> Result<()> {
+    keymgmt::rename_key(storage, old_name, new_name, root_prompt!(root))
+}
+
+pub fn set_min_keys(
+    storage: &dyn Storage,
+    count: &str,
+    root: &PassphraseConfig,
+) -> Result<()> {
+    let min_keys = if "none" == count {
+        None
+    } else {
+        Some(
+            count
+                .parse()
+                .map_err(|_| format!("Invalid key count '{}'", count))?,
+        )
+    };
+    keymgmt::set_min_keys(storage, min_keys, root_prompt!(root))
+}
+
 pub fn create_group<IT: Iterator + Clone>(
     storage: &dyn Storage,
     key: &PassphraseConfig,
     root: &PassphraseConfig,
     names: IT,
+    verify: bool,
 ) -> Result<()>
 where
     IT::Item: AsRef<str>,
 {
     let pass = key.read_passphrase("passphrase", false)?;
 
-    keymgmt::create_group(storage, &pass, names, root_prompt!(root))
+    keymgmt::create_group_with(
+        storage,
+        &pass,
+        names,
+        verify,
+        root_prompt!(root),
+    )
+}
+
+pub fn list_groups(storage: &dyn Storage, group: Option<&str>) -> Result<()> {
+    if let Some(group) = group {
+        for key in keymgmt::keys_in_group(storage, group)? {
+            println!("{}", key.name);
+        }
+        return Ok(());
+    }
+
+    for group in keymgmt::list_groups(storage)? {
+        println!("{}: {}", group.name, group.keys.join(", "));
+    }
+    Ok(())
 }
 
 pub fn assoc_group<IT: Iterator + Clone>(
     storage: &dyn Storage,
     from: &PassphraseConfig,
-    to: &PassphraseConfig,
+    to: &[PassphraseConfig],
     root: &PassphraseConfig,
     names: IT,
+    skip_existing: bool,
+    verify: bool,
 ) -> Result<()>
 where
     IT::Item: AsRef<str>,
 {
     let from_pass =
         from.read_passphrase("passphrase with these groups", false)?;
-    let to_pass = to.read_passphrase("passphrase to receive groups", false)?;
 
-    keymgmt::assoc_group(
+    if !skip_existing && 1 == to.len() {
+        let to_pass =
+            to[0].read_passphrase("passphrase to receive groups", false)?;
+        return keymgmt::assoc_group_with(
+            storage,
+            &from_pass,
+            &to_pass,
+            names,
+            verify,
+            root_prompt!(root),
+        );
+    }
+
+    let mut to_passes = Vec::with_capacity(to.len());
+    for (ix, to) in to.iter().enumerate() {
+        to_passes.push(to.read_passphrase(
+            &format!("passphrase {} of {} to receive groups", ix + 1, to.len()),
+            false,
+        )?);
+    }
+    let to_passes: Vec<&[u8]> = to_passes.iter().map(|p| &p[..]).collect();
+
+    keymgmt::assoc_group_multi(
         storage,
         &from_pass,
-        &to_pass,
+        &to_passes,
         names,
+        skip_existing,
+        verify,
         root_prompt!(root),
     )
 }
@@ -163,7 +500,16 @@ pub fn disassoc_group<IT: Iterator + Clone>(
 where
     IT::Item: AsRef<str>,
 {
-    keymgmt::disassoc_group(storage, from, names, root_prompt!(root))
+    let remaining =
+        keymgmt::disassoc_group(storage, from, names, root_prompt!(root))?;
+    for group in remaining {
+        println!(
+            "Group {} now held by: {}",
+            group.name,
+            group.keys.join(", ")
+        );
+    }
+    Ok(())
 }
 
 pub fn destroy_group<IT: Iterator + Clone>(
@@ -196,3 +542,77 @@ If you are certain you want to do this, type \"yes\": "
 
     keymgmt::destroy_group(storage, names, root_prompt!(root))
 }
+
+/// Parses a field name as accepted by `key ls --sort`.
+fn parse_key_sort(name: &str) -> Result<keymgmt::KeySort> {
+    match name {
+        "name" => Ok(keymgmt::KeySort::Name),
+        "created" => Ok(keymgmt::KeySort::Created),
+        "updated" => Ok(keymgmt::KeySort::Updated),
+        "used" => Ok(keymgmt::KeySort::Used),
+        _ => Err(format!("Unknown key sort field '{}'", name).into()),
+    }
+}
+
+/// Parses a hash variant name as accepted by `key format --hash-variant`.
+fn parse_hash_variant(name: &str) -> Result<HashVariant> {
+    match name {
+        "sha3-256" => Ok(HashVariant::Sha3_256),
+        "keccak-256" => Ok(HashVariant::Keccak256),
+        "blake3" => Ok(HashVariant::Blake3),
+        _ => Err(format!("Unknown hash variant '{}'", name).into()),
+    }
+}
+
+/// Parses a key size in bits as accepted by `key format --cipher-width`.
+fn parse_cipher_width(bits: &str) -> Result<CipherWidth> {
+    match bits {
+        "128" => Ok(CipherWidth::Aes128),
+        "256" => Ok(CipherWidth::Aes256),
+        _ => Err(format!("Unsupported cipher width '{}'", bits).into()),
+    }
+}
+
+/// Parses a directory version format name as accepted by
+/// `key format --dir-format`.
+fn parse_dir_ver_fmt(name: &str) -> Result<u32> {
+    match name {
+        "legacy" => Ok(DIR_VER_FMT_LEGACY),
+        "mac" => Ok(DIR_VER_FMT_MAC),
+        _ => Err(format!("Unknown directory format '{}'", name).into()),
+    }
+}
+
+pub fn set_format(
+    config: &Config,
+    storage: Arc<dyn Storage>,
+    root: &PassphraseConfig,
+    hash_variant: Option<&str>,
+    cipher_width: Option<&str>,
+    dir_format: Option<&str>,
+) -> Result<()> {
+    let hash_variant = hash_variant.map(parse_hash_variant).transpose()?;
+    let cipher_width = cipher_width.map(parse_cipher_width).transpose()?;
+    let dir_format = dir_format.map(parse_dir_ver_fmt).transpose()?;
+
+    let mut get_root = root_prompt!(root);
+    if let Some(variant) = hash_variant {
+        keymgmt::set_hash_variant(&*storage, variant, &mut get_root)?;
+    }
+    if let Some(width) = cipher_width {
+        keymgmt::set_cipher_width(&*storage, width, &mut get_root)?;
+    }
+    // Done last, since opening the replica makes the store non-empty. A store
+    // which has never been opened has no directories to convert, so the
+    // format can just be recorded.
+    if let Some(fmt) = dir_format {
+        match keymgmt::set_dir_ver_fmt(&*storage, fmt, &mut get_root) {
+            Err(Error(ErrorKind::StoreNotEmpty, _)) => {
+                let replica = open_server_replica(config, storage, None)?;
+                replica.upgrade_dir_ver_fmt(fmt, &mut get_root)?;
+            }
+            r => r?,
+        }
+    }
+    Ok(())
+}
@@ -197,6 +197,7 @@ pub fn rmdir<'a, S: Storage + ?Sized, IT: Iterator<Item = impl AsRef<Path>>>(
 pub fn cat<'a, S: Storage + ?Sized, IT: Iterator<Item = impl AsRef<Path>>>(
     replica: &ServerReplica<S>,
     paths: IT,
+    range: Option<(FileSize, FileSize)>,
 ) -> Result<()> {
     for path in paths {
         let path: &Path = path.as_ref();
@@ -224,14 +225,30 @@ pub fn cat<'a, S: Storage + ?Sized, IT: Iterator<Item = impl AsRef<Path>>>(
                 format!("'{}' is not a regular file", path.display())
             })?;
         let stdout_handle = io::stdout();
-        block_xfer::blocks_to_stream(
-            &xfer.blocks,
-            stdout_handle.lock(),
-            replica.key_chain().obj_hmac_secret().chain_err(|| {
-                format!("Error transferring '{}'", path.display())
-            })?,
-            |id| xfer.fetch.fetch(id),
-        )
+        let variant = replica.key_chain().hash_variant;
+        let secret = replica
+            .key_chain()
+            .obj_hmac_secret()
+            .chain_err(|| format!("Error transferring '{}'", path.display()))?;
+        if let Some((offset, len)) = range {
+            block_xfer::read_range_with(
+                variant,
+                &xfer.blocks,
+                offset,
+                len,
+                stdout_handle.lock(),
+                secret,
+                |id| xfer.fetch.fetch(id),
+            )
+        } else {
+            block_xfer::blocks_to_stream_with(
+                variant,
+                &xfer.blocks,
+                stdout_handle.lock(),
+                secret,
+                |id| xfer.fetch.fetch(id),
+            )
+        }
         .chain_err(|| format!("Error transferring '{}'", path.display()))?;
     }
 
@@ -334,7 +351,8 @@ pub fn get<S: Storage + ?Sized, P1: AsRef<Path>, P2: AsRef<Path>>(
                             )
                         })?
                         .ok_or(ErrorKind::MissingXfer)?;
-                    block_xfer::blocks_to_stream(
+                    block_xfer::blocks_to_stream_with(
+                        replica.key_chain().hash_variant,
                         &xfer.blocks,
                         &mut tmpfile,
                         replica.key_chain().obj_hmac_secret().chain_err(
@@ -746,6 +764,105 @@ fn rm_one<S: Storage + ?Sized, P: AsRef<Path>>(
     }
 }
 
+pub fn verify<S: Storage + ?Sized, IT: Iterator<Item = impl AsRef<Path>>>(
+    replica: &ServerReplica<S>,
+    paths: IT,
+    parallelism: usize,
+    verbose: bool,
+) -> Result<()> {
+    let mut names = Vec::new();
+    let mut lists = Vec::new();
+
+    for path in paths {
+        let (dir, single) = navigate(replica, path.as_ref(), true)?;
+        collect_block_lists(
+            replica,
+            dir,
+            single.as_ref().map(|s| &**s),
+            &mut names,
+            &mut lists,
+        )?;
+    }
+
+    let mut bad = 0;
+    for (ix, result) in replica.verify_files(&lists, parallelism)? {
+        match result {
+            Ok(()) => {
+                if verbose {
+                    println!("{}: OK", names[ix]);
+                }
+            }
+            Err(e) => {
+                bad += 1;
+                println!("{}: {}", names[ix], e);
+            }
+        }
+    }
+
+    if bad > 0 {
+        Err(
+            format!("{} of {} file(s) failed verification", bad, lists.len())
+                .into(),
+        )
+    } else {
+        Ok(())
+    }
+}
+
+/// Collects the block list of every regular file within `dir`, recursively,
+/// into `lists`, and the full path of each into `names`.
+///
+/// If `single` is given, only the entry of `dir` with that name is considered.
+fn collect_block_lists<S: Storage + ?Sized>(
+    replica: &ServerReplica<S>,
+    mut dir: <ServerReplica<S> as Replica>::Directory,
+    single: Option<&OsStr>,
+    names: &mut Vec<String>,
+    lists: &mut Vec<block_xfer::BlockList>,
+) -> Result<()> {
+    let list = replica.list(&mut dir).chain_err(|| {
+        format!("Failed to list '{}'", dir.full_path().to_string_lossy())
+    })?;
+
+    let mut found = false;
+    for (name, fd) in list {
+        if single.map_or(false, |s| s != name) {
+            continue;
+        }
+        found = true;
+
+        let full_name = format!(
+            "{}/{}",
+            dir.full_path().to_string_lossy(),
+            name.to_string_lossy()
+        );
+
+        if fd.is_dir() {
+            let subdir = replica
+                .chdir(&dir, &name)
+                .chain_err(|| format!("Failed to enter '{}'", full_name))?;
+            collect_block_lists(replica, subdir, None, names, lists)?;
+        } else if let Some(xfer) = replica
+            .transfer(&dir, File(&name, &fd))
+            .chain_err(|| format!("Error fetching '{}'", full_name))?
+        {
+            names.push(full_name);
+            lists.push(xfer.blocks);
+        }
+    }
+
+    if let (false, Some(single)) = (found, single) {
+        return Err(format!(
+            "{}/{}: File not found",
+            dir.full_path().to_string_lossy(),
+            single.to_string_lossy()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
 fn navigate<S: Storage + ?Sized, P: AsRef<Path>>(
     replica: &ServerReplica<S>,
     path: P,
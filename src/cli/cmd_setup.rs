@@ -230,22 +230,7 @@ Does this look reasonable? ",
     };
 
     let config_file_name = Config::file_location(&full_config)?;
-    // Parse a dummy configuration so we get all the derived paths.
-    let dummy_config = Config::parse(
-        &config_file_name,
-        r#"
-[general]
-path = "\u0000"
-server = "path:\u0000"
-server_root = "/"
-passphrase = "file:/dev/null"
-compression = "none"
-
-[[rules.root.files]]
-mode = "---/---"
-"#,
-    )
-    .unwrap();
+    let dummy_config = parse_dummy_config(&config_file_name);
 
     fs::create_dir_all(&dummy_config.private_root).chain_err(|| {
         format!("Failed to create '{}'", dummy_config.private_root.display())
@@ -748,9 +733,31 @@ fn unix_permissions_survive<P: AsRef<Path>>(path: P) -> Result<bool> {
     Ok(0o111 == md.permissions().mode() & 0o777)
 }
 
+/// Parses a dummy configuration located at `config_file_name` so we get all
+/// the derived paths.
+fn parse_dummy_config(config_file_name: &Path) -> Config {
+    Config::parse(
+        config_file_name,
+        r#"
+[general]
+path = "\u0000"
+server = "path:\u0000"
+server_root = "dummy"
+passphrase = "file:/dev/null"
+compression = "none"
+
+[[rules.root.files]]
+mode = "---/---"
+"#,
+    )
+    .unwrap()
+}
+
 #[cfg(test)]
 mod test {
-    use super::shell_escape;
+    use std::path::Path;
+
+    use super::{parse_dummy_config, shell_escape};
 
     #[test]
     fn shell_escape_works() {
@@ -761,4 +768,15 @@ mod test {
         assert_eq!("'foo bar'", &shell_escape("foo bar"));
         assert_eq!("'o'\"'\"'ryan'", &shell_escape("o'ryan"));
     }
+
+    #[test]
+    fn dummy_config_parses() {
+        let config =
+            parse_dummy_config(Path::new("/nonexistent/ensync/config.toml"));
+        assert_eq!("dummy", config.server_root);
+        assert_eq!(
+            Path::new("/nonexistent/ensync/internal.ensync"),
+            config.private_root
+        );
+    }
 }
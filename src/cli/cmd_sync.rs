@@ -22,7 +22,7 @@ use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fmt;
 use std::fs;
-use std::io::{self, stderr, stdout, Read, Write};
+use std::io::{self, stderr, stdout, Read, Stdout, Write};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering::SeqCst;
@@ -93,12 +93,67 @@ impl<'a> fmt::Display for PathDisplay<'a, (&'a OsStr, &'a OsStr)> {
     }
 }
 
+fn pretty_size(mut size: u64) -> String {
+    let suffixes = ["bytes", "kB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
+    let mut suffix_ix = 0usize;
+    while size > 10000 {
+        size /= 1024;
+        suffix_ix += 1;
+    }
+
+    format!("{} {}", size, suffixes[suffix_ix])
+}
+
+fn name_side<S: Into<ReplicaSide>>(side: S) -> &'static str {
+    match side.into() {
+        ReplicaSide::Client => "local",
+        ReplicaSide::Server => "remote",
+        ReplicaSide::Ancestor => "ancestor",
+    }
+}
+
+fn name_error_operation(op: ErrorOperationKind) -> &'static str {
+    match op {
+        ErrorOperationKind::List => "list",
+        ErrorOperationKind::MarkClean => "mark clean",
+        ErrorOperationKind::Chdir => "enter directory",
+        ErrorOperationKind::Create => "create",
+        ErrorOperationKind::Update => "update",
+        ErrorOperationKind::Rename => "rename",
+        ErrorOperationKind::Remove => "remove",
+        ErrorOperationKind::Rmdir => "remove directory",
+        ErrorOperationKind::Access => "access",
+    }
+}
+
+/// The logger used by `ensync sync`, passing each event to every requested
+/// output.
+#[derive(Debug)]
+struct SyncLogger {
+    human: Option<FilterLogger<LoggerImpl>>,
+    json: Option<JsonLogger<Stdout>>,
+    itemised: Option<ItemisedLogger<Stdout>>,
+}
+
+impl Logger for SyncLogger {
+    fn log(&self, level: LogLevel, what: &Log) {
+        if let Some(ref human) = self.human {
+            human.log(level, what);
+        }
+        if let Some(ref json) = self.json {
+            json.log(level, what);
+        }
+        if let Some(ref itemised) = self.itemised {
+            itemised.log(level, what);
+        }
+    }
+}
+
+/// Writes human-readable log messages to stderr.
 #[derive(Debug)]
 struct LoggerImpl {
     client_root: PathBuf,
-    verbose_level: LogLevel,
     include_ops_under_opped_directory: bool,
-    itemise_level: LogLevel,
     include_ancestors: bool,
     colour: bool,
     created_directories: RwLock<HashSet<PathBuf>>,
@@ -123,12 +178,7 @@ struct ReplicaSpinState {
 
 impl Logger for LoggerImpl {
     fn log(&self, level: LogLevel, what: &Log) {
-        if level <= self.verbose_level {
-            self.write_human_readable(level, what);
-        }
-        if level <= self.itemise_level {
-            self.write_itemised(what);
-        }
+        self.write_human_readable(level, what);
     }
 }
 
@@ -148,14 +198,6 @@ impl LoggerImpl {
             }
         }
 
-        fn name_side<S: Into<ReplicaSide>>(side: S) -> &'static str {
-            match side.into() {
-                ReplicaSide::Client => "local",
-                ReplicaSide::Server => "remote",
-                ReplicaSide::Ancestor => "ancestor",
-            }
-        }
-
         fn name_edit(e: ConflictingEdit) -> &'static str {
             match e {
                 ConflictingEdit::Mode => "file mode",
@@ -163,18 +205,6 @@ impl LoggerImpl {
             }
         }
 
-        fn pretty_size(mut size: u64) -> String {
-            let suffixes =
-                ["bytes", "kB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
-            let mut suffix_ix = 0usize;
-            while size > 10000 {
-                size /= 1024;
-                suffix_ix += 1;
-            }
-
-            format!("{} {}", size, suffixes[suffix_ix])
-        }
-
         struct FDD<'a>(&'a FileData);
         impl<'a> fmt::Display for FDD<'a> {
             fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -493,6 +523,11 @@ impl LoggerImpl {
                     }
                 }
             }
+
+            // These are for consumers which track the tree structure; they
+            // would only be noise here.
+            Log::EnterDirectory(..) | Log::LeaveDirectory(..) => {}
+            Log::Progress(..) => {}
         }
 
         if reprint_spin {
@@ -514,247 +549,6 @@ impl LoggerImpl {
             }
         }
     }
-
-    fn write_itemised(&self, what: &Log) {
-        // Match the format output by rsync as best we can
-        // The rsync format is an 11-character string which is either the
-        // following sequence of flags, or a '*', and a short message,
-        // right-padded.
-        //
-        // 0. Update type
-        //    < Transfer to remote host
-        //    > Transfer to local host
-        //    c Item being created
-        //    h Create hard link (we don't support this)
-        //    . No update
-        //
-        // 1. File type
-        //    f Regular
-        //    d Directory
-        //    L Symlink
-        //    D Device (we don't distinguish from special)
-        //    S Special
-        //
-        // 2. 'c' if content change, fill otherwise.
-        //
-        // 3. 's' file size changed, fill otherwise.
-        //
-        // 4. 't' file modification time changed, fill otherwise.
-        //
-        // 5. 'p' file mode changed, fill otherwise.
-        //
-        // 6. 'o' owner changed. We don't track this, so always fill.
-        //
-        // 7. 'g' group changed. We don't track this, so always fill.
-        //
-        // 8. 'f' "fileflags" changed. Again, always fill.
-        //
-        // 9. 'a' ACL changed. Always fill.
-        //
-        // 10. 'x' extended attributes changed. Always fill.
-        //
-        // The fill character is '.' by default. If something is being created,
-        // it is instead '+'. If the item is being completely unchanged, it is
-        // ' ' instead.
-        //
-        // We need to extend this a bit. For the most part, this is simply a
-        // matter of using more '*'-format things, but renaming is complicated
-        // by the fact that there are two filenames in play. We handle this by
-        // emitting consecutive `*renamefrom` and `*renameto  ` lines.
-
-        let stdout_handle = stdout();
-        let mut stdout_lock = stdout_handle.lock();
-
-        #[derive(Debug, Clone, Copy, Default)]
-        struct LineItem {
-            update_type: Option<char>,
-            file_type: Option<char>,
-            content_change: bool,
-            size_change: bool,
-            time_change: bool,
-            mode_change: bool,
-            fill: Option<char>,
-        }
-
-        impl fmt::Display for LineItem {
-            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                trait Ifc {
-                    fn ifc(self, ifc: char, fill: char) -> char;
-                }
-                impl Ifc for bool {
-                    fn ifc(self, ifc: char, fill: char) -> char {
-                        if self {
-                            ifc
-                        } else {
-                            fill
-                        }
-                    }
-                }
-
-                let fill = self.fill.unwrap_or('.');
-                write!(
-                    f,
-                    "{}{}{}{}{}{}{}{}{}{}{}",
-                    self.update_type.unwrap_or(fill),
-                    self.file_type.unwrap_or(fill),
-                    self.content_change.ifc('c', fill),
-                    self.size_change.ifc('s', fill),
-                    self.time_change.ifc('t', fill),
-                    self.mode_change.ifc('p', fill),
-                    fill,
-                    fill,
-                    fill,
-                    fill,
-                    fill
-                )
-            }
-        }
-
-        macro_rules! say {
-            ($item:expr, $path:expr) => {{
-                let _ = writeln!(
-                    stdout_lock,
-                    "{:<11} {}",
-                    $item,
-                    PathDisplay(&self.client_root, $path)
-                );
-            }};
-        }
-
-        fn file_type(fd: &FileData) -> Option<char> {
-            match *fd {
-                FileData::Regular(..) => Some('f'),
-                FileData::Directory(..) => Some('d'),
-                FileData::Symlink(..) => Some('L'),
-                FileData::Special => Some('S'),
-            }
-        }
-
-        fn update_type(side: ReplicaSide, data: &FileData) -> Option<char> {
-            match (side, data) {
-                (ReplicaSide::Client, &FileData::Regular(..)) => Some('>'),
-                (ReplicaSide::Server, &FileData::Regular(..)) => Some('<'),
-                _ => Some('c'),
-            }
-        }
-
-        fn nan(side: ReplicaSide) -> bool {
-            ReplicaSide::Ancestor != side
-        }
-
-        match *what {
-            Log::Error(..) => {}
-            Log::RecursiveDelete(..) => {}
-
-            Log::Inspect(parent, name, Reconciliation::InSync, _)
-            | Log::Inspect(parent, name, Reconciliation::Unsync, _)
-            | Log::Inspect(parent, name, Reconciliation::Irreconcilable, _) => {
-                // We can't really output a file type even if the data were
-                // included in this log type, since the three replicas could
-                // each have a different file type.
-                say!(
-                    LineItem {
-                        fill: Some(' '),
-                        update_type: Some('.'),
-                        file_type: Some('?'),
-                        ..LineItem::default()
-                    },
-                    (parent, name)
-                );
-            }
-
-            Log::Inspect(..) => {}
-
-            Log::Create(side, parent, name, data) => {
-                if nan(side) {
-                    say!(
-                        LineItem {
-                            fill: Some('+'),
-                            update_type: update_type(side, data),
-                            file_type: file_type(data),
-                            ..LineItem::default()
-                        },
-                        (parent, name)
-                    )
-                }
-            }
-
-            Log::Update(side, parent, name, old, new) => {
-                if nan(side) {
-                    let (content_change, size_change, time_change, mode_change) =
-                        match (old, new) {
-                            (
-                                &FileData::Regular(
-                                    mode1,
-                                    size1,
-                                    time1,
-                                    content1,
-                                ),
-                                &FileData::Regular(
-                                    mode2,
-                                    size2,
-                                    time2,
-                                    content2,
-                                ),
-                            ) => (
-                                content1 != content2,
-                                size1 != size2,
-                                time1 != time2,
-                                mode1 != mode2,
-                            ),
-
-                            (
-                                &FileData::Symlink(..),
-                                &FileData::Symlink(..),
-                            ) => (true, false, false, false),
-
-                            (
-                                &FileData::Directory(..),
-                                &FileData::Directory(..),
-                            ) => (false, false, false, true),
-
-                            _ => (true, false, false, true),
-                        };
-
-                    say!(
-                        LineItem {
-                            update_type: if content_change {
-                                update_type(side, new)
-                            } else {
-                                Some('.')
-                            },
-                            file_type: file_type(new),
-                            content_change: content_change,
-                            mode_change: mode_change,
-                            time_change: time_change,
-                            size_change: size_change,
-                            ..LineItem::default()
-                        },
-                        (parent, name)
-                    );
-                }
-            }
-
-            Log::Rename(side, parent, old, new) => {
-                if nan(side) {
-                    say!("*renamefrom", (parent, old));
-                    say!("*renameto", (parent, new));
-                }
-            }
-
-            Log::Remove(side, parent, name, _) => {
-                if nan(side) {
-                    say!("*delete", (parent, name));
-                }
-            }
-
-            Log::Rmdir(side, path) => {
-                if nan(side) {
-                    say!("*delete", path);
-                }
-            }
-        }
-    }
 }
 
 pub fn run(
@@ -766,9 +560,13 @@ pub fn run(
     itemise_unchanged: bool,
     colour: &str,
     spin: &str,
+    log_format: &str,
     include_ancestors: bool,
     dry_run: bool,
+    estimate: bool,
+    stats: bool,
     watch: Option<u64>,
+    stall_timeout: Option<Duration>,
     num_threads: u32,
     prepare_type: &str,
     override_mode: Option<rules::SyncMode>,
@@ -785,10 +583,11 @@ pub fn run(
         "auto" => 1 == unsafe { isatty(2) },
         _ => false,
     };
+    let json = "json" == log_format;
     let spin = match spin {
         "never" => false,
         "always" => true,
-        "auto" => 1 == unsafe { isatty(2) },
+        "auto" => 1 == unsafe { isatty(2) } && !json,
         _ => false,
     };
     let prepare_type = match prepare_type {
@@ -808,8 +607,20 @@ pub fn run(
     if key_chain.is_none() {
         let passphrase =
             config.passphrase.read_passphrase("passphrase", false)?;
-        *key_chain =
-            Some(Arc::new(keymgmt::derive_key_chain(&*storage, &passphrase)?));
+        // Installed only now so that interrupting the prompt still just
+        // kills the process, but interrupting derivation stops it cleanly.
+        interrupt::install_signal_handler();
+        let mut chain = if dry_run {
+            keymgmt::derive_key_chain(&*storage, &passphrase)?
+        } else {
+            keymgmt::record_key_use_throttled(
+                &*storage,
+                &passphrase,
+                chrono::Duration::seconds(config.key_use_interval as i64),
+            )?
+        };
+        chain.block_secret = config.read_block_secret()?;
+        *key_chain = Some(Arc::new(chain));
     }
 
     let key_chain = key_chain.as_ref().unwrap().clone();
@@ -831,6 +642,10 @@ pub fn run(
         config.block_size as usize,
     )
     .chain_err(|| "Failed to set up client replica")?;
+    client_replica.set_hash_variant(key_chain.hash_variant);
+    if let Some(stall_timeout) = stall_timeout {
+        client_replica.set_stall_timeout(stall_timeout);
+    }
 
     let ancestor_replica = AncestorReplica::open(
         config
@@ -862,17 +677,23 @@ pub fn run(
 
     let level = max(FATAL as i32, min(255, nominal_log_level)) as LogLevel;
 
-    let log = LoggerImpl {
+    if level >= INFO {
+        let client_root = config.client_root.to_owned();
+        client_replica.set_preflight(Arc::new(move |path, blocks, size| {
+            perrln!(
+                "\x1B[K[INFO ] local  {}{}{}: Reconstructing ({} blocks, {})",
+                if colour { "\x1B[1m" } else { "" },
+                PathDisplay(&client_root, path.as_os_str()),
+                if colour { "\x1B[0m" } else { "" },
+                blocks,
+                pretty_size(size)
+            );
+        }));
+    }
+
+    let human = LoggerImpl {
         client_root: config.client_root.to_owned(),
-        verbose_level: level,
         include_ops_under_opped_directory: include_ops_under_opped_directory,
-        itemise_level: if !itemise {
-            0
-        } else if !itemise_unchanged {
-            EDIT
-        } else {
-            INFO
-        },
         include_ancestors: include_ancestors,
         colour: colour,
         created_directories: RwLock::new(HashSet::new()),
@@ -883,6 +704,27 @@ pub fn run(
             None
         },
     };
+    let log = Arc::new(SummaryLogger::new(SyncLogger {
+        human: if json {
+            None
+        } else {
+            Some(FilterLogger::new(human, level))
+        },
+        json: if json {
+            Some(JsonLogger::new(stdout(), level))
+        } else {
+            None
+        },
+        itemised: if itemise {
+            Some(ItemisedLogger::new(
+                stdout(),
+                config.client_root.to_owned(),
+                itemise_unchanged,
+            ))
+        } else {
+            None
+        },
+    }));
 
     interrupt::install_signal_handler();
 
@@ -898,7 +740,9 @@ pub fn run(
             cli: DryRunReplica(client_replica),
             anc: DryRunReplica(ancestor_replica),
             srv: DryRunReplica(server_replica),
-            log: Box::new(log),
+            log: log.clone(),
+            resolver: None,
+            conflict_suffix: config.conflict_suffix.clone(),
             root_rules: rules::engine::FileEngine::new(rules),
             work: work_stack::WorkStack::new(),
             tasks: reconcile::UnqueuedTasks::new(),
@@ -906,6 +750,7 @@ pub fn run(
 
         run_sync(
             context,
+            &log,
             level,
             num_threads,
             prepare_type,
@@ -914,6 +759,46 @@ pub fn run(
             spin,
         )
     } else {
+        let (mut client_replica, ancestor_replica, mut server_replica) =
+            if estimate {
+                client_replica
+                    .prepare(prepare_type)
+                    .chain_err(|| "Scanning for local changes failed")?;
+                server_replica
+                    .prepare(prepare_type)
+                    .chain_err(|| "Scanning for remote changes failed")?;
+                let (estimate, cli, anc, srv) = reconcile::estimate::plan(
+                    client_replica,
+                    ancestor_replica,
+                    server_replica,
+                    rules::engine::FileEngine::new(rules.clone()),
+                )
+                .chain_err(|| "Failed to estimate the work to do")?;
+                if level >= EDIT {
+                    let upload = crate::server::estimate::estimate_storage(
+                        estimate.upload_sizes.iter().cloned(),
+                        config.block_size as usize,
+                        config.compression.level() > 0,
+                    );
+                    perrln!(
+                        "Estimated work: {} files ({}) to transfer, \
+                         {} conflicts; {} uploads take up to {} on the \
+                         server{}",
+                        estimate.files,
+                        pretty_size(estimate.bytes),
+                        estimate.conflicts,
+                        upload.files,
+                        pretty_size(upload.server_bytes),
+                        upload.amplification().map_or(String::new(), |a| {
+                            format!(" ({:.2}x their size)", a)
+                        })
+                    );
+                }
+                (cli, anc, srv)
+            } else {
+                (client_replica, ancestor_replica, server_replica)
+            };
+
         let watch_handle = Arc::new(WatchHandle::new()?);
         if let Some(seconds) = watch {
             let debounce = Duration::new(seconds, 0);
@@ -931,7 +816,9 @@ pub fn run(
             cli: client_replica,
             anc: ancestor_replica,
             srv: server_replica,
-            log: Box::new(log),
+            log: log.clone(),
+            resolver: None,
+            conflict_suffix: config.conflict_suffix.clone(),
             root_rules: rules::engine::FileEngine::new(rules),
             work: work_stack::WorkStack::new(),
             tasks: reconcile::UnqueuedTasks::new(),
@@ -939,6 +826,7 @@ pub fn run(
 
         run_sync(
             context.clone(),
+            &log,
             level,
             num_threads,
             prepare_type,
@@ -946,6 +834,9 @@ pub fn run(
             true,
             spin,
         )?;
+        if stats {
+            print_upload_stats(&context.srv.take_upload_stats());
+        }
 
         if watch.is_some() && !interrupt::is_interrupted() && level >= EDIT {
             perrln!(
@@ -973,6 +864,7 @@ pub fn run(
 
                 run_sync(
                     context.clone(),
+                    &log,
                     level,
                     num_threads,
                     if watch_handle.check_context_lost() {
@@ -984,6 +876,9 @@ pub fn run(
                     false,
                     spin,
                 )?;
+                if stats {
+                    print_upload_stats(&context.srv.take_upload_stats());
+                }
             }
         }
 
@@ -998,6 +893,7 @@ fn run_sync<
         + 'static,
 >(
     context: Arc<reconcile::Context<CLI, ANC, SRV>>,
+    summary: &SummaryLogger<SyncLogger>,
     level: LogLevel,
     num_threads: u32,
     prepare_type: PrepareType,
@@ -1098,6 +994,23 @@ fn run_sync<
         }
     }
 
+    let summary = summary.take();
+    if summary.total_errors() > 0 && level >= ERROR {
+        let counts: Vec<String> = summary
+            .errors
+            .iter()
+            .map(|(&(side, op), n)| {
+                format!(
+                    "{} {} {}",
+                    n,
+                    name_side(side),
+                    name_error_operation(op)
+                )
+            })
+            .collect();
+        perrln!("Errors: {}", counts.join(", "));
+    }
+
     if level >= EDIT && show_messages {
         perrln!("Cleaning up...");
     }
@@ -1118,6 +1031,53 @@ fn run_sync<
     Ok(())
 }
 
+fn print_upload_stats(stats: &UploadStats) {
+    perrln!(
+        "Uploaded {} blocks ({}), of which {} ({}) were already on the \
+         server and {} ({}) were all zeroes",
+        stats.blocks.blocks,
+        pretty_size(stats.blocks.bytes),
+        stats.present_blocks,
+        pretty_size(stats.present_bytes),
+        stats.blocks.zero_blocks,
+        pretty_size(stats.blocks.zero_bytes)
+    );
+}
+
+pub fn explain_path(config: &Config, path: &OsStr) -> Result<()> {
+    use crate::rules::engine::ConflictPolicy::*;
+
+    let explanation = config.sync_rules.explain(path);
+    println!(
+        "Rule:            {}",
+        explanation.rule.as_ref().map_or("(none)", |r| &r[..])
+    );
+    println!("Mode:            {}", explanation.mode);
+    println!(
+        "Trust unix mode: {}",
+        if explanation.trust_client_unix_mode {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+    if let Some(block_size) = explanation.block_size {
+        println!("Block size:      {}", block_size);
+    }
+    println!(
+        "Edit conflicts:  {}",
+        match explanation.conflict_policy {
+            Ignore => "ignored",
+            KeepBoth => "both versions kept",
+            Irreconcilable => "left unresolved",
+            PreferClient => "client version wins",
+            PreferServer => "server version wins",
+            PreferNewer => "newer version wins",
+        }
+    );
+    Ok(())
+}
+
 fn check_for_copied_private_dir(private_dir: &Path) -> Result<()> {
     const FSID_VERSION: &str = "FSID2:";
 
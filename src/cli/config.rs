@@ -16,26 +16,44 @@
 // You should have received a copy of the GNU General Public License along with
 // Ensync. If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::ffi::OsString;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
 use std::process;
 use std::result::Result as StdResult;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use flate2;
+#[cfg(feature = "passphrase-keyring")]
+use keyring;
+use rand::{rngs::OsRng, Rng};
 #[cfg(feature = "passphrase-prompt")]
 use rpassword;
 use tiny_keccak;
 use toml;
 
-use crate::defs::{HashId, PRIVATE_DIR_NAME};
+use crate::defs::{HashId, Passphrase, PRIVATE_DIR_NAME};
 use crate::errors::*;
+use crate::reconcile::compute::ConflictSuffix;
 use crate::rules::engine::SyncRules;
 
 const CONFIG_FILE_NAME: &'static str = "config.toml";
+const STDIN_FILE_NAME: &'static str = "-";
+
+/// The block size used when the configuration does not specify one.
+///
+/// This shaves a bit off of 1MB to account for gzip headers, so if there are a
+/// lot of large uncompressible blocks, they do not just barely spill over into
+/// another allocation unit.
+pub const DEFAULT_BLOCK_SIZE: u32 = 1024 * 1024 - 512;
 
 #[derive(Clone)]
 pub struct Config {
@@ -54,9 +72,19 @@ pub struct Config {
     pub block_size: u32,
     /// The compression level to use.
     pub compression: flate2::Compression,
+    /// The minimum number of seconds between updates of the last-used time of
+    /// the key used to sync.
+    pub key_use_interval: u32,
+    /// The template for the names of files renamed aside to keep both
+    /// versions of a conflict, or `None` to use the default `~N` naming.
+    pub conflict_suffix: Option<ConflictSuffix>,
+    /// If set, a file whose content is used as the secret keying block ids,
+    /// instead of the secret derived from the key store.
+    pub block_secret_file: Option<PathBuf>,
     /// The sync rules to use for reconciliation.
     pub sync_rules: Arc<SyncRules>,
-    /// The hash of the raw configuration text.
+    /// The hash of the raw configuration text, and that of any files named by
+    /// `include`.
     pub hash: HashId,
 }
 
@@ -79,11 +107,22 @@ pub enum PassphraseConfig {
     String(String),
     /// Use the binary content, excluding any trailing LF or CR characters, of
     /// the named file as the passphrase. Fail if the file cannot be read.
-    File(PathBuf),
+    ///
+    /// If the flag is set, the file is overwritten with random bytes and
+    /// truncated after it is read.
+    File(PathBuf, bool),
     /// Invoke the given shell command and use its full binary output,
     /// excluding any trailing LF or CR characters, as the passphrase. Fail if
     /// the command does not exit successfully or emits no output.
     Shell(String, Option<PathBuf>),
+    /// Use the binary value, excluding any trailing LF or CR characters, of
+    /// the named environment variable as the passphrase. Fail if the variable
+    /// is not set.
+    Env(String),
+    /// Use the password stored in the system keyring under the given service
+    /// and account, verbatim, as the passphrase. Fail if there is no such
+    /// entry or Ensync was built without keyring support.
+    Keyring { service: String, account: String },
 }
 
 impl Config {
@@ -112,7 +151,17 @@ impl Config {
     /// passed through `file_location` so that this function can tolerate
     /// relative paths and references to the whole directory instead of the
     /// configuration itself.
+    ///
+    /// If `filename` is `-`, the configuration is instead read from standard
+    /// input, relative to the current directory (see `read_stdin`).
     pub fn read<P: AsRef<Path>>(filename: P) -> Result<Self> {
+        if STDIN_FILE_NAME == filename.as_ref().as_os_str() {
+            return Self::read_stdin(
+                env::current_dir()
+                    .chain_err(|| "Failed to determine current directory")?,
+            );
+        }
+
         let filename = Self::file_location(filename)?;
 
         let mut text = String::new();
@@ -123,7 +172,61 @@ impl Config {
         Self::parse(&filename, &text)
     }
 
+    /// Loads the configuration from standard input.
+    ///
+    /// Since there is no file whose parent could be used, relative paths in
+    /// the configuration (including the private directory) are resolved
+    /// against `base_dir` instead.
+    pub fn read_stdin<P: AsRef<Path>>(base_dir: P) -> Result<Self> {
+        Self::read_from(io::stdin(), base_dir)
+    }
+
+    /// Loads the configuration from `src`, resolving relative paths against
+    /// `base_dir`.
+    pub fn read_from<R: Read, P: AsRef<Path>>(
+        mut src: R,
+        base_dir: P,
+    ) -> Result<Self> {
+        let mut base_dir = base_dir.as_ref().to_owned();
+        if base_dir.is_relative() {
+            base_dir = env::current_dir()
+                .chain_err(|| "Failed to determine current directory")?
+                .join(base_dir);
+        }
+
+        let mut text = String::new();
+        src.read_to_string(&mut text)
+            .map_err(|e| format!("<stdin>: {}", e))?;
+
+        Self::parse_in("<stdin>".as_ref(), &base_dir, &text)
+    }
+
+    /// Reads the secret from `block_secret_file`, if configured.
+    ///
+    /// The whole binary content of the file is the secret; it must not be
+    /// empty.
+    pub fn read_block_secret(&self) -> Result<Option<Passphrase>> {
+        let path = match self.block_secret_file {
+            Some(ref path) => path,
+            None => return Ok(None),
+        };
+
+        let mut data = Vec::new();
+        fs::File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .chain_err(|| {
+                format!("Failed to read block secret '{}'", path.display())
+            })?;
+        if data.is_empty() {
+            bail!(format!("Block secret '{}' is empty", path.display()));
+        }
+        Ok(Some(data.into()))
+    }
+
     /// Returns the path to the configuration file itself.
+    ///
+    /// For configurations read from standard input, this is the path the file
+    /// would have had within the base directory.
     pub fn full_path(&self) -> PathBuf {
         self.private_root
             .parent()
@@ -135,18 +238,19 @@ impl Config {
     /// the text was loaded and must end with `CONFIG_FILE_NAME` and have a
     /// parent.
     pub fn parse<P: AsRef<Path>>(filename: P, s: &str) -> Result<Self> {
-        let hash = {
-            let mut hash = HashId::default();
-            let mut kc = tiny_keccak::Keccak::new_sha3_256();
-            kc.update(s.as_bytes());
-            kc.finalize(&mut hash);
-            hash
-        };
-
         let filename = filename.as_ref();
         assert!(filename.ends_with(CONFIG_FILE_NAME));
         let parent = filename.parent().expect("Config path missing parent");
 
+        Self::parse_in(filename, parent, s)
+    }
+
+    /// Parses the configuration in `s`. `filename` is only used in error
+    /// messages; relative paths are resolved against `parent`.
+    fn parse_in(filename: &Path, parent: &Path, s: &str) -> Result<Self> {
+        let mut kc = tiny_keccak::Keccak::new_sha3_256();
+        kc.update(s.as_bytes());
+
         let table: toml::value::Table = toml::from_str(s).map_err(|e| {
             format!("{}: Syntax error: {}", filename.display(), e)
         })?;
@@ -229,7 +333,19 @@ impl Config {
         }
 
         let general = extract!(table, "top level", [general])?;
-        let rules = extract!(table, "top level", [rules])?;
+        let includes = parse_includes(filename, general)?;
+        let mut rules = if includes.is_empty() || table.contains_key("rules") {
+            extract!(table, "top level", [rules])?.clone()
+        } else {
+            toml::value::Table::new()
+        };
+        merge_included_rules(filename, parent, &includes, &mut rules, &mut kc)?;
+
+        let hash = {
+            let mut hash = HashId::default();
+            kc.finalize(&mut hash);
+            hash
+        };
 
         Ok(Config {
             client_root: parent.join(extract!(
@@ -245,8 +361,11 @@ impl Config {
                 .parse::<ServerConfig>()
                 .map_err(|e| format!("{}: {}", filename.display(), e))?
                 .relativise(parent),
-            server_root: extract!(general, "[general]", server_root, str)?
-                .to_owned(),
+            server_root: validate_server_root(
+                filename,
+                extract!(general, "[general]", server_root, str)?,
+            )?
+            .to_owned(),
             passphrase: extract!(general, "[general]", passphrase, str)?
                 .parse::<PassphraseConfig>()
                 .map_err(|e| format!("{}: {}", filename.display(), e))?
@@ -256,12 +375,8 @@ impl Config {
                     general,
                     "[general]",
                     block_size,
-                    // Shave a bit off of 1MB to account for gzip
-                    // headers, so if there are a lot of large
-                    // uncompressible blocks, they do not just
-                    // barely spill over into another allocation
-                    // unit.
-                    i64 = Some(&toml::Value::Integer(1024 * 1024 - 512))
+                    i64 =
+                        Some(&toml::Value::Integer(DEFAULT_BLOCK_SIZE as i64))
                 )?;
                 // There is strictly speaking nothing preventing use of really
                 // tiny or really large blocks, but it is not useful either, so
@@ -294,6 +409,55 @@ impl Config {
                 parse_compression_name(filename, name)?
             },
 
+            key_use_interval: {
+                let interval = extract!(
+                    general,
+                    "[general]",
+                    key_use_interval,
+                    i64 = Some(&toml::Value::Integer(0))
+                )?;
+                if interval < 0 || interval > u32::MAX as i64 {
+                    bail!(format!(
+                        "{}: Invalid key use interval {}",
+                        filename.display(),
+                        interval
+                    ));
+                }
+                interval as u32
+            },
+
+            conflict_suffix: {
+                let default = toml::Value::String(String::new());
+                let template = extract!(
+                    general,
+                    "[general]",
+                    conflict_suffix,
+                    str = Some(&default)
+                )?;
+                if template.is_empty() {
+                    None
+                } else {
+                    Some(template.parse::<ConflictSuffix>().map_err(|e| {
+                        format!("{}: {}", filename.display(), e)
+                    })?)
+                }
+            },
+
+            block_secret_file: {
+                let default = toml::Value::String(String::new());
+                let name = extract!(
+                    general,
+                    "[general]",
+                    block_secret_file,
+                    str = Some(&default)
+                )?;
+                if name.is_empty() {
+                    None
+                } else {
+                    Some(parent.join(name))
+                }
+            },
+
             sync_rules: SyncRules::parse(&rules, "rules")
                 .map(Arc::new)
                 .chain_err(|| {
@@ -308,6 +472,134 @@ impl Config {
     }
 }
 
+/// Returns the file names given by the `include` key of `general`, which may
+/// be absent, a string, or an array of strings.
+fn parse_includes<'a>(
+    filename: &Path,
+    general: &'a toml::value::Table,
+) -> Result<Vec<&'a str>> {
+    let invalid = || {
+        format!(
+            "{}: Key 'include' under [general] must be a string or an \
+             array of strings",
+            filename.display()
+        )
+    };
+
+    match general.get("include") {
+        None => Ok(vec![]),
+        Some(&toml::Value::String(ref name)) => Ok(vec![name.as_str()]),
+        Some(&toml::Value::Array(ref names)) => names
+            .iter()
+            .map(|name| name.as_str().ok_or_else(invalid))
+            .collect::<StdResult<_, _>>()
+            .map_err(Into::into),
+        Some(_) => Err(invalid().into()),
+    }
+}
+
+/// Reads each file in `includes` (relative to `parent`) and merges the states
+/// in its `rules` section into `rules`, also feeding its text into `kc`.
+///
+/// Fails if any state is defined more than once, naming both files which
+/// define it.
+fn merge_included_rules(
+    filename: &Path,
+    parent: &Path,
+    includes: &[&str],
+    rules: &mut toml::value::Table,
+    kc: &mut tiny_keccak::Keccak,
+) -> Result<()> {
+    let mut origins: BTreeMap<String, PathBuf> = rules
+        .keys()
+        .map(|state| (state.clone(), filename.to_owned()))
+        .collect();
+
+    for include in includes {
+        let path = parent.join(include);
+        let text = fs::read_to_string(&path).chain_err(|| {
+            format!(
+                "{}: Failed to read included file {}",
+                filename.display(),
+                path.display()
+            )
+        })?;
+        kc.update(text.as_bytes());
+
+        let mut table: toml::value::Table = toml::from_str(&text)
+            .map_err(|e| format!("{}: Syntax error: {}", path.display(), e))?;
+        let included_rules = match table.remove("rules") {
+            Some(toml::Value::Table(included_rules)) => included_rules,
+            _ => bail!(format!(
+                "{}: Missing section [rules] under top level",
+                path.display()
+            )),
+        };
+
+        for (state, def) in included_rules {
+            if let Some(other) = origins.get(&state) {
+                bail!(format!(
+                    "{}: Rules state '{}' is defined in both {} and {}",
+                    filename.display(),
+                    state,
+                    other.display(),
+                    path.display()
+                ));
+            }
+            origins.insert(state.clone(), path.clone());
+            rules.insert(state, def);
+        }
+    }
+
+    Ok(())
+}
+
+lazy_static! {
+    /// The environment variables named by `env:` passphrase sources, which
+    /// `shell_command` keeps from the commands it runs.
+    static ref PASSPHRASE_ENV_VARS: Mutex<BTreeSet<OsString>> =
+        Mutex::new(BTreeSet::new());
+}
+
+/// Returns a command which runs `command` through the platform's shell, i.e.,
+/// `cmd /C` on Windows and `/bin/sh -c` elsewhere.
+///
+/// The command inherits our stderr and runs in `workdir` if given; the caller
+/// is responsible for setting up stdin and stdout. Environment variables
+/// which have been used as passphrases are not passed on to it.
+pub fn shell_command(
+    command: &str,
+    workdir: Option<&Path>,
+) -> process::Command {
+    let mut process = shell_process(command);
+    process.stderr(process::Stdio::inherit());
+    for name in &*PASSPHRASE_ENV_VARS.lock().unwrap() {
+        process.env_remove(name);
+    }
+    if let Some(workdir) = workdir {
+        process.current_dir(workdir);
+    }
+    process
+}
+
+#[cfg(windows)]
+fn shell_process(command: &str) -> process::Command {
+    use std::os::windows::process::CommandExt;
+
+    // `cmd` does its own parsing of the rest of the command line, which does
+    // not understand the quoting `arg` would apply.
+    let mut process = process::Command::new("cmd");
+    process.arg("/C").raw_arg(command);
+    process
+}
+
+#[cfg(not(windows))]
+fn shell_process(command: &str) -> process::Command {
+    let mut process = process::Command::new("/bin/sh");
+    process.arg("-c").arg(command);
+    process
+}
+
 /// Parses the given string as a compression level.
 pub fn parse_compression_name(
     filename: &Path,
@@ -326,6 +618,34 @@ pub fn parse_compression_name(
     })
 }
 
+/// Checks that `name` is usable as the name of a server root.
+///
+/// Since it names a logical root rather than a path, it must be non-empty and
+/// may not contain path separators or control characters.
+pub fn validate_server_root<'a>(
+    filename: &Path,
+    name: &'a str,
+) -> Result<&'a str> {
+    if name.is_empty() {
+        bail!(format!(
+            "{}: server_root must not be empty",
+            filename.display()
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| '/' == c || '\\' == c || c.is_control())
+    {
+        bail!(format!(
+            "{}: Invalid server_root {:?}: may not contain {:?}",
+            filename.display(),
+            name,
+            c
+        ));
+    }
+    Ok(name)
+}
+
 impl FromStr for ServerConfig {
     type Err = String;
 
@@ -394,8 +714,32 @@ impl FromStr for PassphraseConfig {
         let value = &s[colon + 1..];
         match typ {
             "string" => Ok(PassphraseConfig::String(value.to_owned())),
-            "file" => Ok(PassphraseConfig::File(value.to_owned().into())),
+            "file" => {
+                Ok(PassphraseConfig::File(value.to_owned().into(), false))
+            }
+            "file-shred" => {
+                Ok(PassphraseConfig::File(value.to_owned().into(), true))
+            }
             "shell" => Ok(PassphraseConfig::Shell(value.to_owned(), None)),
+            "env" => {
+                // Registered as soon as it is known, since the server command
+                // may be started before the passphrase is read.
+                PASSPHRASE_ENV_VARS.lock().unwrap().insert(value.into());
+                Ok(PassphraseConfig::Env(value.to_owned()))
+            }
+            "keyring" => match value.rfind('/') {
+                Some(slash) if slash > 0 && slash + 1 < value.len() => {
+                    Ok(PassphraseConfig::Keyring {
+                        service: value[..slash].to_owned(),
+                        account: value[slash + 1..].to_owned(),
+                    })
+                }
+                _ => Err(format!(
+                    "Invalid keyring passphrase config '{}'; syntax is \
+                     `keyring:service/account`",
+                    value
+                )),
+            },
             _ => Err(format!("Invalid passphrase config type '{}'", typ)),
         }
     }
@@ -424,7 +768,142 @@ fn do_prompt_passphrase(_: &str, _: bool) -> Result<Vec<u8>> {
     )
 }
 
+#[cfg(feature = "passphrase-keyring")]
+fn read_keyring_passphrase(service: &str, account: &str) -> Result<Vec<u8>> {
+    let password = keyring::Entry::new(service, account)
+        .get_password()
+        .chain_err(|| {
+            ErrorKind::PassphraseSourceUnavailable(format!(
+                "keyring entry {}/{}",
+                service, account
+            ))
+        })?;
+    Ok(password.into())
+}
+
+#[cfg(not(feature = "passphrase-keyring"))]
+fn read_keyring_passphrase(_: &str, _: &str) -> Result<Vec<u8>> {
+    Err(
+        "Reading the passphrase from the system keyring is not supported in \
+         this build of Ensync (requires the `passphrase-keyring` feature)"
+            .into(),
+    )
+}
+
+/// How long to wait for the writer of a FIFO passphrase file to finish.
+const PASSPHRASE_FIFO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reads the whole content of the passphrase file `filename`.
+///
+/// Regular files are simply read. FIFOs are read until EOF on a separate
+/// thread, and the read is abandoned if the writer has not closed its end
+/// within `fifo_timeout`, so a writer which never does cannot hang us forever.
+/// Anything else (directories, devices, sockets) is rejected outright.
+///
+/// Returns the data and whether `filename` was a FIFO.
+fn read_passphrase_file(
+    filename: &Path,
+    fifo_timeout: Duration,
+) -> Result<(Vec<u8>, bool)> {
+    let file_type = fs::metadata(filename)?.file_type();
+    if file_type.is_file() {
+        let mut data = Vec::new();
+        fs::File::open(filename)?.read_to_end(&mut data)?;
+        return Ok((data, false));
+    }
+
+    if !file_type.is_fifo() {
+        let what = if file_type.is_dir() {
+            "a directory"
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            "a device"
+        } else if file_type.is_socket() {
+            "a socket"
+        } else {
+            "not a regular file"
+        };
+        return Err(format!("{} is {}", filename.display(), what).into());
+    }
+
+    // Opening a FIFO blocks until there is a writer, and reading blocks until
+    // the writer closes it, so do both off-thread. If we give up, the thread
+    // is left blocked, but we are about to report an error anyway.
+    let (send, recv) = mpsc::channel();
+    let path = filename.to_owned();
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        let result = fs::File::open(&path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map(|_| data);
+        let _ = send.send(result);
+    });
+
+    match recv.recv_timeout(fifo_timeout) {
+        Ok(result) => Ok((result?, true)),
+        Err(_) => Err(format!(
+            "Timed out after {}s waiting for the writer of {} to finish",
+            fifo_timeout.as_secs(),
+            filename.display()
+        )
+        .into()),
+    }
+}
+
+/// Overwrites the first `len` bytes of `filename` with random data, then
+/// truncates it to zero length.
+fn shred_file(filename: &Path, len: usize) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).open(filename)?;
+    let mut noise = vec![0u8; len];
+    OsRng.fill(&mut noise[..]);
+    file.write_all(&noise)?;
+    file.sync_all()?;
+    file.set_len(0)?;
+    file.sync_all()
+}
+
+/// A passphrase source which is read at most once.
+///
+/// Commands which need the same passphrase in several places should go
+/// through this (see `PassphraseConfig::read_once()`) rather than calling
+/// `read_passphrase()` repeatedly, since sources which are not replayable
+/// would otherwise prompt or run a command again.
+pub struct OncePassphrase<'a> {
+    config: &'a PassphraseConfig,
+    what: &'a str,
+    confirm: bool,
+    value: Option<Passphrase>,
+}
+
+impl<'a> OncePassphrase<'a> {
+    /// Returns the passphrase, reading it from the underlying source on the
+    /// first call only.
+    pub fn get(&mut self) -> Result<&Passphrase> {
+        if self.value.is_none() {
+            self.value =
+                Some(self.config.read_passphrase(self.what, self.confirm)?);
+        }
+
+        Ok(self.value.as_ref().unwrap())
+    }
+}
+
 impl PassphraseConfig {
+    /// Returns a handle which reads this passphrase (as with
+    /// `read_passphrase()`) the first time it is needed and caches it
+    /// thereafter.
+    pub fn read_once<'a>(
+        &'a self,
+        what: &'a str,
+        confirm: bool,
+    ) -> OncePassphrase<'a> {
+        OncePassphrase {
+            config: self,
+            what: what,
+            confirm: confirm,
+            value: None,
+        }
+    }
+
     /// Read the value of this passphrase value.
     ///
     /// `what` will be printed in interactive prompts; it should be a noun
@@ -432,23 +911,36 @@ impl PassphraseConfig {
     /// will read the password twice and fail if the two attempts do not match.
     ///
     /// Any trailing newlines on the passphrase are implicitly stripped. Empty
-    /// passphrases are forbidden.
+    /// passphrases are forbidden and fail with `PassphraseEmpty`; a source
+    /// which cannot be read at all (e.g., a missing file) fails with
+    /// `PassphraseSourceUnavailable` instead.
+    ///
+    /// The returned passphrase is wiped from memory when dropped.
     pub fn read_passphrase(
         &self,
         what: &str,
         confirm: bool,
-    ) -> Result<Vec<u8>> {
+    ) -> Result<Passphrase> {
         let mut data = self.read_passphrase_impl(what, confirm)?;
 
         // Strip any trailing newlines since these are often left at the end of
         // text files or process output and aren't intended to be part of the
-        // password.
-        while Some(&b'\n') == data.last() || Some(&b'\r') == data.last() {
-            data.pop();
+        // password. Keyring entries were stored explicitly, so they are used
+        // as-is.
+        let verbatim = match *self {
+            PassphraseConfig::Keyring { .. } => true,
+            _ => false,
+        };
+        if !verbatim {
+            while Some(&b'\n') == data.last() || Some(&b'\r') == data.last() {
+                data.pop();
+            }
         }
+        // Wrap before checking so that the stripped bytes still get wiped.
+        let data = Passphrase::from(data);
 
         if data.is_empty() {
-            return Err("Password is empty".into());
+            return Err(ErrorKind::PassphraseEmpty.into());
         }
 
         Ok(data)
@@ -464,44 +956,67 @@ impl PassphraseConfig {
 
             PassphraseConfig::String(ref s) => Ok(s.clone().into()),
 
-            PassphraseConfig::File(ref filename) => {
-                let mut data = Vec::new();
-                fs::File::open(filename)
-                    .and_then(|mut file| file.read_to_end(&mut data))
-                    .chain_err(|| {
-                        format!(
-                            "Failed to read passphrase from {}",
-                            filename.display()
-                        )
-                    })?;
+            PassphraseConfig::File(ref filename, shred_after_read) => {
+                let (data, is_fifo) =
+                    read_passphrase_file(filename, PASSPHRASE_FIFO_TIMEOUT)
+                        .chain_err(|| {
+                            ErrorKind::PassphraseSourceUnavailable(format!(
+                                "file {}",
+                                filename.display()
+                            ))
+                        })?;
+
+                // There is nothing to shred in a pipe.
+                if shred_after_read && !is_fifo {
+                    if let Err(e) = shred_file(filename, data.len()) {
+                        eprintln!(
+                            "Warning: failed to shred passphrase file {}: {}",
+                            filename.display(),
+                            e
+                        );
+                    }
+                }
+
                 Ok(data)
             }
 
             PassphraseConfig::Shell(ref command, ref workdir) => {
-                // If we ever support Windows this will need to be updated.
-                let mut process = process::Command::new("/bin/sh");
-                process
-                    .arg("-c")
-                    .arg(command)
-                    .stderr(process::Stdio::inherit())
-                    .stdin(process::Stdio::null());
-                if let Some(ref workdir) = *workdir {
-                    process.current_dir(workdir);
-                }
+                let mut process = shell_command(command, workdir.as_deref());
+                process.stdin(process::Stdio::null());
 
                 let output = process.output().chain_err(|| {
-                    format!("Failed to execute command `{}`", command)
+                    ErrorKind::PassphraseSourceUnavailable(format!(
+                        "command `{}`",
+                        command
+                    ))
                 })?;
                 if !output.status.success() {
-                    return Err(format!(
-                        "Command `{}` failed with {}",
-                        command, output.status
+                    return Err(ErrorKind::PassphraseSourceUnavailable(
+                        format!(
+                            "command `{}` (failed with {})",
+                            command, output.status
+                        ),
                     )
                     .into());
                 }
 
                 Ok(output.stdout)
             }
+
+            PassphraseConfig::Env(ref name) => {
+                env::var_os(name).map(OsString::into_vec).ok_or_else(|| {
+                    ErrorKind::PassphraseSourceUnavailable(format!(
+                        "environment variable `{}` (not set)",
+                        name
+                    ))
+                    .into()
+                })
+            }
+
+            PassphraseConfig::Keyring {
+                ref service,
+                ref account,
+            } => read_keyring_passphrase(service, account),
         }
     }
 
@@ -510,10 +1025,13 @@ impl PassphraseConfig {
     pub fn relativise<P: AsRef<Path>>(self, parent: P) -> Self {
         let parent = parent.as_ref();
         match self {
-            PassphraseConfig::Prompt | PassphraseConfig::String(_) => self,
+            PassphraseConfig::Prompt
+            | PassphraseConfig::String(_)
+            | PassphraseConfig::Env(_)
+            | PassphraseConfig::Keyring { .. } => self,
 
-            PassphraseConfig::File(basename) => {
-                PassphraseConfig::File(parent.join(basename))
+            PassphraseConfig::File(basename, shred_after_read) => {
+                PassphraseConfig::File(parent.join(basename), shred_after_read)
             }
 
             PassphraseConfig::Shell(command, _) => {
@@ -530,12 +1048,20 @@ impl PassphraseConfig {
         match *self {
             PassphraseConfig::Prompt => "prompt".to_owned(),
             PassphraseConfig::String(ref s) => format!("string:{}", s),
-            PassphraseConfig::File(ref name) => {
+            PassphraseConfig::File(ref name, false) => {
                 format!("file:{}", name.display())
             }
+            PassphraseConfig::File(ref name, true) => {
+                format!("file-shred:{}", name.display())
+            }
             PassphraseConfig::Shell(ref command, _) => {
                 format!("shell:{}", command)
             }
+            PassphraseConfig::Env(ref name) => format!("env:{}", name),
+            PassphraseConfig::Keyring {
+                ref service,
+                ref account,
+            } => format!("keyring:{}/{}", service, account),
         }
     }
 }
@@ -578,6 +1104,118 @@ mode = "---/---"
         assert_eq!(Compression::best(), config.compression);
     }
 
+    #[test]
+    fn block_size_defaults_when_omitted() {
+        let config = Config::parse(
+            "/foo/bar/config.toml",
+            r#"
+[general]
+path = "/the/client/path"
+server = "path:/the/server/path"
+server_root = "r00t"
+passphrase = "prompt"
+
+[[rules.root.files]]
+mode = "---/---"
+"#,
+        )
+        .unwrap();
+        assert_eq!(DEFAULT_BLOCK_SIZE, config.block_size);
+    }
+
+    #[test]
+    fn block_size_bounds_checked_when_present() {
+        let parse = |block_size: &str| {
+            Config::parse(
+                "/foo/bar/config.toml",
+                &format!(
+                    r#"
+[general]
+path = "/the/client/path"
+server = "path:/the/server/path"
+server_root = "r00t"
+passphrase = "prompt"
+block_size = {}
+
+[[rules.root.files]]
+mode = "---/---"
+"#,
+                    block_size
+                ),
+            )
+        };
+
+        assert_eq!(256, parse("256").unwrap().block_size);
+        assert_eq!(1 << 30, parse("1073741824").unwrap().block_size);
+
+        let err = parse("255").err().unwrap().to_string();
+        assert!(err.contains("too small (minimum 256)"), "{}", err);
+        let err = parse("1073741825").err().unwrap().to_string();
+        assert!(err.contains("too large (maximum 1GB)"), "{}", err);
+        assert!(parse("\"big\"").is_err());
+    }
+
+    #[test]
+    fn rules_merged_from_included_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("common.toml"),
+            r#"
+[[rules.git.files]]
+mode = "---/---"
+"#,
+        )
+        .unwrap();
+        let parse = |extra: &str| {
+            Config::parse(
+                dir.path().join("config.toml"),
+                &format!(
+                    r#"
+[general]
+path = "/the/client/path"
+server = "path:/the/server/path"
+server_root = "r00t"
+passphrase = "prompt"
+include = "common.toml"
+
+[[rules.root.files]]
+mode = "cud/cud"
+
+[[rules.root.files]]
+switch = "git"
+{}
+"#,
+                    extra
+                ),
+            )
+        };
+
+        // The `switch` to `git` only resolves if the included state is there
+        let base = parse("").unwrap();
+
+        fs::write(
+            dir.path().join("common.toml"),
+            r#"
+[[rules.git.files]]
+mode = "cud/cud"
+"#,
+        )
+        .unwrap();
+        assert!(parse("").unwrap().hash != base.hash);
+
+        let err = parse(
+            r#"
+[[rules.git.files]]
+mode = "---/---"
+"#,
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(err.contains("'git' is defined in both"), "{}", err);
+        assert!(err.contains("common.toml"), "{}", err);
+    }
+
     #[test]
     fn relative_filenames_in_config_relativised_against_config_parent() {
         let config = Config::parse(
@@ -603,9 +1241,172 @@ mode = "---/---"
             config.server
         );
         assert_eq!(
-            PassphraseConfig::File("/foo/bar/password".to_owned().into()),
+            PassphraseConfig::File(
+                "/foo/bar/password".to_owned().into(),
+                false
+            ),
+            config.passphrase
+        );
+    }
+
+    #[test]
+    fn server_root_validated() {
+        let parse = |root: &str| {
+            Config::parse(
+                "/foo/bar/config.toml",
+                &format!(
+                    r#"
+[general]
+path = "/the/client/path"
+server = "path:/the/server/path"
+server_root = "{}"
+passphrase = "prompt"
+
+[[rules.root.files]]
+mode = "---/---"
+"#,
+                    root
+                ),
+            )
+            .map(|config| config.server_root)
+        };
+
+        assert_eq!("r00t", parse("r00t").unwrap());
+        assert!(parse("").is_err());
+        assert!(parse("r00t/sub").is_err());
+        assert!(parse("r00t\\\\sub").is_err());
+        assert!(parse("r00t\tsub").is_err());
+    }
+
+    #[test]
+    fn key_use_interval_defaults_to_zero() {
+        let parse = |line: &str| {
+            let text = format!(
+                r#"
+[general]
+path = "/foo"
+server = "path:/bar"
+server_root = "r00t"
+passphrase = "prompt"
+{}
+
+[[rules.root.files]]
+mode = "---/---"
+"#,
+                line
+            );
+            Config::read_from(text.as_bytes(), "/base")
+                .map(|config| config.key_use_interval)
+        };
+
+        assert_eq!(0, parse("").unwrap());
+        assert_eq!(3600, parse("key_use_interval = 3600").unwrap());
+        assert!(parse("key_use_interval = -1").is_err());
+    }
+
+    #[test]
+    fn parse_conflict_suffix() {
+        let parse = |line: &str| {
+            let text = format!(
+                r#"
+[general]
+path = "/foo"
+server = "path:/bar"
+server_root = "r00t"
+passphrase = "prompt"
+{}
+
+[[rules.root.files]]
+mode = "---/---"
+"#,
+                line
+            );
+            Config::read_from(text.as_bytes(), "/base")
+                .map(|config| config.conflict_suffix)
+        };
+
+        assert_eq!(None, parse("").unwrap());
+        assert_eq!(
+            Some(".conflict-{side}-{time}".parse().unwrap()),
+            parse(r#"conflict_suffix = ".conflict-{side}-{time}""#).unwrap()
+        );
+        assert!(parse(r#"conflict_suffix = ".conflict-{host}""#).is_err());
+    }
+
+    #[test]
+    fn block_secret_file_keys_block_ids() {
+        use crate::block_xfer::stream_to_blocks;
+
+        let parse = |line: &str| {
+            let text = format!(
+                r#"
+[general]
+path = "/foo"
+server = "path:/bar"
+server_root = "r00t"
+passphrase = "prompt"
+{}
+
+[[rules.root.files]]
+mode = "---/---"
+"#,
+                line
+            );
+            Config::read_from(text.as_bytes(), "/base").unwrap()
+        };
+
+        let config = parse("");
+        assert_eq!(None, config.block_secret_file);
+        assert_eq!(None, config.read_block_secret().unwrap());
+        assert_eq!(
+            Some(PathBuf::from("/base/keyfile")),
+            parse(r#"block_secret_file = "keyfile""#).block_secret_file
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let keyfile = dir.path().join("keyfile");
+        let block_ids = |secret: &[u8]| {
+            fs::write(&keyfile, secret).unwrap();
+            let config = parse(&format!(
+                "block_secret_file = \"{}\"",
+                keyfile.to_str().unwrap()
+            ));
+            let secret = config.read_block_secret().unwrap().unwrap();
+            stream_to_blocks(&b"hello world"[..], 256, &secret, |_, _| Ok(()))
+                .unwrap()
+                .blocks
+        };
+
+        assert_eq!(block_ids(b"plugh"), block_ids(b"plugh"));
+        assert!(block_ids(b"plugh") != block_ids(b"xyzzy"));
+        assert!(parse(r#"block_secret_file = "/nonexistent/keyfile""#)
+            .read_block_secret()
+            .is_err());
+    }
+
+    #[test]
+    fn read_from_reader_relativises_against_base_dir() {
+        let text = r#"
+[general]
+path = "sync/client"
+server = "path:sync/server"
+server_root = "r00t"
+passphrase = "file:password"
+
+[[rules.root.files]]
+mode = "---/---"
+"#;
+        let config = Config::read_from(text.as_bytes(), "/base").unwrap();
+        assert_eq!("/base/sync/client", config.client_root.to_str().unwrap());
+        assert_eq!(
+            ServerConfig::Path("/base/sync/server".to_owned().into()),
+            config.server
+        );
+        assert_eq!(
+            PassphraseConfig::File("/base/password".to_owned().into(), false),
             config.passphrase
         );
+        assert_eq!("/base/config.toml", config.full_path().to_str().unwrap());
     }
 
     #[test]
@@ -661,6 +1462,68 @@ mode = "---/---"
         assert_eq!(b"hunter2", &pconf.read_passphrase("", false).unwrap()[..]);
     }
 
+    #[test]
+    fn passphrase_from_env() {
+        let pconf: PassphraseConfig =
+            "env:ENSYNC_TEST_PASSPHRASE_FROM_ENV".parse().unwrap();
+        assert_eq!(
+            PassphraseConfig::Env("ENSYNC_TEST_PASSPHRASE_FROM_ENV".to_owned()),
+            pconf
+        );
+        assert!(pconf.read_passphrase("", false).is_err());
+
+        env::set_var("ENSYNC_TEST_PASSPHRASE_FROM_ENV", "hunter2\n");
+        assert_eq!(b"hunter2", &pconf.read_passphrase("", false).unwrap()[..]);
+        assert_eq!(pconf, pconf.clone().relativise("/foo"));
+        assert_eq!(
+            "env:ENSYNC_TEST_PASSPHRASE_FROM_ENV",
+            pconf.to_string_lossy()
+        );
+        env::remove_var("ENSYNC_TEST_PASSPHRASE_FROM_ENV");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn env_passphrase_not_passed_to_shell_commands() {
+        env::set_var("ENSYNC_TEST_PASSPHRASE_NOT_PASSED", "hunter2");
+        let command = "echo \"${ENSYNC_TEST_PASSPHRASE_NOT_PASSED-unset}\"";
+        let output = shell_command(command, None).output().unwrap();
+        assert_eq!(b"hunter2\n", &output.stdout[..]);
+
+        let pconf: PassphraseConfig =
+            "env:ENSYNC_TEST_PASSPHRASE_NOT_PASSED".parse().unwrap();
+        let output = shell_command(command, None).output().unwrap();
+        assert_eq!(b"unset\n", &output.stdout[..]);
+        // It is still in our own environment
+        assert_eq!(b"hunter2", &pconf.read_passphrase("", false).unwrap()[..]);
+        env::remove_var("ENSYNC_TEST_PASSPHRASE_NOT_PASSED");
+    }
+
+    #[test]
+    fn passphrase_from_keyring_parsed() {
+        let pconf: PassphraseConfig =
+            "keyring:org/ensync/alice".parse().unwrap();
+        assert_eq!(
+            PassphraseConfig::Keyring {
+                service: "org/ensync".to_owned(),
+                account: "alice".to_owned(),
+            },
+            pconf
+        );
+        assert_eq!("keyring:org/ensync/alice", pconf.to_string_lossy());
+        assert_eq!(pconf, pconf.clone().relativise("/foo"));
+        assert!("keyring:ensync".parse::<PassphraseConfig>().is_err());
+        assert!("keyring:/alice".parse::<PassphraseConfig>().is_err());
+        assert!("keyring:ensync/".parse::<PassphraseConfig>().is_err());
+    }
+
+    #[cfg(not(feature = "passphrase-keyring"))]
+    #[test]
+    fn passphrase_from_keyring_unsupported() {
+        let pconf: PassphraseConfig = "keyring:ensync/alice".parse().unwrap();
+        assert!(pconf.read_passphrase("", false).is_err());
+    }
+
     #[test]
     fn passphrase_from_file() {
         use std::io::Write;
@@ -673,14 +1536,138 @@ mode = "---/---"
         assert_eq!(b"hunter2", &pconf.read_passphrase("", false).unwrap()[..]);
     }
 
+    #[test]
+    fn passphrase_from_empty_or_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+
+        let pconf = PassphraseConfig::File(path.clone(), false);
+        match pconf.read_passphrase("", false) {
+            Err(Error(ErrorKind::PassphraseSourceUnavailable(..), _)) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Read passphrase from missing file"),
+        }
+
+        fs::write(&path, b"\n").unwrap();
+        match pconf.read_passphrase("", false) {
+            Err(Error(ErrorKind::PassphraseEmpty, _)) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Read passphrase from empty file"),
+        }
+    }
+
+    #[test]
+    fn passphrase_from_shredded_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("passphrase");
+        let configstr = format!("file-shred:{}", file.to_str().unwrap());
+        let pconf: PassphraseConfig = configstr.parse().unwrap();
+        assert_eq!(configstr, pconf.to_string_lossy());
+
+        fs::write(&file, "hunter2\n").unwrap();
+        assert_eq!(b"hunter2", &pconf.read_passphrase("", false).unwrap()[..]);
+
+        let mut remaining = Vec::new();
+        fs::File::open(&file)
+            .unwrap()
+            .read_to_end(&mut remaining)
+            .unwrap();
+        assert!(remaining.is_empty());
+        assert!(pconf.read_passphrase("", false).is_err());
+    }
+
+    fn mkfifo(path: &Path) {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(0, unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) });
+    }
+
+    #[test]
+    fn passphrase_from_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("fifo");
+        mkfifo(&fifo);
+
+        let writer_path = fifo.clone();
+        let writer = thread::spawn(move || {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(&writer_path)
+                .unwrap();
+            file.write_all(b"hunter").unwrap();
+            file.flush().unwrap();
+            file.write_all(b"2\n").unwrap();
+        });
+
+        let pconf = PassphraseConfig::File(fifo.clone(), true);
+        assert_eq!(b"hunter2", &pconf.read_passphrase("", false).unwrap()[..]);
+        writer.join().unwrap();
+        // Shredding was skipped rather than failing or blocking
+        assert!(fs::metadata(&fifo).unwrap().file_type().is_fifo());
+
+        // Nobody ever writes to it this time
+        assert!(
+            read_passphrase_file(&fifo, Duration::from_millis(100)).is_err()
+        );
+    }
+
+    #[test]
+    fn passphrase_from_directory_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let pconf = PassphraseConfig::File(dir.path().to_owned(), false);
+        let err = pconf.read_passphrase("", false).unwrap_err();
+        assert!(
+            err.iter().any(|e| e.to_string().contains("is a directory")),
+            "Unexpected error: {}",
+            err
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn shell_command_runs_in_workdir() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = shell_command("pwd", Some(dir.path()))
+            .stdin(process::Stdio::null())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            fs::canonicalize(dir.path()).unwrap(),
+            fs::canonicalize(
+                String::from_utf8(output.stdout).unwrap().trim_end()
+            )
+            .unwrap()
+        );
+    }
+
+    #[cfg(not(windows))]
     #[test]
     fn passphrase_from_shell() {
-        // Another thing that won't work on Windows
         let pconf: PassphraseConfig =
             "shell:printf 'hunter%d\r\n' 2".parse().unwrap();
         assert_eq!(b"hunter2", &pconf.read_passphrase("", false).unwrap()[..]);
     }
 
+    #[test]
+    fn read_once_reads_source_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let pconf: PassphraseConfig = format!(
+            "shell:echo >>'{}'; printf hunter2",
+            counter.to_str().unwrap()
+        )
+        .parse()
+        .unwrap();
+
+        let mut once = pconf.read_once("", false);
+        assert_eq!(b"hunter2", &once.get().unwrap()[..]);
+        assert_eq!(b"hunter2", &once.get().unwrap()[..]);
+        assert_eq!("\n", fs::read_to_string(&counter).unwrap());
+    }
+
     #[test]
     fn relativise_prompt_password() {
         assert_eq!(
@@ -700,8 +1687,8 @@ mode = "---/---"
     #[test]
     fn relativise_file_password() {
         assert_eq!(
-            PassphraseConfig::File("/foo/password".to_owned().into()),
-            PassphraseConfig::File("password".to_owned().into())
+            PassphraseConfig::File("/foo/password".to_owned().into(), false),
+            PassphraseConfig::File("password".to_owned().into(), false)
                 .relativise("/foo")
         );
     }
@@ -117,16 +117,10 @@ pub fn open_server_storage(
             // way to stop it.
             //
             // The former option seems the lesser of these two evils.
-            let mut process = process::Command::new("/bin/sh");
+            let mut process = shell_command(command, workdir.as_deref());
             process
-                .arg("-c")
-                .arg(command)
-                .stderr(process::Stdio::inherit())
                 .stdin(process::Stdio::piped())
                 .stdout(process::Stdio::piped());
-            if let Some(ref workdir) = *workdir {
-                process.current_dir(workdir);
-            }
 
             let mut child = process.spawn().chain_err(|| {
                 format!("Failed to start server command `{}`", command)
@@ -162,7 +156,9 @@ pub fn open_server_replica(
     } else {
         let passphrase =
             config.passphrase.read_passphrase("passphrase", false)?;
-        Arc::new(keymgmt::derive_key_chain(&*storage, &passphrase[..])?)
+        let mut chain = keymgmt::derive_key_chain(&*storage, &passphrase[..])?;
+        chain.block_secret = config.read_block_secret()?;
+        Arc::new(chain)
     };
 
     Ok(ServerReplica::new(
@@ -18,6 +18,9 @@
 
 use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
 
 /// Type for content hashes of regular files and for blob identifiers on the
 /// server.
@@ -206,6 +209,52 @@ pub fn is_dir(fd: Option<&FileData>) -> bool {
     }
 }
 
+/// Owns the bytes of a passphrase, overwriting them with zeroes when dropped.
+///
+/// This reduces (but cannot eliminate) the window during which a passphrase
+/// lingers in memory after it is no longer needed. The whole capacity of the
+/// buffer is cleared, so bytes trimmed off the end before wrapping are wiped
+/// as well.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Passphrase(Vec<u8>);
+
+impl Passphrase {
+    fn wipe(&mut self) {
+        let base = self.0.as_mut_ptr();
+        for i in 0..self.0.capacity() {
+            // Volatile writes so the stores cannot be elided as dead.
+            unsafe { ptr::write_volatile(base.add(i), 0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+impl From<Vec<u8>> for Passphrase {
+    fn from(data: Vec<u8>) -> Self {
+        Passphrase(data)
+    }
+}
+
+impl Deref for Passphrase {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Passphrase(<redacted>)")
+    }
+}
+
+impl Drop for Passphrase {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
 #[cfg(test)]
 pub mod test_helpers {
     use std::ffi::{OsStr, OsString};
@@ -284,4 +333,20 @@ mod test {
         assert!(special.matches_content(&special));
         assert!(!special.matches_content(&f1));
     }
+
+    #[test]
+    fn passphrase_derefs_and_wipes() {
+        let mut data = b"hunter2\n".to_vec();
+        data.pop();
+        let mut pass = Passphrase::from(data);
+        assert_eq!(b"hunter2", &*pass);
+        assert_eq!("Passphrase(<redacted>)", format!("{:?}", pass));
+
+        pass.wipe();
+        assert_eq!(&[0u8; 7], &*pass);
+        assert!(pass.0.capacity() >= 8);
+
+        let other = Passphrase::from(b"hunter3".to_vec());
+        drop(other);
+    }
 }
@@ -156,6 +156,12 @@ error_chain! {
                     latest_ver, latest_len, actual_ver, actual_len,
                     dir.to_string_lossy())
         }
+        DirectoryVersionInvalid(dir: ffi::OsString) {
+            description("Server directory version invalid")
+            display("Server directory version for '{}' is invalid; it has \
+                     been corrupted or tampered with",
+                    dir.to_string_lossy())
+        }
         DirectoryEmbeddedIdMismatch(dir: ffi::OsString) {
             description("Server directory content does not \
                          correspond to directory id")
@@ -207,6 +213,39 @@ error_chain! {
         ReconciliationStopped {
             description("Reconciliation stopped")
         }
+        TransferCancelled {
+            description("Transfer cancelled")
+            display("Transfer cancelled")
+        }
+        KeyDerivationCancelled {
+            description("Key derivation cancelled")
+            display("Key derivation cancelled")
+        }
+        StreamSizeMismatch(expected: u64, actual: u64) {
+            description("Reconstructed stream has the wrong size")
+            display("Reconstructed stream has {} bytes, expected {}",
+                    actual, expected)
+        }
+        BlockCountMismatch(blocks: usize, sizes: usize) {
+            description("Block list has the wrong number of block sizes")
+            display("Block list has {} blocks but {} block sizes",
+                    blocks, sizes)
+        }
+        BlockSizesUnknown {
+            description("Block sizes unknown")
+            display("Sizes of blocks are not known, so a range of the \
+                     file cannot be read")
+        }
+        InvalidCryptBufferSize(size: usize) {
+            description("Invalid crypt buffer size")
+            display("Crypt buffer size {} is not a non-zero multiple of \
+                     the cipher block size", size)
+        }
+        StalledTransfer {
+            description("Transfer stalled")
+            display("Transfer stalled: no block completed within the \
+                     inactivity timeout")
+        }
         BadServerDirConfig(config: String) {
             description("Invalid server directory configuration")
             display("Invalid server directory configuration '{}'", config)
@@ -236,6 +275,10 @@ error_chain! {
         PassphraseNotInKdfList {
             description("Passphrase not found in key store")
         }
+        PassphraseNotForKey(name: String) {
+            description("Passphrase does not belong to the key")
+            display("Passphrase does not belong to key '{}'", name)
+        }
         PassphraseInKdfList {
             description("Passphrase already bound to another key in the \
                          key store.")
@@ -258,6 +301,17 @@ error_chain! {
         EmptyKeyName {
             description("Empty key name")
         }
+        PassphraseEmpty {
+            description("Passphrase is empty")
+        }
+        PassphraseSourceUnavailable(source: String) {
+            description("Passphrase source unavailable")
+            display("Could not read passphrase from {}", source)
+        }
+        PassphraseTooWeak(strength: server::keymgmt::PassphraseStrength) {
+            description("Passphrase is too weak")
+            display("Passphrase is too weak ({:?})", strength)
+        }
         KeyNameAlreadyInUse(name: String) {
             description("Key name already in use")
             display("Key name '{}' already in use \
@@ -276,6 +330,12 @@ error_chain! {
             description("Key not in group")
             display("Key not in group '{}'", name)
         }
+        KeyNotInGroups(names: Vec<String>) {
+            description("Key not in groups")
+            display("Key not in groups {}", names.iter()
+                    .map(|n| format!("'{}'", n))
+                    .collect::<Vec<_>>().join(", "))
+        }
         KeyAlreadyInGroup(name: String) {
             description("Key already in group")
             display("Key already in group '{}'", name)
@@ -299,9 +359,82 @@ error_chain! {
             description("Group not found in key store")
             display("Group '{}' not found in key store", name)
         }
+        GroupKeyMissing(group: String, key: String) {
+            description("Key is missing a required group")
+            display("Key '{}' does not hold the '{}' group", key, group)
+        }
+        GroupKeyMismatch(group: String, first: String, other: String) {
+            description("Group key differs between keys")
+            display("Group '{}' resolves to different internal keys for \
+                     key '{}' and key '{}'", group, first, other)
+        }
+        UnsupportedKdfAlgorithm(algorithm: String) {
+            description("Unsupported key derivation algorithm")
+            display("Unsupported key derivation algorithm '{}'", algorithm)
+        }
+        UnsupportedDirVerFormat(fmt: u32) {
+            description("Unsupported directory version format")
+            display("Unsupported directory version format {}; a newer \
+                     version of Ensync is required to use this store", fmt)
+        }
+        KdfListFromNewerVersion(version: u32) {
+            description("Key store created by a newer version of Ensync")
+            display("Key store has format version {}, but this version of \
+                     Ensync only supports up to {}; a newer version of \
+                     Ensync is required to use this store", version,
+                    crate::server::KDFLIST_FORMAT_VERSION)
+        }
+        UnsupportedCipherWidth(code: u32) {
+            description("Unsupported cipher width")
+            display("Unsupported cipher width {}; a newer version of Ensync \
+                     is required to use this store", code)
+        }
+        UnsupportedHashVariant(code: u32) {
+            description("Unsupported hash variant")
+            display("Unsupported hash variant {}; a newer version of Ensync \
+                     is required to use this store", code)
+        }
+        StoreNotEmpty {
+            description("Store already contains data")
+            display("The store already contains data; this setting can \
+                     only be changed before anything is synced")
+        }
+        MinKeyPolicyViolation(min: u32) {
+            description("Operation would violate the minimum key policy")
+            display("The key store policy requires at least {} keys \
+                     (and at least {} keys in the `root` group)",
+                    min, min)
+        }
+        IncompatibleKeyImport(name: String) {
+            description("Imported key belongs to a different key store")
+            display("Key '{}' belongs to a key store with different \
+                     internal keys and cannot be imported here", name)
+        }
+        KdfListVerificationFailed {
+            description("Key store failed verification after edit")
+            display("The edited key store no longer derives the expected \
+                     keys; the edit has been rolled back")
+        }
+        IncompatibleKdfListImport {
+            description("Imported key store has different internal keys")
+            display("Imported key store does not give the root passphrase \
+                     the same internal keys and cannot replace this one")
+        }
+        NotKdfListExport {
+            description("Data is not an exported key store")
+        }
+        UnsupportedKdfListExport(version: u32) {
+            description("Exported key store from a newer version of Ensync")
+            display("Exported key store has header version {}, which this \
+                     version of Ensync does not support", version)
+        }
         SanityCheckFailed {
             description("Sanity check failed")
         }
+        AuditLogCorrupt(message: String) {
+            description("Key management audit log corrupt")
+            display("Key management audit log corrupt: {}", message)
+        }
     }
 }
 
@@ -322,6 +455,10 @@ impl Error {
             ErrorKind::ServerFatalError(_) => return true,
             ErrorKind::ServerProtocolError => return true,
             ErrorKind::ServerConnectionClosed => return true,
+            // The server is serving old or forged directory versions, so
+            // nothing else it says can be trusted either.
+            ErrorKind::DirectoryVersionRecessed(..) => return true,
+            ErrorKind::DirectoryVersionInvalid(..) => return true,
             _ => (),
         }
 
@@ -358,4 +495,15 @@ mod test {
 
         assert!(second().err().unwrap().is_fatal());
     }
+
+    #[test]
+    fn directory_version_tampering_is_fatal() {
+        let invalid: Error =
+            ErrorKind::DirectoryVersionInvalid("dir".into()).into();
+        let recessed: Error =
+            ErrorKind::DirectoryVersionRecessed("dir".into(), 2, 10, 1, 10)
+                .into();
+        assert_eq!(log::FATAL, invalid.level());
+        assert_eq!(log::FATAL, recessed.level());
+    }
 }
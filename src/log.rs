@@ -19,11 +19,21 @@
 //! Defines structured logs emitted primarily by the reconciler.
 //!
 //! The intent is to immediately support normal "verbose" operation while also
-//! providing useful output for tests, while also allowing eventual rsync-style
-//! itemised output.
+//! providing useful output for tests and rsync-style itemised output (see
+//! `ItemisedLogger`).
 
 use crate::reconcile::compute::{Conflict, Reconciliation};
+use chrono::Utc;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
+use std::fmt::{self, Write as FmtWrite};
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use crate::defs::*;
 use crate::errors::Error;
@@ -43,7 +53,7 @@ pub const EDIT: LogLevel = 4;
 /// being made.
 pub const INFO: LogLevel = 5;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ReplicaSide {
     Client,
     Ancestor,
@@ -63,6 +73,36 @@ pub enum ErrorOperation<'a> {
     Access(&'a OsStr),
 }
 
+/// The kind of an `ErrorOperation`, without the file it applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorOperationKind {
+    List,
+    MarkClean,
+    Chdir,
+    Create,
+    Update,
+    Rename,
+    Remove,
+    Rmdir,
+    Access,
+}
+
+impl<'a> ErrorOperation<'a> {
+    pub fn kind(&self) -> ErrorOperationKind {
+        match *self {
+            ErrorOperation::List => ErrorOperationKind::List,
+            ErrorOperation::MarkClean => ErrorOperationKind::MarkClean,
+            ErrorOperation::Chdir(_) => ErrorOperationKind::Chdir,
+            ErrorOperation::Create(_) => ErrorOperationKind::Create,
+            ErrorOperation::Update(_) => ErrorOperationKind::Update,
+            ErrorOperation::Rename(_) => ErrorOperationKind::Rename,
+            ErrorOperation::Remove(_) => ErrorOperationKind::Remove,
+            ErrorOperation::Rmdir => ErrorOperationKind::Rmdir,
+            ErrorOperation::Access(_) => ErrorOperationKind::Access,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Log<'a> {
     Inspect(&'a OsStr, &'a OsStr, Reconciliation, Conflict),
@@ -79,6 +119,18 @@ pub enum Log<'a> {
     Rmdir(ReplicaSide, &'a OsStr),
     RecursiveDelete(ReplicaSide, &'a OsStr),
     Error(ReplicaSide, &'a OsStr, ErrorOperation<'a>, &'a Error),
+    /// The reconciler is about to process the contents of the given
+    /// directory. Every other event for files in that directory comes after
+    /// this one and before the matching `LeaveDirectory`.
+    EnterDirectory(&'a OsStr),
+    /// The reconciler has finished with the given directory, including all of
+    /// its subdirectories.
+    LeaveDirectory(&'a OsStr),
+    /// The given number of bytes out of the total have been transferred so
+    /// far for the file at the given path, which is being written to the
+    /// given side. Emitted at a limited rate while transferring file
+    /// content.
+    Progress(ReplicaSide, &'a OsStr, FileSize, FileSize),
 }
 
 pub trait Logger {
@@ -91,6 +143,902 @@ impl<T: Logger + ?Sized> Logger for Box<T> {
     }
 }
 
+impl<T: Logger + ?Sized> Logger for Arc<T> {
+    fn log(&self, level: LogLevel, what: &Log) {
+        (**self).log(level, what);
+    }
+}
+
+/// A `Logger` which passes events on to another logger only if they are at
+/// `max_level` or more severe (i.e., numerically less than or equal to it).
+#[derive(Debug)]
+pub struct FilterLogger<L> {
+    inner: L,
+    max_level: LogLevel,
+}
+
+impl<L: Logger> FilterLogger<L> {
+    pub fn new(inner: L, max_level: LogLevel) -> Self {
+        FilterLogger {
+            inner: inner,
+            max_level: max_level,
+        }
+    }
+}
+
+impl<L: Logger> Logger for FilterLogger<L> {
+    fn log(&self, level: LogLevel, what: &Log) {
+        if level <= self.max_level {
+            self.inner.log(level, what);
+        }
+    }
+}
+
+/// Counts of the errors reported during a run, as collected by
+/// `SummaryLogger`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// The number of errors for each combination of replica and operation.
+    /// Combinations which had no errors are absent.
+    pub errors: BTreeMap<(ReplicaSide, ErrorOperationKind), u64>,
+}
+
+impl Summary {
+    /// Returns the total number of errors.
+    pub fn total_errors(&self) -> u64 {
+        self.errors.values().sum()
+    }
+}
+
+/// A `Logger` which passes everything through to another logger, while
+/// tallying the errors it sees into a `Summary`.
+///
+/// To read the summary back after handing the logger to the reconciler, wrap
+/// it in an `Arc` and keep a reference.
+pub struct SummaryLogger<L> {
+    inner: L,
+    summary: Mutex<Summary>,
+}
+
+impl<L: Logger> SummaryLogger<L> {
+    pub fn new(inner: L) -> Self {
+        SummaryLogger {
+            inner: inner,
+            summary: Mutex::new(Summary::default()),
+        }
+    }
+
+    /// Returns the summary so far, and starts counting afresh.
+    pub fn take(&self) -> Summary {
+        mem::take(&mut *self.summary.lock().unwrap())
+    }
+}
+
+impl<L: Logger> Logger for SummaryLogger<L> {
+    fn log(&self, level: LogLevel, what: &Log) {
+        if let Log::Error(side, _, op, _) = *what {
+            *self
+                .summary
+                .lock()
+                .unwrap()
+                .errors
+                .entry((side, op.kind()))
+                .or_insert(0) += 1;
+        }
+        self.inner.log(level, what);
+    }
+}
+
+/// A `Logger` which writes rsync-style itemised changes to a stream, one line
+/// per change.
+///
+/// Only events at `EDIT` level or more severe are written, unless unchanged
+/// items are requested, in which case files found to be in sync are written
+/// as well. Changes to the ancestor replica are never written.
+#[derive(Debug)]
+pub struct ItemisedLogger<W> {
+    out: Mutex<W>,
+    root: PathBuf,
+    level: LogLevel,
+}
+
+impl<W: io::Write> ItemisedLogger<W> {
+    /// Creates a logger which writes to `out`, displaying paths relative to
+    /// `root`.
+    pub fn new(out: W, root: PathBuf, include_unchanged: bool) -> Self {
+        ItemisedLogger {
+            out: Mutex::new(out),
+            root: root,
+            level: if include_unchanged { INFO } else { EDIT },
+        }
+    }
+
+    /// Consumes the logger, returning the underlying stream.
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap()
+    }
+
+    fn display_path(&self, dir: &OsStr, name: Option<&OsStr>) -> PathBuf {
+        let dir = Path::new(dir);
+        let dir = dir.strip_prefix(&self.root).unwrap_or(dir);
+        match name {
+            Some(name) => dir.join(name),
+            None => dir.to_owned(),
+        }
+    }
+}
+
+// Match the format output by rsync as best we can
+// The rsync format is an 11-character string which is either the
+// following sequence of flags, or a '*', and a short message,
+// right-padded.
+//
+// 0. Update type
+//    < Transfer to remote host
+//    > Transfer to local host
+//    c Item being created
+//    h Create hard link (we don't support this)
+//    . No update
+//
+// 1. File type
+//    f Regular
+//    d Directory
+//    L Symlink
+//    D Device (we don't distinguish from special)
+//    S Special
+//
+// 2. 'c' if content change, fill otherwise.
+//
+// 3. 's' file size changed, fill otherwise.
+//
+// 4. 't' file modification time changed, fill otherwise.
+//
+// 5. 'p' file mode changed, fill otherwise.
+//
+// 6. 'o' owner changed. We don't track this, so always fill.
+//
+// 7. 'g' group changed. We don't track this, so always fill.
+//
+// 8. 'f' "fileflags" changed. Again, always fill.
+//
+// 9. 'a' ACL changed. Always fill.
+//
+// 10. 'x' extended attributes changed. Always fill.
+//
+// The fill character is '.' by default. If something is being created,
+// it is instead '+'. If the item is being completely unchanged, it is
+// ' ' instead.
+//
+// We need to extend this a bit. For the most part, this is simply a
+// matter of using more '*'-format things, but renaming is complicated
+// by the fact that there are two filenames in play. We handle this by
+// emitting consecutive `*renamefrom` and `*renameto  ` lines.
+#[derive(Debug, Clone, Copy, Default)]
+struct LineItem {
+    update_type: Option<char>,
+    file_type: Option<char>,
+    content_change: bool,
+    size_change: bool,
+    time_change: bool,
+    mode_change: bool,
+    fill: Option<char>,
+}
+
+impl fmt::Display for LineItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        trait Ifc {
+            fn ifc(self, ifc: char, fill: char) -> char;
+        }
+        impl Ifc for bool {
+            fn ifc(self, ifc: char, fill: char) -> char {
+                if self {
+                    ifc
+                } else {
+                    fill
+                }
+            }
+        }
+
+        let fill = self.fill.unwrap_or('.');
+        write!(
+            f,
+            "{}{}{}{}{}{}{}{}{}{}{}",
+            self.update_type.unwrap_or(fill),
+            self.file_type.unwrap_or(fill),
+            self.content_change.ifc('c', fill),
+            self.size_change.ifc('s', fill),
+            self.time_change.ifc('t', fill),
+            self.mode_change.ifc('p', fill),
+            fill,
+            fill,
+            fill,
+            fill,
+            fill
+        )
+    }
+}
+
+impl<W: io::Write> Logger for ItemisedLogger<W> {
+    fn log(&self, level: LogLevel, what: &Log) {
+        if level > self.level {
+            return;
+        }
+
+        let mut out = self.out.lock().unwrap();
+
+        macro_rules! say {
+            ($item:expr, $dir:expr, $name:expr) => {{
+                let _ = writeln!(
+                    out,
+                    "{:<11} {}",
+                    $item,
+                    self.display_path($dir, $name).display()
+                );
+            }};
+        }
+
+        fn file_type(fd: &FileData) -> Option<char> {
+            match *fd {
+                FileData::Regular(..) => Some('f'),
+                FileData::Directory(..) => Some('d'),
+                FileData::Symlink(..) => Some('L'),
+                FileData::Special => Some('S'),
+            }
+        }
+
+        fn update_type(side: ReplicaSide, data: &FileData) -> Option<char> {
+            match (side, data) {
+                (ReplicaSide::Client, &FileData::Regular(..)) => Some('>'),
+                (ReplicaSide::Server, &FileData::Regular(..)) => Some('<'),
+                _ => Some('c'),
+            }
+        }
+
+        fn nan(side: ReplicaSide) -> bool {
+            ReplicaSide::Ancestor != side
+        }
+
+        match *what {
+            Log::Error(..) => {}
+            Log::RecursiveDelete(..) => {}
+            Log::EnterDirectory(..) | Log::LeaveDirectory(..) => {}
+            Log::Progress(..) => {}
+
+            Log::Inspect(parent, name, Reconciliation::InSync, _)
+            | Log::Inspect(parent, name, Reconciliation::Unsync, _)
+            | Log::Inspect(parent, name, Reconciliation::Irreconcilable, _) => {
+                // We can't really output a file type even if the data were
+                // included in this log type, since the three replicas could
+                // each have a different file type.
+                say!(
+                    LineItem {
+                        fill: Some(' '),
+                        update_type: Some('.'),
+                        file_type: Some('?'),
+                        ..LineItem::default()
+                    },
+                    parent,
+                    Some(name)
+                );
+            }
+
+            Log::Inspect(..) => {}
+
+            Log::Create(side, parent, name, data) => {
+                if nan(side) {
+                    say!(
+                        LineItem {
+                            fill: Some('+'),
+                            update_type: update_type(side, data),
+                            file_type: file_type(data),
+                            ..LineItem::default()
+                        },
+                        parent,
+                        Some(name)
+                    )
+                }
+            }
+
+            Log::Update(side, parent, name, old, new) => {
+                if nan(side) {
+                    let (content_change, size_change, time_change, mode_change) =
+                        match (old, new) {
+                            (
+                                &FileData::Regular(
+                                    mode1,
+                                    size1,
+                                    time1,
+                                    content1,
+                                ),
+                                &FileData::Regular(
+                                    mode2,
+                                    size2,
+                                    time2,
+                                    content2,
+                                ),
+                            ) => (
+                                content1 != content2,
+                                size1 != size2,
+                                time1 != time2,
+                                mode1 != mode2,
+                            ),
+
+                            (
+                                &FileData::Symlink(..),
+                                &FileData::Symlink(..),
+                            ) => (true, false, false, false),
+
+                            (
+                                &FileData::Directory(..),
+                                &FileData::Directory(..),
+                            ) => (false, false, false, true),
+
+                            _ => (true, false, false, true),
+                        };
+
+                    say!(
+                        LineItem {
+                            update_type: if content_change {
+                                update_type(side, new)
+                            } else {
+                                Some('.')
+                            },
+                            file_type: file_type(new),
+                            content_change: content_change,
+                            mode_change: mode_change,
+                            time_change: time_change,
+                            size_change: size_change,
+                            ..LineItem::default()
+                        },
+                        parent,
+                        Some(name)
+                    );
+                }
+            }
+
+            Log::Rename(side, parent, old, new) => {
+                if nan(side) {
+                    say!("*renamefrom", parent, Some(old));
+                    say!("*renameto", parent, Some(new));
+                }
+            }
+
+            Log::Remove(side, parent, name, _) => {
+                if nan(side) {
+                    say!("*delete", parent, Some(name));
+                }
+            }
+
+            Log::Rmdir(side, path) => {
+                if nan(side) {
+                    say!("*delete", path, None);
+                }
+            }
+        }
+    }
+}
+
+/// A `Logger` which writes each event at or below a given level to a stream as
+/// one JSON object per line (NDJSON), for consumption by other programs.
+///
+/// Every object has `level` and `type` (the name of the `Log` variant) fields;
+/// the rest depend on the type. Paths and symlink targets are written as
+/// UTF-8 strings, lossily converted if necessary; if the conversion was lossy,
+/// the exact bytes are also given, base64-encoded, in a field of the same
+/// name suffixed with `_base64`.
+///
+/// Errors writing to the stream are ignored.
+#[derive(Debug)]
+pub struct JsonLogger<W> {
+    out: Mutex<W>,
+    level: LogLevel,
+}
+
+impl<W: io::Write> JsonLogger<W> {
+    /// Creates a logger which writes events at `level` or more severe to `out`.
+    pub fn new(out: W, level: LogLevel) -> Self {
+        JsonLogger {
+            out: Mutex::new(out),
+            level: level,
+        }
+    }
+
+    /// Consumes the logger, returning the underlying stream.
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap()
+    }
+}
+
+impl<W: io::Write> Logger for JsonLogger<W> {
+    fn log(&self, level: LogLevel, what: &Log) {
+        if level > self.level {
+            return;
+        }
+
+        let line = log_to_json(level, what);
+        let _ = writeln!(self.out.lock().unwrap(), "{}", line);
+    }
+}
+
+fn log_to_json(level: LogLevel, what: &Log) -> String {
+    let mut obj = JsonObject::new();
+    match level {
+        FATAL => obj.str("level", "fatal"),
+        ERROR => obj.str("level", "error"),
+        WARN => obj.str("level", "warn"),
+        EDIT => obj.str("level", "edit"),
+        INFO => obj.str("level", "info"),
+        _ => obj.display("level", level),
+    }
+
+    match *what {
+        Log::Inspect(dir, name, recon, conflict) => {
+            obj.str("type", "Inspect");
+            obj.path("dir", dir);
+            obj.path("name", name);
+            obj.str("reconciliation", &format!("{:?}", recon));
+            obj.str("conflict", &format!("{:?}", conflict));
+        }
+        Log::Create(side, dir, name, data) => {
+            obj.str("type", "Create");
+            obj.side(side);
+            obj.path("dir", dir);
+            obj.path("name", name);
+            obj.raw("data", &file_data_to_json(data));
+        }
+        Log::Update(side, dir, name, old, new) => {
+            obj.str("type", "Update");
+            obj.side(side);
+            obj.path("dir", dir);
+            obj.path("name", name);
+            obj.raw("old", &file_data_to_json(old));
+            obj.raw("new", &file_data_to_json(new));
+        }
+        Log::Rename(side, dir, old, new) => {
+            obj.str("type", "Rename");
+            obj.side(side);
+            obj.path("dir", dir);
+            obj.path("old", old);
+            obj.path("new", new);
+        }
+        Log::Remove(side, dir, name, data) => {
+            obj.str("type", "Remove");
+            obj.side(side);
+            obj.path("dir", dir);
+            obj.path("name", name);
+            obj.raw("data", &file_data_to_json(data));
+        }
+        Log::Rmdir(side, dir) => {
+            obj.str("type", "Rmdir");
+            obj.side(side);
+            obj.path("dir", dir);
+        }
+        Log::RecursiveDelete(side, dir) => {
+            obj.str("type", "RecursiveDelete");
+            obj.side(side);
+            obj.path("dir", dir);
+        }
+        Log::Error(side, dir, op, err) => {
+            obj.str("type", "Error");
+            obj.side(side);
+            obj.path("dir", dir);
+            obj.str("operation", &format!("{:?}", op.kind()));
+            match op {
+                ErrorOperation::Chdir(name)
+                | ErrorOperation::Create(name)
+                | ErrorOperation::Update(name)
+                | ErrorOperation::Rename(name)
+                | ErrorOperation::Remove(name)
+                | ErrorOperation::Access(name) => obj.path("name", name),
+                ErrorOperation::List
+                | ErrorOperation::MarkClean
+                | ErrorOperation::Rmdir => (),
+            }
+            obj.str("error", &err.to_string());
+        }
+        Log::EnterDirectory(dir) => {
+            obj.str("type", "EnterDirectory");
+            obj.path("dir", dir);
+        }
+        Log::LeaveDirectory(dir) => {
+            obj.str("type", "LeaveDirectory");
+            obj.path("dir", dir);
+        }
+        Log::Progress(side, path, done, total) => {
+            obj.str("type", "Progress");
+            obj.side(side);
+            obj.path("path", path);
+            obj.display("done", done);
+            obj.display("total", total);
+        }
+    }
+
+    obj.finish()
+}
+
+fn file_data_to_json(data: &FileData) -> String {
+    let mut obj = JsonObject::new();
+    match *data {
+        FileData::Directory(mode) => {
+            obj.str("type", "directory");
+            obj.display("mode", mode);
+        }
+        FileData::Regular(mode, size, time, ref hash) => {
+            obj.str("type", "regular");
+            obj.display("mode", mode);
+            obj.display("size", size);
+            obj.display("time", time);
+            let mut hex = String::with_capacity(2 * hash.len());
+            for b in hash {
+                let _ = write!(hex, "{:02x}", b);
+            }
+            obj.str("hash", &hex);
+        }
+        FileData::Symlink(ref target) => {
+            obj.str("type", "symlink");
+            obj.path("target", target);
+        }
+        FileData::Special => obj.str("type", "special"),
+    }
+    obj.finish()
+}
+
+/// Incrementally builds the text of a JSON object.
+struct JsonObject(String);
+
+impl JsonObject {
+    fn new() -> Self {
+        JsonObject("{".to_owned())
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        push_json_string(&mut self.0, key);
+        self.0.push(':');
+    }
+
+    fn str(&mut self, key: &str, value: &str) {
+        self.key(key);
+        push_json_string(&mut self.0, value);
+    }
+
+    /// Adds a value which is already valid JSON, such as a number.
+    fn display<T: fmt::Display>(&mut self, key: &str, value: T) {
+        self.key(key);
+        let _ = write!(self.0, "{}", value);
+    }
+
+    fn raw(&mut self, key: &str, json: &str) {
+        self.key(key);
+        self.0.push_str(json);
+    }
+
+    fn path(&mut self, key: &str, path: &OsStr) {
+        match path.to_str() {
+            Some(s) => self.str(key, s),
+            None => {
+                self.str(key, &path.to_string_lossy());
+                self.str(&format!("{}_base64", key), &base64(path.as_bytes()));
+            }
+        }
+    }
+
+    fn side(&mut self, side: ReplicaSide) {
+        self.str(
+            "side",
+            match side {
+                ReplicaSide::Client => "client",
+                ReplicaSide::Ancestor => "ancestor",
+                ReplicaSide::Server => "server",
+            },
+        );
+    }
+
+    fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Encodes `data` as standard, padded base64.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// A `Logger` which appends every event, with a timestamp, to a file.
+///
+/// Each event is flushed as soon as it is written. Before each write, the
+/// open file is compared against whatever is currently at the path, and if
+/// the file was rotated away (renamed or removed), the path is reopened so
+/// that writing continues in the new file. If writing fails, the file is
+/// likewise reopened and the write retried once; further errors are ignored.
+///
+/// Clones share the same underlying file. Use `FilterLogger` to limit which
+/// levels are written.
+#[derive(Clone, Debug)]
+pub struct FileLogger {
+    path: Arc<PathBuf>,
+    file: Arc<Mutex<fs::File>>,
+}
+
+impl FileLogger {
+    /// Opens `path` for appending, creating it if it does not exist.
+    pub fn new<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let file = FileLogger::open(&path)?;
+        Ok(FileLogger {
+            path: Arc::new(path),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    fn open(path: &Path) -> io::Result<fs::File> {
+        fs::OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Returns whether `file` is still the file found at `path`.
+    fn is_current(path: &Path, file: &fs::File) -> bool {
+        match (fs::metadata(path), file.metadata()) {
+            (Ok(at_path), Ok(open)) => {
+                at_path.dev() == open.dev() && at_path.ino() == open.ino()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Logger for FileLogger {
+    fn log(&self, level: LogLevel, what: &Log) {
+        let line = format!(
+            "{} [{}] {:?}\n",
+            Utc::now().to_rfc3339(),
+            level_name(level),
+            what
+        );
+
+        fn write(file: &mut fs::File, line: &str) -> io::Result<()> {
+            file.write_all(line.as_bytes())?;
+            file.flush()
+        }
+
+        let mut file = self.file.lock().unwrap();
+        if !FileLogger::is_current(&self.path, &file) {
+            if let Ok(reopened) = FileLogger::open(&self.path) {
+                *file = reopened;
+            }
+        }
+        if write(&mut file, &line).is_err() {
+            if let Ok(reopened) = FileLogger::open(&self.path) {
+                *file = reopened;
+                let _ = write(&mut file, &line);
+            }
+        }
+    }
+}
+
+/// Returns a fixed-width name for `level`.
+fn level_name(level: LogLevel) -> &'static str {
+    match level {
+        FATAL => "FATAL",
+        ERROR => "ERROR",
+        WARN => " WARN",
+        EDIT => " EDIT",
+        INFO => " INFO",
+        _ => "?????",
+    }
+}
+
+#[cfg(test)]
+mod channel_logger {
+    use std::ffi::OsString;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::mpsc;
+
+    use super::*;
+
+    /// Owned counterpart of `ErrorOperation`.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum OwnedErrorOperation {
+        List,
+        MarkClean,
+        Chdir(OsString),
+        Create(OsString),
+        Update(OsString),
+        Rename(OsString),
+        Remove(OsString),
+        Rmdir,
+        Access(OsString),
+    }
+
+    impl<'a> From<ErrorOperation<'a>> for OwnedErrorOperation {
+        fn from(op: ErrorOperation<'a>) -> Self {
+            match op {
+                ErrorOperation::List => OwnedErrorOperation::List,
+                ErrorOperation::MarkClean => OwnedErrorOperation::MarkClean,
+                ErrorOperation::Chdir(n) => {
+                    OwnedErrorOperation::Chdir(n.to_owned())
+                }
+                ErrorOperation::Create(n) => {
+                    OwnedErrorOperation::Create(n.to_owned())
+                }
+                ErrorOperation::Update(n) => {
+                    OwnedErrorOperation::Update(n.to_owned())
+                }
+                ErrorOperation::Rename(n) => {
+                    OwnedErrorOperation::Rename(n.to_owned())
+                }
+                ErrorOperation::Remove(n) => {
+                    OwnedErrorOperation::Remove(n.to_owned())
+                }
+                ErrorOperation::Rmdir => OwnedErrorOperation::Rmdir,
+                ErrorOperation::Access(n) => {
+                    OwnedErrorOperation::Access(n.to_owned())
+                }
+            }
+        }
+    }
+
+    /// Owned counterpart of `Log`, for handing log events to other threads.
+    ///
+    /// Errors cannot be cloned, so `Error` carries the error message instead.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum OwnedLog {
+        Inspect(OsString, OsString, Reconciliation, Conflict),
+        Create(ReplicaSide, OsString, OsString, FileData),
+        Update(ReplicaSide, OsString, OsString, FileData, FileData),
+        Rename(ReplicaSide, OsString, OsString, OsString),
+        Remove(ReplicaSide, OsString, OsString, FileData),
+        Rmdir(ReplicaSide, OsString),
+        RecursiveDelete(ReplicaSide, OsString),
+        Error(ReplicaSide, OsString, OwnedErrorOperation, String),
+        EnterDirectory(OsString),
+        LeaveDirectory(OsString),
+        Progress(ReplicaSide, OsString, FileSize, FileSize),
+    }
+
+    impl<'a, 'b> From<&'b Log<'a>> for OwnedLog {
+        fn from(log: &'b Log<'a>) -> Self {
+            match *log {
+                Log::Inspect(dir, name, recon, conflict) => OwnedLog::Inspect(
+                    dir.to_owned(),
+                    name.to_owned(),
+                    recon,
+                    conflict,
+                ),
+                Log::Create(side, dir, name, data) => OwnedLog::Create(
+                    side,
+                    dir.to_owned(),
+                    name.to_owned(),
+                    data.clone(),
+                ),
+                Log::Update(side, dir, name, old, new) => OwnedLog::Update(
+                    side,
+                    dir.to_owned(),
+                    name.to_owned(),
+                    old.clone(),
+                    new.clone(),
+                ),
+                Log::Rename(side, dir, old, new) => OwnedLog::Rename(
+                    side,
+                    dir.to_owned(),
+                    old.to_owned(),
+                    new.to_owned(),
+                ),
+                Log::Remove(side, dir, name, data) => OwnedLog::Remove(
+                    side,
+                    dir.to_owned(),
+                    name.to_owned(),
+                    data.clone(),
+                ),
+                Log::Rmdir(side, dir) => OwnedLog::Rmdir(side, dir.to_owned()),
+                Log::RecursiveDelete(side, dir) => {
+                    OwnedLog::RecursiveDelete(side, dir.to_owned())
+                }
+                Log::Error(side, dir, op, err) => OwnedLog::Error(
+                    side,
+                    dir.to_owned(),
+                    op.into(),
+                    err.to_string(),
+                ),
+                Log::EnterDirectory(dir) => {
+                    OwnedLog::EnterDirectory(dir.to_owned())
+                }
+                Log::LeaveDirectory(dir) => {
+                    OwnedLog::LeaveDirectory(dir.to_owned())
+                }
+                Log::Progress(side, path, done, total) => {
+                    OwnedLog::Progress(side, path.to_owned(), done, total)
+                }
+            }
+        }
+    }
+
+    /// A `Logger` which sends every event, with its level, through a bounded
+    /// channel, so that tests can examine what the reconciler logged.
+    ///
+    /// Logging never blocks: if the channel is full, or the receiver has gone
+    /// away, the event is discarded and counted in `dropped()` instead.
+    pub struct ChannelLogger {
+        sender: Mutex<mpsc::SyncSender<(LogLevel, OwnedLog)>>,
+        dropped: AtomicU64,
+    }
+
+    impl ChannelLogger {
+        /// Creates a logger whose channel holds up to `capacity` events, along
+        /// with the receiving end of that channel.
+        pub fn new(
+            capacity: usize,
+        ) -> (Self, mpsc::Receiver<(LogLevel, OwnedLog)>) {
+            let (sender, receiver) = mpsc::sync_channel(capacity);
+            (
+                ChannelLogger {
+                    sender: Mutex::new(sender),
+                    dropped: AtomicU64::new(0),
+                },
+                receiver,
+            )
+        }
+
+        /// Returns the number of events discarded so far.
+        pub fn dropped(&self) -> u64 {
+            self.dropped.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Logger for ChannelLogger {
+        fn log(&self, level: LogLevel, what: &Log) {
+            let event = (level, OwnedLog::from(what));
+            if self.sender.lock().unwrap().try_send(event).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub use self::channel_logger::{ChannelLogger, OwnedLog};
+
 #[cfg(test)]
 mod println_logger {
     use super::*;
@@ -102,18 +1050,370 @@ mod println_logger {
 
     impl Logger for PrintlnLogger {
         fn log(&self, level: LogLevel, what: &Log) {
-            let level_str = match level {
-                FATAL => "FATAL",
-                ERROR => "ERROR",
-                WARN => " WARN",
-                EDIT => " EDIT",
-                INFO => " INFO",
-                _ => "?????",
-            };
-            println!("[{}] {:?}", level_str, what);
+            println!("[{}] {:?}", level_name(level), what);
         }
     }
 }
 
 #[cfg(test)]
 pub use self::println_logger::PrintlnLogger;
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+
+    use super::*;
+    use crate::errors::ErrorKind;
+
+    #[test]
+    fn summary_logger_counts_errors_by_side_and_operation() {
+        let logger = SummaryLogger::new(PrintlnLogger);
+        let error = ErrorKind::TransferCancelled.into();
+        let dir = OsStr::new("dir");
+        let file = OsStr::new("file");
+
+        for &(side, op) in &[
+            (ReplicaSide::Client, ErrorOperation::Create(file)),
+            (ReplicaSide::Client, ErrorOperation::Create(file)),
+            (ReplicaSide::Client, ErrorOperation::Create(file)),
+            (ReplicaSide::Client, ErrorOperation::Rename(file)),
+            (ReplicaSide::Server, ErrorOperation::List),
+        ] {
+            logger.log(ERROR, &Log::Error(side, dir, op, &error));
+        }
+        logger.log(EDIT, &Log::RecursiveDelete(ReplicaSide::Server, dir));
+
+        let summary = logger.take();
+        assert_eq!(5, summary.total_errors());
+        let errors: Vec<_> = summary.errors.into_iter().collect();
+        assert_eq!(
+            vec![
+                ((ReplicaSide::Client, ErrorOperationKind::Create), 3),
+                ((ReplicaSide::Client, ErrorOperationKind::Rename), 1),
+                ((ReplicaSide::Server, ErrorOperationKind::List), 1),
+            ],
+            errors
+        );
+
+        assert_eq!(Summary::default(), logger.take());
+    }
+
+    fn itemise(include_unchanged: bool, events: &[(LogLevel, Log)]) -> String {
+        let logger = ItemisedLogger::new(
+            Vec::new(),
+            PathBuf::from("/root"),
+            include_unchanged,
+        );
+        for &(level, ref what) in events {
+            logger.log(level, what);
+        }
+        String::from_utf8(logger.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn itemised_logger_formats_changes() {
+        let root = OsStr::new("/root");
+        let sub = OsStr::new("/root/sub");
+        let old = FileData::Regular(0o644, 10, 100, [1; 32]);
+
+        let output = itemise(
+            false,
+            &[
+                (INFO, Log::EnterDirectory(root)),
+                (
+                    INFO,
+                    Log::Inspect(
+                        root,
+                        OsStr::new("same"),
+                        Reconciliation::InSync,
+                        Conflict::NoConflict,
+                    ),
+                ),
+                (
+                    EDIT,
+                    Log::Create(
+                        ReplicaSide::Server,
+                        root,
+                        OsStr::new("new"),
+                        &old,
+                    ),
+                ),
+                (
+                    EDIT,
+                    Log::Create(
+                        ReplicaSide::Client,
+                        sub,
+                        OsStr::new("dir"),
+                        &FileData::Directory(0o755),
+                    ),
+                ),
+                (
+                    EDIT,
+                    Log::Create(
+                        ReplicaSide::Ancestor,
+                        root,
+                        OsStr::new("new"),
+                        &old,
+                    ),
+                ),
+                (
+                    EDIT,
+                    Log::Update(
+                        ReplicaSide::Client,
+                        root,
+                        OsStr::new("grown"),
+                        &old,
+                        &FileData::Regular(0o600, 20, 100, [2; 32]),
+                    ),
+                ),
+                (
+                    EDIT,
+                    Log::Update(
+                        ReplicaSide::Server,
+                        root,
+                        OsStr::new("touched"),
+                        &old,
+                        &FileData::Regular(0o644, 10, 200, [1; 32]),
+                    ),
+                ),
+                (
+                    EDIT,
+                    Log::Update(
+                        ReplicaSide::Client,
+                        root,
+                        OsStr::new("retyped"),
+                        &old,
+                        &FileData::Symlink("target".into()),
+                    ),
+                ),
+                (
+                    EDIT,
+                    Log::Rename(
+                        ReplicaSide::Server,
+                        root,
+                        OsStr::new("from"),
+                        OsStr::new("to"),
+                    ),
+                ),
+                (
+                    EDIT,
+                    Log::Remove(
+                        ReplicaSide::Client,
+                        sub,
+                        OsStr::new("gone"),
+                        &old,
+                    ),
+                ),
+                (EDIT, Log::Rmdir(ReplicaSide::Server, sub)),
+                (INFO, Log::LeaveDirectory(root)),
+            ],
+        );
+
+        assert_eq!(
+            "<f+++++++++ new\n\
+             cd+++++++++ sub/dir\n\
+             >fcs.p..... grown\n\
+             .f..t...... touched\n\
+             cLc..p..... retyped\n\
+             *renamefrom from\n\
+             *renameto   to\n\
+             *delete     sub/gone\n\
+             *delete     sub\n",
+            output
+        );
+    }
+
+    #[test]
+    fn itemised_logger_includes_unchanged_on_request() {
+        let root = OsStr::new("/root");
+        let inspect = Log::Inspect(
+            root,
+            OsStr::new("same"),
+            Reconciliation::InSync,
+            Conflict::NoConflict,
+        );
+
+        assert_eq!("", itemise(false, &[(INFO, inspect)]));
+        assert_eq!(".?          same\n", itemise(true, &[(INFO, inspect)]));
+    }
+
+    #[test]
+    fn base64_encodes_with_padding() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9v", base64(b"foo"));
+        assert_eq!("Zm9vYg==", base64(b"foob"));
+        assert_eq!("/w==", base64(b"\xff"));
+    }
+
+    #[test]
+    fn json_logger_writes_one_object_per_line() {
+        let logger = JsonLogger::new(Vec::new(), EDIT);
+        let error: Error = ErrorKind::TransferCancelled.into();
+        let dir = OsStr::new("dir");
+
+        logger.log(
+            EDIT,
+            &Log::Create(
+                ReplicaSide::Server,
+                dir,
+                OsStr::from_bytes(b"a\"\xffb"),
+                &FileData::Regular(0o644, 3, 42, [0xab; 32]),
+            ),
+        );
+        // Filtered out by level
+        logger.log(INFO, &Log::EnterDirectory(dir));
+        logger.log(
+            ERROR,
+            &Log::Error(
+                ReplicaSide::Client,
+                dir,
+                ErrorOperation::Remove(OsStr::new("f")),
+                &error,
+            ),
+        );
+        logger.log(WARN, &Log::Rmdir(ReplicaSide::Ancestor, dir));
+
+        let output = String::from_utf8(logger.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            vec![
+                format!(
+                    "{{\"level\":\"edit\",\"type\":\"Create\",\
+                     \"side\":\"server\",\"dir\":\"dir\",\
+                     \"name\":\"a\\\"\u{fffd}b\",\
+                     \"name_base64\":\"YSL/Yg==\",\
+                     \"data\":{{\"type\":\"regular\",\"mode\":420,\
+                     \"size\":3,\"time\":42,\"hash\":\"{}\"}}}}",
+                    "ab".repeat(32)
+                ),
+                format!(
+                    "{{\"level\":\"error\",\"type\":\"Error\",\
+                     \"side\":\"client\",\"dir\":\"dir\",\
+                     \"operation\":\"Remove\",\"name\":\"f\",\
+                     \"error\":\"{}\"}}",
+                    error
+                ),
+                "{\"level\":\"warn\",\"type\":\"Rmdir\",\
+                 \"side\":\"ancestor\",\"dir\":\"dir\"}"
+                    .to_owned(),
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn filter_logger_drops_less_severe_events() {
+        let (logger, receiver) = ChannelLogger::new(16);
+        let logger = FilterLogger::new(logger, WARN);
+        let dir = OsStr::new("dir");
+
+        for &level in &[INFO, EDIT, WARN, ERROR, FATAL] {
+            logger.log(level, &Log::EnterDirectory(dir));
+        }
+
+        let levels: Vec<LogLevel> =
+            receiver.try_iter().map(|(level, _)| level).collect();
+        assert_eq!(vec![WARN, ERROR, FATAL], levels);
+    }
+
+    #[test]
+    fn file_logger_appends_timestamped_lines() {
+        let dir = tempfile::Builder::new()
+            .prefix("file-logger")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("log");
+        let logger = FileLogger::new(&path).unwrap();
+        let clone = logger.clone();
+        let event = Log::Rmdir(ReplicaSide::Client, OsStr::new("dir"));
+
+        logger.log(EDIT, &event);
+        fs::rename(&path, dir.path().join("log.1")).unwrap();
+        clone.log(ERROR, &event);
+
+        let check = |path: &Path, level: &str| {
+            let content = fs::read_to_string(path).unwrap();
+            let lines: Vec<&str> = content.lines().collect();
+            assert_eq!(1, lines.len());
+            let (timestamp, rest) =
+                lines[0].split_at(lines[0].find(' ').unwrap());
+            chrono::DateTime::parse_from_rfc3339(timestamp).unwrap();
+            assert_eq!(format!(" [{}] {:?}", level, event), rest);
+        };
+        check(&dir.path().join("log.1"), " EDIT");
+        check(&path, "ERROR");
+    }
+
+    #[test]
+    fn file_logger_reopens_removed_file() {
+        let dir = tempfile::Builder::new()
+            .prefix("file-logger")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("log");
+        let logger = FileLogger::new(&path).unwrap();
+        let event = Log::Rmdir(ReplicaSide::Client, OsStr::new("dir"));
+
+        logger.log(EDIT, &event);
+        fs::remove_file(&path).unwrap();
+        logger.log(ERROR, &event);
+        logger.log(WARN, &event);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].contains("[ERROR]"));
+        assert!(lines[1].contains("[ WARN]"));
+    }
+
+    #[test]
+    fn channel_logger_sends_events_in_order() {
+        let (logger, receiver) = ChannelLogger::new(2);
+        let error = ErrorKind::TransferCancelled.into();
+        let dir = OsStr::new("dir");
+        let file = OsStr::new("file");
+
+        logger.log(EDIT, &Log::Rmdir(ReplicaSide::Client, dir));
+        logger.log(
+            ERROR,
+            &Log::Error(
+                ReplicaSide::Server,
+                dir,
+                ErrorOperation::Create(file),
+                &error,
+            ),
+        );
+        // The channel is full, so this is dropped rather than blocking
+        logger.log(EDIT, &Log::RecursiveDelete(ReplicaSide::Server, dir));
+        assert_eq!(1, logger.dropped());
+
+        assert_eq!(
+            (EDIT, OwnedLog::Rmdir(ReplicaSide::Client, dir.to_owned())),
+            receiver.recv().unwrap()
+        );
+        assert_eq!(
+            (
+                ERROR,
+                OwnedLog::Error(
+                    ReplicaSide::Server,
+                    dir.to_owned(),
+                    OwnedErrorOperation::Create(file.to_owned()),
+                    error.to_string(),
+                )
+            ),
+            receiver.recv().unwrap()
+        );
+
+        logger.log(INFO, &Log::Rmdir(ReplicaSide::Ancestor, dir));
+        assert_eq!(
+            (INFO, OwnedLog::Rmdir(ReplicaSide::Ancestor, dir.to_owned())),
+            receiver.recv().unwrap()
+        );
+
+        drop(receiver);
+        logger.log(INFO, &Log::Rmdir(ReplicaSide::Ancestor, dir));
+        assert_eq!(2, logger.dropped());
+    }
+}
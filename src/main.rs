@@ -107,6 +107,9 @@ enum Command {
     Put(PutSubcommand),
     #[structopt(alias = "del")]
     Rm(RmSubcommand),
+    #[structopt(alias = "fsck")]
+    Verify(VerifySubcommand),
+    ExplainPath(ExplainPathSubcommand),
     Server(ServerSubcommand),
 }
 
@@ -119,9 +122,22 @@ enum KeySubcommand {
     Change(KeyChangeSubcommand),
     #[structopt(alias = "del")]
     Rm(KeyRmSubcommand),
+    #[structopt(alias = "mv")]
+    Rename(KeyRenameSubcommand),
     #[structopt(alias = "list")]
     Ls(KeyLsSubcommand),
+    Probe(KeyProbeSubcommand),
+    Access(KeyAccessSubcommand),
+    Check(KeyCheckSubcommand),
+    Rewrap(KeyRewrapSubcommand),
+    Backup(KeyBackupSubcommand),
+    Restore(KeyRestoreSubcommand),
+    Audit(KeyAuditSubcommand),
+    Export(KeyExportSubcommand),
+    Import(KeyImportSubcommand),
     Group(KeyGroupSubcommand),
+    Format(KeyFormatSubcommand),
+    MinKeys(KeyMinKeysSubcommand),
 }
 
 /// Manage key groups.
@@ -133,6 +149,8 @@ enum KeyGroupSubcommand {
     #[structopt(alias = "dissoc")]
     Disassoc(KeyGroupDisassocSubcommand),
     Destroy(KeyGroupDestroySubcommand),
+    #[structopt(alias = "list")]
+    Ls(KeyGroupLsSubcommand),
 }
 
 /// Wizard to set up simple ensync configurations.
@@ -194,7 +212,7 @@ As one might expect, `ensync sync` is the main subcommand. When invoked, \
 to and from the server according to the configuration.
 
 All default output goes to standard error. Standard output is reserved for \
-the output of `--itemise`.
+the output of `--itemise`, or for the log itself with `--log-format json`.
 
 The \"verbose\" and \"quiet\" arguments move the standard error verbosity \
 along the below spectrum, where \"quiet\" is negative and \"verbose\" is \
@@ -252,10 +270,28 @@ struct SyncSubcommand {
                 possible_values = &["auto", "always", "never"])]
     spin: String,
 
+    /// Format of the log. With `json`, each event is written to stdout as a
+    /// JSON object on its own line instead of to stderr.
+    #[structopt(long, default_value = "human",
+                possible_values = &["human", "json"],
+                conflicts_with = "itemise")]
+    log_format: String,
+
     /// Don't actually make any changes.
     #[structopt(short = "n", long)]
     dry_run: bool,
 
+    /// Before syncing, work out and print how many files and bytes will be
+    /// transferred, and how many conflicts there are. This requires a dry run
+    /// of the whole sync, so it takes roughly twice as long.
+    #[structopt(long, conflicts_with = "dry_run")]
+    estimate: bool,
+
+    /// After syncing, print how much file content was uploaded, how much of
+    /// it the server already had, and how much of it was all zeroes.
+    #[structopt(long, conflicts_with = "dry_run")]
+    stats: bool,
+
     /// When done syncing, continue running and monitor for file changes and
     /// resync when detected. Syncs happen a short time after the changes occur
     /// to give the system time to reach quiescence. This requires extra
@@ -275,6 +311,12 @@ struct SyncSubcommand {
     #[structopt(long, requires = "watch")]
     reconnect: Option<u64>,
 
+    /// Fail a transfer from the server if this many seconds pass without a
+    /// block of it completing, instead of waiting indefinitely on a stuck
+    /// connection. Combined with `--reconnect`, the sync is then restarted.
+    #[structopt(long)]
+    stall_timeout: Option<u64>,
+
     /// Control what is checked for syncing. "fast" means to only scan
     /// directories that have obviously changed. "clean" causes all directories
     /// to be scanned. "scrub" additionally causes all cached hashes to be
@@ -311,6 +353,23 @@ struct KeyInitSubcommand {
     #[structopt(long, default_value = "initial-key")]
     key_name: String,
 
+    /// Key derivation algorithm for the first key, one of
+    /// `scrypt-18/14/12-8-1`, `scrypt-<log2 N>-<r>-<p>` or `argon2id-64m-3-4`,
+    /// optionally followed by `+sha3-512` to verify the derived key with
+    /// SHA3-512.
+    #[structopt(long, default_value = "scrypt-18/14/12-8-1")]
+    algorithm: String,
+
+    /// Free-form comment to attach to the first key.
+    #[structopt(long)]
+    comment: Option<String>,
+
+    /// Never record when each key is last used. The key store is readable
+    /// by anyone with access to the server, so these times reveal when each
+    /// client syncs. This cannot be changed later.
+    #[structopt(long)]
+    no_record_key_use: bool,
+
     #[structopt(skip)]
     verbosity: NonVerbose,
 }
@@ -398,7 +457,8 @@ struct KeyChangeSubcommand {
     #[structopt(flatten)]
     root: RootKeyArg,
 
-    /// The name of the key to edit.
+    /// The name of the key to edit. If omitted and there is more than one
+    /// key, the key is chosen from a menu when run from a terminal.
     key_name: Option<String>,
 
     /// Change `key-name` even if the old passphrase does not correspond to
@@ -406,6 +466,11 @@ struct KeyChangeSubcommand {
     #[structopt(short, long)]
     force: bool,
 
+    /// Key derivation algorithm for the new passphrase, in the same format as
+    /// for `key init`. By default, the algorithm of the key is kept.
+    #[structopt(long)]
+    algorithm: Option<String>,
+
     #[structopt(skip)]
     verbosity: NonVerbose,
 }
@@ -440,12 +505,356 @@ struct KeyRmSubcommand {
     verbosity: NonVerbose,
 }
 
+/// Rename a key.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+Only the name of the key changes; its passphrase, groups, and timestamps are \
+kept as they were. A passphrase in the `root` group is required. By default, \
+this prompts the terminal, but the `--root` argument can be used to use other \
+passphrase methods."
+))]
+struct KeyRenameSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(flatten)]
+    root: RootKeyArg,
+
+    /// The current name of the key.
+    old_name: String,
+
+    /// The new name for the key.
+    new_name: String,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Set the minimum number of keys the key store must keep.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+Once set, deleting a key or removing the `root` group from a key fails if it \
+would leave fewer than <count> keys in the key store, or fewer than <count> \
+keys in the `root` group. Setting the minimum fails if the key store does \
+not already satisfy it. Use `none` to remove the minimum.
+
+A passphrase in the `root` group is required. By default, this prompts the \
+terminal, but the `--root` argument can be used to use other passphrase \
+methods."
+))]
+struct KeyMinKeysSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(flatten)]
+    root: RootKeyArg,
+
+    /// The minimum number of keys, or `none`.
+    count: String,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
 /// List the keys in the key store.
 #[derive(StructOpt)]
 struct KeyLsSubcommand {
     #[structopt(flatten)]
     config: ConfigArg,
 
+    /// Instead of listing the keys, show how many keys use each KDF
+    /// algorithm.
+    #[structopt(long)]
+    algorithms: bool,
+
+    /// Order the keys by this field. Keys which have never been changed or
+    /// used are listed last when ordering by those times.
+    #[structopt(
+        long,
+        default_value = "name",
+        possible_values = &["name", "created", "updated", "used"]
+    )]
+    sort: String,
+
+    /// Reverse the order of the keys.
+    #[structopt(short, long)]
+    reverse: bool,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Check that the server is reachable and the passphrase is valid.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+This command reads the key store from the server and checks the passphrase \
+against it, printing how long each step took. Nothing is written to the \
+server; in particular, the key is not recorded as used.
+
+The command fails if the key store cannot be read, has not been initialised, \
+or does not contain a key matching the passphrase, which makes it suitable \
+for health checks."
+))]
+struct KeyProbeSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Show which key groups the passphrase can and cannot unlock.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+This command lists the groups which the key matching the passphrase is in, \
+followed by the groups which exist in the key store but which that key is not \
+in. The latter are the groups to ask to be added to if some data on the \
+server cannot be accessed. Only group names are shown, and the key is not \
+recorded as used."
+))]
+struct KeyAccessSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Check that keys in the key store agree with each other.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+This command prompts for the passphrase of each named key (or of every key, \
+if none are named) and checks that they all unlock the same internal keys: \
+every key must be in the `everyone` group, and each group must have the same \
+internal key for every key in it. Nothing is written to the server.
+
+This is intended as a final check after setting up several keys and groups."
+))]
+struct KeyCheckSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    /// The name(s) of the key(s) to check.
+    key_name: Vec<String>,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Regenerate the stored key material of every key.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+This command gives every key in the key store a new salt, and thus new pads \
+between its passphrase and the internal keys of its groups. It prompts for \
+the passphrase of every key, since each must be re-derived; if any \
+passphrase is wrong, nothing is changed.
+
+This does NOT change the internal keys, so it does nothing for data which is \
+already encrypted. It is only useful if a copy of the key store (for example, \
+one made with `key backup`) is suspected to have leaked but the passphrases \
+and internal keys have not.
+
+A passphrase in the `root` group is also required. By default, this prompts \
+the terminal, but the `--root` argument can be used to use other passphrase \
+methods."
+))]
+struct KeyRewrapSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(flatten)]
+    root: RootKeyArg,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Save a copy of the key store to a file.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+The file contains the keys, groups, and policy settings of the key store, \
+and can be used with `key restore` to put the key store back as it was, for \
+example if a `key change` goes wrong.
+
+The file contains only what is already stored on the server, but it is only \
+as secure as the weakest passphrase in it, including passphrases which are \
+later changed or removed from the key store. It should be protected \
+accordingly."
+))]
+struct KeyBackupSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    /// The file to write the backup to.
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Restore the key store from a file written by `key backup`.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+A passphrase in the `root` group of the backup is required. By default, this \
+prompts the terminal, but the `--root` argument can be used to use other \
+passphrase methods.
+
+If the key store already exists, the command fails unless `--force` is \
+given, in which case the key store is replaced by the backup. The root \
+passphrase must then also be valid in the existing key store, and the backup \
+must be of that same store; a backup of a different store cannot be \
+restored over an existing one. The audit log of the existing key store is \
+kept.
+
+With `--merge`, the keys of the backup are instead merged into the existing \
+key store: keys only in the key store are kept, keys in the backup replace \
+those of the same name, and the policy settings of the key store are kept. \
+The root passphrase must be valid in both."
+))]
+struct KeyRestoreSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(flatten)]
+    root: RootKeyArg,
+
+    /// Replace the key store if it already exists.
+    #[structopt(short, long)]
+    force: bool,
+
+    /// Merge the keys of the backup into the existing key store.
+    #[structopt(short, long, conflicts_with = "force")]
+    merge: bool,
+
+    /// The file to read the backup from.
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Show the log of changes made to the key store.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+Prints each recorded change to the key store, oldest first, with the \
+operation performed and the names of the keys or groups it was given. The \
+command fails if the log has been tampered with.
+
+A passphrase in the `root` group is required. By default, this prompts the \
+terminal, but the `--root` argument can be used to use other passphrase \
+methods."
+))]
+struct KeyAuditSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(flatten)]
+    root: RootKeyArg,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Save a single key to a file.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+The file contains the named key as stored on the server, and can be added to \
+another key store with `key import`. It is only as secure as the passphrase \
+of the key, and should be protected accordingly."
+))]
+struct KeyExportSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    /// The name of the key to export.
+    key_name: String,
+
+    /// The file to write the key to.
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Add a key written by `key export` to the key store.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+The passphrase of the exported key is read from the terminal. The key is only \
+added if every group it shares with this key store has the same internal key \
+here, and if no key of the same name already exists.
+
+Since this operation modifies the key store, a key in the `root` group is \
+required. By default, this prompts the terminal, but the `--root` argument \
+can be used to use other passphrase methods."
+))]
+struct KeyImportSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(flatten)]
+    root: RootKeyArg,
+
+    /// The file to read the key from.
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// Change how data in the store is hashed and encrypted.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+This command changes store-wide settings that determine how data in the \
+store is hashed and encrypted. Every client of the store picks these up from \
+the key store automatically.
+
+The hash variant and cipher width cannot be changed once anything has been \
+synced to the store, since existing data is not converted; set them after \
+`key init` and before `setup` or the first `sync`. The directory format can \
+be changed at any time, since existing directories are converted in place. \
+Other clients should not sync while this happens. If the conversion is \
+interrupted, running the command again resumes it.
+
+Since this operation modifies the key store, a key in the `root` group is \
+required. By default, this prompts the terminal, but the `--root` argument \
+can be used to use other passphrase methods."
+))]
+struct KeyFormatSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(flatten)]
+    root: RootKeyArg,
+
+    /// Hash used to identify blocks of file content.
+    #[structopt(long, possible_values = &["sha3-256", "keccak-256", "blake3"])]
+    hash_variant: Option<String>,
+
+    /// AES key size, in bits, used for file content and directories.
+    #[structopt(long, possible_values = &["128", "256"])]
+    cipher_width: Option<String>,
+
+    /// Format used to encrypt directory versions. `mac` binds each version
+    /// to its directory.
+    #[structopt(long, possible_values = &["legacy", "mac"])]
+    dir_format: Option<String>,
+
     #[structopt(skip)]
     verbosity: NonVerbose,
 }
@@ -477,6 +886,24 @@ struct KeyGroupCreateSubcommand {
     #[structopt(required = true)]
     group: Vec<String>,
 
+    /// Check that the existing passphrase still derives all of its groups
+    /// before saving the change. This costs two extra key derivations.
+    #[structopt(long)]
+    verify: bool,
+
+    #[structopt(skip)]
+    verbosity: NonVerbose,
+}
+
+/// List the key groups and the keys in each.
+#[derive(StructOpt)]
+struct KeyGroupLsSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    /// Only list the keys in this group, one per line.
+    group: Option<String>,
+
     #[structopt(skip)]
     verbosity: NonVerbose,
 }
@@ -493,7 +920,9 @@ the listed groups and is used to derive the information needed to grant them. \
 By default, it is obtained as described by the configuration, but this can be \
 overridden with `--from`. The second is the one to which the groups are to be \
 granted. By default, it is read from the terminal, but `--to` can override \
-this.
+this. `--to` can be given more than once to grant the groups to several keys \
+in one change to the key store, in which case nothing is changed unless every \
+key can receive them.
 
 Since this operation modifies the key store, a key in the `root` group is \
 required. If either of the above keys are in the `root` group, it will be \
@@ -516,6 +945,16 @@ struct KeyGroupAssocSubcommand {
     #[structopt(required = true)]
     group: Vec<String>,
 
+    /// Check that the `--from` passphrase still derives all of its groups
+    /// before saving the change. This costs two extra key derivations.
+    #[structopt(long)]
+    verify: bool,
+
+    /// Leave keys which already have one of the groups alone instead of
+    /// failing.
+    #[structopt(long)]
+    skip_existing: bool,
+
     #[structopt(skip)]
     verbosity: NonVerbose,
 }
@@ -688,6 +1127,9 @@ Dumps the raw content of all listed files to standard output, without any \
 other information. Every path must be a regular file (symlinks are not \
 permitted).
 
+With `--offset` or `--length`, only that part of each file is dumped, and \
+only the blocks which overlap it are fetched from the server.
+
 If <path> does not start with `/`, it is relative to `server_root` value \
 in the configuration. Otherwise, it starts from the physical root of the \
 server."
@@ -696,6 +1138,14 @@ struct CatSubcommand {
     #[structopt(flatten)]
     config: ConfigArg,
 
+    /// Start dumping at this byte offset into each file.
+    #[structopt(long)]
+    offset: Option<u64>,
+
+    /// Dump at most this many bytes of each file.
+    #[structopt(long)]
+    length: Option<u64>,
+
     /// The path(s) to dump.
     #[structopt(required = true, parse(from_os_str))]
     path: Vec<PathBuf>,
@@ -811,6 +1261,64 @@ struct RmSubcommand {
     path: Vec<PathBuf>,
 }
 
+/// Check the integrity of files on the server.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+Fetches every block of each given file, or of every file under each given \
+directory, and checks that it decrypts and matches its hash. Nothing is \
+written locally. Files that fail verification are printed along with the \
+first problem found.
+
+If <path> does not start with `/`, it is relative to the `server_root` value \
+in the configuration. Otherwise, it starts from the physical root of the \
+server.
+
+Example, to check everything on the server:
+
+        ensync verify /path/to/config /"
+))]
+struct VerifySubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    #[structopt(flatten)]
+    verbosity: VerbosityArgs,
+
+    /// Specify the number of files to verify concurrently [default: CPUs]
+    #[structopt(long)]
+    threads: Option<u32>,
+
+    /// The path(s) to verify.
+    #[structopt(required = true, parse(from_os_str))]
+    path: Vec<PathBuf>,
+}
+
+/// Show which sync rule applies to a path.
+#[derive(StructOpt)]
+#[structopt(after_help(
+    "\
+Evaluates the sync rules in the configuration against <path> and prints the \
+rule which determines its sync mode, along with the resulting mode and how \
+edit conflicts on the file would be resolved.
+
+<path> is relative to the sync root. No files are examined; every component \
+but the last is assumed to be a directory and the last to be a regular file, \
+and rules matching on sibling files never match.
+
+Example:
+
+        ensync explain-path /path/to/config docs/notes.txt"
+))]
+struct ExplainPathSubcommand {
+    #[structopt(flatten)]
+    config: ConfigArg,
+
+    /// The path to explain, relative to the sync root.
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+}
+
 /// Run the server-side component.
 #[derive(StructOpt)]
 #[structopt(after_help(
@@ -910,9 +1418,10 @@ struct ToKeyArg {
     /// Use this value instead of `passphrase` from the config to get the
     /// passphrase of the key which shall receive the groups to be granted.
     /// This argument is in the same format as the config. e.g., `prompt` or
-    /// `file:/some/path`.
-    #[structopt(short, long, default_value = "prompt")]
-    to: PassphraseConfig,
+    /// `file:/some/path`. It can be given several times to grant the groups
+    /// to several keys at once.
+    #[structopt(short, long, default_value = "prompt", number_of_values = 1)]
+    to: Vec<PassphraseConfig>,
 }
 
 #[derive(StructOpt)]
@@ -998,7 +1507,14 @@ fn main_impl() -> Result<()> {
 
         Command::Key(KeySubcommand::Init(sc)) => {
             set_up!(sc, config, storage);
-            cli::cmd_keymgmt::init_keys(&config, &*storage, &sc.key_name)
+            cli::cmd_keymgmt::init_keys(
+                &config,
+                &*storage,
+                &sc.key_name,
+                &sc.algorithm,
+                sc.comment.as_ref().map(|s| s.as_str()),
+                !sc.no_record_key_use,
+            )
         }
 
         Command::Key(KeySubcommand::Add(sc)) => {
@@ -1015,7 +1531,62 @@ fn main_impl() -> Result<()> {
 
         Command::Key(KeySubcommand::Ls(sc)) => {
             set_up!(sc, config, storage);
-            cli::cmd_keymgmt::list_keys(&*storage)
+            if sc.algorithms {
+                cli::cmd_keymgmt::list_algorithms(&*storage)
+            } else {
+                cli::cmd_keymgmt::list_keys(&*storage, &sc.sort, sc.reverse)
+            }
+        }
+
+        Command::Key(KeySubcommand::Probe(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::probe(&*storage, &config.passphrase)
+        }
+
+        Command::Key(KeySubcommand::Access(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::access(&*storage, &config.passphrase)
+        }
+
+        Command::Key(KeySubcommand::Check(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::check_keys(&*storage, sc.key_name)
+        }
+
+        Command::Key(KeySubcommand::Rewrap(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::rewrap_keys(&*storage, &sc.root.root)
+        }
+
+        Command::Key(KeySubcommand::Backup(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::backup_keys(&*storage, &sc.file)
+        }
+
+        Command::Key(KeySubcommand::Restore(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::restore_keys(
+                &*storage,
+                &sc.file,
+                sc.force,
+                sc.merge,
+                &sc.root.root,
+            )
+        }
+
+        Command::Key(KeySubcommand::Audit(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::audit_log(&*storage, &sc.root.root)
+        }
+
+        Command::Key(KeySubcommand::Export(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::export_key(&*storage, &sc.key_name, &sc.file)
+        }
+
+        Command::Key(KeySubcommand::Import(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::import_key(&*storage, &sc.file, &sc.root.root)
         }
 
         Command::Key(KeySubcommand::Change(sc)) => {
@@ -1029,6 +1600,7 @@ fn main_impl() -> Result<()> {
                 &sc.root.root,
                 sc.key_name.as_deref(),
                 sc.force,
+                sc.algorithm.as_deref(),
             )
         }
 
@@ -1037,6 +1609,21 @@ fn main_impl() -> Result<()> {
             cli::cmd_keymgmt::del_key(&*storage, &sc.key_name, &sc.root.root)
         }
 
+        Command::Key(KeySubcommand::Rename(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::rename_key(
+                &*storage,
+                &sc.old_name,
+                &sc.new_name,
+                &sc.root.root,
+            )
+        }
+
+        Command::Key(KeySubcommand::MinKeys(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::set_min_keys(&*storage, &sc.count, &sc.root.root)
+        }
+
         Command::Key(KeySubcommand::Group(KeyGroupSubcommand::Create(sc))) => {
             set_up!(sc, config, storage);
             cli::cmd_keymgmt::create_group(
@@ -1044,9 +1631,15 @@ fn main_impl() -> Result<()> {
                 &config.passphrase,
                 &sc.root.root,
                 sc.group.into_iter(),
+                sc.verify,
             )
         }
 
+        Command::Key(KeySubcommand::Group(KeyGroupSubcommand::Ls(sc))) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::list_groups(&*storage, sc.group.as_deref())
+        }
+
         Command::Key(KeySubcommand::Group(KeyGroupSubcommand::Assoc(sc))) => {
             set_up!(sc, config, storage);
             let from = passphrase_or_config!(sc.from.from, config);
@@ -1056,6 +1649,8 @@ fn main_impl() -> Result<()> {
                 &sc.to.to,
                 &sc.root.root,
                 sc.group.into_iter(),
+                sc.skip_existing,
+                sc.verify,
             )
         }
 
@@ -1081,6 +1676,18 @@ fn main_impl() -> Result<()> {
             )
         }
 
+        Command::Key(KeySubcommand::Format(sc)) => {
+            set_up!(sc, config, storage);
+            cli::cmd_keymgmt::set_format(
+                &config,
+                storage,
+                &sc.root.root,
+                sc.hash_variant.as_deref(),
+                sc.cipher_width.as_deref(),
+                sc.dir_format.as_deref(),
+            )
+        }
+
         Command::Setup(sc) => cli::cmd_setup::run(
             &sc.key,
             sc.config,
@@ -1115,9 +1722,13 @@ fn main_impl() -> Result<()> {
                     sc.itemise_unchanged,
                     &sc.colour,
                     &sc.spin,
+                    &sc.log_format,
                     sc.include_ancestors,
                     sc.dry_run,
+                    sc.estimate,
+                    sc.stats,
                     sc.watch.then(|| sc.quiescence),
+                    sc.stall_timeout.map(std::time::Duration::from_secs),
                     num_threads,
                     &sc.strategy,
                     sc.override_mode,
@@ -1181,7 +1792,12 @@ fn main_impl() -> Result<()> {
 
         Command::Cat(sc) => {
             set_up!(sc, config, storage, replica);
-            cli::cmd_manual::cat(&replica, sc.path.iter())
+            let range = if sc.offset.is_some() || sc.length.is_some() {
+                Some((sc.offset.unwrap_or(0), sc.length.unwrap_or(u64::MAX)))
+            } else {
+                None
+            };
+            cli::cmd_manual::cat(&replica, sc.path.iter(), range)
         }
 
         Command::Get(sc) => {
@@ -1215,6 +1831,21 @@ fn main_impl() -> Result<()> {
                 sc.verbosity.is_verbose(),
             )
         }
+
+        Command::Verify(sc) => {
+            set_up!(sc, config, storage, replica);
+            cli::cmd_manual::verify(
+                &replica,
+                sc.path.iter(),
+                sc.threads.map_or_else(num_cpus::get, |n| n as usize),
+                sc.verbosity.is_verbose(),
+            )
+        }
+
+        Command::ExplainPath(sc) => {
+            set_up!(sc, config);
+            cli::cmd_sync::explain_path(&config, sc.path.as_os_str())
+        }
     }
 }
 
@@ -179,6 +179,9 @@ pub struct MemoryReplicaImpl {
     /// notated as the full path of the parent directory followed by a "/" and
     /// the name of the subdirectory.
     pub dirs: HashMap<OsString, Directory>,
+    /// Block sizes requested via `transfer_with_block_size()`, keyed by the
+    /// full path of the file transferred.
+    pub block_sizes: HashMap<OsString, usize>,
 }
 
 impl MemoryReplicaImpl {
@@ -189,6 +192,7 @@ impl MemoryReplicaImpl {
         MemoryReplicaImpl {
             faults: HashMap::new(),
             dirs: root,
+            block_sizes: HashMap::new(),
         }
     }
 
@@ -667,6 +671,21 @@ impl Replica for MemoryReplica {
             Err(ErrorKind::NotFound.into())
         }
     }
+
+    fn transfer_with_block_size(
+        &self,
+        dir: &DirHandle,
+        file: File,
+        block_size: Option<usize>,
+    ) -> Result<Option<HashId>> {
+        let ret = self.transfer(dir, file)?;
+        if let Some(block_size) = block_size {
+            self.data()
+                .block_sizes
+                .insert(catpath(&dir.path, file.0), block_size);
+        }
+        Ok(ret)
+    }
 }
 
 impl NullTransfer for MemoryReplica {
@@ -34,9 +34,13 @@ use std::time::Duration;
 use notify::{self, Watcher};
 use tempfile::NamedTempFile;
 
-use crate::block_xfer::{blocks_to_stream, hash_block, stream_to_blocks};
 use crate::block_xfer::{
-    BlockFetch, BlockList, ContentAddressableSource, StreamSource,
+    blocks_to_temp_file_with, hash_block_with, stream_to_blocks_with,
+};
+use crate::block_xfer::{
+    BlockFetch, BlockList, ContentAddressableSource, HashVariant, PreflightFn,
+    ProgressFn, ProgressReader, StreamSource, TransferProgress,
+    TransferWatchdog,
 };
 use crate::defs::*;
 use crate::errors::*;
@@ -57,13 +61,17 @@ impl<T: MetadataExt> MetadataExt2 for T {
     }
 }
 
+#[derive(Clone)]
 struct Config {
     hmac_secret: Vec<u8>,
+    hash_variant: HashVariant,
     root: PathBuf,
     private_dir: PathBuf,
     private_dir_dev: u64,
     block_size: usize,
     cache_generation: i64,
+    preflight: Option<PreflightFn>,
+    stall_timeout: Option<Duration>,
 }
 
 struct WatcherStatus {
@@ -191,7 +199,8 @@ fn get_or_compute_hash<D: OnDao>(
 
     let file = fs::File::open(path)
         .chain_err(|| format!("Unable to open '{}'", path.display()))?;
-    let blocklist = stream_to_blocks(
+    let blocklist = stream_to_blocks_with(
+        config.hash_variant,
         file,
         config.block_size,
         &config.hmac_secret[..],
@@ -591,6 +600,15 @@ impl Replica for PosixReplica {
         &self,
         dir: &DirHandle,
         file: File,
+    ) -> Result<Option<Box<dyn StreamSource>>> {
+        self.transfer_with_block_size(dir, file, None)
+    }
+
+    fn transfer_with_block_size(
+        &self,
+        dir: &DirHandle,
+        file: File,
+        block_size: Option<usize>,
     ) -> Result<Option<Box<dyn StreamSource>>> {
         Ok(match *file.1 {
             FileData::Regular(mode, _, _, expected_hash) => {
@@ -600,6 +618,7 @@ impl Replica for PosixReplica {
                     name: file.0.to_owned(),
                     mode: mode,
                     expected_hash: expected_hash,
+                    block_size: block_size,
                     config: self.config.clone(),
                     dao: self.dao.clone(),
                 }))
@@ -608,6 +627,27 @@ impl Replica for PosixReplica {
         })
     }
 
+    fn transfer_with_progress(
+        &self,
+        dir: &DirHandle,
+        file: File,
+        block_size: Option<usize>,
+        progress: ProgressFn,
+    ) -> Result<Option<Box<dyn StreamSource>>> {
+        let size = match *file.1 {
+            FileData::Regular(_, size, _, _) => size,
+            _ => 0,
+        };
+        Ok(self.transfer_with_block_size(dir, file, block_size)?.map(
+            |xfer| -> Box<dyn StreamSource> {
+                Box::new(ProgressReader::new(
+                    xfer,
+                    Arc::new(Mutex::new(TransferProgress::new(size, progress))),
+                ))
+            },
+        ))
+    }
+
     fn prepare(&self, typ: PrepareType) -> Result<()> {
         // Reclaim any files left over from a crashed run
         self.clean_scratch()?;
@@ -729,11 +769,14 @@ impl PosixReplica {
         Ok(PosixReplica {
             config: Arc::new(Config {
                 hmac_secret: hmac_secret.to_vec(),
+                hash_variant: HashVariant::default(),
                 root: root.to_owned(),
                 private_dir: private_dir.to_owned(),
                 private_dir_dev: private_dir_dev,
                 block_size: block_size,
                 cache_generation: cache_generation,
+                preflight: None,
+                stall_timeout: None,
             }),
             dao: Arc::new(Mutex::new(dao)),
             tmpix: AtomicUsize::new(0),
@@ -741,6 +784,29 @@ impl PosixReplica {
         })
     }
 
+    /// Sets the hash variant used for block hashing.
+    ///
+    /// This must match the variant used by whatever the replica is synced
+    /// with, and should be set before the replica is used.
+    pub fn set_hash_variant(&mut self, variant: HashVariant) {
+        Arc::make_mut(&mut self.config).hash_variant = variant;
+    }
+
+    /// Sets a function to be called whenever a file is about to be
+    /// reconstructed block by block, i.e., when no identical local file could
+    /// simply be copied.
+    ///
+    /// The size passed is the one given in the new `FileData` of the file.
+    pub fn set_preflight(&mut self, preflight: PreflightFn) {
+        Arc::make_mut(&mut self.config).preflight = Some(preflight);
+    }
+
+    /// Sets how long fetching files from the other replica may go without
+    /// completing a block before the transfer fails with `StalledTransfer`.
+    pub fn set_stall_timeout(&mut self, timeout: Duration) {
+        Arc::make_mut(&mut self.config).stall_timeout = Some(timeout);
+    }
+
     fn named_temp_file(&self, dir: &DirHandle) -> io::Result<NamedTempFile> {
         let mut opts = tempfile::Builder::new();
         opts.prefix(INVASIVE_TMP_PREFIX);
@@ -910,52 +976,51 @@ impl PosixReplica {
                 Ok(source.1.clone())
             }
 
-            FileData::Regular(mode, _, time, _) => {
-                let (mut scratch_file, scratch_path) = self
-                    .named_temp_file(dir)
-                    .chain_err(|| {
+            FileData::Regular(mode, size, time, _) => {
+                let mut scratch =
+                    self.named_temp_file(dir).chain_err(|| {
                         format!(
                             "Failed to create temporary file in '{}'",
                             dir.path().display()
                         )
-                    })?
-                    .into_parts();
+                    })?;
 
                 if let Some(xfer) = xfer {
                     // Copy the file to the local filesystem
-                    self.xfer_file(&mut scratch_file, &xfer).chain_err(
-                        || {
+                    let new_path = dir.child(source.0);
+                    self.xfer_file(&mut scratch, &xfer, &new_path, size)
+                        .chain_err(|| {
                             format!(
                                 "Failed to transfer content of '{}'",
-                                dir.child(source.0).display()
+                                new_path.display()
                             )
-                        },
-                    )?;
+                        })?;
                     // Move anything out of the way as needed
                     before_establish()?;
-                    let new_path = dir.child(source.0);
                     // Atomically put into place after setting the mode and
                     // mtime
                     fs::set_permissions(
-                        &scratch_path,
+                        scratch.path(),
                         fs::Permissions::from_mode(mode),
                     )
                     .chain_err(|| {
                         format!(
                             "Failed to set permissions on '{}'",
-                            scratch_path.display()
-                        )
-                    })?;
-                    posix::set_mtime(&scratch_file, time).chain_err(|| {
-                        format!(
-                            "Failed to set mtime on '{}'",
-                            scratch_path.display()
+                            scratch.path().display()
                         )
                     })?;
-                    scratch_file.sync_all().chain_err(|| {
+                    posix::set_mtime(scratch.as_file(), time).chain_err(
+                        || {
+                            format!(
+                                "Failed to set mtime on '{}'",
+                                scratch.path().display()
+                            )
+                        },
+                    )?;
+                    scratch.as_file().sync_all().chain_err(|| {
                         format!("Error fsync'ing '{}'", new_path.display())
                     })?;
-                    scratch_path.persist(&new_path).chain_err(|| {
+                    scratch.persist(&new_path).chain_err(|| {
                         format!("Failed to persist '{}'", new_path.display())
                     })?;
                     // Cache the content of the file, assuming that nobody
@@ -1025,44 +1090,57 @@ impl PosixReplica {
         Ok(())
     }
 
-    /// Transfers the file described by `xfer` into `dst`.
+    /// Transfers the file described by `xfer` into the temporary file `dst`.
     ///
     /// If an identical file is available locally, it is copied into `dst`,
     /// with a check that it actually has the content we expect it to.
     ///
     /// Otherwise, the file is copied block by block either from known-correct
     /// file blocks locally or by using `xfer.fetch` fo obtain them from the
-    /// other replica.
+    /// other replica, and fully verified by `blocks_to_temp_file_with`. The
+    /// preflight function, if any, is called with `path` and `size` first.
     ///
     /// If this call fails, `dst` may be left in an intermediate state.
     fn xfer_file(
         &self,
-        dst: &mut fs::File,
+        dst: &mut NamedTempFile,
         xfer: &ContentAddressableSource,
+        path: &Path,
+        size: FileSize,
     ) -> Result<()> {
         // Try to copy from a known local file first. But don't bother if
         // `xfer` specifies zero blocks, since it's not worth consulting the
         // cache and "copying" another empty file.
         if !xfer.blocks.blocks.is_empty()
-            && self.copy_file_local(dst, &xfer.blocks.total, xfer.block_size)
+            && self.copy_file_local(
+                dst.as_file_mut(),
+                &xfer.blocks.total,
+                xfer.block_size,
+            )
         {
             return Ok(());
         }
 
-        // We may have partially written the file in the above attempt, so
-        // reset it to 0-length
-        dst.seek(io::SeekFrom::Start(0))?;
-        dst.set_len(0)?;
+        if let Some(ref preflight) = self.config.preflight {
+            preflight(path, xfer.blocks.blocks.len(), size);
+        }
 
-        // Write the file a block at a time. Use local blocks when possible,
-        // otherwise fetch from the transfer object.
-        blocks_to_stream(
+        // Write the file a block at a time, replacing anything the above
+        // attempt may have written. Use local blocks when possible, otherwise
+        // fetch from the transfer object.
+        let watchdog = self.config.stall_timeout.map(TransferWatchdog::new);
+        blocks_to_temp_file_with(
+            self.config.hash_variant,
             &xfer.blocks,
             dst,
             &self.config.hmac_secret[..],
-            |hid| self.xfer_block(hid, &*xfer.fetch),
-        )?;
-        Ok(())
+            |hid| match watchdog {
+                Some(ref watchdog) => {
+                    watchdog.watch(|| self.xfer_block(hid, &*xfer.fetch))
+                }
+                None => self.xfer_block(hid, &*xfer.fetch),
+            },
+        )
     }
 
     /// Copies a local file whose content is `hash` into `dst`.
@@ -1087,7 +1165,8 @@ impl PosixReplica {
             let actual_hash = fs::File::open(&srcname)
                 .map_err(Error::from)
                 .and_then(|src| {
-                    stream_to_blocks(
+                    stream_to_blocks_with(
+                        self.config.hash_variant,
                         src,
                         block_size,
                         &self.config.hmac_secret[..],
@@ -1149,7 +1228,13 @@ impl PosixReplica {
             });
 
             // Make sure we read the correct data in
-            if *hash == hash_block(&self.config.hmac_secret[..], &data[..]) {
+            if *hash
+                == hash_block_with(
+                    self.config.hash_variant,
+                    &self.config.hmac_secret[..],
+                    &data[..],
+                )
+            {
                 // Matched
                 Some(data)
             } else {
@@ -1240,6 +1325,9 @@ struct FileStreamSource {
     /// The hash that was originally reported for the transfer, so the
     /// directory hash can be updated.
     expected_hash: HashId,
+    /// The block size requested for the transfer, if it differs from the
+    /// configured one.
+    block_size: Option<usize>,
 
     config: Arc<Config>,
     dao: Arc<Mutex<Dao>>,
@@ -1270,7 +1358,7 @@ impl StreamSource for FileStreamSource {
                 path.as_os_str(),
                 &blocks.total,
                 &blocks.blocks[..],
-                self.config.block_size,
+                self.block_size.unwrap_or(self.config.block_size),
                 &stat,
                 self.config.cache_generation,
             );
@@ -1289,6 +1377,10 @@ impl StreamSource for FileStreamSource {
 
         Ok(())
     }
+
+    fn block_size(&self) -> Option<usize> {
+        self.block_size
+    }
 }
 
 impl WatcherStatus {
@@ -1621,6 +1713,36 @@ mod test {
         );
     }
 
+    #[test]
+    fn create_regular_file_calls_preflight() {
+        let (root, _private, mut replica) = new_simple();
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls2 = calls.clone();
+        replica.set_preflight(Arc::new(move |path, blocks, size| {
+            calls2.lock().unwrap().push((path.to_owned(), blocks, size))
+        }));
+        replica.prepare(PrepareType::Fast).unwrap();
+        let mut dir = replica.root().unwrap();
+
+        let xfer = make_ca_source("Three pounds of VAX!");
+        replica
+            .create(
+                &mut dir,
+                File(
+                    &oss("vax"),
+                    &FileData::Regular(0o600, 20, 0, xfer.blocks.total),
+                ),
+                Some(xfer),
+            )
+            .unwrap();
+
+        assert_eq!(
+            vec![(root.path().join("vax"), 5, 20)],
+            *calls.lock().unwrap()
+        );
+    }
+
     #[test]
     fn create_regular_file_with_perm_777() {
         let (root, _private, replica) = new_simple();
@@ -1739,6 +1861,46 @@ mod test {
         assert_eq!("Three pounds of flax", slurp(root.path().join("foo")));
     }
 
+    #[test]
+    fn replace_file_with_corrupt_xfer_leaves_file_untouched() {
+        let (root, private, replica) = new_simple();
+
+        replica.prepare(PrepareType::Fast).unwrap();
+        let mut dir = replica.root().unwrap();
+
+        spit(root.path().join("foo"), "Three pounds of VAX");
+        let list = replica.list(&mut dir).unwrap();
+        assert_eq!(1, list.len());
+
+        let mut xfer = make_ca_source("Three pounds of flax");
+        let mut blocks = HashMap::new();
+        for h in &xfer.blocks.blocks {
+            let mut data = Vec::new();
+            xfer.fetch.fetch(h).unwrap().read_to_end(&mut data).unwrap();
+            blocks.insert(*h, data);
+        }
+        // Only the last block differs from the file already there.
+        let last = *xfer.blocks.blocks.last().unwrap();
+        blocks.get_mut(&last).unwrap()[0] ^= 1;
+        xfer.fetch = Arc::new(MemoryBlockFetch { blocks: blocks });
+
+        let new_fd = FileData::Regular(0o611, 0, 0, xfer.blocks.total);
+        assert!(replica
+            .update(&mut dir, &oss("foo"), &list[0].1, &new_fd, Some(xfer))
+            .is_err());
+
+        assert_eq!("Three pounds of VAX", slurp(root.path().join("foo")));
+        for d in &[root.path(), private.path()] {
+            for entry in fs::read_dir(d).unwrap() {
+                let name = entry.unwrap().file_name();
+                assert!(!name
+                    .to_str()
+                    .unwrap()
+                    .starts_with(INVASIVE_TMP_PREFIX));
+            }
+        }
+    }
+
     #[test]
     fn chmod_file() {
         let (root, _private, replica) = new_simple();
@@ -18,6 +18,9 @@
 
 use std::ffi::{OsStr, OsString};
 use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
 
 use crate::defs::*;
 use crate::log::ReplicaSide;
@@ -67,6 +70,93 @@ pub fn gen_alternate_name<F: Fn(&OsStr) -> bool>(
         orig.to_string_lossy()
     );
 }
+/// A template for the suffix appended to the name of a file which is renamed
+/// aside to keep both versions of it.
+///
+/// The placeholders `{side}` (`client` or `server`, whichever side's file is
+/// renamed) and `{time}` (the current UTC time, as `YYYYMMDDTHHMMSSZ`) are
+/// substituted; everything else is copied literally.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConflictSuffix(String);
+
+impl FromStr for ConflictSuffix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s.is_empty() {
+            return Err("Conflict suffix must not be empty".to_owned());
+        }
+        if s.contains('/') || s.contains('\0') {
+            return Err(format!(
+                "Conflict suffix \"{}\" contains a character not \
+                 permitted in file names",
+                s
+            ));
+        }
+
+        let mut rest = s;
+        while let Some(open) = rest.find('{') {
+            let close = rest[open..].find('}').map(|c| open + c);
+            match close.map(|c| &rest[open + 1..c]) {
+                Some("side") | Some("time") => (),
+                _ => {
+                    return Err(format!(
+                        "Conflict suffix \"{}\" has an invalid placeholder; \
+                         only {{side}} and {{time}} are supported",
+                        s
+                    ))
+                }
+            }
+            rest = &rest[close.unwrap() + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!(
+                "Conflict suffix \"{}\" has an unmatched `}}`",
+                s
+            ));
+        }
+
+        Ok(ConflictSuffix(s.to_owned()))
+    }
+}
+
+impl ConflictSuffix {
+    /// Substitutes the placeholders in this template.
+    pub fn expand(
+        &self,
+        side: ReconciliationSide,
+        time: DateTime<Utc>,
+    ) -> String {
+        let side = match side {
+            ReconciliationSide::Client => "client",
+            ReconciliationSide::Server => "server",
+        };
+        self.0
+            .replace("{side}", side)
+            .replace("{time}", &time.format("%Y%m%dT%H%M%SZ").to_string())
+    }
+
+    /// Generates the new name for `orig`, renamed aside on `side`.
+    ///
+    /// If the name produced by the template is already in use, falls back to
+    /// `gen_alternate_name()` based on that name.
+    pub fn gen_name<F: Fn(&OsStr) -> bool>(
+        &self,
+        orig: &OsStr,
+        side: ReconciliationSide,
+        time: DateTime<Utc>,
+        in_use: F,
+    ) -> OsString {
+        let mut name = orig.to_owned();
+        name.push(self.expand(side, time));
+        if in_use(&name) {
+            gen_alternate_name(&name, in_use)
+        } else {
+            name
+        }
+    }
+}
+
 /// When a reconciliation sources from or affects one side, indicates which
 /// replica is to be used.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -158,6 +248,71 @@ pub enum ConflictingEdit {
     Content,
 }
 
+/// A choice made by a `ConflictResolver` for a single conflict.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// Make no choice; reconcile according to the sync rules as usual.
+    Default,
+    /// Keep the client version of the file, replacing the server version.
+    KeepClient,
+    /// Keep the server version of the file, replacing the client version.
+    KeepServer,
+    /// Keep both versions by renaming the server version. Only meaningful for
+    /// edit/edit conflicts; treated as `Default` otherwise.
+    KeepBoth,
+}
+
+/// Returns the reconciliation which replaces `dst_file` on `dst_side` with
+/// `src_file` from `src_side`.
+fn replace(
+    dst_file: Option<&FileData>,
+    dst_side: ReconciliationSide,
+    src_file: Option<&FileData>,
+    src_side: ReconciliationSide,
+) -> Reconciliation {
+    use self::Reconciliation::*;
+
+    match (dst_file, src_file) {
+        // If either side is nonexistent, we don't need anything special
+        (None, _) | (_, None) |
+        // If the src is a directory, it doesn't matter what dst is. If not
+        // a directory, it can be simply removed; if a directory, we merge
+        // the two.
+        (Some(_), Some(&FileData::Directory(..))) => Use(src_side),
+        // If the dst is a directory but src is not, we need to move dst
+        // out of the way first and treat it like an edit/delete conflict,
+        // since we can't (and don't want to) remove the whole thing
+        // atomically.
+        (Some(&FileData::Directory(..)), Some(_)) =>
+            Split(dst_side, SplitAncestorState::Move),
+        // Any pair of atomic files needs no special handling.
+        (Some(_), Some(_)) => Use(src_side),
+    }
+}
+
+/// Translates a `Resolution` chosen for `conflict` into the reconciliation to
+/// apply to the given client and server files.
+///
+/// Returns `None` if the resolution defers to the sync rules.
+pub fn resolve_conflict(
+    cli: Option<&FileData>,
+    srv: Option<&FileData>,
+    conflict: Conflict,
+    resolution: Resolution,
+) -> Option<Reconciliation> {
+    use self::ReconciliationSide::*;
+
+    match (resolution, conflict) {
+        (_, Conflict::NoConflict) | (Resolution::Default, _) => None,
+        (Resolution::KeepClient, _) => Some(replace(srv, Server, cli, Client)),
+        (Resolution::KeepServer, _) => Some(replace(cli, Client, srv, Server)),
+        (Resolution::KeepBoth, Conflict::EditEdit(..)) => {
+            Some(Reconciliation::Split(Server, SplitAncestorState::Delete))
+        }
+        (Resolution::KeepBoth, Conflict::EditDelete(..)) => None,
+    }
+}
+
 /// Determines the abstract reconciliation path for the given file triple and
 /// sync mode.
 ///
@@ -177,30 +332,6 @@ pub fn choose_reconciliation(
     use self::Reconciliation::*;
     use self::ReconciliationSide::*;
 
-    fn replace(
-        dst_file: Option<&FileData>,
-        dst_side: ReconciliationSide,
-        src_file: Option<&FileData>,
-        src_side: ReconciliationSide,
-    ) -> Reconciliation {
-        match (dst_file, src_file) {
-            // If either side is nonexistent, we don't need anything special
-            (None, _) | (_, None) |
-            // If the src is a directory, it doesn't matter what dst is. If not
-            // a directory, it can be simply removed; if a directory, we merge
-            // the two.
-            (Some(_), Some(&FileData::Directory(..))) => Use(src_side),
-            // If the dst is a directory but src is not, we need to move dst
-            // out of the way first and treat it like an edit/delete conflict,
-            // since we can't (and don't want to) remove the whole thing
-            // atomically.
-            (Some(&FileData::Directory(..)), Some(_)) =>
-                Split(dst_side, SplitAncestorState::Move),
-            // Any pair of atomic files needs no special handling.
-            (Some(_), Some(_)) => Use(src_side),
-        }
-    }
-
     let use_server = replace(cli, Client, srv, Server);
     let use_client = replace(srv, Server, cli, Client);
 
@@ -498,6 +629,36 @@ mod test {
         assert_eq!(oss("foo~2.txt"), result);
     }
 
+    #[test]
+    fn parse_and_expand_conflict_suffix() {
+        use chrono::TimeZone;
+
+        let suffix: ConflictSuffix = ".conflict-{side}-{time}".parse().unwrap();
+        let time = Utc.ymd(2021, 3, 4).and_hms(5, 6, 7);
+        assert_eq!(
+            ".conflict-server-20210304T050607Z",
+            suffix.expand(ReconciliationSide::Server, time)
+        );
+
+        let mut names = HashSet::new();
+        names.insert(oss("foo.conflict-client-20210304T050607Z"));
+        assert_eq!(
+            oss("foo~1.conflict-client-20210304T050607Z"),
+            suffix.gen_name(
+                &oss("foo"),
+                ReconciliationSide::Client,
+                time,
+                |n| names.contains(n)
+            )
+        );
+
+        assert!("".parse::<ConflictSuffix>().is_err());
+        assert!("~{host}".parse::<ConflictSuffix>().is_err());
+        assert!("~{side".parse::<ConflictSuffix>().is_err());
+        assert!("~side}".parse::<ConflictSuffix>().is_err());
+        assert!("/{side}".parse::<ConflictSuffix>().is_err());
+    }
+
     fn for_every_sync_triple<
         F: Fn(Option<&FileData>, Option<&FileData>, Option<&FileData>),
     >(
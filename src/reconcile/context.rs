@@ -20,8 +20,9 @@ use std::cmp::{Ord, Ordering};
 use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::num::Wrapping;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+use super::compute::{Conflict, ConflictSuffix, Resolution};
 use crate::defs::*;
 use crate::log::Logger;
 use crate::replica::Replica;
@@ -77,11 +78,24 @@ impl<T> UnqueuedTasks<T> {
     }
 }
 
+/// Callback used to resolve conflicts interactively.
+///
+/// It is invoked with the name of the conflicting file and the type of
+/// conflict, and returns the user's choice. `Resolution::Default` falls back
+/// to the normal rule-based reconciliation.
+pub type ConflictResolver =
+    Box<dyn FnMut(&OsStr, Conflict) -> Resolution + Send>;
+
 pub struct Context<CLI, ANC, SRV> {
     pub cli: CLI,
     pub anc: ANC,
     pub srv: SRV,
-    pub log: Box<dyn Logger + Send + Sync>,
+    pub log: Arc<dyn Logger + Send + Sync>,
+    /// If set, consulted for every conflict before applying the sync rules.
+    pub resolver: Option<Mutex<ConflictResolver>>,
+    /// The template for names of files renamed aside to keep both versions.
+    /// If unset, `gen_alternate_name()` is used.
+    pub conflict_suffix: Option<ConflictSuffix>,
     pub root_rules: FileEngine,
     pub work: WorkStack<Task<Self>>,
     pub tasks: UnqueuedTasks<Task<Self>>,
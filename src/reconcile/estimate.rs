@@ -0,0 +1,230 @@
+//-
+// Copyright (c) 2016, 2017, 2021, Jason Lingle
+//
+// This file is part of Ensync.
+//
+// Ensync is free software: you can  redistribute it and/or modify it under the
+// terms of  the GNU General Public  License as published by  the Free Software
+// Foundation, either version  3 of the License, or (at  your option) any later
+// version.
+//
+// Ensync is distributed  in the hope that  it will be useful,  but WITHOUT ANY
+// WARRANTY; without  even the implied  warranty of MERCHANTABILITY  or FITNESS
+// FOR  A PARTICULAR  PURPOSE.  See the  GNU General  Public  License for  more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// Ensync. If not, see <http://www.gnu.org/licenses/>.
+
+//! Support for estimating the work a sync will do before doing it.
+//!
+//! The estimate is produced by running the full reconciliation against
+//! `DryRunReplica`s and tallying the edits it reports, so it is only as
+//! accurate as the dry run itself; in particular, changes made to either
+//! replica between the plan and the real sync are not accounted for.
+
+use std::sync::{Arc, Mutex};
+
+use super::context::{Context, UnqueuedTasks};
+use crate::defs::*;
+use crate::dry_run_replica::DryRunReplica;
+use crate::errors::*;
+use crate::log::{Log, LogLevel, Logger, ReplicaSide};
+use crate::reconcile::compute::Conflict;
+use crate::replica::{Condemn, NullTransfer, Replica};
+use crate::rules::engine::FileEngine;
+use crate::work_stack::WorkStack;
+
+/// The amount of work a sync would do.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncEstimate {
+    /// The number of regular files whose content would be transferred to the
+    /// client or server.
+    pub files: u64,
+    /// The total size of the files counted in `files`.
+    pub bytes: u64,
+    /// The number of conflicts encountered.
+    pub conflicts: u64,
+    /// The sizes of those files counted in `files` which would be transferred
+    /// to the server.
+    pub upload_sizes: Vec<FileSize>,
+}
+
+impl SyncEstimate {
+    fn add_transfer(&mut self, side: ReplicaSide, data: &FileData) {
+        if let FileData::Regular(_, size, _, _) = *data {
+            self.files += 1;
+            self.bytes += size as u64;
+            if ReplicaSide::Server == side {
+                self.upload_sizes.push(size);
+            }
+        }
+    }
+
+    /// Updates the estimate to account for the given log from the reconciler.
+    pub fn record(&mut self, what: &Log) {
+        match *what {
+            Log::Inspect(_, _, _, Conflict::NoConflict) => (),
+            Log::Inspect(..) => self.conflicts += 1,
+            Log::Create(ReplicaSide::Ancestor, ..)
+            | Log::Update(ReplicaSide::Ancestor, ..) => (),
+            Log::Create(side, _, _, data) => self.add_transfer(side, data),
+            Log::Update(side, _, _, old, new) => {
+                if !old.matches_content(new) {
+                    self.add_transfer(side, new);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A `Logger` which tallies everything it is given into a `SyncEstimate`.
+///
+/// Clones share the same estimate, so a clone can be handed to a `Context`
+/// and the result read back through the original.
+#[derive(Clone, Debug, Default)]
+pub struct EstimatingLogger(Arc<Mutex<SyncEstimate>>);
+
+impl EstimatingLogger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the estimate accumulated so far.
+    pub fn estimate(&self) -> SyncEstimate {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Logger for EstimatingLogger {
+    fn log(&self, _: LogLevel, what: &Log) {
+        self.0.lock().unwrap().record(what);
+    }
+}
+
+/// Runs reconciliation over the given replicas without applying any changes,
+/// and returns an estimate of the work the real sync would do.
+///
+/// The replicas are handed back afterwards so that the real sync can be run
+/// with them.
+pub fn plan<
+    CLI: Replica,
+    ANC: Replica + NullTransfer + Condemn,
+    SRV: Replica,
+>(
+    cli: CLI,
+    anc: ANC,
+    srv: SRV,
+    root_rules: FileEngine,
+) -> Result<(SyncEstimate, CLI, ANC, SRV)> {
+    let logger = EstimatingLogger::new();
+    let context = Context {
+        cli: DryRunReplica(cli),
+        anc: DryRunReplica(anc),
+        srv: DryRunReplica(srv),
+        log: Arc::new(logger.clone()),
+        resolver: None,
+        conflict_suffix: None,
+        root_rules: root_rules,
+        work: WorkStack::new(),
+        tasks: UnqueuedTasks::new(),
+    };
+
+    let result = context.start_root().map(|_| context.run_work());
+    let Context { cli, anc, srv, .. } = context;
+    result?;
+    Ok((logger.estimate(), cli.0, anc.0, srv.0))
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::mem;
+
+    use super::super::mutate::test::*;
+    use super::*;
+    use crate::memory_replica::*;
+
+    fn reg(size: FileSize, hash: u8) -> FileData {
+        FileData::Regular(0o644, size, 0, [hash; 32])
+    }
+
+    fn create(replica: &MemoryReplica, dir: &mut DirHandle, f: File) {
+        let xfer = match *f.1 {
+            FileData::Regular(_, _, _, hash) => Some(hash),
+            _ => Some([0; 32]),
+        };
+        replica.create(dir, f, xfer).unwrap();
+    }
+
+    #[test]
+    fn plan_matches_real_sync() {
+        let mut fx = Fixture::new();
+        {
+            let mut root = fx.client.root().unwrap();
+            create(&fx.client, &mut root, File(OsStr::new("a"), &reg(10, 1)));
+            create(
+                &fx.client,
+                &mut root,
+                File(OsStr::new("sub"), &FileData::Directory(0o755)),
+            );
+            let mut sub = fx.client.chdir(&root, OsStr::new("sub")).unwrap();
+            create(&fx.client, &mut sub, File(OsStr::new("b"), &reg(20, 2)));
+
+            // Edited on both sides since the ancestor: a conflict
+            for (replica, hash) in
+                vec![(&fx.client, 3), (&fx.ancestor, 4), (&fx.server, 5)]
+            {
+                let mut root = replica.root().unwrap();
+                create(
+                    replica,
+                    &mut root,
+                    File(OsStr::new("c"), &reg(5, hash)),
+                );
+            }
+
+            let mut root = fx.server.root().unwrap();
+            create(&fx.server, &mut root, File(OsStr::new("d"), &reg(40, 6)));
+        }
+
+        let rules = fx.rules.file(File(OsStr::new(""), &FileData::Special));
+        let (estimate, client, ancestor, server) = plan(
+            mem::replace(&mut fx.client, MemoryReplica::empty()),
+            mem::replace(&mut fx.ancestor, MemoryReplica::empty()),
+            mem::replace(&mut fx.server, MemoryReplica::empty()),
+            rules.clone(),
+        )
+        .unwrap();
+        // a, sub/b and d at least; resolving the conflict on c may transfer
+        // more depending on the sync mode.
+        assert!(estimate.files >= 3);
+        assert!(estimate.bytes >= 70);
+        assert_eq!(1, estimate.conflicts);
+
+        // The plan must not have changed anything.
+        assert!(server
+            .list(&mut server.root().unwrap())
+            .unwrap()
+            .iter()
+            .all(|&(ref name, _)| name == OsStr::new("c")
+                || name == OsStr::new("d")));
+
+        let actual = EstimatingLogger::new();
+        let context = Context {
+            cli: client,
+            anc: ancestor,
+            srv: server,
+            log: Arc::new(actual.clone()),
+            resolver: None,
+            conflict_suffix: None,
+            root_rules: rules,
+            work: WorkStack::new(),
+            tasks: UnqueuedTasks::new(),
+        };
+        context.start_root().unwrap();
+        context.run_work();
+
+        assert_eq!(estimate, actual.estimate());
+    }
+}
@@ -18,8 +18,9 @@
 
 #[macro_use]
 mod context;
-pub use self::context::{Context, UnqueuedTasks};
+pub use self::context::{ConflictResolver, Context, UnqueuedTasks};
 
 pub mod compute;
+pub mod estimate;
 pub mod mutate;
 pub mod tree_walk;
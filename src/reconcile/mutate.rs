@@ -18,11 +18,16 @@
 
 use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
+use std::path::Path;
 use std::result;
+use std::sync::Arc;
+
+use chrono::Utc;
 
 use super::compute::SplitAncestorState;
 use super::compute::{gen_alternate_name, Reconciliation, ReconciliationSide};
 use super::context::*;
+use crate::block_xfer::ProgressFn;
 use crate::defs::*;
 use crate::errors::*;
 use crate::log;
@@ -158,11 +163,15 @@ fn try_replace_ancestor<A: Replica + NullTransfer, LOG: Logger>(
 /// Note that this does not handle removal or replacement of non-empty
 /// directories itself; such operations will simply fail.
 ///
+/// If `block_size` is given, it is passed to the source as the block size
+/// the destination should use for the transfer. The progress of the transfer
+/// is logged as `Log::Progress`.
+///
 /// Returns the actual new state of the file on success.
 fn replace_replica<
     DST: Replica,
     SRC: Replica<TransferOut = DST::TransferIn>,
-    LOG: Logger,
+    LOG: Logger + Clone + Send + Sync + 'static,
 >(
     dst: &DST,
     dst_dir: &mut DST::Directory,
@@ -173,6 +182,7 @@ fn replace_replica<
     name: &OsStr,
     old: Option<&FileData>,
     new: Option<&FileData>,
+    block_size: Option<usize>,
     log: &LOG,
     side: ReplicaSide,
 ) -> Result<Option<FileData>> {
@@ -215,7 +225,12 @@ fn replace_replica<
                 name,
                 oldfd,
                 newfd,
-                src.transfer(src_dir, File(name, newfd))?,
+                src.transfer_with_progress(
+                    src_dir,
+                    File(name, newfd),
+                    block_size,
+                    progress_logger(log, side, dir_name, name),
+                )?,
             ) {
                 Ok(r) => {
                     // We need to insert r, not newfd, because newfd may be
@@ -245,7 +260,12 @@ fn replace_replica<
             match dst.create(
                 dst_dir,
                 File(name, newfd),
-                src.transfer(src_dir, File(name, newfd))?,
+                src.transfer_with_progress(
+                    src_dir,
+                    File(name, newfd),
+                    block_size,
+                    progress_logger(log, side, dir_name, name),
+                )?,
             ) {
                 Ok(r) => {
                     // We need to insert r, not newfd, because newfd may be
@@ -272,6 +292,21 @@ fn replace_replica<
     }
 }
 
+/// Returns a `ProgressFn` which logs the progress of transferring `name`
+/// within `dir_name` to `side`.
+fn progress_logger<LOG: Logger + Clone + Send + Sync + 'static>(
+    log: &LOG,
+    side: ReplicaSide,
+    dir_name: &OsStr,
+    name: &OsStr,
+) -> ProgressFn {
+    let log = log.clone();
+    let path = Path::new(dir_name).join(name).into_os_string();
+    Arc::new(move |done: FileSize, total: FileSize| {
+        log.log(log::INFO, &Log::Progress(side, &path, done, total))
+    })
+}
+
 /// Renames a file on a replica.
 ///
 /// The file identified by `old_name` is renamed to `new_name` within `dir` in
@@ -335,7 +370,7 @@ fn apply_use<
     DST: Replica,
     ANC: Replica + NullTransfer,
     SRC: Replica<TransferOut = DST::TransferIn>,
-    LOG: Logger,
+    LOG: Logger + Clone + Send + Sync + 'static,
 >(
     dst: &DST,
     dst_dir: &mut DST::Directory,
@@ -349,6 +384,7 @@ fn apply_use<
     name: &OsStr,
     old_dst: Option<&FileData>,
     old_src: Option<&FileData>,
+    block_size: Option<usize>,
     log: &LOG,
     side: ReconciliationSide,
 ) -> result::Result<Option<Option<FileData>>, ApplyResult> {
@@ -398,6 +434,7 @@ fn apply_use<
             name,
             old_dst,
             old_src,
+            block_size,
             log,
             side.into(),
         ) {
@@ -427,6 +464,24 @@ impl<
         recon: Reconciliation,
         old_cli: Option<&FileData>,
         old_srv: Option<&FileData>,
+    ) -> ApplyResult {
+        self.apply_reconciliation_with(
+            dir, dir_name, name, recon, old_cli, old_srv, None,
+        )
+    }
+
+    /// Like `apply_reconciliation()`, but if `block_size` is given, requests
+    /// that any file content propagated be blocked with that size instead of
+    /// the destination's default.
+    pub fn apply_reconciliation_with(
+        &self,
+        dir: &mut <Self as ContextExt>::Dir,
+        dir_name: &OsStr,
+        name: &OsStr,
+        recon: Reconciliation,
+        old_cli: Option<&FileData>,
+        old_srv: Option<&FileData>,
+        block_size: Option<usize>,
     ) -> ApplyResult {
         use super::compute::Reconciliation::*;
 
@@ -478,6 +533,7 @@ impl<
                 name,
                 old_srv,
                 old_cli,
+                block_size,
                 &self.log,
                 ReconciliationSide::Server,
             ) {
@@ -497,6 +553,7 @@ impl<
                 name,
                 old_cli,
                 old_srv,
+                block_size,
                 &self.log,
                 ReconciliationSide::Client,
             ) {
@@ -511,7 +568,14 @@ impl<
             // renaming succeeds, it requeues both sides of the split for another
             // pass through reconciliation.
             Split(side, anc_state) => {
-                let new_name = gen_alternate_name(name, |n| dir.name_in_use(n));
+                let new_name = match self.conflict_suffix {
+                    Some(ref suffix) => {
+                        suffix.gen_name(name, side, Utc::now(), |n| {
+                            dir.name_in_use(n)
+                        })
+                    }
+                    None => gen_alternate_name(name, |n| dir.name_in_use(n)),
+                };
 
                 // Instead of renaming the ancestor, effectively remove it, then
                 // rename the end replica, and then recreate the ancestor if it was
@@ -661,7 +725,7 @@ pub mod test {
     use std::collections::{BTreeMap, BinaryHeap};
     use std::ffi::{OsStr, OsString};
     use std::mem;
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
 
     use crate::defs::test_helpers::*;
     use crate::defs::*;
@@ -675,7 +739,9 @@ pub mod test {
     use crate::work_stack::WorkStack;
 
     use super::super::compute::SplitAncestorState;
-    use super::super::compute::{Reconciliation, ReconciliationSide};
+    use super::super::compute::{
+        ConflictSuffix, Reconciliation, ReconciliationSide,
+    };
     use super::*;
     use super::{replace_ancestor, replace_replica, try_rename_replica};
 
@@ -704,6 +770,8 @@ pub mod test {
         pub server: MemoryReplica,
         pub logger: PrintlnLogger,
         pub rules: DirEngine,
+        pub resolver: Option<ConflictResolver>,
+        pub conflict_suffix: Option<ConflictSuffix>,
     }
 
     impl Fixture {
@@ -719,6 +787,8 @@ pub mod test {
                 )))
                 .subdir()
                 .build(),
+                resolver: None,
+                conflict_suffix: None,
             }
         }
 
@@ -727,7 +797,9 @@ pub mod test {
                 cli: self.client,
                 anc: self.ancestor,
                 srv: self.server,
-                log: Box::new(self.logger),
+                log: Arc::new(self.logger),
+                resolver: self.resolver.map(Mutex::new),
+                conflict_suffix: self.conflict_suffix,
                 root_rules: self
                     .rules
                     .file(File(OsStr::new(""), &FileData::Special)),
@@ -744,7 +816,9 @@ pub mod test {
                 cli: mem::replace(&mut self.client, MemoryReplica::empty()),
                 anc: mem::replace(&mut self.ancestor, MemoryReplica::empty()),
                 srv: mem::replace(&mut self.server, MemoryReplica::empty()),
-                log: Box::new(self.logger.clone()),
+                log: Arc::new(self.logger.clone()),
+                resolver: self.resolver.take().map(Mutex::new),
+                conflict_suffix: self.conflict_suffix.clone(),
                 root_rules: self
                     .rules
                     .file(File(OsStr::new(""), &FileData::Special)),
@@ -755,6 +829,7 @@ pub mod test {
             self.client = context.cli;
             self.ancestor = context.anc;
             self.server = context.srv;
+            self.resolver = context.resolver.map(|r| r.into_inner().unwrap());
             ret
         }
     }
@@ -1060,6 +1135,7 @@ pub mod test {
                 &foo,
                 Some(&fd),
                 Some(&fd2),
+                None,
                 &PrintlnLogger,
                 ReplicaSide::Server
             )
@@ -1089,6 +1165,7 @@ pub mod test {
             &foo,
             None,
             Some(&actual_fd),
+            None,
             &PrintlnLogger,
             ReplicaSide::Client,
         )
@@ -1122,6 +1199,7 @@ pub mod test {
             &foo,
             Some(&to_replace),
             Some(&actual_fd),
+            None,
             &PrintlnLogger,
             ReplicaSide::Client,
         )
@@ -1130,6 +1208,100 @@ pub mod test {
         assert_eq!(Some(&fd), files.get(&foo));
     }
 
+    #[test]
+    fn replace_replica_reports_progress_from_server() {
+        use std::fs;
+
+        use crate::log::{ChannelLogger, OwnedLog};
+        use crate::posix::PosixReplica;
+        use crate::replica::PrepareType;
+        use crate::server::{KeyChain, LocalStorage, ServerReplica};
+
+        let storage_dir = tempfile::tempdir().unwrap();
+        let key_chain = Arc::new(KeyChain::generate_new());
+        let server = ServerReplica::new(
+            ":memory:",
+            key_chain.clone(),
+            Arc::new(LocalStorage::open(storage_dir.path()).unwrap()),
+            "r00t",
+            1024,
+            flate2::Compression::fast(),
+        )
+        .unwrap();
+        server.create_root().unwrap();
+        let mut server_root = server.root().unwrap();
+
+        let posix_replica = || {
+            let root = tempfile::tempdir().unwrap();
+            let private = tempfile::tempdir().unwrap();
+            let replica = PosixReplica::new(
+                root.path(),
+                private.path(),
+                key_chain.obj_hmac_secret().unwrap(),
+                1024,
+            )
+            .unwrap();
+            (root, private, replica)
+        };
+
+        let foo = oss("foo");
+        let (src_dir, _src_private, src) = posix_replica();
+        fs::write(src_dir.path().join("foo"), vec![42u8; 4000]).unwrap();
+        src.prepare(PrepareType::Fast).unwrap();
+        let mut src_root = src.root().unwrap();
+        let src_fd = src.list(&mut src_root).unwrap()[0].1.clone();
+
+        let (logger, _) = ChannelLogger::new(0);
+        let fd = replace_replica(
+            &server,
+            &mut server_root,
+            &mut BTreeMap::new(),
+            &src,
+            &src_root,
+            &oss(""),
+            &foo,
+            None,
+            Some(&src_fd),
+            None,
+            &Arc::new(logger),
+            ReplicaSide::Server,
+        )
+        .unwrap()
+        .unwrap();
+
+        let (_dst_dir, _dst_private, dst) = posix_replica();
+        let mut dst_root = dst.root().unwrap();
+        let mut files = BTreeMap::new();
+        let (logger, progress) = ChannelLogger::new(1024);
+        replace_replica(
+            &dst,
+            &mut dst_root,
+            &mut files,
+            &server,
+            &server_root,
+            &oss(""),
+            &foo,
+            None,
+            Some(&fd),
+            None,
+            &Arc::new(logger),
+            ReplicaSide::Client,
+        )
+        .unwrap();
+        assert!(files.contains_key(&foo));
+
+        let reports: Vec<_> = progress
+            .try_iter()
+            .filter_map(|(_, log)| match log {
+                OwnedLog::Progress(_, _, done, total) => Some((done, total)),
+                _ => None,
+            })
+            .collect();
+        assert!(!reports.is_empty());
+        assert!(reports.iter().all(|&(_, total)| 4000 == total));
+        assert_eq!(Some(&(4000, 4000)), reports.last());
+    }
+
     #[test]
     fn replace_replica_delete_regular() {
         let (mut dst, mut dst_root) = replica();
@@ -1149,6 +1321,7 @@ pub mod test {
             &foo,
             Some(&fd),
             None,
+            None,
             &PrintlnLogger,
             ReplicaSide::Client,
         )
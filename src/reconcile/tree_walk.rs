@@ -118,13 +118,27 @@ impl<
             }
         }
 
-        let (recon, conflict) = choose_reconciliation(
+        let (mut recon, conflict) = choose_reconciliation(
             cli.as_ref(),
             anc.as_ref(),
             srv.as_ref(),
             rules.sync_mode(),
         );
 
+        if conflict > Conflict::NoConflict {
+            if let Some(ref resolver) = self.resolver {
+                let resolution = (resolver.lock().unwrap())(name, conflict);
+                if let Some(resolved) = resolve_conflict(
+                    cli.as_ref(),
+                    srv.as_ref(),
+                    conflict,
+                    resolution,
+                ) {
+                    recon = resolved;
+                }
+            }
+        }
+
         self.log.log(
             if conflict > Conflict::NoConflict {
                 log::WARN
@@ -134,13 +148,14 @@ impl<
             &Log::Inspect(dir_path, name, recon, conflict),
         );
 
-        let res = self.apply_reconciliation(
+        let res = self.apply_reconciliation_with(
             dir,
             dir_path,
             name,
             recon,
             cli.as_ref(),
             srv.as_ref(),
+            rules.block_size(),
         );
 
         match res {
@@ -459,6 +474,10 @@ impl<
     /// function ANDed with the subdirectory's `success` flag. The flags of the
     /// subdirectory state are ANDed into `state`, and `state`'s completion queued
     /// if this was the last task.
+    ///
+    /// `Log::EnterDirectory` is logged when the task starts, and
+    /// `Log::LeaveDirectory` once the subdirectory is complete (including after
+    /// `on_success`) or has failed to initialise.
     fn recurse_and_then<
         F: FnOnce(&Self, <Self as ContextExt>::Dir, &DirStateRef) -> bool
             + Send
@@ -475,6 +494,10 @@ impl<
         state.pending.fetch_add(1, SeqCst);
 
         self.work.push(task(move |this| {
+            let dir_path = cli_dir.full_path().to_owned();
+            let leave_path = dir_path.clone();
+            this.log.log(log::INFO, &Log::EnterDirectory(&dir_path));
+
             let state2 = state.clone();
             let success = Self::process_dir(
                 this,
@@ -490,11 +513,14 @@ impl<
                                     .fail("recurse_and_then on_success failed");
                             }
                         }
+                        this.log
+                            .log(log::INFO, &Log::LeaveDirectory(&leave_path));
                         this.finish_task_in_dir(&state, &subdirstate);
                     })
                 },
             );
             if !success {
+                this.log.log(log::INFO, &Log::LeaveDirectory(&dir_path));
                 state2.fail("process_dir failed early");
                 // process_dir() didn't create the task to decrement
                 // `pending`, so we need to do that now.
@@ -740,18 +766,19 @@ impl<
 #[cfg(test)]
 mod test {
     use std::collections::{HashMap, HashSet};
-    use std::ffi::OsStr;
+    use std::ffi::{OsStr, OsString};
     use std::iter::Iterator;
     use std::sync::atomic::Ordering::SeqCst;
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
 
     use proptest;
     use proptest::strategy::{BoxedStrategy, Singleton, Strategy};
 
     use super::super::mutate::test::*;
     use super::*;
+    use crate::log::{ChannelLogger, OwnedLog};
     use crate::memory_replica::*;
-    use crate::rules::engine::DirEngine;
+    use crate::rules::engine::{DirEngine, FileEngine, SyncRules};
     use crate::rules::{HalfSyncMode, SyncMode, SyncModeSetting};
 
     // Structs which describe a file tree, used for initialising and verifying
@@ -1072,6 +1099,46 @@ mod test {
         test_single(&vec![], "---/---", &vec![]);
     }
 
+    #[test]
+    fn resolver_overrides_edit_edit_conflict() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut fx = init(&vec![En(
+            "foo",
+            (Reg(7, 1), Z),
+            (Reg(7, 0), Z),
+            (Reg(7, 2), Z),
+            vec![],
+        )]);
+        fx.rules = "cud/cud".into_rules();
+        let seen2 = seen.clone();
+        fx.resolver = Some(Box::new(move |name: &OsStr, conflict| {
+            seen2.lock().unwrap().push((name.to_owned(), conflict));
+            Resolution::KeepClient
+        }));
+        run_full(&mut fx);
+
+        verify(
+            &fx,
+            &vec![En(
+                "foo",
+                (Reg(7, 1), Z),
+                (Reg(7, 1), Z),
+                (Reg(7, 1), Z),
+                vec![],
+            )],
+        );
+        assert_eq!(
+            vec![(
+                OsStr::new("foo").to_owned(),
+                Conflict::EditEdit(
+                    ConflictingEdit::Content,
+                    ConflictingEdit::Content
+                )
+            )],
+            *seen.lock().unwrap()
+        );
+    }
+
     #[test]
     fn sync_flat() {
         test_single(
@@ -1261,6 +1328,40 @@ mod test {
         );
     }
 
+    #[test]
+    fn sync_edit_conflict_uses_conflict_suffix() {
+        let mut fx = init(&vec![En(
+            "foo",
+            (Reg(7, 1), Z),
+            (Reg(7, 2), Z),
+            (Reg(7, 3), Z),
+            vec![],
+        )]);
+        fx.rules = "cud/cud".into_rules();
+        fx.conflict_suffix = Some(".conflict-{side}".parse().unwrap());
+        run_full(&mut fx);
+
+        verify(
+            &fx,
+            &vec![
+                En(
+                    "foo",
+                    (Reg(7, 1), Z),
+                    (Reg(7, 1), Z),
+                    (Reg(7, 1), Z),
+                    vec![],
+                ),
+                En(
+                    "foo.conflict-server",
+                    (Reg(7, 3), Z),
+                    (Reg(7, 3), Z),
+                    (Reg(7, 3), Z),
+                    vec![],
+                ),
+            ],
+        );
+    }
+
     #[test]
     fn sync_replace_dir_with_reg() {
         test_single(
@@ -1318,6 +1419,121 @@ mod test {
         );
     }
 
+    #[test]
+    fn enter_and_leave_directory_bracket_file_events() {
+        let mut fx = init(&vec![
+            En(
+                "d1",
+                (Dir(7), Z),
+                (Nil, Z),
+                (Nil, Z),
+                vec![En("f1", (Reg(7, 1), Z), (Nil, Z), (Nil, Z), vec![])],
+            ),
+            En(
+                "d2",
+                (Nil, Z),
+                (Nil, Z),
+                (Dir(7), Z),
+                vec![En(
+                    "d3",
+                    (Nil, Z),
+                    (Nil, Z),
+                    (Dir(7), Z),
+                    vec![En("f3", (Nil, Z), (Nil, Z), (Reg(7, 3), Z), vec![])],
+                )],
+            ),
+        ]);
+        fx.rules = "cud/cud".into_rules();
+        let (logger, receiver) = ChannelLogger::new(1024);
+        let mut context = fx.context();
+        context.log = Arc::new(logger);
+        context.start_root().unwrap();
+        context.run_work();
+
+        let events: Vec<OwnedLog> =
+            receiver.try_iter().map(|(_, event)| event).collect();
+        let position = |target: OwnedLog| {
+            events
+                .iter()
+                .position(|e| *e == target)
+                .unwrap_or_else(|| panic!("{:?} not logged", target))
+        };
+
+        for &path in &["", "/d1", "/d2", "/d2/d3"] {
+            let path = OsString::from(path);
+            let enter = position(OwnedLog::EnterDirectory(path.clone()));
+            let leave = position(OwnedLog::LeaveDirectory(path.clone()));
+            assert!(enter < leave);
+
+            let mut nfile_events = 0;
+            for (ix, event) in events.iter().enumerate() {
+                let dir = match *event {
+                    OwnedLog::Inspect(ref dir, ..)
+                    | OwnedLog::Create(_, ref dir, ..)
+                    | OwnedLog::Update(_, ref dir, ..)
+                    | OwnedLog::Rename(_, ref dir, ..)
+                    | OwnedLog::Remove(_, ref dir, ..) => dir,
+                    _ => continue,
+                };
+                if *dir == path {
+                    nfile_events += 1;
+                    assert!(
+                        enter < ix && ix < leave,
+                        "{:?} outside of {:?}",
+                        event,
+                        path
+                    );
+                }
+            }
+            assert!(nfile_events > 0);
+        }
+
+        // Subdirectories are entered and left within their parents.
+        assert!(
+            position(OwnedLog::EnterDirectory("/d2".into()))
+                < position(OwnedLog::EnterDirectory("/d2/d3".into()))
+        );
+        assert!(
+            position(OwnedLog::LeaveDirectory("/d2/d3".into()))
+                < position(OwnedLog::LeaveDirectory("/d2".into()))
+        );
+    }
+
+    #[test]
+    fn sync_honours_rule_block_size() {
+        let mut fx = init(&vec![
+            En("foo.iso", (Reg(7, 1), Z), (Nil, Z), (Nil, Z), vec![]),
+            En("bar", (Nil, Z), (Nil, Z), (Reg(7, 2), Z), vec![]),
+            En("baz", (Reg(7, 3), Z), (Nil, Z), (Nil, Z), vec![]),
+        ]);
+        let table: toml::value::Table = toml::from_str(
+            r#"
+[[rules.root.files]]
+mode = "cud/cud"
+
+[[rules.root.files]]
+name = "\\.iso$|^bar$"
+block_size = 4096
+"#,
+        )
+        .unwrap();
+        fx.rules = FileEngine::new(Arc::new(
+            SyncRules::parse(table["rules"].as_table().unwrap(), "rules")
+                .unwrap(),
+        ))
+        .subdir()
+        .build();
+        run_full(&mut fx);
+
+        let cli_sizes = fx.client.data().block_sizes.clone();
+        let srv_sizes = fx.server.data().block_sizes.clone();
+        // Client-to-server transfers are requested from the client, and vice
+        // versa.
+        assert_eq!(Some(&4096), cli_sizes.get(OsStr::new("/foo.iso")));
+        assert_eq!(None, cli_sizes.get(OsStr::new("/baz")));
+        assert_eq!(Some(&4096), srv_sizes.get(OsStr::new("/bar")));
+    }
+
     #[test]
     fn sync_no_trust_client_unix_mode_steady_state() {
         test_single(
@@ -21,6 +21,7 @@ use std::os::unix::net::UnixDatagram;
 use std::sync::{Mutex, Weak};
 use std::time::Duration;
 
+use crate::block_xfer::ProgressFn;
 use crate::defs::*;
 use crate::errors::Result;
 use crate::interrupt;
@@ -218,6 +219,36 @@ pub trait Replica: Sync + Send {
         file: File,
     ) -> Result<Self::TransferOut>;
 
+    /// Like `transfer()`, but requests that the file be blocked with
+    /// `block_size` instead of whatever the destination would use by default,
+    /// if `block_size` is given.
+    ///
+    /// The default ignores `block_size` and delegates to `transfer()`.
+    fn transfer_with_block_size(
+        &self,
+        dir: &Self::Directory,
+        file: File,
+        _block_size: Option<usize>,
+    ) -> Result<Self::TransferOut> {
+        self.transfer(dir, file)
+    }
+
+    /// Like `transfer_with_block_size()`, but additionally arranges for
+    /// `progress` to be called as the content of the file is read out of the
+    /// returned object.
+    ///
+    /// The default ignores `progress` and delegates to
+    /// `transfer_with_block_size()`.
+    fn transfer_with_progress(
+        &self,
+        dir: &Self::Directory,
+        file: File,
+        block_size: Option<usize>,
+        _progress: ProgressFn,
+    ) -> Result<Self::TransferOut> {
+        self.transfer_with_block_size(dir, file, block_size)
+    }
+
     /// Performs any initial setup of this replica.
     ///
     /// This is generally a scan for dirty directories, sanity checks, etc.
@@ -22,7 +22,9 @@
 //! semantics of the rules engine is described in the README and not repeated
 //! here.
 
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fmt;
 use std::mem;
 use std::ops::Deref;
@@ -94,6 +96,7 @@ impl Condition {
 enum Action {
     Mode(SyncMode),
     TrustClientUnixMode(bool),
+    BlockSize(usize),
     Include(Vec<usize>),
     Switch(usize),
     Stop(StopType),
@@ -110,6 +113,8 @@ enum StopType {
 struct Rule {
     conditions: Vec<Condition>,
     actions: Vec<Action>,
+    /// Where the rule was defined in the configuration, if anywhere.
+    location: Option<String>,
 }
 
 impl Rule {
@@ -150,6 +155,8 @@ struct EngineState {
     mode: SyncMode,
     /// Whether `trust_client_unix_mode` is on.
     trust_client_unix_mode: bool,
+    /// The block size set by `block_size`, if any.
+    block_size: Option<usize>,
     /// The index of the `RuleState` in effect.
     state: usize,
     /// If present, the new value of `state` the next time the engine descends
@@ -165,6 +172,7 @@ impl EngineState {
         EngineState {
             mode: SyncMode::default(),
             trust_client_unix_mode: true,
+            block_size: None,
             state: init_state,
             switch: None,
             path: String::default(),
@@ -267,6 +275,11 @@ quick_error! {
             description("File size out of range")
             display("Size {} is out of file size range, in {}", i, loc)
         }
+        BlockSizeOutOfRange(loc: ErrorLocation, i: i64) {
+            description("Block size out of range")
+            display("Block size {} is out of range (256 to 1GB), in {}",
+                    i, loc)
+        }
         UnreachableState(path: String) {
             description("Unreachable state")
             display("Unreachable state: {} (maybe a typo or you forgot \
@@ -294,6 +307,7 @@ impl SyncRules {
                     Action::Mode(mode),
                     Action::TrustClientUnixMode(trust_client_unix_mode),
                 ],
+                location: None,
             }],
         }
     }
@@ -350,6 +364,7 @@ impl SyncRules {
                         match *action {
                             Action::Mode(..)
                             | Action::TrustClientUnixMode(..)
+                            | Action::BlockSize(..)
                             | Action::Stop(..) => (),
                             Action::Include(ref reffed) => {
                                 for &r in reffed {
@@ -443,6 +458,7 @@ impl SyncRules {
         if let Some(def) = def_raw.as_table() {
             let ix = self.rules.len();
             let mut rule: Rule = Default::default();
+            rule.location = Some(format!("{} #{}", path, ref_ix + 1));
 
             for (e_name, e_val) in def {
                 let loc = ErrorLocation::new(
@@ -477,6 +493,9 @@ impl SyncRules {
                     rule.actions.push(Action::TrustClientUnixMode(
                         convert_bool(e_val, loc)?,
                     ));
+                } else if "block_size" == e_name {
+                    rule.actions
+                        .push(Action::BlockSize(parse_block_size(e_val, loc)?));
                 } else if "include" == e_name {
                     rule.actions.push(Action::Include(parse_state_ref_list(
                         e_val,
@@ -503,9 +522,10 @@ impl SyncRules {
             rule.actions.sort_by_key(|a| match *a {
                 Action::Mode(..) => 0,
                 Action::TrustClientUnixMode(..) => 1,
-                Action::Include(..) => 2,
-                Action::Switch(..) => 3,
-                Action::Stop(..) => 4,
+                Action::BlockSize(..) => 2,
+                Action::Include(..) => 3,
+                Action::Switch(..) => 4,
+                Action::Stop(..) => 5,
             });
 
             self.rules.push(rule);
@@ -543,6 +563,19 @@ fn parse_file_size(val: &toml::Value, loc: ErrorLocation) -> Result<FileSize> {
     }
 }
 
+fn parse_block_size(val: &toml::Value, loc: ErrorLocation) -> Result<usize> {
+    if let Some(i) = val.as_integer() {
+        // Same bounds as the global `block_size`
+        if i >= 256 && i <= 1024 * 1024 * 1024 {
+            Ok(i as usize)
+        } else {
+            Err(Error::BlockSizeOutOfRange(loc, i))
+        }
+    } else {
+        Err(Error::WrongType(loc, "integer"))
+    }
+}
+
 fn parse_mode(val: &toml::Value, loc: ErrorLocation) -> Result<SyncMode> {
     if let Some(s) = val.as_str() {
         Ok(s.parse().context((loc, s))?)
@@ -601,6 +634,59 @@ fn parse_stop_type(val: &toml::Value, loc: ErrorLocation) -> Result<StopType> {
     }
 }
 
+/// How an edit/edit conflict on file content is settled under a particular
+/// sync mode. This mirrors the decision made by the reconciler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Updates are disabled in both directions, so the conflict is ignored.
+    Ignore,
+    /// Both versions are kept by renaming the server version.
+    KeepBoth,
+    /// Neither version can be replaced, so the conflict is left as-is.
+    Irreconcilable,
+    /// The client version replaces the server version.
+    PreferClient,
+    /// The server version replaces the client version.
+    PreferServer,
+    /// Whichever version is newer replaces the other.
+    PreferNewer,
+}
+
+impl ConflictPolicy {
+    pub fn for_mode(mode: SyncMode) -> Self {
+        use crate::rules::defs::SyncModeSetting::Force;
+
+        match (mode.inbound.update, mode.outbound.update) {
+            (Force, Force) => ConflictPolicy::PreferNewer,
+            (Force, _) => ConflictPolicy::PreferServer,
+            (_, Force) => ConflictPolicy::PreferClient,
+            (inbound, outbound) if !inbound.on() && !outbound.on() => {
+                ConflictPolicy::Ignore
+            }
+            _ if mode.inbound.create.on() && mode.outbound.create.on() => {
+                ConflictPolicy::KeepBoth
+            }
+            _ => ConflictPolicy::Irreconcilable,
+        }
+    }
+}
+
+/// Describes how the rules engine treats a particular path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleExplanation {
+    /// The location of the rule which set the sync mode, or `None` if no rule
+    /// from the configuration did so.
+    pub rule: Option<String>,
+    /// The sync mode in effect for the path.
+    pub mode: SyncMode,
+    /// Whether `trust_client_unix_mode` is in effect for the path.
+    pub trust_client_unix_mode: bool,
+    /// The block size set by `block_size` for the path, if any.
+    pub block_size: Option<usize>,
+    /// How content conflicts on the path are resolved.
+    pub conflict_policy: ConflictPolicy,
+}
+
 #[derive(Clone, Debug)]
 pub struct DirEngine {
     rules: Arc<SyncRules>,
@@ -672,6 +758,7 @@ impl SyncRules {
                     Action::TrustClientUnixMode(trust) => {
                         engstate.trust_client_unix_mode = trust
                     }
+                    Action::BlockSize(size) => engstate.block_size = Some(size),
                     Action::Include(ref subs) => {
                         for &sub in subs {
                             if !self.apply_rules_impl(
@@ -695,6 +782,64 @@ impl SyncRules {
         occurs[state_ix] = false;
         keep_going
     }
+
+    /// Explains which rule determines the sync mode of `path`, which is
+    /// relative to the sync root, and what that implies for conflicts.
+    ///
+    /// No files are actually examined. Every component of the path but the
+    /// last is taken to be a directory with mode 0755, and the last to be an
+    /// empty regular file with mode 0644. Since the contents of the
+    /// directories are unknown, `siblings` rules never match.
+    pub fn explain(&self, path: &OsStr) -> RuleExplanation {
+        let path = path.to_string_lossy();
+        let names: Vec<&str> =
+            path.split('/').filter(|s| !s.is_empty()).collect();
+        let mode_rule = Cell::new(None);
+        let mut state = EngineState::new(self.root_ix);
+
+        for (ix, &name) in names.iter().enumerate() {
+            let data = if ix + 1 == names.len() {
+                FileData::Regular(0o644, 0, 0, UNKNOWN_HASH)
+            } else {
+                FileData::Directory(0o755)
+            };
+
+            state.push_dir(name);
+            state.apply_switch();
+
+            let mut path = String::new();
+            mem::swap(&mut path, &mut state.path);
+            self.apply_rules(
+                &mut state,
+                |g| &g.files,
+                |r| {
+                    let rule = &self.rules[r];
+                    let matched = rule.matches(&path, name, &data);
+                    if matched
+                        && rule.actions.iter().any(|a| match *a {
+                            Action::Mode(..) => true,
+                            _ => false,
+                        })
+                    {
+                        mode_rule.set(Some(r));
+                    }
+                    matched
+                },
+            );
+            mem::swap(&mut state.path, &mut path);
+
+            // Descend as `DirEngineBuilder::build()` would with no siblings.
+            state.apply_switch();
+        }
+
+        RuleExplanation {
+            rule: mode_rule.get().and_then(|r| self.rules[r].location.clone()),
+            mode: state.mode,
+            trust_client_unix_mode: state.trust_client_unix_mode,
+            block_size: state.block_size,
+            conflict_policy: ConflictPolicy::for_mode(state.mode),
+        }
+    }
 }
 
 impl DirEngine {
@@ -738,6 +883,12 @@ impl FileEngine {
         self.state.trust_client_unix_mode
     }
 
+    /// Returns the block size to use for transfers of this file instead of
+    /// the configured one, if any rule set one.
+    pub fn block_size(&self) -> Option<usize> {
+        self.state.block_size
+    }
+
     pub fn subdir(self) -> DirEngineBuilder {
         let mut matched = Vec::new();
         matched.resize(self.rules.rules.len(), false);
@@ -1068,6 +1219,19 @@ bigger = -1"#,
         }
     }
 
+    #[test]
+    fn parse_error_block_size_out_of_range() {
+        let res = parse_rules(
+            r#"
+[[rules.root.files]]
+block_size = 16"#,
+        );
+        match res {
+            Err(Error::BlockSizeOutOfRange(..)) => (),
+            unexpected => panic!("Unexpected parse result: {:?}", unexpected),
+        }
+    }
+
     #[test]
     fn parse_error_bad_rules_group_name() {
         let res = parse_rules(
@@ -1206,6 +1370,20 @@ mode = "cud/cud"
         assert!(regular(&sde, "foo", 0, 0).trust_client_unix_mode());
     }
 
+    #[test]
+    fn block_size_set_by_rule() {
+        let de = engine(
+            r#"
+[[rules.root.files]]
+name = "\\.iso$"
+block_size = 4194304
+"#,
+        );
+
+        assert_eq!(Some(4194304), regular(&de, "disk.iso", 0, 0).block_size());
+        assert_eq!(None, regular(&de, "notes.txt", 0, 0).block_size());
+    }
+
     #[test]
     fn match_condition_path() {
         let de = engine(
@@ -1518,4 +1696,50 @@ mode = "cud/cud"
             regular(&sde, "quux", 0, 0).sync_mode().to_string()
         );
     }
+
+    #[test]
+    fn explain_reports_matching_rule() {
+        let rules = parse_rules(
+            r#"
+[[rules.root.files]]
+mode = "cud/cud"
+
+[[rules.root.files]]
+name = '\.bak$'
+mode = "---/---"
+
+[[rules.root.files]]
+name = "^private$"
+switch = "private"
+
+[[rules.private.files]]
+mode = "CUD/cud"
+trust_client_unix_mode = false
+"#,
+        )
+        .unwrap();
+
+        let explanation = rules.explain(&oss("docs/foo.txt"));
+        assert_eq!(Some("rules.root.files #1"), explanation.rule.as_deref());
+        assert_eq!("cud/cud", explanation.mode.to_string());
+        assert!(explanation.trust_client_unix_mode);
+        assert_eq!(ConflictPolicy::KeepBoth, explanation.conflict_policy);
+
+        let explanation = rules.explain(&oss("notes.bak"));
+        assert_eq!(Some("rules.root.files #2"), explanation.rule.as_deref());
+        assert_eq!("---/---", explanation.mode.to_string());
+        assert_eq!(ConflictPolicy::Ignore, explanation.conflict_policy);
+
+        let explanation = rules.explain(&oss("private/notes.bak"));
+        assert_eq!(Some("rules.private.files #1"), explanation.rule.as_deref());
+        assert_eq!("CUD/cud", explanation.mode.to_string());
+        assert!(!explanation.trust_client_unix_mode);
+        assert_eq!(ConflictPolicy::PreferServer, explanation.conflict_policy);
+
+        let explanation =
+            SyncRules::single_mode("cud/CUD".parse().unwrap(), true)
+                .explain(&oss("foo"));
+        assert_eq!(None, explanation.rule);
+        assert_eq!(ConflictPolicy::PreferClient, explanation.conflict_policy);
+    }
 }
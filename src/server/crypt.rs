@@ -166,7 +166,24 @@
 //! file system, and the latter are more likely to be accessible to attackers
 //! than the contents of the ensync private directory.
 //!
-//! Objects are padded to the block size with PKCS.
+//! Objects are padded to the block size with PKCS. Empty objects are not
+//! special-cased, since none are ever stored: directories are compressed
+//! before being encrypted, so are never empty, and empty files have no blocks.
+//!
+//! Objects carry no MAC of their own. None is needed, since the object id is
+//! itself the HMAC of the object content and is verified by the block transfer
+//! layer after decryption, so a corrupted or forged object cannot be accepted
+//! regardless of whether its padding happens to be valid.
+//!
+//! A consequence of the above is that object encryption is convergent: since
+//! nothing random goes into the key or IV, identical content always produces
+//! identical ciphertext (and an identical server-side id) within a store. This
+//! is what allows the server to deduplicate blocks. The usual caveats of
+//! convergent encryption apply; an attacker with access to the server who can
+//! guess the exact content of an object can confirm whether the store holds
+//! it, but only if they also know the `everyone` HMAC secret, which means they
+//! could simply read the directories anyway. Content is *not* convergent
+//! across stores, since each store has its own `everyone` key.
 //!
 //! # Directory Versions
 //!
@@ -214,14 +231,22 @@
 //! with surrogate 1-byte entries (see the directory format for more details).
 //! Appending is done by using the last ciphertext block as the IV.
 
+use std::cmp;
 use std::collections::BTreeMap;
 use std::fmt;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ptr;
 use std::result::Result as StdResult;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::thread;
 
 use crate::rust_crypto::buffer::{
     BufferResult, ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer,
 };
+use crate::rust_crypto::hmac::Hmac;
+use crate::rust_crypto::pbkdf2::pbkdf2;
+use crate::rust_crypto::sha2::Sha256;
 use crate::rust_crypto::symmetriccipher::{
     Decryptor, Encryptor, SymmetricCipherError,
 };
@@ -229,14 +254,29 @@ use crate::rust_crypto::{aes, blockmodes, scrypt};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use fourleaf::adapt::Copied;
 use fourleaf::{self, UnknownFields};
+use num_cpus;
 use rand::{rngs::OsRng, Rng};
 use tiny_keccak;
 
-use crate::defs::{HashId, UNKNOWN_HASH};
+use crate::block_xfer::HashVariant;
+use crate::defs::{HashId, Passphrase, UNKNOWN_HASH};
 use crate::errors::*;
 
 const SCRYPT_18_14_12_8_1: &'static str = "scrypt-18/14/12-8-1";
+/// Argon2id with 64 MiB of memory, 3 passes and 4 lanes, the second
+/// recommended option of RFC 9106.
+pub const ARGON2ID_64M_3_4: &'static str = "argon2id-64m-3-4";
+/// The KDF algorithm used for new keys unless the caller specifies another.
+pub const DEFAULT_KDF_ALGORITHM: &'static str = SCRYPT_18_14_12_8_1;
+/// The length of the salt of new KDF entries when not otherwise specified.
+pub const DEFAULT_SALT_LEN: usize = 32;
+/// The newest `KdfList::format_version` this version of Ensync understands,
+/// and the one given to new key stores.
+pub const KDFLIST_FORMAT_VERSION: u32 = 1;
 pub const BLKSZ: usize = 16;
+/// The size of the source buffer used by `crypt_stream` when the caller does
+/// not specify otherwise.
+pub const DEFAULT_CRYPT_BUF_SIZE: usize = 4096;
 pub const GROUP_EVERYONE: &'static str = "everyone";
 pub const GROUP_ROOT: &'static str = "root";
 
@@ -247,14 +287,85 @@ pub const GROUP_ROOT: &'static str = "root";
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct KdfList {
     pub keys: BTreeMap<String, KdfEntry>,
+    /// The MAC of the most recent entry in the key management audit log, if
+    /// there is one.
+    ///
+    /// Since this is updated atomically with the log itself, it allows
+    /// detecting the log being truncated or rolled back.
+    pub audit_head: Option<HashId>,
+    /// If set, the minimum number of keys which must remain in the key store
+    /// and in the `root` group.
+    pub min_keys: Option<u32>,
+    /// The format of the encrypted directory versions in this store, one of
+    /// the `DIR_VER_FMT_*` constants. `None` is equivalent to
+    /// `DIR_VER_FMT_LEGACY`.
+    pub dir_ver_fmt: Option<u32>,
+    /// The hash variant used for block and stream ids in this store, as
+    /// returned by `HashVariant::code()`. `None` is equivalent to
+    /// `HashVariant::Sha3_256`.
+    pub hash_variant: Option<u32>,
+    /// The version of the key store format, which clients must check before
+    /// using the store. `None` is equivalent to 1, the version of stores
+    /// created before this field existed.
+    pub format_version: Option<u32>,
+    /// The AES key size used for objects and directories in this store, as
+    /// returned by `CipherWidth::code()`. `None` is equivalent to
+    /// `CipherWidth::Aes128`.
+    pub cipher_width: Option<u32>,
+    /// Whether the time each key is used is recorded in its `used` field.
+    /// `None` is equivalent to `true`.
+    pub record_used: Option<bool>,
+    /// Where the key management audit log currently ends, if there is one.
+    pub audit_tail: Option<AuditTail>,
     pub unknown: UnknownFields<'static>,
 }
 
 fourleaf_retrofit!(struct KdfList : {} {} {
     |_context, this|
     [1] keys: BTreeMap<String, KdfEntry> = &this.keys,
+    [2] audit_head: Option<HashId> = this.audit_head,
+    [3] min_keys: Option<u32> = this.min_keys,
+    [4] dir_ver_fmt: Option<u32> = this.dir_ver_fmt,
+    [5] hash_variant: Option<u32> = this.hash_variant,
+    [6] format_version: Option<u32> = this.format_version,
+    [7] cipher_width: Option<u32> = this.cipher_width,
+    [8] record_used: Option<bool> = this.record_used,
+    [9] audit_tail: Option<AuditTail> = &this.audit_tail,
     (?) unknown: Copied<UnknownFields<'static>> = &this.unknown,
-    { Ok(KdfList { keys: keys, unknown: unknown.0 }) }
+    { Ok(KdfList { keys: keys, audit_head: audit_head, min_keys: min_keys,
+                   dir_ver_fmt: dir_ver_fmt, hash_variant: hash_variant,
+                   format_version: format_version, cipher_width: cipher_width,
+                   record_used: record_used, audit_tail: audit_tail,
+                   unknown: unknown.0 }) }
+});
+
+/// The position of the end of the key management audit log.
+///
+/// This holds everything needed to append to the log without reading it
+/// back, none of which is secret: it is all either public or ciphertext
+/// which is also stored in the log itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditTail {
+    /// The version of the audit log directory.
+    pub ver: HashId,
+    /// The length of the audit log directory, in bytes.
+    pub len: u32,
+    /// The encrypted session key and IV at the start of the audit log
+    /// directory.
+    pub prefix: Vec<u8>,
+    /// The last cipher block of the audit log directory, i.e., the IV for
+    /// the next append.
+    pub last_block: Vec<u8>,
+}
+
+fourleaf_retrofit!(struct AuditTail : {} {} {
+    |_context, this|
+    [1] ver: HashId = this.ver,
+    [2] len: u32 = this.len,
+    [3] prefix: Vec<u8> = &this.prefix,
+    [4] last_block: Vec<u8> = &this.last_block,
+    { Ok(AuditTail { ver: ver, len: len, prefix: prefix,
+                     last_block: last_block }) }
 });
 
 /// A single passphrase which may be used to derive internal keys
@@ -266,20 +377,31 @@ pub struct KdfEntry {
     pub updated: Option<DateTime<Utc>>,
     /// The algorithm used.
     ///
-    /// This includes the parameters used. Note that these are not parsed;
-    /// the possible combinations are hardwired.
-    ///
-    /// The only option right now is "scrypt-18-8-1".
+    /// This includes the parameters used. Either "scrypt-18/14/12-8-1",
+    /// whose parameters vary with the passphrase length, or
+    /// "scrypt-<log2 N>-<r>-<p>" with fixed parameters. Either may be
+    /// followed by "+sha3-512" to indicate that `wide_hash` is used to verify
+    /// the derived key instead of `hash`.
     pub algorithm: String,
     /// The randomly-generated salt.
-    pub salt: HashId,
+    ///
+    /// This is normally `DEFAULT_SALT_LEN` bytes long, but entries created
+    /// with an explicit salt length may have a salt of any length.
+    pub salt: Vec<u8>,
     /// The SHA3 hash of the derived key, to determine whether the key is
-    /// correct.
+    /// correct. All zeroes if `wide_hash` is used instead.
     pub hash: HashId,
     /// The groups to which this entry is associated. Each key is a group name,
     /// and the value is the XOR of the internal key of the group with the HMAC
     /// of the group name and the derived key.
     pub groups: BTreeMap<String, HashId>,
+    /// The time this entry was last used to derive keys for syncing, if
+    /// known.
+    pub used: Option<DateTime<Utc>>,
+    /// An arbitrary human-readable comment describing the key.
+    pub comment: Option<String>,
+    /// The SHA3-512 hash of the derived key, if `algorithm` calls for it.
+    pub wide_hash: Option<Vec<u8>>,
     pub unknown: UnknownFields<'static>,
 }
 
@@ -300,14 +422,20 @@ fourleaf_retrofit!(struct KdfEntry : {} {} {
     [1] created: SerDt = SerDt(this.created),
     [2] updated: Option<SerDt> = this.updated.map(SerDt),
     [3] algorithm: String = &this.algorithm,
-    [4] salt: HashId = this.salt,
+    [4] salt: Vec<u8> = &this.salt,
     [5] hash: HashId = this.hash,
     [6] groups: BTreeMap<String, HashId> = &this.groups,
+    [7] used: Option<SerDt> = this.used.map(SerDt),
+    [8] comment: Option<String> = &this.comment,
+    [9] wide_hash: Option<Vec<u8>> = &this.wide_hash,
     (?) unknown: Copied<UnknownFields<'static>> = &this.unknown,
     { Ok(KdfEntry { created: created.0,
                     updated: updated.map(|v| v.0),
                     algorithm: algorithm, salt: salt, hash: hash,
                     groups: groups,
+                    used: used.map(|v| v.0),
+                    comment: comment,
+                    wide_hash: wide_hash,
                     unknown: unknown.0 }) }
 });
 
@@ -328,6 +456,20 @@ pub struct KeyChain {
     pub keys: BTreeMap<String, InternalKey>,
     /// The intermediate key derived from the passphrase.
     pub derived: InternalKey,
+    /// The directory version format (one of the `DIR_VER_FMT_*` constants)
+    /// used by `encrypt_dir_ver()` and `decrypt_dir_ver()`.
+    pub dir_ver_fmt: u32,
+    /// The hash variant to use for block and stream ids.
+    pub hash_variant: HashVariant,
+    /// The AES key size to use for objects and directories.
+    pub cipher_width: CipherWidth,
+    /// If set, the secret returned by `obj_hmac_secret()` in place of the one
+    /// derived from the `everyone` group.
+    ///
+    /// This allows block ids to be keyed by a secret managed outside the key
+    /// store entirely. Every client of the store must then use the same
+    /// secret, or they will not be able to read each other's files.
+    pub block_secret: Option<Passphrase>,
 }
 
 impl KeyChain {
@@ -341,6 +483,10 @@ impl KeyChain {
         KeyChain {
             keys: keys,
             derived: InternalKey(UNKNOWN_HASH),
+            dir_ver_fmt: DIR_VER_FMT_LEGACY,
+            hash_variant: HashVariant::default(),
+            cipher_width: CipherWidth::default(),
+            block_secret: None,
         }
     }
 
@@ -349,6 +495,10 @@ impl KeyChain {
         KeyChain {
             keys: BTreeMap::new(),
             derived: InternalKey(UNKNOWN_HASH),
+            dir_ver_fmt: DIR_VER_FMT_LEGACY,
+            hash_variant: HashVariant::default(),
+            cipher_width: CipherWidth::default(),
+            block_secret: None,
         }
     }
 
@@ -361,11 +511,25 @@ impl KeyChain {
     }
 
     /// Returns the HMAC secret to use for object hashing.
+    ///
+    /// This is `block_secret` if set, and otherwise the HMAC secret of the
+    /// `everyone` group.
     pub fn obj_hmac_secret(&self) -> Result<&[u8]> {
+        if let Some(ref secret) = self.block_secret {
+            return Ok(&secret[..]);
+        }
         self.key(GROUP_EVERYONE).map(InternalKey::hmac_secret)
     }
 }
 
+/// A 256-bit internal key, associated with a group.
+///
+/// The first half is the AES key protecting directory contents and versions;
+/// the second half is the HMAC secret. Note that the HMAC secret is not
+/// specific to objects: besides naming objects and blocks, it also
+/// authenticates directory versions (`secret_dir_ver()`, `DIR_VER_FMT_MAC`)
+/// and directory contents, so neither half can be rotated without rewriting
+/// every directory of the group.
 #[derive(Clone, PartialEq, Eq)]
 pub struct InternalKey(HashId);
 
@@ -384,6 +548,111 @@ impl InternalKey {
     pub fn hmac_secret(&self) -> &[u8] {
         &self.0[BLKSZ..BLKSZ * 2]
     }
+
+    /// Derives an independent internal key from this one for the purpose
+    /// identified by `label`.
+    ///
+    /// Anyone with this key can compute the derived key, but not vice versa.
+    pub fn derive(&self, label: &[u8]) -> InternalKey {
+        InternalKey(hmac(label, &self.0))
+    }
+
+    /// Returns the key used to encrypt directories with the given cipher
+    /// width.
+    ///
+    /// For `Aes128`, this is simply `dir_key()`. There are not enough bits in
+    /// `dir_key()` for `Aes256`, so instead the key is derived from the whole
+    /// internal key.
+    fn dir_key_for(&self, width: CipherWidth) -> HashId {
+        let mut key = HashId::default();
+        match width {
+            CipherWidth::Aes128 => key[..BLKSZ].copy_from_slice(self.dir_key()),
+            CipherWidth::Aes256 => key = hmac(&self.0, b"ensync-dir-aes256"),
+        }
+        key
+    }
+}
+
+/// The AES key size used to encrypt objects and directories.
+///
+/// This is chosen per store and recorded in the `KdfList` (see
+/// `keymgmt::set_cipher_width()`). Stores which do not record one use
+/// `Aes128`, which was the only option originally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherWidth {
+    Aes128,
+    Aes256,
+}
+
+/// Code recorded for `CipherWidth::Aes128`.
+pub const CIPHER_WIDTH_AES128: u32 = 0;
+/// Code recorded for `CipherWidth::Aes256`.
+pub const CIPHER_WIDTH_AES256: u32 = 1;
+
+impl Default for CipherWidth {
+    fn default() -> Self {
+        CipherWidth::Aes128
+    }
+}
+
+impl CipherWidth {
+    /// Returns the width with the given recorded code, if known.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            CIPHER_WIDTH_AES128 => Some(CipherWidth::Aes128),
+            CIPHER_WIDTH_AES256 => Some(CipherWidth::Aes256),
+            _ => None,
+        }
+    }
+
+    /// Returns the code under which this width is recorded.
+    pub fn code(self) -> u32 {
+        match self {
+            CipherWidth::Aes128 => CIPHER_WIDTH_AES128,
+            CipherWidth::Aes256 => CIPHER_WIDTH_AES256,
+        }
+    }
+
+    fn key_size(self) -> aes::KeySize {
+        match self {
+            CipherWidth::Aes128 => aes::KeySize::KeySize128,
+            CipherWidth::Aes256 => aes::KeySize::KeySize256,
+        }
+    }
+
+    /// The length of keys of this width, in bytes.
+    fn key_len(self) -> usize {
+        match self {
+            CipherWidth::Aes128 => 16,
+            CipherWidth::Aes256 => 32,
+        }
+    }
+}
+
+/// The key with which the content of a single directory is encrypted, as
+/// returned by `encrypt_whole_dir()` and `decrypt_whole_dir()`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SessionKey {
+    width: CipherWidth,
+    key: HashId,
+}
+
+impl SessionKey {
+    fn key(&self) -> &[u8] {
+        &self.key[..self.width.key_len()]
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SessionKey({:?}, <redacted>)", self.width)
+    }
+}
+
+impl Drop for InternalKey {
+    fn drop(&mut self) {
+        wipe_hash(&mut self.0);
+    }
 }
 
 impl fmt::Debug for InternalKey {
@@ -394,6 +663,20 @@ impl fmt::Debug for InternalKey {
     }
 }
 
+/// Overwrites `hash` with zeroes.
+///
+/// This is used for key material which should not linger in memory after it is
+/// no longer needed. Copies made implicitly (e.g., when a `HashId` is returned
+/// by value) are not covered, so this narrows the window rather than closing
+/// it.
+fn wipe_hash(hash: &mut HashId) {
+    for byte in hash.iter_mut() {
+        // Volatile writes so the stores cannot be elided as dead.
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
 fn scrypt_18_14_12_8_1(passphrase: &[u8], salt: &[u8]) -> HashId {
     // Scrypt paper recommends n=2**14, r=8, p=1
     // Slides in http://www.tarsnap.com/scrypt/scrypt-slides.pdf suggest
@@ -442,6 +725,281 @@ fn scrypt_18_14_12_8_1(passphrase: &[u8], salt: &[u8]) -> HashId {
     return derived;
 }
 
+fn argon2id_64m_3_4(passphrase: &[u8], salt: &[u8]) -> Option<HashId> {
+    // As with scrypt, the real parameters make the test suite take forever
+    // in debug builds, so tests implicitly use much weaker ones.
+    #[cfg(not(test))]
+    const M_COST_KIB: u32 = 64 * 1024;
+    #[cfg(test)]
+    const M_COST_KIB: u32 = 256;
+    #[cfg(not(test))]
+    const T_COST: u32 = 3;
+    #[cfg(test)]
+    const T_COST: u32 = 1;
+    const P_COST: u32 = 4;
+
+    let params = argon2::Params::new(M_COST_KIB, T_COST, P_COST, Some(32))
+        .expect("Hardwired Argon2 parameters are invalid");
+    let argon2 = argon2::Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        params,
+    );
+    let mut derived: HashId = Default::default();
+    // This only fails if the salt is too short (less than 8 bytes), which
+    // can only happen with a malformed entry.
+    argon2
+        .hash_password_into(passphrase, salt, &mut derived)
+        .ok()
+        .map(|_| derived)
+}
+
+/// Computes scrypt, evaluating the `p` independent lanes in parallel.
+///
+/// rust-crypto evaluates the lanes one after another, so there a larger `p`
+/// only makes derivation slower. This produces the same output, but spreads the
+/// lanes over up to one thread per CPU. Each thread needs its own
+/// `N * r * 128` bytes of scratch memory, so fewer threads are used if needed
+/// to keep the total within `SCRYPT_MAX_LANE_MEMORY`.
+fn scrypt_parallel(
+    passphrase: &[u8],
+    salt: &[u8],
+    log_n: u8,
+    r: u32,
+    p: u32,
+) -> HashId {
+    let n = 1usize << log_n;
+    let r128 = r as usize * 128;
+
+    let mut mac = Hmac::new(Sha256::new(), passphrase);
+    let mut b = vec![0u8; p as usize * r128];
+    pbkdf2(&mut mac, salt, 1, &mut b);
+
+    let max_threads = SCRYPT_MAX_LANE_MEMORY / (n * r128);
+    let nthreads = cmp::max(
+        1,
+        cmp::min(p as usize, cmp::min(num_cpus::get(), max_threads)),
+    );
+    let mut partitions: Vec<Vec<Vec<u8>>> =
+        (0..nthreads).map(|_| Vec::new()).collect();
+    for (ix, lane) in b.chunks(r128).enumerate() {
+        partitions[ix % nthreads].push(lane.to_vec());
+    }
+
+    let threads: Vec<_> = partitions
+        .into_iter()
+        .map(|mut lanes| {
+            thread::spawn(move || {
+                let mut v = vec![0u8; n * r128];
+                let mut t = vec![0u8; r128];
+                for lane in &mut lanes {
+                    scrypt_ro_mix(lane, &mut v, &mut t, n);
+                }
+                lanes
+            })
+        })
+        .collect();
+    let partitions: Vec<Vec<Vec<u8>>> = threads
+        .into_iter()
+        .map(|t| t.join().expect("scrypt thread panicked"))
+        .collect();
+
+    for (ix, lane) in b.chunks_mut(r128).enumerate() {
+        lane.copy_from_slice(&partitions[ix % nthreads][ix / nthreads]);
+    }
+
+    let mut derived = HashId::default();
+    pbkdf2(&mut mac, &b, 1, &mut derived);
+    derived
+}
+
+/// The Salsa20/8 core, applied in-place to one 64-byte block.
+fn salsa20_8(block: &mut [u8]) {
+    fn quarter(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    }
+
+    let mut input = [0u32; 16];
+    for (word, bytes) in input.iter_mut().zip(block.chunks(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+
+    let mut x = input;
+    for _ in 0..4 {
+        quarter(&mut x, 0, 4, 8, 12);
+        quarter(&mut x, 5, 9, 13, 1);
+        quarter(&mut x, 10, 14, 2, 6);
+        quarter(&mut x, 15, 3, 7, 11);
+        quarter(&mut x, 0, 1, 2, 3);
+        quarter(&mut x, 5, 6, 7, 4);
+        quarter(&mut x, 10, 11, 8, 9);
+        quarter(&mut x, 15, 12, 13, 14);
+    }
+
+    for ((bytes, &x), &input) in
+        block.chunks_mut(4).zip(x.iter()).zip(input.iter())
+    {
+        bytes.copy_from_slice(&x.wrapping_add(input).to_le_bytes());
+    }
+}
+
+/// The scrypt BlockMix operation. `input` and `output` are `r * 128` bytes.
+fn scrypt_block_mix(input: &[u8], output: &mut [u8]) {
+    let half = input.len() / 2;
+    let mut x = [0u8; 64];
+    x.copy_from_slice(&input[input.len() - 64..]);
+
+    for (i, chunk) in input.chunks(64).enumerate() {
+        for (x, &c) in x.iter_mut().zip(chunk) {
+            *x ^= c;
+        }
+        salsa20_8(&mut x);
+
+        let pos = i / 2 * 64 + if 0 == i % 2 { 0 } else { half };
+        output[pos..pos + 64].copy_from_slice(&x);
+    }
+}
+
+/// The scrypt ROMix operation, applied in-place to the lane `b`. `v` and `t`
+/// are scratch space of `n * b.len()` and `b.len()` bytes, respectively.
+fn scrypt_ro_mix(b: &mut [u8], v: &mut [u8], t: &mut [u8], n: usize) {
+    let len = b.len();
+
+    for chunk in v.chunks_mut(len) {
+        chunk.copy_from_slice(b);
+        scrypt_block_mix(chunk, b);
+    }
+
+    for _ in 0..n {
+        let last = &b[len - 64..len - 60];
+        let j = u32::from_le_bytes([last[0], last[1], last[2], last[3]])
+            as usize
+            & (n - 1);
+        for ((t, &b), &v) in t.iter_mut().zip(b.iter()).zip(&v[j * len..]) {
+            *t = b ^ v;
+        }
+        scrypt_block_mix(t, b);
+    }
+}
+
+/// Suffix of `KdfEntry::algorithm` indicating that the derived key is verified
+/// with a SHA3-512 hash.
+const WIDE_HASH_SUFFIX: &'static str = "+sha3-512";
+
+/// Splits `name` into the name of the KDF proper and whether it uses the wide
+/// verification hash.
+fn split_kdf_algorithm(name: &str) -> (&str, bool) {
+    if name.ends_with(WIDE_HASH_SUFFIX) {
+        (&name[..name.len() - WIDE_HASH_SUFFIX.len()], true)
+    } else {
+        (name, false)
+    }
+}
+
+/// A parsed `KdfEntry::algorithm`, excluding any `WIDE_HASH_SUFFIX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KdfAlgorithm {
+    Scrypt18_14_12_8_1,
+    Argon2id64M3_4,
+    /// Scrypt with fixed parameters, named `scrypt-<log_n>-<r>-<p>`.
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+}
+
+/// The smallest `log_n` accepted for a parameterised scrypt algorithm.
+const SCRYPT_MIN_LOG_N: u8 = 10;
+/// The largest `p` accepted for a parameterised scrypt algorithm.
+const SCRYPT_MAX_P: u32 = 16;
+/// The most memory a single scrypt lane (`128 * r * N` bytes) may use. This
+/// also bounds the total across lanes run in parallel.
+const SCRYPT_MAX_LANE_MEMORY: usize = 1 << 30;
+
+impl KdfAlgorithm {
+    fn parse(name: &str) -> Option<Self> {
+        if SCRYPT_18_14_12_8_1 == name {
+            return Some(KdfAlgorithm::Scrypt18_14_12_8_1);
+        }
+        if ARGON2ID_64M_3_4 == name {
+            return Some(KdfAlgorithm::Argon2id64M3_4);
+        }
+
+        let mut parts = name.split('-');
+        if Some("scrypt") != parts.next() {
+            return None;
+        }
+        let log_n: u8 = parts.next()?.parse().ok()?;
+        let r: u32 = parts.next()?.parse().ok()?;
+        let p: u32 = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        // Reject anything `ScryptParams::new()` would panic on, so that bad
+        // algorithm strings (whether from the user or the key store) are just
+        // errors.
+        if 0 == log_n
+            || 0 == r
+            || 0 == p
+            || log_n as usize >= mem::size_of::<usize>() * 8
+            || log_n as u64 >= r as u64 * 16
+            || r as u64 * p as u64 >= 0x40000000
+        {
+            return None;
+        }
+        let r128 = (r as usize).checked_mul(128)?;
+        let lane_memory = r128.checked_mul(1 << log_n)?;
+        r128.checked_mul(p as usize)?;
+
+        // The key store is controlled by the server, so also reject
+        // parameters which are trivially weak or which would have us spend
+        // unreasonable amounts of memory or time.
+        if log_n < SCRYPT_MIN_LOG_N
+            || p > SCRYPT_MAX_P
+            || lane_memory > SCRYPT_MAX_LANE_MEMORY
+        {
+            return None;
+        }
+
+        Some(KdfAlgorithm::Scrypt { log_n, r, p })
+    }
+
+    /// Runs the KDF, returning `None` if it cannot be run with the given
+    /// salt.
+    fn derive(self, passphrase: &[u8], salt: &[u8]) -> Option<HashId> {
+        match self {
+            KdfAlgorithm::Scrypt18_14_12_8_1 => {
+                Some(scrypt_18_14_12_8_1(passphrase, salt))
+            }
+            KdfAlgorithm::Argon2id64M3_4 => argon2id_64m_3_4(passphrase, salt),
+            KdfAlgorithm::Scrypt { log_n, r, p: 1 } => {
+                let sparms = scrypt::ScryptParams::new(log_n, r, 1);
+                let mut derived: HashId = Default::default();
+                scrypt::scrypt(passphrase, salt, &sparms, &mut derived);
+                Some(derived)
+            }
+            KdfAlgorithm::Scrypt { log_n, r, p } => {
+                Some(scrypt_parallel(passphrase, salt, log_n, r, p))
+            }
+        }
+    }
+}
+
+/// Fails with `UnsupportedKdfAlgorithm` if `algorithm` does not name a KDF
+/// algorithm which can be used for `create_key_with_algorithm`.
+pub fn validate_kdf_algorithm(algorithm: &str) -> Result<()> {
+    KdfAlgorithm::parse(split_kdf_algorithm(algorithm).0)
+        .map(|_| ())
+        .ok_or_else(|| {
+            ErrorKind::UnsupportedKdfAlgorithm(algorithm.to_owned()).into()
+        })
+}
+
 fn sha3(data: &[u8]) -> HashId {
     let mut hash = HashId::default();
     let mut kc = tiny_keccak::Keccak::new_sha3_256();
@@ -450,7 +1008,16 @@ fn sha3(data: &[u8]) -> HashId {
     hash
 }
 
-fn hmac(data: &[u8], secret: &[u8]) -> HashId {
+fn sha3_512(data: &[u8]) -> Vec<u8> {
+    let mut hash = vec![0u8; 64];
+    let mut kc = tiny_keccak::Keccak::new_sha3_512();
+    kc.update(data);
+    kc.finalize(&mut hash);
+    hash
+}
+
+/// Computes the SHA-3 HMAC of `data` with the given secret.
+pub fn hmac(data: &[u8], secret: &[u8]) -> HashId {
     let mut hash = HashId::default();
     let mut kc = tiny_keccak::Keccak::new_sha3_256();
     kc.update(data);
@@ -477,17 +1044,71 @@ fn hixor(a: &HashId, b: &HashId) -> HashId {
 ///
 /// The caller must provide the logic for determining the various date-time
 /// fields itself.
+///
+/// `salt_len` gives the length of the random salt in bytes, defaulting to
+/// `DEFAULT_SALT_LEN`. Other lengths are only useful for reproducing keys
+/// from systems which used a different salt length.
 pub fn create_key(
     passphrase: &[u8],
     chain: &mut KeyChain,
     created: DateTime<Utc>,
     updated: Option<DateTime<Utc>>,
+    salt_len: Option<usize>,
 ) -> KdfEntry {
-    let mut salt = HashId::default();
+    create_key_full(
+        passphrase,
+        chain,
+        created,
+        updated,
+        DEFAULT_KDF_ALGORITHM,
+        salt_len.unwrap_or(DEFAULT_SALT_LEN),
+    )
+    .expect("Default KDF algorithm is invalid")
+}
+
+/// Like `create_key`, but uses the KDF algorithm named by `algorithm`.
+///
+/// Fails with `UnsupportedKdfAlgorithm` if `algorithm` is not valid.
+pub fn create_key_with_algorithm(
+    passphrase: &[u8],
+    chain: &mut KeyChain,
+    created: DateTime<Utc>,
+    updated: Option<DateTime<Utc>>,
+    algorithm: &str,
+) -> Result<KdfEntry> {
+    create_key_full(
+        passphrase,
+        chain,
+        created,
+        updated,
+        algorithm,
+        DEFAULT_SALT_LEN,
+    )
+}
+
+/// Like `create_key_with_algorithm`, but with a salt of `salt_len` bytes.
+///
+/// This is used to recreate an existing entry with the same parameters as it
+/// was created with.
+pub fn create_key_full(
+    passphrase: &[u8],
+    chain: &mut KeyChain,
+    created: DateTime<Utc>,
+    updated: Option<DateTime<Utc>>,
+    algorithm: &str,
+    salt_len: usize,
+) -> Result<KdfEntry> {
+    let (kdf_name, wide) = split_kdf_algorithm(algorithm);
+    let kdf = KdfAlgorithm::parse(kdf_name).ok_or_else(|| {
+        ErrorKind::UnsupportedKdfAlgorithm(algorithm.to_owned())
+    })?;
+
+    let mut salt = vec![0u8; salt_len];
     rand(&mut salt);
 
-    let algorithm = SCRYPT_18_14_12_8_1;
-    let derived = scrypt_18_14_12_8_1(passphrase, &salt);
+    let mut derived = kdf.derive(passphrase, &salt).ok_or_else(|| {
+        ErrorKind::UnsupportedKdfAlgorithm(algorithm.to_owned())
+    })?;
 
     chain.derived = InternalKey(derived);
 
@@ -496,12 +1117,16 @@ pub fn create_key(
         updated: updated,
         algorithm: algorithm.to_owned(),
         salt: salt,
-        hash: sha3(&derived),
+        hash: if wide { UNKNOWN_HASH } else { sha3(&derived) },
         groups: BTreeMap::new(),
+        used: None,
+        comment: None,
+        wide_hash: if wide { Some(sha3_512(&derived)) } else { None },
         unknown: UnknownFields::default(),
     };
+    wipe_hash(&mut derived);
     reassoc_keys(&mut entry, chain);
-    entry
+    Ok(entry)
 }
 
 /// Clear the groups on `entry`, then repopulate to match the internal keys on
@@ -521,29 +1146,48 @@ pub fn try_derive_key_single(
     passphrase: &[u8],
     entry: &KdfEntry,
 ) -> Option<KeyChain> {
-    match entry.algorithm.as_str() {
-        SCRYPT_18_14_12_8_1 => {
-            Some(scrypt_18_14_12_8_1(passphrase, &entry.salt))
-        }
-        _ => None,
-    }
-    .and_then(|derived| {
-        if sha3(&derived) == entry.hash {
-            let mut keys = BTreeMap::new();
-            for (name, diff) in &entry.groups {
-                keys.insert(
-                    name.to_owned(),
-                    InternalKey(hixor(&hmac(name.as_bytes(), &derived), diff)),
-                );
-            }
+    KdfAlgorithm::parse(split_kdf_algorithm(&entry.algorithm).0)
+        .and_then(|kdf| kdf.derive(passphrase, &entry.salt))
+        .and_then(|mut derived| {
+            let chain = verify_derived_key(&derived, entry);
+            wipe_hash(&mut derived);
+            chain
+        })
+}
 
-            Some(KeyChain {
-                keys: keys,
-                derived: InternalKey(derived),
-            })
-        } else {
-            None
-        }
+/// Builds the key chain for `entry` from the output of its KDF, or returns
+/// `None` if `derived` does not belong to `entry`.
+fn verify_derived_key(derived: &HashId, entry: &KdfEntry) -> Option<KeyChain> {
+    // Which hash to check is determined by the algorithm rather than by which
+    // fields are present, so that dropping `wide_hash` cannot downgrade the
+    // verification.
+    let verified = if split_kdf_algorithm(&entry.algorithm).1 {
+        entry
+            .wide_hash
+            .as_ref()
+            .map_or(false, |wide| sha3_512(derived) == *wide)
+    } else {
+        sha3(derived) == entry.hash
+    };
+    if !verified {
+        return None;
+    }
+
+    let mut keys = BTreeMap::new();
+    for (name, diff) in &entry.groups {
+        keys.insert(
+            name.to_owned(),
+            InternalKey(hixor(&hmac(name.as_bytes(), derived), diff)),
+        );
+    }
+
+    Some(KeyChain {
+        keys: keys,
+        derived: InternalKey(*derived),
+        dir_ver_fmt: DIR_VER_FMT_LEGACY,
+        hash_variant: HashVariant::default(),
+        cipher_width: CipherWidth::default(),
+        block_secret: None,
     })
 }
 
@@ -560,6 +1204,30 @@ pub fn try_derive_key(
         .next()
 }
 
+/// Like `try_derive_key`, but calls `is_cancelled` before trying each entry,
+/// and fails with `KeyDerivationCancelled` as soon as it returns true. The
+/// name of the matching entry is returned along with the key chain.
+///
+/// Individual derivations cannot be interrupted, so cancellation only takes
+/// effect once the derivation in progress (if any) completes.
+pub fn try_derive_key_cancellable<'a, F: FnMut() -> bool>(
+    passphrase: &[u8],
+    keys: &'a BTreeMap<String, KdfEntry>,
+    mut is_cancelled: F,
+) -> Result<Option<(&'a str, KeyChain)>> {
+    for (name, entry) in keys {
+        if is_cancelled() {
+            return Err(ErrorKind::KeyDerivationCancelled.into());
+        }
+
+        if let Some(chain) = try_derive_key_single(passphrase, entry) {
+            return Ok(Some((name, chain)));
+        }
+    }
+
+    Ok(None)
+}
+
 // Since rust-crypto uses two traits which are identical except for method
 // names, for some reason, which are also incompatible with std::io
 trait Cryptor {
@@ -609,7 +1277,10 @@ impl Cryptor for WDecryptor {
 /// how the data is handled.
 ///
 /// In the case of objects, there are no such attacks, since objects are
-/// validated by feeding the whole thing into an HMAC function.
+/// validated by feeding the whole thing into an HMAC function. Note that this
+/// does not happen here: the object id *is* the HMAC of the (uncompressed)
+/// cleartext, and it is checked by `block_xfer::blocks_to_stream()` once the
+/// object has been decrypted and inflated.
 ///
 /// For directories, we validate each chunk's signature before attempting to
 /// parse it. Even if an attacker knew the chunk boundaries (which could be
@@ -618,21 +1289,45 @@ impl Cryptor for WDecryptor {
 /// forging SHA-3 HMAC. (Also note again that directories do not use PKCS
 /// padding.)
 fn crypt_stream<W: Write, R: Read, C: Cryptor>(
+    dst: W,
+    src: R,
+    crypt: &mut C,
+    panic_on_crypt_err: bool,
+) -> Result<()> {
+    crypt_stream_buffered(
+        dst,
+        src,
+        crypt,
+        panic_on_crypt_err,
+        DEFAULT_CRYPT_BUF_SIZE,
+    )
+}
+
+/// Like `crypt_stream()`, but reads `src` in chunks of `buf_size` bytes.
+///
+/// `buf_size` must be a non-zero multiple of `BLKSZ`; otherwise, this fails
+/// with `InvalidCryptBufferSize` without reading from `src`.
+///
+/// At most `buf_size` bytes of `src` are held at once, however `src` chooses
+/// to split up its reads; each chunk is filled completely before it is passed
+/// to the cryptor, so only the last one can be partial and the cryptor sees
+/// EOF (and applies or strips padding) exactly once.
+fn crypt_stream_buffered<W: Write, R: Read, C: Cryptor>(
     mut dst: W,
     mut src: R,
     crypt: &mut C,
     panic_on_crypt_err: bool,
+    buf_size: usize,
 ) -> Result<()> {
-    let mut src_buf = [0u8; 4096];
-    let mut dst_buf = [0u8; 4112]; // Extra space for final padding block
+    check_crypt_buf_size(buf_size)?;
+
+    let mut src_buf = vec![0u8; buf_size];
+    // Extra space for final padding block
+    let mut dst_buf = vec![0u8; buf_size + BLKSZ];
     let mut eof = false;
     while !eof {
-        let mut nread = 0;
-        while !eof && nread < src_buf.len() {
-            let n = src.read(&mut src_buf[nread..])?;
-            eof |= 0 == n;
-            nread += n;
-        }
+        let nread = read_up_to(&mut src, &mut src_buf)?;
+        eof = nread < src_buf.len();
 
         // Passing src_buf through the cryptor should always result in the
         // entire thing being consumed, as either we have read a multiple of
@@ -663,50 +1358,70 @@ fn crypt_stream<W: Write, R: Read, C: Cryptor>(
     Ok(())
 }
 
-fn split_key_and_iv(key_and_iv: &[u8; 32]) -> ([u8; BLKSZ], [u8; BLKSZ]) {
-    let mut key = [0u8; BLKSZ];
-    key.copy_from_slice(&key_and_iv[0..BLKSZ]);
+/// Fails with `InvalidCryptBufferSize` unless `buf_size` is a non-zero
+/// multiple of `BLKSZ`.
+fn check_crypt_buf_size(buf_size: usize) -> Result<()> {
+    if 0 == buf_size || 0 != buf_size % BLKSZ {
+        return Err(ErrorKind::InvalidCryptBufferSize(buf_size).into());
+    }
+    Ok(())
+}
+
+fn split_key_and_iv(
+    key_and_iv: &[u8],
+    width: CipherWidth,
+) -> (SessionKey, [u8; BLKSZ]) {
+    let key_len = width.key_len();
+    let mut key = SessionKey {
+        width: width,
+        key: HashId::default(),
+    };
+    key.key[..key_len].copy_from_slice(&key_and_iv[..key_len]);
     let mut iv = [0u8; BLKSZ];
-    iv.copy_from_slice(&key_and_iv[BLKSZ..32]);
+    iv.copy_from_slice(&key_and_iv[key_len..key_len + BLKSZ]);
     (key, iv)
 }
 
 /// Generates and writes the CBC encryption prefix to `dst`.
 ///
-/// `master` is the portion of the master key used to encrypt this prefix.
+/// `master` is the portion of the master key used to encrypt this prefix. The
+/// prefix is the session key followed by the IV, so it is 32 bytes long for
+/// `Aes128` and 48 for `Aes256`.
 fn write_cbc_prefix<W: Write>(
     dst: W,
     master: &[u8],
-) -> Result<([u8; BLKSZ], [u8; BLKSZ])> {
-    let mut key_and_iv = [0u8; 32];
+    width: CipherWidth,
+) -> Result<(SessionKey, [u8; BLKSZ])> {
+    let mut key_and_iv = vec![0u8; width.key_len() + BLKSZ];
     rand(&mut key_and_iv);
 
     let mut cryptor = WEncryptor(aes::cbc_encryptor(
-        aes::KeySize::KeySize128,
+        width.key_size(),
         master,
         &[0u8; BLKSZ],
         blockmodes::NoPadding,
     ));
     crypt_stream(dst, &mut &key_and_iv[..], &mut cryptor, true)?;
 
-    Ok(split_key_and_iv(&key_and_iv))
+    Ok(split_key_and_iv(&key_and_iv, width))
 }
 
 /// Reads out the data written by `write_cbc_prefix()`.
 fn read_cbc_prefix<R: Read>(
     mut src: R,
     master: &[u8],
-) -> Result<([u8; BLKSZ], [u8; BLKSZ])> {
-    let mut cipher_head = [0u8; 32];
+    width: CipherWidth,
+) -> Result<(SessionKey, [u8; BLKSZ])> {
+    let mut cipher_head = vec![0u8; width.key_len() + BLKSZ];
     src.read_exact(&mut cipher_head)?;
     let mut cryptor = WDecryptor(aes::cbc_decryptor(
-        aes::KeySize::KeySize128,
+        width.key_size(),
         master,
         &[0u8; BLKSZ],
         blockmodes::NoPadding,
     ));
 
-    let mut key_and_iv = [0u8; 32];
+    let mut key_and_iv = vec![0u8; cipher_head.len()];
     crypt_stream(
         &mut &mut key_and_iv[..],
         &mut &cipher_head[..],
@@ -714,49 +1429,123 @@ fn read_cbc_prefix<R: Read>(
         false,
     )?;
 
-    Ok(split_key_and_iv(&key_and_iv))
+    Ok(split_key_and_iv(&key_and_iv, width))
+}
+
+/// Returns the key and IV for encrypting the object with the given id.
+///
+/// For `Aes128`, the id is simply split into the key and IV. For `Aes256`, the
+/// whole id is the key, and the IV is derived from it.
+fn obj_key_and_iv(id: &HashId, width: CipherWidth) -> (HashId, [u8; BLKSZ]) {
+    let mut key = HashId::default();
+    let mut iv = [0u8; BLKSZ];
+    match width {
+        CipherWidth::Aes128 => {
+            key[..BLKSZ].copy_from_slice(&id[..BLKSZ]);
+            iv.copy_from_slice(&id[BLKSZ..]);
+        }
+        CipherWidth::Aes256 => {
+            key = *id;
+            iv.copy_from_slice(&hmac(id, b"ensync-obj-iv")[..BLKSZ]);
+        }
+    }
+    (key, iv)
+}
+
+/// Reads from `src` until `buf` is full or EOF is reached, returning the
+/// number of bytes read.
+fn read_up_to<R: Read>(mut src: R, buf: &mut [u8]) -> Result<usize> {
+    let mut nread = 0;
+    while nread < buf.len() {
+        match src.read(&mut buf[nread..]) {
+            Ok(0) => break,
+            Ok(n) => nread += n,
+            Err(ref e) if io::ErrorKind::Interrupted == e.kind() => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(nread)
 }
 
 /// Encrypts the object data in `src` using the key from the object's id,
 /// writing the encrypted result to `dst`.
-pub fn encrypt_obj<W: Write, R: Read>(
+///
+/// `width` selects the AES key size; objects stored before wider keys existed
+/// use `CipherWidth::default()`.
+pub fn encrypt_obj_with<W: Write, R: Read>(
+    width: CipherWidth,
     dst: W,
     src: R,
     id: &HashId,
 ) -> Result<()> {
-    let mut key = [0u8; BLKSZ];
-    let mut iv = [0u8; BLKSZ];
-    key.copy_from_slice(&id[..BLKSZ]);
-    iv.copy_from_slice(&id[BLKSZ..]);
+    encrypt_obj_buffered_with(width, dst, src, id, DEFAULT_CRYPT_BUF_SIZE)
+}
 
+/// Like `encrypt_obj_with()`, but processes `src` in chunks of `buf_size`
+/// bytes.
+///
+/// Larger buffers reduce the number of read and write calls made for large
+/// objects. The output is identical regardless of buffer size. `buf_size`
+/// must be a non-zero multiple of `BLKSZ`, or this fails with
+/// `InvalidCryptBufferSize`.
+pub fn encrypt_obj_buffered_with<W: Write, R: Read>(
+    width: CipherWidth,
+    dst: W,
+    src: R,
+    id: &HashId,
+    buf_size: usize,
+) -> Result<()> {
+    check_crypt_buf_size(buf_size)?;
+    let (key, iv) = obj_key_and_iv(id, width);
     let mut cryptor = WEncryptor(aes::cbc_encryptor(
-        aes::KeySize::KeySize128,
-        &key,
+        width.key_size(),
+        &key[..width.key_len()],
         &iv,
         blockmodes::PkcsPadding,
     ));
-    crypt_stream(dst, src, &mut cryptor, true)?;
+    crypt_stream_buffered(dst, src, &mut cryptor, true, buf_size)?;
     Ok(())
 }
 
-/// Reverses `encrypt_obj()`.
-pub fn decrypt_obj<W: Write, R: Read>(
+/// Returns the length of the ciphertext `encrypt_obj_with()` produces for
+/// `len` bytes of cleartext.
+///
+/// PKCS padding always adds between 1 and `BLKSZ` bytes.
+pub fn encrypted_obj_len(len: u64) -> u64 {
+    (len / BLKSZ as u64 + 1) * BLKSZ as u64
+}
+
+/// Reverses `encrypt_obj_with()`, using the same `width`.
+pub fn decrypt_obj_with<W: Write, R: Read>(
+    width: CipherWidth,
     dst: W,
     src: R,
     id: &HashId,
 ) -> Result<()> {
-    let mut key = [0u8; BLKSZ];
-    let mut iv = [0u8; BLKSZ];
-    key.copy_from_slice(&id[..BLKSZ]);
-    iv.copy_from_slice(&id[BLKSZ..]);
+    decrypt_obj_buffered_with(width, dst, src, id, DEFAULT_CRYPT_BUF_SIZE)
+}
 
+/// Like `decrypt_obj_with()`, but processes `src` in chunks of `buf_size`
+/// bytes.
+///
+/// `buf_size` must be a non-zero multiple of `BLKSZ`. It need not match the
+/// size used to encrypt the object.
+pub fn decrypt_obj_buffered_with<W: Write, R: Read>(
+    width: CipherWidth,
+    dst: W,
+    src: R,
+    id: &HashId,
+    buf_size: usize,
+) -> Result<()> {
+    check_crypt_buf_size(buf_size)?;
+    let (key, iv) = obj_key_and_iv(id, width);
     let mut cryptor = WDecryptor(aes::cbc_decryptor(
-        aes::KeySize::KeySize128,
-        &key,
+        width.key_size(),
+        &key[..width.key_len()],
         &iv,
         blockmodes::PkcsPadding,
     ));
-    crypt_stream(dst, src, &mut cryptor, false)?;
+    crypt_stream_buffered(dst, src, &mut cryptor, false, buf_size)?;
     Ok(())
 }
 
@@ -774,15 +1563,27 @@ pub fn xform_obj_id(id: &HashId) -> HashId {
 ///
 /// `src` must produce data which is a multiple of BLKSZ bytes long.
 pub fn encrypt_whole_dir<W: Write, R: Read>(
+    dst: W,
+    src: R,
+    key: &InternalKey,
+) -> Result<SessionKey> {
+    encrypt_whole_dir_with(CipherWidth::default(), dst, src, key)
+}
+
+/// Like `encrypt_whole_dir()`, but with an explicit cipher width.
+pub fn encrypt_whole_dir_with<W: Write, R: Read>(
+    width: CipherWidth,
     mut dst: W,
     src: R,
     key: &InternalKey,
-) -> Result<[u8; BLKSZ]> {
-    let (key, iv) = write_cbc_prefix(&mut dst, key.dir_key())?;
+) -> Result<SessionKey> {
+    let master = key.dir_key_for(width);
+    let (key, iv) =
+        write_cbc_prefix(&mut dst, &master[..width.key_len()], width)?;
 
     let mut cryptor = WEncryptor(aes::cbc_encryptor(
-        aes::KeySize::KeySize128,
-        &key,
+        width.key_size(),
+        key.key(),
         &iv,
         blockmodes::NoPadding,
     ));
@@ -797,12 +1598,12 @@ pub fn encrypt_whole_dir<W: Write, R: Read>(
 pub fn encrypt_append_dir<W: Write, R: Read>(
     dst: W,
     src: R,
-    key: &[u8; BLKSZ],
+    key: &SessionKey,
     iv: &[u8; BLKSZ],
 ) -> Result<()> {
     let mut cryptor = WEncryptor(aes::cbc_encryptor(
-        aes::KeySize::KeySize128,
-        key,
+        key.width.key_size(),
+        key.key(),
         iv,
         blockmodes::NoPadding,
     ));
@@ -813,15 +1614,27 @@ pub fn encrypt_append_dir<W: Write, R: Read>(
 /// Inverts `encrypt_whole_dir()` and any subsequent calls to
 /// `encrypt_append_dir()`.
 pub fn decrypt_whole_dir<W: Write, R: Read>(
+    dst: W,
+    src: R,
+    key: &InternalKey,
+) -> Result<SessionKey> {
+    decrypt_whole_dir_with(CipherWidth::default(), dst, src, key)
+}
+
+/// Like `decrypt_whole_dir()`, but with an explicit cipher width.
+pub fn decrypt_whole_dir_with<W: Write, R: Read>(
+    width: CipherWidth,
     dst: W,
     mut src: R,
     key: &InternalKey,
-) -> Result<[u8; BLKSZ]> {
-    let (key, iv) = read_cbc_prefix(&mut src, key.dir_key())?;
+) -> Result<SessionKey> {
+    let master = key.dir_key_for(width);
+    let (key, iv) =
+        read_cbc_prefix(&mut src, &master[..width.key_len()], width)?;
 
     let mut cryptor = WDecryptor(aes::cbc_decryptor(
-        aes::KeySize::KeySize128,
-        &key,
+        width.key_size(),
+        key.key(),
         &iv,
         blockmodes::NoPadding,
     ));
@@ -829,6 +1642,22 @@ pub fn decrypt_whole_dir<W: Write, R: Read>(
     Ok(key)
 }
 
+/// Returns the session key of a directory encrypted by `encrypt_whole_dir()`
+/// given only a prefix of its ciphertext.
+///
+/// `prefix` must be at least the first `DIR_PREFIX_LEN` bytes of the
+/// directory.
+pub fn dir_session_key(prefix: &[u8], key: &InternalKey) -> Result<SessionKey> {
+    let width = CipherWidth::default();
+    let master = key.dir_key_for(width);
+    let (key, _) = read_cbc_prefix(prefix, &master[..width.key_len()], width)?;
+    Ok(key)
+}
+
+/// The length of the prefix of a directory encrypted by `encrypt_whole_dir()`
+/// which holds its session key.
+pub const DIR_PREFIX_LEN: usize = 2 * BLKSZ;
+
 /// Given a suffix of the full ciphertext content of a directory `data`, return
 /// the IV to pass to `encrypt_append_dir` to append more data to that
 /// directory.
@@ -846,22 +1675,56 @@ fn dir_ver_iv(dir: &HashId) -> [u8; BLKSZ] {
     iv
 }
 
+/// Directory version format where the version is encrypted with zero
+/// padding. Stores created before `DIR_VER_FMT_MAC` use this format.
+pub const DIR_VER_FMT_LEGACY: u32 = 0;
+/// Directory version format where the padding of the version is instead a MAC
+/// binding the version to the directory id.
+pub const DIR_VER_FMT_MAC: u32 = 1;
+
+/// Returns whether `fmt` is a directory version format this version of
+/// Ensync understands.
+pub fn is_supported_dir_ver_fmt(fmt: u32) -> bool {
+    DIR_VER_FMT_LEGACY == fmt || DIR_VER_FMT_MAC == fmt
+}
+
+/// Computes the 24 bytes following the 8-byte version in the cleartext of an
+/// encrypted directory version.
+fn dir_ver_padding(
+    dir: &HashId,
+    ver: &[u8],
+    key: &InternalKey,
+    fmt: u32,
+) -> [u8; 24] {
+    let mut padding = [0u8; 24];
+    if DIR_VER_FMT_MAC == fmt {
+        let mut data = dir.to_vec();
+        data.extend_from_slice(ver);
+        padding.copy_from_slice(&hmac(&data, key.hmac_secret())[..24]);
+    }
+    padding
+}
+
 /// Encrypts the version of the given directory.
-pub fn encrypt_dir_ver(dir: &HashId, mut ver: u64, key: &KeyChain) -> HashId {
+///
+/// The encoding used is determined by `key.dir_ver_fmt`.
+pub fn encrypt_dir_ver(dir: &HashId, ver: u64, key: &KeyChain) -> HashId {
+    let fmt = key.dir_ver_fmt;
+    let width = key.cipher_width;
     let key = key
         .key(GROUP_EVERYONE)
         .expect("Key chain does not have `everyone` group");
 
     let mut cleartext = HashId::default();
-    for ix in 0..8 {
-        cleartext[ix] = (ver & 0xFF) as u8;
-        ver >>= 8;
-    }
+    cleartext[..8].copy_from_slice(&pack_dir_ver(ver));
+    let padding = dir_ver_padding(dir, &cleartext[..8], key, fmt);
+    cleartext[8..].copy_from_slice(&padding);
 
     let mut res = HashId::default();
+    let master = key.dir_key_for(width);
     let mut cryptor = WEncryptor(aes::cbc_encryptor(
-        aes::KeySize::KeySize128,
-        key.dir_key(),
+        width.key_size(),
+        &master[..width.key_len()],
         &dir_ver_iv(dir),
         blockmodes::NoPadding,
     ));
@@ -873,21 +1736,27 @@ pub fn encrypt_dir_ver(dir: &HashId, mut ver: u64, key: &KeyChain) -> HashId {
 
 /// Inverts `encrypt_dir_ver()`.
 ///
-/// If `ciphertext` is invalid, 0 is silently returned instead.
+/// Returns `None` if `ciphertext` is invalid, including if it is not in the
+/// format given by `key.dir_ver_fmt` or was encrypted for a different
+/// directory. Since the server cannot produce a valid ciphertext without the
+/// key, this indicates corruption or tampering rather than an old version.
 pub fn decrypt_dir_ver(
     dir: &HashId,
     ciphertext: &HashId,
     key: &KeyChain,
-) -> u64 {
+) -> Option<u64> {
+    let fmt = key.dir_ver_fmt;
+    let width = key.cipher_width;
     let key = key
         .key(GROUP_EVERYONE)
         .expect("Key chain does not have `everyone` group");
 
     // Initialise to something that we'd reject below
     let mut cleartext = [255u8; 32];
+    let master = key.dir_key_for(width);
     let mut cryptor = WDecryptor(aes::cbc_decryptor(
-        aes::KeySize::KeySize128,
-        key.dir_key(),
+        width.key_size(),
+        &master[..width.key_len()],
         &dir_ver_iv(dir),
         blockmodes::NoPadding,
     ));
@@ -896,20 +1765,24 @@ pub fn decrypt_dir_ver(
     let _ =
         crypt_stream(&mut cleartext[..], &ciphertext[..], &mut cryptor, false);
 
-    // If the padding is invalid, silently return 0 so it gets rejected the
-    // same way as simply receding the version.
-    for &padding in &cleartext[8..] {
-        if 0 != padding {
-            return 0;
-        }
+    if dir_ver_padding(dir, &cleartext[..8], key, fmt)[..] != cleartext[8..] {
+        return None;
     }
 
-    let mut ver = 0u64;
-    for ix in 0..8 {
-        ver |= (cleartext[ix] as u64) << (ix * 8);
-    }
+    Some(unpack_dir_ver(&cleartext[..8]))
+}
 
-    ver
+/// Encodes a directory version as the first 8 bytes of the cleartext of an
+/// encrypted directory version, which are little-endian.
+fn pack_dir_ver(ver: u64) -> [u8; 8] {
+    ver.to_le_bytes()
+}
+
+/// Inverts `pack_dir_ver()`. `packed` must be exactly 8 bytes long.
+fn unpack_dir_ver(packed: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(packed);
+    u64::from_le_bytes(bytes)
 }
 
 /// Returns the secret version corresponding to the encrypted version `v`.
@@ -929,7 +1802,7 @@ mod test {
     use super::*;
 
     fn ck(passphrase: &[u8], keychain: &mut KeyChain) -> KdfEntry {
-        create_key(passphrase, keychain, Utc::now(), None)
+        create_key(passphrase, keychain, Utc::now(), None, None)
     }
 
     #[test]
@@ -950,6 +1823,118 @@ mod test {
         assert_eq!(None, try_derive_key(b"foo", &keys));
     }
 
+    #[test]
+    fn derive_key_can_be_cancelled_between_entries() {
+        let mut keychain = KeyChain::generate_new();
+        let mut keys = BTreeMap::new();
+        keys.insert("a".to_owned(), ck(b"plugh", &mut keychain));
+        keys.insert("b".to_owned(), ck(b"xyzzy", &mut keychain));
+
+        let mut checks = 0;
+        let result = try_derive_key_cancellable(b"xyzzy", &keys, || {
+            checks += 1;
+            checks > 1
+        });
+        match result {
+            Err(Error(ErrorKind::KeyDerivationCancelled, _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Cancelled after trying "a", before getting to "b"
+        assert_eq!(2, checks);
+
+        assert_eq!(
+            Some(("b", &keychain.keys)),
+            try_derive_key_cancellable(b"xyzzy", &keys, || false)
+                .unwrap()
+                .as_ref()
+                .map(|&(name, ref c)| (name, &c.keys))
+        );
+        assert!(try_derive_key_cancellable(b"foo", &keys, || false)
+            .unwrap()
+            .is_none());
+    }
+
+    /// `KdfEntry` as it was before salts could have any length.
+    struct BaselineKdfEntry {
+        created: DateTime<Utc>,
+        updated: Option<DateTime<Utc>>,
+        algorithm: String,
+        salt: HashId,
+        hash: HashId,
+        groups: BTreeMap<String, HashId>,
+        unknown: UnknownFields<'static>,
+    }
+
+    fourleaf_retrofit!(struct BaselineKdfEntry : {} {} {
+        |_context, this|
+        [1] created: SerDt = SerDt(this.created),
+        [2] updated: Option<SerDt> = this.updated.map(SerDt),
+        [3] algorithm: String = &this.algorithm,
+        [4] salt: HashId = this.salt,
+        [5] hash: HashId = this.hash,
+        [6] groups: BTreeMap<String, HashId> = &this.groups,
+        (?) unknown: Copied<UnknownFields<'static>> = &this.unknown,
+        { Ok(BaselineKdfEntry { created: created.0,
+                                updated: updated.map(|v| v.0),
+                                algorithm: algorithm, salt: salt,
+                                hash: hash, groups: groups,
+                                unknown: unknown.0 }) }
+    });
+
+    #[test]
+    fn baseline_kdf_entry_decodes_and_derives() {
+        let mut keychain = KeyChain::generate_new();
+        let entry = ck(b"plugh", &mut keychain);
+        let mut salt = HashId::default();
+        salt.copy_from_slice(&entry.salt);
+        let baseline = BaselineKdfEntry {
+            created: entry.created,
+            updated: entry.updated,
+            algorithm: entry.algorithm.clone(),
+            salt: salt,
+            hash: entry.hash,
+            groups: entry.groups.clone(),
+            unknown: Default::default(),
+        };
+
+        let data = fourleaf::to_vec(&baseline).unwrap();
+        let decoded: KdfEntry =
+            fourleaf::from_slice_copy(&data, &fourleaf::DeConfig::default())
+                .unwrap();
+        assert_eq!(&salt[..], &decoded.salt[..]);
+        assert_eq!(
+            Some(&keychain.keys),
+            try_derive_key_single(b"plugh", &decoded)
+                .as_ref()
+                .map(|c| &c.keys)
+        );
+
+        // Entries with the default salt length can still be read by older
+        // versions too.
+        let data = fourleaf::to_vec(&decoded).unwrap();
+        let old: BaselineKdfEntry =
+            fourleaf::from_slice_copy(&data, &fourleaf::DeConfig::default())
+                .unwrap();
+        assert_eq!(salt, old.salt);
+    }
+
+    #[test]
+    fn generate_and_derive_key_short_salt() {
+        let mut keychain = KeyChain::generate_new();
+        let entry =
+            create_key(b"plugh", &mut keychain, Utc::now(), None, Some(16));
+        assert_eq!(16, entry.salt.len());
+        assert_eq!(DEFAULT_SALT_LEN, ck(b"xyzzy", &mut keychain).salt.len());
+
+        assert_eq!(
+            Some(&keychain.keys),
+            try_derive_key_single(b"plugh", &entry)
+                .as_ref()
+                .map(|c| &c.keys)
+        );
+        assert_eq!(None, try_derive_key_single(b"xyzzy", &entry));
+    }
+
     #[test]
     fn generate_and_derive_keys_long_passphrase() {
         let pw_a = [b'a'; 1024];
@@ -971,6 +1956,64 @@ mod test {
         );
         assert_eq!(None, try_derive_key(&pw_c, &keys));
     }
+
+    #[test]
+    fn argon2id_and_scrypt_keys_coexist() {
+        let mut keychain = KeyChain::generate_new();
+        let mut keys = BTreeMap::new();
+        keys.insert("a".to_owned(), ck(b"plugh", &mut keychain));
+        keys.insert(
+            "b".to_owned(),
+            create_key_with_algorithm(
+                b"xyzzy",
+                &mut keychain,
+                Utc::now(),
+                None,
+                ARGON2ID_64M_3_4,
+            )
+            .unwrap(),
+        );
+        assert_eq!(ARGON2ID_64M_3_4, keys["b"].algorithm);
+
+        assert_eq!(
+            Some(&keychain.keys),
+            try_derive_key(b"plugh", &keys).as_ref().map(|c| &c.keys)
+        );
+        assert_eq!(
+            Some(&keychain.keys),
+            try_derive_key(b"xyzzy", &keys).as_ref().map(|c| &c.keys)
+        );
+        assert_eq!(None, try_derive_key(b"foo", &keys));
+        assert_eq!(None, try_derive_key_single(b"plugh", &keys["b"]));
+
+        // Argon2 cannot run with such a short salt; this must not panic.
+        let mut short_salt = keys["b"].clone();
+        short_salt.salt.truncate(4);
+        assert_eq!(None, try_derive_key_single(b"xyzzy", &short_salt));
+    }
+
+    #[test]
+    fn generate_and_derive_parallel_scrypt_key() {
+        let mut keychain = KeyChain::generate_new();
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            "a".to_owned(),
+            create_key_with_algorithm(
+                b"plugh",
+                &mut keychain,
+                Utc::now(),
+                None,
+                "scrypt-14-8-4",
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(
+            Some(&keychain.keys),
+            try_derive_key(b"plugh", &keys).as_ref().map(|c| &c.keys)
+        );
+        assert_eq!(None, try_derive_key(b"xyzzy", &keys));
+    }
 }
 
 // Separate module so only the fast tess can be run when so desired
@@ -981,19 +2024,237 @@ mod fast_test {
     use super::hmac;
     use super::*;
 
+    #[test]
+    fn internal_key_wiped_on_drop() {
+        let mut key = mem::ManuallyDrop::new(InternalKey([42u8; 32]));
+        unsafe { ptr::drop_in_place(&mut *key) };
+        assert_eq!([0u8; 32], key.0);
+
+        let mut derived = [42u8; 32];
+        wipe_hash(&mut derived);
+        assert_eq!([0u8; 32], derived);
+    }
+
+    #[test]
+    fn verify_derived_key_checks_hash() {
+        let mut keychain = KeyChain::generate_new();
+        let entry = create_key_with_algorithm(
+            b"plugh",
+            &mut keychain,
+            Utc::now(),
+            None,
+            "scrypt-10-8-1",
+        )
+        .unwrap();
+
+        let chain = verify_derived_key(&keychain.derived.0, &entry).unwrap();
+        assert_eq!(keychain.keys, chain.keys);
+        assert_eq!(keychain.derived, chain.derived);
+
+        let mut wrong = keychain.derived.0;
+        wrong[0] ^= 1;
+        assert!(verify_derived_key(&wrong, &entry).is_none());
+    }
+
+    #[test]
+    fn derive_key_with_scrypt_cost() {
+        let mut keychain = KeyChain::generate_new();
+        let entry = create_key_with_algorithm(
+            b"plugh",
+            &mut keychain,
+            Utc::now(),
+            None,
+            "scrypt-10-8-2",
+        )
+        .unwrap();
+        assert_eq!("scrypt-10-8-2", entry.algorithm);
+        assert_eq!(
+            keychain.keys,
+            try_derive_key_single(b"plugh", &entry).unwrap().keys
+        );
+
+        assert!(create_key_with_algorithm(
+            b"plugh",
+            &mut keychain,
+            Utc::now(),
+            None,
+            "scrypt-0-8-1",
+        )
+        .is_err());
+
+        // Corrupt or unknown algorithms are skipped rather than panicking
+        for algorithm in &["scrypt-10-8", "scrypt-x-8-1", "scrypt-64-1-1", ""] {
+            let mut bad = entry.clone();
+            bad.algorithm = algorithm.to_string();
+            assert_eq!(None, try_derive_key_single(b"plugh", &bad));
+        }
+    }
+
+    #[test]
+    fn derive_key_with_wide_verification_hash() {
+        let mut keychain = KeyChain::generate_new();
+        let entry = create_key_with_algorithm(
+            b"plugh",
+            &mut keychain,
+            Utc::now(),
+            None,
+            "scrypt-10-8-1+sha3-512",
+        )
+        .unwrap();
+        assert_eq!(UNKNOWN_HASH, entry.hash);
+        assert_eq!(64, entry.wide_hash.as_ref().unwrap().len());
+
+        assert_eq!(
+            keychain.keys,
+            try_derive_key_single(b"plugh", &entry).unwrap().keys
+        );
+        assert_eq!(None, try_derive_key_single(b"xyzzy", &entry));
+
+        // The wide hash cannot be stripped to fall back to `hash`.
+        let mut stripped = entry.clone();
+        stripped.wide_hash = None;
+        assert_eq!(None, try_derive_key_single(b"plugh", &stripped));
+
+        assert!(validate_kdf_algorithm("scrypt-10-8-1+sha3-512").is_ok());
+        assert!(validate_kdf_algorithm("scrypt-10-8-1+sha3-384").is_err());
+    }
+
     fn test_crypt_obj(data: &[u8]) {
         let keychain = KeyChain::generate_new();
         let id = hmac(data, keychain.obj_hmac_secret().unwrap());
 
         let mut ciphertext = Vec::new();
-        encrypt_obj(&mut ciphertext, data, &id).unwrap();
+        encrypt_obj_with(CipherWidth::default(), &mut ciphertext, data, &id)
+            .unwrap();
+        assert_eq!(
+            encrypted_obj_len(data.len() as u64),
+            ciphertext.len() as u64
+        );
 
         let mut cleartext = Vec::new();
-        decrypt_obj(&mut cleartext, &ciphertext[..], &id).unwrap();
+        decrypt_obj_with(
+            CipherWidth::default(),
+            &mut cleartext,
+            &ciphertext[..],
+            &id,
+        )
+        .unwrap();
 
         assert_eq!(data, &cleartext[..]);
     }
 
+    #[test]
+    fn parallel_scrypt_matches_reference() {
+        for &(log_n, r, p) in &[(4, 1, 2), (4, 2, 3), (6, 4, 5)] {
+            let mut expected = HashId::default();
+            scrypt::scrypt(
+                b"passphrase",
+                b"salt",
+                &scrypt::ScryptParams::new(log_n, r, p),
+                &mut expected,
+            );
+            assert_eq!(
+                expected,
+                scrypt_parallel(b"passphrase", b"salt", log_n, r, p),
+                "Mismatch for log_n={}, r={}, p={}",
+                log_n,
+                r,
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn parallel_scrypt_rfc7914_vectors() {
+        // RFC 7914 section 12. The RFC gives 64 bytes of output, but PBKDF2
+        // output is a prefix of any longer output, so the first 32 suffice.
+        let vectors: &[(&[u8], &[u8], u8, u32, u32, HashId)] = &[
+            (
+                b"",
+                b"",
+                4,
+                1,
+                1,
+                [
+                    0x77, 0xd6, 0x57, 0x62, 0x38, 0x65, 0x7b, 0x20, 0x3b, 0x19,
+                    0xca, 0x42, 0xc1, 0x8a, 0x04, 0x97, 0xf1, 0x6b, 0x48, 0x44,
+                    0xe3, 0x07, 0x4a, 0xe8, 0xdf, 0xdf, 0xfa, 0x3f, 0xed, 0xe2,
+                    0x14, 0x42,
+                ],
+            ),
+            (
+                b"password",
+                b"NaCl",
+                10,
+                8,
+                16,
+                [
+                    0xfd, 0xba, 0xbe, 0x1c, 0x9d, 0x34, 0x72, 0x00, 0x78, 0x56,
+                    0xe7, 0x19, 0x0d, 0x01, 0xe9, 0xfe, 0x7c, 0x6a, 0xd7, 0xcb,
+                    0xc8, 0x23, 0x78, 0x30, 0xe7, 0x73, 0x76, 0x63, 0x4b, 0x37,
+                    0x31, 0x62,
+                ],
+            ),
+            (
+                b"pleaseletmein",
+                b"SodiumChloride",
+                14,
+                8,
+                1,
+                [
+                    0x70, 0x23, 0xbd, 0xcb, 0x3a, 0xfd, 0x73, 0x48, 0x46, 0x1c,
+                    0x06, 0xcd, 0x81, 0xfd, 0x38, 0xeb, 0xfd, 0xa8, 0xfb, 0xba,
+                    0x90, 0x4f, 0x8e, 0x3e, 0xa9, 0xb5, 0x43, 0xf6, 0x54, 0x5d,
+                    0xa1, 0xf2,
+                ],
+            ),
+        ];
+
+        for &(passphrase, salt, log_n, r, p, expected) in vectors {
+            assert_eq!(
+                expected,
+                scrypt_parallel(passphrase, salt, log_n, r, p),
+                "Mismatch for log_n={}, r={}, p={}",
+                log_n,
+                r,
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn kdf_algorithm_parsing() {
+        assert_eq!(
+            Some(KdfAlgorithm::Scrypt18_14_12_8_1),
+            KdfAlgorithm::parse("scrypt-18/14/12-8-1")
+        );
+        assert_eq!(
+            Some(KdfAlgorithm::Scrypt {
+                log_n: 10,
+                r: 4,
+                p: 2
+            }),
+            KdfAlgorithm::parse("scrypt-10-4-2")
+        );
+        assert_eq!(None, KdfAlgorithm::parse("scrypt-10-4"));
+        assert_eq!(None, KdfAlgorithm::parse("scrypt-10-4-2-1"));
+        assert_eq!(None, KdfAlgorithm::parse("scrypt-0-8-1"));
+        assert_eq!(None, KdfAlgorithm::parse("scrypt-16-1-1"));
+        assert_eq!(None, KdfAlgorithm::parse("scrypt-10-8-0"));
+        // Out of bounds
+        assert_eq!(None, KdfAlgorithm::parse("scrypt-9-8-1"));
+        assert_eq!(None, KdfAlgorithm::parse("scrypt-10-8-17"));
+        assert_eq!(None, KdfAlgorithm::parse("scrypt-24-128-1"));
+        assert!(KdfAlgorithm::parse("scrypt-20-8-16").is_some());
+        assert_eq!(
+            Some(KdfAlgorithm::Argon2id64M3_4),
+            KdfAlgorithm::parse("argon2id-64m-3-4")
+        );
+        assert_eq!(None, KdfAlgorithm::parse("argon2id-64m-3-5"));
+        assert_eq!(None, KdfAlgorithm::parse("bcrypt-10-8-1"));
+        assert!(validate_kdf_algorithm("plugh").is_err());
+    }
+
     #[test]
     fn crypt_obj_empty() {
         test_crypt_obj(&[]);
@@ -1035,6 +2296,170 @@ mod fast_test {
         test_crypt_obj(&data);
     }
 
+    #[test]
+    fn crypt_obj_is_convergent() {
+        let keychain = KeyChain::generate_new();
+        let data = b"the same content twice";
+        let id = hmac(data, keychain.obj_hmac_secret().unwrap());
+
+        let mut a = Vec::new();
+        encrypt_obj_with(CipherWidth::default(), &mut a, &data[..], &id)
+            .unwrap();
+        let mut b = Vec::new();
+        encrypt_obj_with(CipherWidth::default(), &mut b, &data[..], &id)
+            .unwrap();
+        assert_eq!(a, b);
+
+        let other = KeyChain::generate_new();
+        let other_id = hmac(data, other.obj_hmac_secret().unwrap());
+        let mut c = Vec::new();
+        encrypt_obj_with(CipherWidth::default(), &mut c, &data[..], &other_id)
+            .unwrap();
+        assert!(a != c);
+    }
+
+    #[test]
+    fn crypt_obj_large_buffer() {
+        let keychain = KeyChain::generate_new();
+        let mut data = vec![0u8; 3 * 1024 * 1024 + 7];
+        rand(&mut data);
+        let id = hmac(&data, keychain.obj_hmac_secret().unwrap());
+
+        let mut ciphertext = Vec::new();
+        encrypt_obj_buffered_with(
+            CipherWidth::default(),
+            &mut ciphertext,
+            &data[..],
+            &id,
+            65536,
+        )
+        .unwrap();
+
+        let mut default_ciphertext = Vec::new();
+        encrypt_obj_with(
+            CipherWidth::default(),
+            &mut default_ciphertext,
+            &data[..],
+            &id,
+        )
+        .unwrap();
+        assert_eq!(default_ciphertext, ciphertext);
+
+        let mut cleartext = Vec::new();
+        decrypt_obj_buffered_with(
+            CipherWidth::default(),
+            &mut cleartext,
+            &ciphertext[..],
+            &id,
+            1 << 20,
+        )
+        .unwrap();
+        assert_eq!(data, cleartext);
+
+        let mut cleartext = Vec::new();
+        decrypt_obj_with(
+            CipherWidth::default(),
+            &mut cleartext,
+            &ciphertext[..],
+            &id,
+        )
+        .unwrap();
+        assert_eq!(data, cleartext);
+    }
+
+    /// Reader which returns at most one byte per call, and fails with
+    /// `Interrupted` before every byte.
+    struct TrickleReader<'a> {
+        data: &'a [u8],
+        interrupt: bool,
+    }
+
+    impl<'a> Read for TrickleReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "EINTR",
+                ));
+            }
+
+            let n = cmp::min(1, cmp::min(buf.len(), self.data.len()));
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn trickle(data: &[u8]) -> TrickleReader {
+        TrickleReader {
+            data: data,
+            interrupt: false,
+        }
+    }
+
+    #[test]
+    fn crypt_obj_tolerates_one_byte_reads() {
+        let keychain = KeyChain::generate_new();
+        let mut data = vec![0u8; 4 * BLKSZ + 1];
+        rand(&mut data);
+
+        // Cover lengths on either side of the cipher block and of the read
+        // buffer, which is set to a single block so that every block
+        // boundary is also a buffer boundary.
+        for len in (0..=3 * BLKSZ + 1).chain(vec![4 * BLKSZ, 4 * BLKSZ + 1]) {
+            let data = &data[..len];
+            let id = hmac(data, keychain.obj_hmac_secret().unwrap());
+
+            let mut expected = Vec::new();
+            encrypt_obj_with(CipherWidth::default(), &mut expected, data, &id)
+                .unwrap();
+
+            for &buf_size in &[BLKSZ, DEFAULT_CRYPT_BUF_SIZE] {
+                let mut ciphertext = Vec::new();
+                encrypt_obj_buffered_with(
+                    CipherWidth::default(),
+                    &mut ciphertext,
+                    trickle(data),
+                    &id,
+                    buf_size,
+                )
+                .unwrap();
+                assert_eq!(expected, ciphertext, "len = {}", len);
+
+                let mut cleartext = Vec::new();
+                decrypt_obj_buffered_with(
+                    CipherWidth::default(),
+                    &mut cleartext,
+                    trickle(&ciphertext),
+                    &id,
+                    buf_size,
+                )
+                .unwrap();
+                assert_eq!(data, &cleartext[..], "len = {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn crypt_obj_rejects_unaligned_buffer() {
+        for &buf_size in &[0, 4095] {
+            let mut ciphertext = Vec::new();
+            match encrypt_obj_buffered_with(
+                CipherWidth::default(),
+                &mut ciphertext,
+                &b"hello"[..],
+                &HashId::default(),
+                buf_size,
+            ) {
+                Err(Error(ErrorKind::InvalidCryptBufferSize(size), _)) => {
+                    assert_eq!(buf_size, size)
+                }
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        }
+    }
+
     #[test]
     fn crypt_dir_oneshot() {
         let key = InternalKey::generate_new();
@@ -1069,6 +2494,105 @@ mod fast_test {
         assert_eq!(&b"0123456789abcdef0123456789ABCDEF"[..], &cleartext[..]);
     }
 
+    #[test]
+    fn crypt_with_both_cipher_widths() {
+        let key = InternalKey::generate_new();
+        let data = b"This is longer than sixteen bytes.";
+        let id = hmac(data, key.hmac_secret());
+        let dir_data = b"0123456789abcdef0123456789ABCDEF";
+
+        let mut obj_ciphertexts = Vec::new();
+        let mut dir_ciphertexts = Vec::new();
+        for &width in &[CipherWidth::Aes128, CipherWidth::Aes256] {
+            let mut ciphertext = Vec::new();
+            encrypt_obj_with(width, &mut ciphertext, &data[..], &id).unwrap();
+            assert_eq!(
+                encrypted_obj_len(data.len() as u64),
+                ciphertext.len() as u64
+            );
+            let mut cleartext = Vec::new();
+            decrypt_obj_with(width, &mut cleartext, &ciphertext[..], &id)
+                .unwrap();
+            assert_eq!(&data[..], &cleartext[..]);
+            obj_ciphertexts.push(ciphertext);
+
+            let mut ciphertext = Vec::new();
+            let sk = encrypt_whole_dir_with(
+                width,
+                &mut ciphertext,
+                &dir_data[..16],
+                &key,
+            )
+            .unwrap();
+            let iv = dir_append_iv(&ciphertext);
+            encrypt_append_dir(&mut ciphertext, &dir_data[16..], &sk, &iv)
+                .unwrap();
+            assert_eq!(
+                width.key_len() + BLKSZ + dir_data.len(),
+                ciphertext.len()
+            );
+
+            let mut cleartext = Vec::new();
+            let sk2 = decrypt_whole_dir_with(
+                width,
+                &mut cleartext,
+                &ciphertext[..],
+                &key,
+            )
+            .unwrap();
+            assert_eq!(&dir_data[..], &cleartext[..]);
+            assert_eq!(sk, sk2);
+            dir_ciphertexts.push(ciphertext);
+
+            let mut keychain = KeyChain::generate_new();
+            keychain.cipher_width = width;
+            let mut dir = HashId::default();
+            rand(&mut dir);
+            assert_eq!(
+                Some(42u64),
+                decrypt_dir_ver(
+                    &dir,
+                    &encrypt_dir_ver(&dir, 42u64, &keychain),
+                    &keychain
+                )
+            );
+        }
+
+        // The default is still the original 128-bit format
+        let mut ciphertext = Vec::new();
+        encrypt_obj_with(
+            CipherWidth::default(),
+            &mut ciphertext,
+            &data[..],
+            &id,
+        )
+        .unwrap();
+        assert_eq!(obj_ciphertexts[0], ciphertext);
+        assert!(obj_ciphertexts[0] != obj_ciphertexts[1]);
+        let mut cleartext = Vec::new();
+        decrypt_whole_dir(&mut cleartext, &dir_ciphertexts[0][..], &key)
+            .unwrap();
+        assert_eq!(&dir_data[..], &cleartext[..]);
+
+        // Data written with one width cannot be read with the other
+        let mut cleartext = Vec::new();
+        assert!(decrypt_obj_with(
+            CipherWidth::Aes128,
+            &mut cleartext,
+            &obj_ciphertexts[1][..],
+            &id
+        )
+        .map_or(true, |_| &data[..] != &cleartext[..]));
+        let mut cleartext = Vec::new();
+        assert!(decrypt_whole_dir_with(
+            CipherWidth::Aes256,
+            &mut cleartext,
+            &dir_ciphertexts[0][..],
+            &key
+        )
+        .map_or(true, |_| &dir_data[..] != &cleartext[..]));
+    }
+
     #[test]
     fn crypt_dir_version() {
         let keychain = KeyChain::generate_new();
@@ -1077,7 +2601,7 @@ mod fast_test {
         rand(&mut dir);
 
         assert_eq!(
-            42u64,
+            Some(42u64),
             decrypt_dir_ver(
                 &dir,
                 &encrypt_dir_ver(&dir, 42u64, &keychain),
@@ -1087,12 +2611,93 @@ mod fast_test {
     }
 
     #[test]
-    fn corrupt_dir_version_decrypted_to_0() {
+    fn corrupt_dir_version_rejected() {
         let keychain = KeyChain::generate_new();
 
         assert_eq!(
-            0u64,
+            None,
             decrypt_dir_ver(&HashId::default(), &HashId::default(), &keychain)
         );
+
+        // A genuine version 0 is still distinguishable from corruption
+        let mut dir = HashId::default();
+        rand(&mut dir);
+        let mut ciphertext = encrypt_dir_ver(&dir, 0, &keychain);
+        assert_eq!(Some(0), decrypt_dir_ver(&dir, &ciphertext, &keychain));
+        ciphertext[31] ^= 1;
+        assert_eq!(None, decrypt_dir_ver(&dir, &ciphertext, &keychain));
+    }
+
+    #[test]
+    fn dir_version_packed_little_endian() {
+        assert_eq!([0u8; 8], pack_dir_ver(0));
+        assert_eq!([1, 0, 0, 0, 0, 0, 0, 0], pack_dir_ver(1));
+        assert_eq!(
+            [8, 7, 6, 5, 4, 3, 2, 1],
+            pack_dir_ver(0x0102_0304_0506_0708)
+        );
+        assert_eq!([0, 0, 0, 0, 0, 0, 0, 0x80], pack_dir_ver(1 << 63));
+        assert_eq!([0xFF; 8], pack_dir_ver(u64::MAX));
+
+        for &ver in &[0, 1, 0x0102_0304_0506_0708, 1 << 63, u64::MAX] {
+            assert_eq!(ver, unpack_dir_ver(&pack_dir_ver(ver)));
+        }
+    }
+
+    #[test]
+    fn crypt_dir_version_boundaries() {
+        let legacy = KeyChain::generate_new();
+        let mut mac = legacy.clone();
+        mac.dir_ver_fmt = DIR_VER_FMT_MAC;
+
+        let mut dir = HashId::default();
+        rand(&mut dir);
+
+        for keychain in &[&legacy, &mac] {
+            for &ver in &[
+                0,
+                1,
+                0xFF,
+                0x100,
+                (1 << 63) - 1,
+                1 << 63,
+                u64::MAX - 1,
+                u64::MAX,
+            ] {
+                assert_eq!(
+                    Some(ver),
+                    decrypt_dir_ver(
+                        &dir,
+                        &encrypt_dir_ver(&dir, ver, keychain),
+                        keychain
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn crypt_dir_version_mac_bound() {
+        let legacy = KeyChain::generate_new();
+        let mut mac = legacy.clone();
+        mac.dir_ver_fmt = DIR_VER_FMT_MAC;
+
+        let mut dir = HashId::default();
+        rand(&mut dir);
+        let mut other_dir = HashId::default();
+        rand(&mut other_dir);
+
+        let legacy_ver = encrypt_dir_ver(&dir, 42u64, &legacy);
+        let mac_ver = encrypt_dir_ver(&dir, 42u64, &mac);
+        assert!(legacy_ver != mac_ver);
+
+        assert_eq!(Some(42u64), decrypt_dir_ver(&dir, &legacy_ver, &legacy));
+        assert_eq!(Some(42u64), decrypt_dir_ver(&dir, &mac_ver, &mac));
+
+        // Each format rejects the other, and the MAC-bound format rejects
+        // versions moved to another directory.
+        assert_eq!(None, decrypt_dir_ver(&dir, &legacy_ver, &mac));
+        assert_eq!(None, decrypt_dir_ver(&dir, &mac_ver, &legacy));
+        assert_eq!(None, decrypt_dir_ver(&other_dir, &mac_ver, &mac));
     }
 }
@@ -75,6 +75,7 @@
 
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
+use std::mem;
 // This is another place where we need to be able to convert between byte
 // arrays and `OsStr[ing]` which will need some attention for a hypothetical
 // Windows port.
@@ -85,6 +86,7 @@ use std::usize;
 
 use flate2;
 use fourleaf::{self, Deserialize, Serialize, UnknownFields};
+use num_cpus;
 use tiny_keccak;
 
 use crate::block_xfer::*;
@@ -104,6 +106,13 @@ pub const DIRID_KEYS: HashId = [0; 32];
 /// directory, but is not the actual root, instead storing pointers to the
 /// various named roots.
 pub const DIRID_PROOT: HashId = [255; 32];
+/// The well-known directory id of the "directory" object which stores the key
+/// management audit log.
+pub const DIRID_KEY_AUDIT: HashId = [1; 32];
+
+/// The number of blocks a file must span for its blocks to be hashed in
+/// parallel when it is uploaded.
+const PARALLEL_HASH_MIN_BLOCKS: FileSize = 4;
 
 /// Stored in the first chunk of directory contents to describe the
 /// directory.
@@ -237,10 +246,33 @@ pub struct Dir<S: Storage + ?Sized + 'static> {
     tx_ctr: Arc<AtomicUsize>,
     block_size: usize,
     compression: flate2::Compression,
+    upload_stats: Arc<Mutex<UploadStats>>,
+    hash_pool: Arc<HashPool>,
 
     content: Mutex<DirContent>,
 }
 
+/// Statistics on the file content uploaded through a tree of `Dir`s.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UploadStats {
+    /// Statistics on the blocks of every file uploaded.
+    pub blocks: BlockStats,
+    /// The number of those blocks which the server already had, and which
+    /// therefore were not sent again.
+    pub present_blocks: u64,
+    /// The total number of bytes in blocks counted by `present_blocks`.
+    pub present_bytes: FileSize,
+}
+
+impl UploadStats {
+    /// Adds the counts from `other` into `self`.
+    pub fn merge(&mut self, other: &UploadStats) {
+        self.blocks.merge(&other.blocks);
+        self.present_blocks += other.present_blocks;
+        self.present_bytes += other.present_bytes;
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 struct DirContent {
     /// The current (cleartext) version of this directory.
@@ -262,7 +294,7 @@ struct DirContent {
     /// when we need to rebuild due to redundant entries.
     physical_entries: u32,
     /// The session key being used for encryption
-    session_key: [u8; BLKSZ],
+    session_key: SessionKey,
     /// The IV to pass to `encrypt_append_dir`
     iv: [u8; BLKSZ],
     /// If `Some`, the directory is currently an unmaterialised synthetic
@@ -300,6 +332,8 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
             tx_ctr: Arc::new(AtomicUsize::new(1)),
             block_size: block_size,
             compression: compression,
+            upload_stats: Arc::new(Mutex::new(UploadStats::default())),
+            hash_pool: Arc::new(HashPool::new(num_cpus::get())),
             content: Mutex::new(DirContent::default()),
         };
 
@@ -333,6 +367,8 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
             tx_ctr: parent.tx_ctr.clone(),
             block_size: parent.block_size,
             compression: parent.compression,
+            upload_stats: parent.upload_stats.clone(),
+            hash_pool: parent.hash_pool.clone(),
             content: Mutex::new(DirContent::default()),
             parent: Some(parent),
         })
@@ -359,6 +395,8 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
             tx_ctr: parent.tx_ctr.clone(),
             block_size: parent.block_size,
             compression: parent.compression,
+            upload_stats: parent.upload_stats.clone(),
+            hash_pool: parent.hash_pool.clone(),
             content: Mutex::new(DirContent {
                 synth: Some((name.to_owned(), mode)),
                 ..DirContent::default()
@@ -457,6 +495,8 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
                     tx_ctr: self.tx_ctr.clone(),
                     block_size: self.block_size,
                     compression: self.compression,
+                    upload_stats: self.upload_stats.clone(),
+                    hash_pool: self.hash_pool.clone(),
                     content: Mutex::new(DirContent::default()),
                 };
                 // Fetch the child directory's data as necessary so we know its
@@ -531,6 +571,8 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
         let mut content = self.content.lock().unwrap();
         self.materialise(&mut content)?;
 
+        // Only the statistics from the attempt which commits are kept.
+        let mut upload = None;
         let ret = self
             .do_tx(&mut content, |tx, content| {
                 let mut subdir_id = None;
@@ -626,34 +668,67 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
                         let mut xfer =
                             xfer.as_mut().ok_or(ErrorKind::MissingXfer)?;
                         xfer.reset()?;
+                        let block_size =
+                            xfer.block_size().unwrap_or(self.block_size);
+                        let secret = self.key.obj_hmac_secret()?;
                         let mut blocks = Vec::new();
-                        let blocklist = stream_to_blocks(
-                            &mut xfer,
-                            self.block_size,
-                            self.key.obj_hmac_secret()?,
-                            |blockid, block_data| {
-                                let linkid = rand_hashid();
-                                blocks.push((*blockid, linkid));
-
-                                if !self.storage.linkobj(
-                                    tx,
-                                    &xform_obj_id(&blockid),
-                                    &linkid,
-                                )? {
-                                    self.upload_object(
-                                        tx, &blockid, &linkid, block_data,
-                                    )?;
-                                }
-                                Ok(())
-                            },
-                        )?;
+                        let mut present_blocks = 0;
+                        let mut present_bytes = 0;
+                        let block_out = |blockid: &HashId,
+                                         block_data: &[u8]|
+                         -> Result<()> {
+                            let linkid = rand_hashid();
+                            blocks.push((*blockid, linkid));
+
+                            // The object may still vanish before it is
+                            // linked, in which case it is uploaded anyway.
+                            let objid = xform_obj_id(&blockid);
+                            if block_present(&*self.storage, &objid)?
+                                && self.storage.linkobj(tx, &objid, &linkid)?
+                            {
+                                present_blocks += 1;
+                                present_bytes += block_data.len() as FileSize;
+                            } else {
+                                self.upload_object(
+                                    tx, &blockid, &linkid, block_data,
+                                )?;
+                            }
+                            Ok(())
+                        };
+                        // Hashing is only worth spreading across threads if
+                        // there are enough blocks to go round.
+                        let blocklist = if size
+                            >= PARALLEL_HASH_MIN_BLOCKS * block_size as FileSize
+                        {
+                            stream_to_blocks_parallel_with(
+                                self.key.hash_variant,
+                                &mut xfer,
+                                block_size,
+                                secret,
+                                &self.hash_pool,
+                                block_out,
+                            )
+                        } else {
+                            stream_to_blocks_with(
+                                self.key.hash_variant,
+                                &mut xfer,
+                                block_size,
+                                secret,
+                                block_out,
+                            )
+                        }?;
                         xfer.finish(&blocklist)?;
+                        upload = Some(UploadStats {
+                            blocks: blocklist.stats.unwrap_or_default(),
+                            present_blocks: present_blocks,
+                            present_bytes: present_bytes,
+                        });
                         v0::Entry::Regular {
                             mode: mode,
-                            size: size,
+                            size: blocklist.size,
                             time: time,
                             hmac: blocklist.total,
-                            block_size: self.block_size as u32,
+                            block_size: block_size as u32,
                             blocks: blocks,
                             unknown: UnknownFields::default(),
                         }
@@ -666,9 +741,18 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
             })
             .map(|r| r.expect("edit() transaction aborted?"))?;
         self.save_latest_dir_ver(&mut *content)?;
+        if let Some(upload) = upload {
+            self.upload_stats.lock().unwrap().merge(&upload);
+        }
         Ok(ret)
     }
 
+    /// Returns the statistics on the file content uploaded through this `Dir`
+    /// and every other one in the same tree, and starts counting afresh.
+    pub fn take_upload_stats(&self) -> UploadStats {
+        mem::take(&mut *self.upload_stats.lock().unwrap())
+    }
+
     fn upload_object(
         &self,
         tx: Tx,
@@ -679,7 +763,12 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
         let mut ciphertext = Vec::<u8>::with_capacity(block_data.len() + 256);
         let compressor =
             flate2::read::GzEncoder::new(block_data, self.compression);
-        encrypt_obj(&mut ciphertext, compressor, blockid)?;
+        encrypt_obj_with(
+            self.key.cipher_width,
+            &mut ciphertext,
+            compressor,
+            blockid,
+        )?;
         self.storage.putobj(
             tx,
             &xform_obj_id(blockid),
@@ -741,6 +830,7 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
             None => Err(ErrorKind::ServerContentDeleted.into()),
             Some(&v0::Entry::Regular {
                 hmac: actual,
+                size,
                 block_size,
                 ref blocks,
                 ..
@@ -749,12 +839,19 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
                     Ok(ContentAddressableSource {
                         blocks: BlockList {
                             total: actual,
-                            size: 0, // Not used
+                            size: size,
                             blocks: blocks.iter().map(|v| v.0).collect(),
+                            block_sizes: uniform_block_sizes(
+                                size,
+                                block_size as usize,
+                                blocks.len(),
+                            ),
+                            stats: None,
                         },
                         block_size: block_size as usize,
                         fetch: Arc::new(ServerTransferOut::new(
                             self.storage.clone(),
+                            self.key.cipher_width,
                         )),
                     })
                 } else {
@@ -775,6 +872,78 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
         self.content.lock().unwrap().list_up_to_date
     }
 
+    /// Re-encodes the encrypted version of `dir` and, recursively, all of its
+    /// subdirectories in the directory version format `fmt`.
+    ///
+    /// Versions are decrypted using the format of the key chain `dir` was
+    /// opened with. Subdirectories are re-encoded before their parent, so a
+    /// directory already in format `fmt` is taken to have nothing left to do
+    /// beneath it either; this lets an interrupted run simply be repeated.
+    /// Once this returns, the `Dir` tree is stale and must be discarded.
+    pub fn reencode_versions(dir: Arc<Self>, fmt: u32) -> Result<()> {
+        let mut new_key = (*dir.key).clone();
+        new_key.dir_ver_fmt = fmt;
+
+        let (cipher_version, _) = dir
+            .storage
+            .getdir(&dir.id)?
+            .ok_or(ErrorKind::DirectoryMissing)?;
+        if decrypt_dir_ver(&dir.id, &cipher_version, &new_key).is_some() {
+            return Ok(());
+        }
+
+        // The content of `dir` can no longer be read with its own key chain
+        // once it has been re-encoded, so this must happen first.
+        let subdirs = dir
+            .list()?
+            .into_iter()
+            .filter(|&(_, ref data)| is_dir(Some(data)))
+            .map(|(name, _)| Dir::subdir(dir.clone(), &name))
+            .collect::<Result<Vec<_>>>()?;
+        for subdir in subdirs {
+            Dir::reencode_versions(Arc::new(subdir), fmt)?;
+        }
+
+        let write_key = dir.write_key()?;
+        loop {
+            let (cipher_version, data) = dir
+                .storage
+                .getdir(&dir.id)?
+                .ok_or(ErrorKind::DirectoryMissing)?;
+            if decrypt_dir_ver(&dir.id, &cipher_version, &new_key).is_some() {
+                break;
+            }
+
+            let version = decrypt_dir_ver(&dir.id, &cipher_version, &dir.key)
+                .ok_or_else(|| {
+                ErrorKind::DirectoryVersionInvalid(dir.path.clone())
+            })?;
+            let new_cipher_version =
+                encrypt_dir_ver(&dir.id, version, &new_key);
+
+            let tx = dir.tx_ctr.fetch_add(1, Ordering::SeqCst) as Tx;
+            dir.storage.start_tx(tx)?;
+            dir.storage.rmdir(
+                tx,
+                &dir.id,
+                &secret_dir_ver(&cipher_version, write_key),
+                data.len() as u32,
+            )?;
+            dir.storage.mkdir(
+                tx,
+                &dir.id,
+                &new_cipher_version,
+                &secret_dir_ver(&new_cipher_version, write_key),
+                &data,
+            )?;
+            if dir.storage.commit(tx)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
     fn lookup_opt<'a>(
         &self,
         content: &'a mut DirContent,
@@ -827,7 +996,13 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
             // because propagating `NotFound` out of the callers of `refresh()`
             // would have different meaning.
             .ok_or(ErrorKind::DirectoryMissing)?;
-        let version = decrypt_dir_ver(&self.id, &cipher_version, &self.key);
+        // A version we cannot decrypt can only come from corruption or from
+        // the server making one up, so refuse it outright rather than letting
+        // it pass as an old version.
+        let version = decrypt_dir_ver(&self.id, &cipher_version, &self.key)
+            .ok_or_else(|| {
+                ErrorKind::DirectoryVersionInvalid(self.path.clone())
+            })?;
 
         // Validate that the version has not recessed from the latest thing we
         // ever successfully parsed.
@@ -858,8 +1033,12 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
 
         // Ok, now decrypt and read the header
         let mut data = Vec::<u8>::new();
-        let session_key =
-            decrypt_whole_dir(&mut data, &cipher_data[..], self.dir_key()?)?;
+        let session_key = decrypt_whole_dir_with(
+            self.key.cipher_width,
+            &mut data,
+            &cipher_data[..],
+            self.dir_key()?,
+        )?;
 
         let mut data_reader = &data[..];
         let mut chunk_hmac = UNKNOWN_HASH;
@@ -1128,7 +1307,8 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
         self.encode_chunk(&mut cleartext, &entries, &mut content.prev_hmac)?;
 
         let mut ciphertext = Vec::<u8>::new();
-        content.session_key = encrypt_whole_dir(
+        content.session_key = encrypt_whole_dir_with(
+            self.key.cipher_width,
             &mut ciphertext,
             &mut &cleartext[..],
             self.dir_key()?,
@@ -1228,6 +1408,8 @@ impl<S: Storage + ?Sized + 'static> Dir<S> {
             tx_ctr: self.tx_ctr.clone(),
             block_size: self.block_size,
             compression: self.compression,
+            upload_stats: self.upload_stats.clone(),
+            hash_pool: self.hash_pool.clone(),
             content: Mutex::new(DirContent::default()),
         };
         child.rewrite(tx, &mut child.content.lock().unwrap(), false)?;
@@ -0,0 +1,156 @@
+//-
+// Copyright (c) 2016, 2017, 2021, Jason Lingle
+//
+// This file is part of Ensync.
+//
+// Ensync is free software: you can  redistribute it and/or modify it under the
+// terms of  the GNU General Public  License as published by  the Free Software
+// Foundation, either version  3 of the License, or (at  your option) any later
+// version.
+//
+// Ensync is distributed  in the hope that  it will be useful,  but WITHOUT ANY
+// WARRANTY; without  even the implied  warranty of MERCHANTABILITY  or FITNESS
+// FOR  A PARTICULAR  PURPOSE.  See the  GNU General  Public  License for  more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// Ensync. If not, see <http://www.gnu.org/licenses/>.
+
+//! Support for estimating how much space files will take on the server.
+//!
+//! Each block of a file is gzipped, then encrypted with PKCS padding, and
+//! stored as its own object; the directory containing the file additionally
+//! records a block id and a link id for every block. The estimates here assume
+//! the data is incompressible and that no blocks are shared between files, so
+//! they are usually an overestimate. The CBC prefix and chunk headers of the
+//! directories themselves are not counted, since they do not scale with the
+//! number or size of files. Nor is the gzip framing when compression is off,
+//! since nothing is then being estimated about how the data compresses.
+
+use super::crypt::encrypted_obj_len;
+use crate::block_xfer::expected_block_count;
+use crate::defs::FileSize;
+
+/// The size of the gzip header and trailer wrapped around every block before
+/// encryption.
+///
+/// The deflate stream itself adds a few more bytes per 64kB even for
+/// incompressible data; that is not accounted for.
+pub const GZIP_FRAMING_LEN: u64 = 18;
+
+/// The number of bytes in a directory entry for each block of the file: the
+/// block id and the link id.
+pub const DIR_BYTES_PER_BLOCK: u64 = 64;
+
+/// Returns the number of bytes a block of `len` bytes of cleartext occupies
+/// on the server, including its share of the directory entry.
+///
+/// `compressed` is whether compression is on; if not, `GZIP_FRAMING_LEN` is
+/// not counted.
+pub fn block_server_bytes(len: u64, compressed: bool) -> u64 {
+    let framing = if compressed { GZIP_FRAMING_LEN } else { 0 };
+    encrypted_obj_len(len + framing) + DIR_BYTES_PER_BLOCK
+}
+
+/// Returns the estimated number of bytes a file of `size` bytes occupies on
+/// the server when split into blocks of `block_size`.
+pub fn server_bytes(
+    size: FileSize,
+    block_size: usize,
+    compressed: bool,
+) -> u64 {
+    let blocks = expected_block_count(size, block_size);
+    if 0 == blocks {
+        return 0;
+    }
+
+    let full_blocks = blocks - 1;
+    let last_block = size - full_blocks * block_size as u64;
+    full_blocks * block_server_bytes(block_size as u64, compressed)
+        + block_server_bytes(last_block, compressed)
+}
+
+/// An estimate of the server space consumed by a set of files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StorageEstimate {
+    /// The number of files counted.
+    pub files: u64,
+    /// The total cleartext size of the files.
+    pub cleartext_bytes: u64,
+    /// The estimated total number of bytes on the server.
+    pub server_bytes: u64,
+}
+
+impl StorageEstimate {
+    /// Adds a file of `size` bytes to the estimate.
+    pub fn add(&mut self, size: FileSize, block_size: usize, compressed: bool) {
+        self.files += 1;
+        self.cleartext_bytes += size;
+        self.server_bytes += server_bytes(size, block_size, compressed);
+    }
+
+    /// Returns the ratio of server bytes to cleartext bytes, or `None` if
+    /// there is no cleartext at all.
+    pub fn amplification(&self) -> Option<f64> {
+        if 0 == self.cleartext_bytes {
+            None
+        } else {
+            Some(self.server_bytes as f64 / self.cleartext_bytes as f64)
+        }
+    }
+}
+
+/// Estimates the server space consumed by files of the given `sizes` when
+/// split into blocks of `block_size`, with compression on if `compressed`.
+pub fn estimate_storage<I: IntoIterator<Item = FileSize>>(
+    sizes: I,
+    block_size: usize,
+    compressed: bool,
+) -> StorageEstimate {
+    let mut estimate = StorageEstimate::default();
+    for size in sizes {
+        estimate.add(size, block_size, compressed);
+    }
+    estimate
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn storage_estimate_sums_per_file_estimates() {
+        assert_eq!(0, server_bytes(0, 1024, true));
+        // 1 byte + 18 framing pads to 32, plus 64 directory bytes
+        assert_eq!(96, server_bytes(1, 1024, true));
+        // Two full blocks and one of 1 byte
+        assert_eq!(2 * (1056 + 64) + 96, server_bytes(2049, 1024, true));
+
+        let sizes = vec![0, 1, 1000, 1024, 2049, 1 << 20];
+        let estimate = estimate_storage(sizes.iter().cloned(), 1024, true);
+        assert_eq!(6, estimate.files);
+        assert_eq!(sizes.iter().sum::<u64>(), estimate.cleartext_bytes);
+        assert_eq!(
+            sizes
+                .iter()
+                .map(|&s| server_bytes(s, 1024, true))
+                .sum::<u64>(),
+            estimate.server_bytes
+        );
+        assert!(estimate.amplification().unwrap() > 1.0);
+
+        assert_eq!(None, estimate_storage(vec![0], 1024, true).amplification());
+    }
+
+    #[test]
+    fn storage_estimate_without_compression_omits_gzip_framing() {
+        // 1 byte pads to 16, plus 64 directory bytes
+        assert_eq!(80, server_bytes(1, 1024, false));
+        // Two full blocks and one of 1 byte
+        assert_eq!(2 * (1040 + 64) + 80, server_bytes(2049, 1024, false));
+        assert!(
+            estimate_storage(vec![1000, 2049], 1024, false).server_bytes
+                < estimate_storage(vec![1000, 2049], 1024, true).server_bytes
+        );
+    }
+}
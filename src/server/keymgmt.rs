@@ -18,15 +18,20 @@
 
 //! Routines for performing high-level key management operations on the server.
 
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{self, Instant};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use fourleaf;
+use rand::{rngs::OsRng, Rng};
 
-use crate::defs::HashId;
+use crate::block_xfer::HashVariant;
+use crate::defs::{HashId, Passphrase, UNKNOWN_HASH};
 use crate::errors::*;
+use crate::interrupt;
 use crate::server::crypt::*;
-use crate::server::dir::DIRID_KEYS;
+use crate::server::dir::{DIRID_KEYS, DIRID_KEY_AUDIT, DIRID_PROOT};
 use crate::server::storage::*;
 
 /// Caches a `root` internal key.
@@ -50,11 +55,11 @@ fn do_tx<S: Storage + ?Sized, R, F: FnMut(Tx) -> Result<R>>(
     storage: &S,
     mut f: F,
 ) -> Result<R> {
-    // For now just always use a constant since we don't run concurrently with
-    // anything.
-    let tx = 0;
-
     for _ in 0..16 {
+        // Other transactions may be in progress on the same storage, e.g. from
+        // a `ServerReplica` or a nested edit, so use a fresh random id for
+        // each attempt rather than a constant which could alias them.
+        let tx: Tx = OsRng.gen();
         storage.start_tx(tx)?;
         match f(tx) {
             Ok(r) => {
@@ -80,16 +85,24 @@ fn get_kdflist<S: Storage + ?Sized>(
     config.max_collect = 65536;
 
     if let Some((ver, data)) = storage.getdir(&DIRID_KEYS)? {
-        Ok(Some((
-            fourleaf::from_slice_copy(&data, &config)?,
-            ver,
-            data.len() as u32,
-        )))
+        let kdflist: KdfList = fourleaf::from_slice_copy(&data, &config)?;
+        check_format_version(&kdflist)?;
+        Ok(Some((kdflist, ver, data.len() as u32)))
     } else {
         Ok(None)
     }
 }
 
+/// Fails with `KdfListFromNewerVersion` if `kdflist` has a format this
+/// version of Ensync does not understand.
+fn check_format_version(kdflist: &KdfList) -> Result<()> {
+    let version = kdflist.format_version.unwrap_or(1);
+    if version > KDFLIST_FORMAT_VERSION {
+        return Err(ErrorKind::KdfListFromNewerVersion(version).into());
+    }
+    Ok(())
+}
+
 fn put_kdflist<S: Storage + ?Sized>(
     storage: &S,
     kdf: &KdfList,
@@ -118,13 +131,188 @@ fn put_kdflist<S: Storage + ?Sized>(
     Ok((new_ver, new_data.len() as u32))
 }
 
+/// A single entry in the key management audit log.
+///
+/// The audit log is stored in directory `DIRID_KEY_AUDIT`, encrypted with a
+/// key derived from the `root` internal key and used for nothing else. Each
+/// record consists of a 32-bit little-endian length, the fourleaf-encoded
+/// `AuditEntry`, and a 32-byte MAC, zero-padded to a multiple of `BLKSZ`. The
+/// MAC of each record is the HMAC (with the audit key's HMAC secret) of the
+/// previous record's MAC (or all zeroes for the first record) followed by the
+/// encoded entry. The MAC of the latest record is stored in
+/// `KdfList::audit_head` so that truncation can be detected too, and
+/// `KdfList::audit_tail` records where the log ends so that it can be appended
+/// to without being read back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// The name of the operation performed, e.g., "add-key".
+    pub operation: String,
+    /// When the operation was performed.
+    pub timestamp: DateTime<Utc>,
+    /// The names of the keys or groups given to the operation.
+    pub names: Vec<String>,
+}
+
+fourleaf_retrofit!(struct AuditEntry : {} {} {
+    |context, this|
+    [1] operation: String = &this.operation,
+    [2] secs: i64 = this.timestamp.naive_utc().timestamp(),
+    [3] nsecs: u32 = this.timestamp.naive_utc().timestamp_subsec_nanos(),
+    [4] names: Vec<String> = &this.names,
+    { NaiveDateTime::from_timestamp_opt(secs, nsecs)
+      .ok_or(fourleaf::de::Error::InvalidValueMsg(
+          context.to_string(), "invalid timestamp"))
+      .map(|ndt| AuditEntry { operation: operation,
+                              timestamp: DateTime::from_utc(ndt, Utc),
+                              names: names }) }
+});
+
+impl AuditEntry {
+    fn new(operation: &str, names: Vec<String>) -> Self {
+        AuditEntry {
+            operation: operation.to_owned(),
+            timestamp: Utc::now(),
+            names: names,
+        }
+    }
+}
+
+fn audit_mac(prev: &HashId, entry_data: &[u8], key: &InternalKey) -> HashId {
+    let mut data = Vec::with_capacity(prev.len() + entry_data.len());
+    data.extend_from_slice(prev);
+    data.extend_from_slice(entry_data);
+    hmac(&data, key.hmac_secret())
+}
+
+/// Label from which the audit log key is derived from the `root` internal key.
+const AUDIT_KEY_LABEL: &[u8] = b"ensync-key-audit";
+
+/// Returns the key with which the audit log is encrypted and authenticated,
+/// given the `root` internal key.
+fn audit_key(root: &InternalKey) -> InternalKey {
+    root.derive(AUDIT_KEY_LABEL)
+}
+
+/// Appends `entry` to the audit log within `tx`, and updates
+/// `kdflist.audit_head` and `kdflist.audit_tail` to match.
+///
+/// The existing log is never read; the tail recorded in the KDF list has
+/// everything needed to append to it.
+fn append_audit_log<S: Storage + ?Sized>(
+    storage: &S,
+    tx: Tx,
+    kdflist: &mut KdfList,
+    entry: &AuditEntry,
+    key: &InternalKey,
+) -> Result<()> {
+    let entry_data = fourleaf::to_vec(entry)?;
+    let mac = audit_mac(
+        &kdflist.audit_head.unwrap_or(UNKNOWN_HASH),
+        &entry_data,
+        key,
+    );
+
+    let mut record = Vec::new();
+    record.extend_from_slice(&(entry_data.len() as u32).to_le_bytes());
+    record.extend_from_slice(&entry_data);
+    record.extend_from_slice(&mac);
+    while 0 != record.len() % BLKSZ {
+        record.push(0);
+    }
+
+    let tail = if let Some(ref tail) = kdflist.audit_tail {
+        let session_key = dir_session_key(&tail.prefix, key)?;
+        let mut append = Vec::new();
+        encrypt_append_dir(
+            &mut append,
+            &record[..],
+            &session_key,
+            &dir_append_iv(&tail.last_block),
+        )?;
+        storage.updir(
+            tx,
+            &DIRID_KEY_AUDIT,
+            &secret_dir_ver(&tail.ver, key),
+            tail.len,
+            &append,
+        )?;
+
+        AuditTail {
+            ver: tail.ver,
+            len: tail.len + append.len() as u32,
+            prefix: tail.prefix.clone(),
+            last_block: dir_append_iv(&append).to_vec(),
+        }
+    } else {
+        let ver = rand_hashid();
+        let mut data = Vec::new();
+        encrypt_whole_dir(&mut data, &record[..], key)?;
+        storage.mkdir(
+            tx,
+            &DIRID_KEY_AUDIT,
+            &ver,
+            &secret_dir_ver(&ver, key),
+            &data,
+        )?;
+
+        AuditTail {
+            ver: ver,
+            len: data.len() as u32,
+            prefix: data[..DIR_PREFIX_LEN].to_vec(),
+            last_block: dir_append_iv(&data).to_vec(),
+        }
+    };
+
+    kdflist.audit_head = Some(mac);
+    kdflist.audit_tail = Some(tail);
+    Ok(())
+}
+
+/// Describes an edit to the KDF list for `edit_kdflist()`.
+struct KdfEdit<'a> {
+    /// The name of the operation, for the audit log.
+    operation: &'a str,
+    /// The names the operation was given, for the audit log.
+    names: Vec<String>,
+    /// If set, a passphrase which must still work after the edit.
+    verify: Option<&'a [u8]>,
+}
+
+impl<'a> KdfEdit<'a> {
+    fn new(operation: &'a str, names: Vec<String>) -> Self {
+        KdfEdit {
+            operation: operation,
+            names: names,
+            verify: None,
+        }
+    }
+
+    /// Checks the edited list against `passphrase` before it is written.
+    ///
+    /// `passphrase` must still derive a key chain from the edited list, and
+    /// every group that it could derive before the edit must still have the
+    /// same internal key; a group which has gone missing counts as a failure.
+    /// If not, the edit is rolled back and `KdfListVerificationFailed` is
+    /// returned. This costs two extra key derivations, so it is opt-in, and
+    /// does nothing if `verify` is false.
+    fn verified(mut self, passphrase: &'a [u8], verify: bool) -> Self {
+        if verify {
+            self.verify = Some(passphrase);
+        }
+        self
+    }
+}
+
+/// Runs `f` to edit the KDF list within a transaction, then records the edit
+/// in the audit log and writes the list back.
 fn edit_kdflist<
     S: Storage + ?Sized,
     R,
-    P: FnMut() -> Result<Vec<u8>>,
+    P: FnMut() -> Result<Passphrase>,
     F: FnMut(&mut KdfList, &mut RootKey) -> Result<R>,
 >(
     storage: &S,
+    edit: KdfEdit,
     mut get_root_passphrase: P,
     mut f: F,
 ) -> Result<R> {
@@ -133,8 +321,23 @@ fn edit_kdflist<
     do_tx(storage, |tx| {
         let (mut kdflist, old_ver, old_len) =
             get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
+        let expected = edit
+            .verify
+            .and_then(|passphrase| try_derive_key(passphrase, &kdflist.keys));
         let r = f(&mut kdflist, &mut root_key)?;
 
+        if let Some(passphrase) = edit.verify {
+            let actual = try_derive_key(passphrase, &kdflist.keys)
+                .ok_or(ErrorKind::KdfListVerificationFailed)?;
+            if let Some(expected) = expected {
+                if expected.keys.iter().any(|(group, key)| {
+                    actual.keys.get(group).map_or(true, |k| k != key)
+                }) {
+                    return Err(ErrorKind::KdfListVerificationFailed.into());
+                }
+            }
+        }
+
         if root_key.0.is_none() {
             let root_passphrase = get_root_passphrase()?;
             root_key.chain(
@@ -143,17 +346,127 @@ fn edit_kdflist<
             );
         }
 
-        put_kdflist(
+        let root =
+            root_key.0.as_ref().ok_or("Input key not in `root` group")?;
+        append_audit_log(
             storage,
-            &kdflist,
             tx,
-            Some((&old_ver, old_len)),
-            root_key.0.as_ref().ok_or("Input key not in `root` group")?,
+            &mut kdflist,
+            &AuditEntry::new(edit.operation, edit.names.clone()),
+            &audit_key(root),
         )?;
+        put_kdflist(storage, &kdflist, tx, Some((&old_ver, old_len)), root)?;
         Ok(r)
     })
 }
 
+/// Names of the items passed to one of the group operations, for the audit
+/// log.
+fn audit_names<IT: Iterator>(names: IT) -> Vec<String>
+where
+    IT::Item: AsRef<str>,
+{
+    names.map(|n| n.as_ref().to_owned()).collect()
+}
+
+/// The minimum length, in bytes, of a passphrase `assess_passphrase` does not
+/// consider weak.
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+/// The minimum estimated entropy, in bits, of a passphrase
+/// `assess_passphrase` does not consider weak.
+pub const MIN_PASSPHRASE_BITS: f64 = 40.0;
+
+/// The result of `assess_passphrase`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PassphraseStrength {
+    /// The passphrase is empty or consists only of whitespace.
+    Empty,
+    /// The passphrase is shorter than the minimum length.
+    TooShort,
+    /// The passphrase is long enough, but its estimated entropy is too low.
+    LowEntropy,
+    /// The passphrase passed all checks.
+    Acceptable,
+}
+
+/// Estimates the entropy of `passphrase` in bits.
+///
+/// This is deliberately crude: the size of the alphabet is inferred from
+/// which classes of character appear, and characters which repeat or step by
+/// one from their predecessor (as in `aaaa` or `1234`) are not counted.
+fn estimate_passphrase_bits(passphrase: &[u8]) -> f64 {
+    let mut pool = 0u32;
+    if passphrase.iter().any(u8::is_ascii_lowercase) {
+        pool += 26;
+    }
+    if passphrase.iter().any(u8::is_ascii_uppercase) {
+        pool += 26;
+    }
+    if passphrase.iter().any(u8::is_ascii_digit) {
+        pool += 10;
+    }
+    if passphrase
+        .iter()
+        .any(|c| c.is_ascii() && !c.is_ascii_alphanumeric())
+    {
+        pool += 33;
+    }
+    if passphrase.iter().any(|c| !c.is_ascii()) {
+        pool += 128;
+    }
+
+    let effective_len = passphrase
+        .iter()
+        .enumerate()
+        .filter(|&(ix, &c)| {
+            0 == ix || {
+                let prev = passphrase[ix - 1] as i32;
+                (c as i32 - prev).abs() > 1
+            }
+        })
+        .count();
+
+    effective_len as f64 * (pool.max(1) as f64).log2()
+}
+
+/// Assesses whether `passphrase` is weak, requiring it to be at least
+/// `MIN_PASSPHRASE_LEN` bytes long.
+///
+/// This is cheap, and so can be used to warn about a passphrase before
+/// spending time on key derivation.
+pub fn assess_passphrase(passphrase: &[u8]) -> PassphraseStrength {
+    assess_passphrase_with(passphrase, MIN_PASSPHRASE_LEN)
+}
+
+/// Like `assess_passphrase`, but with a minimum length of `min_len` bytes.
+pub fn assess_passphrase_with(
+    passphrase: &[u8],
+    min_len: usize,
+) -> PassphraseStrength {
+    if passphrase.iter().all(u8::is_ascii_whitespace) {
+        PassphraseStrength::Empty
+    } else if passphrase.len() < min_len {
+        PassphraseStrength::TooShort
+    } else if estimate_passphrase_bits(passphrase) < MIN_PASSPHRASE_BITS {
+        PassphraseStrength::LowEntropy
+    } else {
+        PassphraseStrength::Acceptable
+    }
+}
+
+/// Fails if `passphrase` is empty, or if `refuse_weak` is set and it is weak
+/// according to `assess_passphrase`.
+fn check_new_passphrase(passphrase: &[u8], refuse_weak: bool) -> Result<()> {
+    match assess_passphrase(passphrase) {
+        PassphraseStrength::Empty => Err(ErrorKind::PassphraseEmpty.into()),
+        PassphraseStrength::Acceptable => Ok(()),
+        strength if refuse_weak => {
+            Err(ErrorKind::PassphraseTooWeak(strength).into())
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Initialises the KDF List with a new internal key set and the given
 /// passphrase associated with the default groups.
 ///
@@ -163,6 +476,39 @@ pub fn init_keys<S: Storage + ?Sized>(
     passphrase: &[u8],
     key_name: &str,
 ) -> Result<KeyChain> {
+    init_keys_with(
+        storage,
+        passphrase,
+        key_name,
+        DEFAULT_KDF_ALGORITHM,
+        None,
+        false,
+        true,
+    )
+}
+
+/// Like `init_keys`, but derives the initial key with the KDF algorithm named
+/// by `algorithm` and attaches `comment` to it.
+///
+/// An invalid `algorithm` is rejected before anything is written, as is an
+/// empty passphrase or, if `refuse_weak` is set, a weak one.
+///
+/// If `record_key_use` is false, `record_key_use` and related functions never
+/// record when keys are used. Since the key store is stored in cleartext, the
+/// recorded times would otherwise reveal when each key is used to anyone who
+/// can read the server. This cannot be changed later.
+pub fn init_keys_with<S: Storage + ?Sized>(
+    storage: &S,
+    passphrase: &[u8],
+    key_name: &str,
+    algorithm: &str,
+    comment: Option<&str>,
+    refuse_weak: bool,
+    record_key_use: bool,
+) -> Result<KeyChain> {
+    validate_kdf_algorithm(algorithm)?;
+    check_new_passphrase(passphrase, refuse_weak)?;
+
     do_tx(storage, |tx| {
         if get_kdflist(storage)?.is_some() {
             return Err(ErrorKind::KdfListAlreadyExists.into());
@@ -171,22 +517,37 @@ pub fn init_keys<S: Storage + ?Sized>(
         let mut key_chain = KeyChain::generate_new();
         let mut kdflist = KdfList {
             keys: BTreeMap::new(),
+            audit_head: None,
+            min_keys: None,
+            dir_ver_fmt: None,
+            hash_variant: None,
+            format_version: Some(KDFLIST_FORMAT_VERSION),
+            cipher_width: None,
+            record_used: if record_key_use { None } else { Some(false) },
+            audit_tail: None,
             unknown: Default::default(),
         };
-        kdflist.keys.insert(
-            key_name.to_owned(),
-            create_key(passphrase, &mut key_chain, Utc::now(), None),
-        );
+        let mut entry = create_key_with_algorithm(
+            passphrase,
+            &mut key_chain,
+            Utc::now(),
+            None,
+            algorithm,
+        )?;
+        entry.comment = comment.map(str::to_owned);
+        kdflist.keys.insert(key_name.to_owned(), entry);
 
-        put_kdflist(
+        let root = key_chain
+            .key(GROUP_ROOT)
+            .expect("Created KeyChain with no `root` group");
+        append_audit_log(
             storage,
-            &kdflist,
             tx,
-            None,
-            key_chain
-                .key(GROUP_ROOT)
-                .expect("Created KeyChain with no `root` group"),
+            &mut kdflist,
+            &AuditEntry::new("init", vec![key_name.to_owned()]),
+            &audit_key(root),
         )?;
+        put_kdflist(storage, &kdflist, tx, None, root)?;
         Ok(key_chain)
     })
 }
@@ -195,18 +556,42 @@ pub fn init_keys<S: Storage + ?Sized>(
 /// `old_passphrase` to derive the key chain.
 ///
 /// The new key will inherit the same groups as the old one.
-pub fn add_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
+pub fn add_key<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
+    storage: &S,
+    old_passphrase: &[u8],
+    new_passphrase: &[u8],
+    new_name: &str,
+    get_root_passphrase: P,
+) -> Result<()> {
+    add_key_with(
+        storage,
+        old_passphrase,
+        new_passphrase,
+        new_name,
+        false,
+        get_root_passphrase,
+    )
+}
+
+/// Like `add_key`, but if `refuse_weak` is set, fails without touching the key
+/// store if `new_passphrase` is weak according to `assess_passphrase`.
+///
+/// An empty `new_passphrase` is always refused.
+pub fn add_key_with<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
     storage: &S,
     old_passphrase: &[u8],
     new_passphrase: &[u8],
     new_name: &str,
+    refuse_weak: bool,
     get_root_passphrase: P,
 ) -> Result<()> {
     if new_name.is_empty() {
         return Err(ErrorKind::EmptyKeyName.into());
     }
+    check_new_passphrase(new_passphrase, refuse_weak)?;
 
-    edit_kdflist(storage, get_root_passphrase, |kdflist, root_key| {
+    let edit = KdfEdit::new("add-key", vec![new_name.to_owned()]);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, root_key| {
         let mut key_chain = try_derive_key(old_passphrase, &kdflist.keys)
             .ok_or(ErrorKind::PassphraseNotInKdfList)?;
 
@@ -221,7 +606,13 @@ pub fn add_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
             .keys
             .insert(
                 new_name.to_owned(),
-                create_key(new_passphrase, &mut key_chain, Utc::now(), None),
+                create_key(
+                    new_passphrase,
+                    &mut key_chain,
+                    Utc::now(),
+                    None,
+                    None,
+                ),
             )
             .is_some()
         {
@@ -233,18 +624,162 @@ pub fn add_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
     })
 }
 
+/// Fails with `MinKeyPolicyViolation` if `kdflist` has fewer keys overall or
+/// in the `root` group than its `min_keys` policy requires.
+///
+/// The `root` group is counted separately because it is the one group whose
+/// loss cannot be recovered from: without a key in it, the key store can no
+/// longer be changed at all. This means that a key in `root` cannot be
+/// deleted once `root` is down to the minimum, even if plenty of other keys
+/// remain; another key must be associated with `root` first. User groups are
+/// not counted, since losing one of those only loses what it protects.
+fn check_min_keys(kdflist: &KdfList) -> Result<()> {
+    if let Some(min) = kdflist.min_keys {
+        let root_keys = kdflist
+            .keys
+            .values()
+            .filter(|e| e.groups.contains_key(GROUP_ROOT))
+            .count();
+        if kdflist.keys.len() < min as usize || root_keys < min as usize {
+            return Err(ErrorKind::MinKeyPolicyViolation(min).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets (or, with `None`, clears) the minimum number of keys which must
+/// remain in the key store and in the `root` group.
+///
+/// Once set, `del_key` and `disassoc_group` refuse to drop below that number,
+/// either overall or in the `root` group (see `check_min_keys`). Fails with
+/// `MinKeyPolicyViolation` if the key store does not already satisfy the new
+/// policy.
+pub fn set_min_keys<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
+    storage: &S,
+    min_keys: Option<u32>,
+    get_root_passphrase: P,
+) -> Result<()> {
+    let edit = KdfEdit::new(
+        "set-min-keys",
+        min_keys.into_iter().map(|n| n.to_string()).collect(),
+    );
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, _root_key| {
+        kdflist.min_keys = min_keys;
+        check_min_keys(kdflist)
+    })
+}
+
+/// Sets the format in which directory versions in the store are encrypted to
+/// `fmt`, one of the `DIR_VER_FMT_*` constants.
+///
+/// This only changes which format key chains from `derive_key_chain` use, so
+/// it fails with `StoreNotEmpty` once a replica has been opened on the store.
+/// Existing stores are upgraded with `ServerReplica::upgrade_dir_ver_fmt()`
+/// instead, which converts the directories first.
+pub fn set_dir_ver_fmt<
+    S: Storage + ?Sized,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    fmt: u32,
+    get_root_passphrase: P,
+) -> Result<()> {
+    check_store_empty(storage)?;
+    record_dir_ver_fmt(storage, fmt, get_root_passphrase)
+}
+
+/// Like `set_dir_ver_fmt`, but without checking whether the store is empty.
+pub(super) fn record_dir_ver_fmt<
+    S: Storage + ?Sized,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    fmt: u32,
+    get_root_passphrase: P,
+) -> Result<()> {
+    if !is_supported_dir_ver_fmt(fmt) {
+        return Err(ErrorKind::UnsupportedDirVerFormat(fmt).into());
+    }
+
+    let edit = KdfEdit::new("set-dir-ver-fmt", vec![fmt.to_string()]);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, _root_key| {
+        kdflist.dir_ver_fmt = Some(fmt);
+        Ok(())
+    })
+}
+
+/// Fails with `StoreNotEmpty` if a replica has ever been opened on `storage`,
+/// i.e., if the pseudo-root directory exists.
+///
+/// This is conservative: a store which was opened but never had anything
+/// written to it is still considered non-empty.
+fn check_store_empty<S: Storage + ?Sized>(storage: &S) -> Result<()> {
+    if storage.getdir(&DIRID_PROOT)?.is_some() {
+        Err(ErrorKind::StoreNotEmpty.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Sets the hash variant used for block and stream ids in the store.
+///
+/// Nothing is rehashed, so this fails with `StoreNotEmpty` once a replica has
+/// been opened on the store; data stored under the old variant would fail to
+/// verify afterwards.
+pub fn set_hash_variant<
+    S: Storage + ?Sized,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    variant: HashVariant,
+    get_root_passphrase: P,
+) -> Result<()> {
+    check_store_empty(storage)?;
+
+    let edit =
+        KdfEdit::new("set-hash-variant", vec![variant.code().to_string()]);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, _root_key| {
+        kdflist.hash_variant = Some(variant.code());
+        Ok(())
+    })
+}
+
+/// Sets the AES key size used for objects and directories in the store.
+///
+/// Nothing is re-encrypted, so this fails with `StoreNotEmpty` once a replica
+/// has been opened on the store; existing data would become unreadable. The
+/// key store and its audit log always use `CipherWidth::Aes128` regardless.
+pub fn set_cipher_width<
+    S: Storage + ?Sized,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    width: CipherWidth,
+    get_root_passphrase: P,
+) -> Result<()> {
+    check_store_empty(storage)?;
+
+    let edit = KdfEdit::new("set-cipher-width", vec![width.code().to_string()]);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, _root_key| {
+        kdflist.cipher_width = Some(width.code());
+        Ok(())
+    })
+}
+
 /// Deletes the key identified by `name`.
 ///
 /// This fails if `name` identifies the last key in the key store, since
 /// removing it would make it impossible to ever derive any internal keys
 /// again. It also fails if the key corresponding to `name` is the last key in
 /// any particular group.
-pub fn del_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
+pub fn del_key<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
     storage: &S,
     name: &str,
     get_root_passphrase: P,
 ) -> Result<()> {
-    edit_kdflist(storage, get_root_passphrase, |kdflist, _root_key| {
+    let edit = KdfEdit::new("del-key", vec![name.to_owned()]);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, _root_key| {
         let old_entry = kdflist
             .keys
             .remove(name)
@@ -253,6 +788,7 @@ pub fn del_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
         if kdflist.keys.is_empty() {
             return Err(ErrorKind::WouldRemoveLastKdfEntry.into());
         }
+        check_min_keys(kdflist)?;
 
         old_entry
             .groups
@@ -276,6 +812,36 @@ pub fn del_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
     })
 }
 
+/// Renames the key identified by `old_name` to `new_name`.
+///
+/// As with `del_key`, the passphrase of the key itself is not needed. The
+/// entry is otherwise unchanged, so its timestamps are kept as they were.
+pub fn rename_key<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
+    storage: &S,
+    old_name: &str,
+    new_name: &str,
+    get_root_passphrase: P,
+) -> Result<()> {
+    let edit = KdfEdit::new(
+        "rename-key",
+        vec![old_name.to_owned(), new_name.to_owned()],
+    );
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, _root_key| {
+        if kdflist.keys.contains_key(new_name) {
+            return Err(
+                ErrorKind::KeyNameAlreadyInUse(new_name.to_owned()).into()
+            );
+        }
+
+        let entry = kdflist
+            .keys
+            .remove(old_name)
+            .ok_or_else(|| ErrorKind::KeyNotInKdfList(old_name.to_owned()))?;
+        kdflist.keys.insert(new_name.to_owned(), entry);
+        Ok(())
+    })
+}
+
 /// Changes the passphrase of a single key.
 ///
 /// If `name` is `Some`, it names the key to edit. Otherwise, there must be
@@ -288,19 +854,126 @@ pub fn del_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
 ///
 /// If the passphrase being changed is not the one being used to derive the
 /// internal keys, the latter must be in a superset of groups as the former.
-pub fn change_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
+///
+/// The new entry uses the same KDF algorithm as the one it replaces, or the
+/// default if that algorithm is not supported. Use `change_key_with_algorithm`
+/// to choose a different one.
+///
+/// As with `add_key`, an empty `new_passphrase` is refused.
+pub fn change_key<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
+    storage: &S,
+    old_passphrase: &[u8],
+    new_passphrase: &[u8],
+    name: Option<&str>,
+    allow_change_via_other_passphrase: bool,
+    get_root_passphrase: P,
+) -> Result<()> {
+    change_key_impl(
+        storage,
+        old_passphrase,
+        new_passphrase,
+        name,
+        allow_change_via_other_passphrase,
+        None,
+        None,
+        get_root_passphrase,
+    )
+}
+
+/// Like `change_key`, but derives the new entry with the KDF algorithm named
+/// by `algorithm` if it is `Some`.
+///
+/// This allows strengthening (or weakening) the KDF of a key while changing
+/// its passphrase. An invalid `algorithm` is rejected before anything is
+/// written.
+pub fn change_key_with_algorithm<
+    S: Storage + ?Sized,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    old_passphrase: &[u8],
+    new_passphrase: &[u8],
+    name: Option<&str>,
+    allow_change_via_other_passphrase: bool,
+    algorithm: Option<&str>,
+    get_root_passphrase: P,
+) -> Result<()> {
+    change_key_impl(
+        storage,
+        old_passphrase,
+        new_passphrase,
+        name,
+        allow_change_via_other_passphrase,
+        algorithm,
+        None,
+        get_root_passphrase,
+    )
+}
+
+/// Like `change_key_with_algorithm`, but if `name` is `None` and there is more
+/// than one key in the key store, `resolver` (if given) is invoked with
+/// information on every key to choose which one to edit, instead of failing
+/// with `AnonChangeKeyButMultipleKdfEntries`.
+///
+/// The resolver returns the name of the chosen key, or an error to abort the
+/// change. It is called within the key store transaction, so it should not
+/// access the key store itself.
+pub fn change_key_resolving<
+    S: Storage + ?Sized,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    old_passphrase: &[u8],
+    new_passphrase: &[u8],
+    name: Option<&str>,
+    allow_change_via_other_passphrase: bool,
+    algorithm: Option<&str>,
+    resolver: Option<&mut dyn FnMut(&[KeyInfo]) -> Result<String>>,
+    get_root_passphrase: P,
+) -> Result<()> {
+    change_key_impl(
+        storage,
+        old_passphrase,
+        new_passphrase,
+        name,
+        allow_change_via_other_passphrase,
+        algorithm,
+        resolver,
+        get_root_passphrase,
+    )
+}
+
+fn change_key_impl<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
     storage: &S,
     old_passphrase: &[u8],
     new_passphrase: &[u8],
     name: Option<&str>,
     allow_change_via_other_passphrase: bool,
+    algorithm: Option<&str>,
+    mut resolver: Option<&mut dyn FnMut(&[KeyInfo]) -> Result<String>>,
     get_root_passphrase: P,
 ) -> Result<()> {
-    edit_kdflist(storage, get_root_passphrase, |kdflist, root_key| {
+    check_new_passphrase(new_passphrase, false)?;
+    if let Some(algorithm) = algorithm {
+        validate_kdf_algorithm(algorithm)?;
+    }
+
+    let edit = KdfEdit::new(
+        "change-key",
+        name.into_iter().map(str::to_owned).collect(),
+    );
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, root_key| {
         let real_name = if let Some(name) = name {
             name.to_owned()
         } else if 1 == kdflist.keys.len() {
             kdflist.keys.iter().next().unwrap().0.to_owned()
+        } else if let Some(ref mut resolver) = resolver {
+            let keys: Vec<KeyInfo> = kdflist
+                .keys
+                .iter()
+                .map(|(name, e)| KeyInfo::new(name, e))
+                .collect();
+            resolver(&keys)?
         } else {
             return Err(ErrorKind::AnonChangeKeyButMultipleKdfEntries.into());
         };
@@ -332,6 +1005,18 @@ pub fn change_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
 
         root_key.chain(&key_chain);
 
+        // Report every group the source can't provide at once rather than
+        // just the first, so the user can see the full extent of the problem.
+        let missing: Vec<String> = old_entry
+            .groups
+            .keys()
+            .filter(|g| !key_chain.keys.contains_key(g.as_str()))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(ErrorKind::KeyNotInGroups(missing).into());
+        }
+
         let mut new_chain = KeyChain::empty();
         for group in old_entry.groups.keys() {
             new_chain
@@ -339,19 +1024,97 @@ pub fn change_key<S: Storage + ?Sized, P: FnMut() -> Result<Vec<u8>>>(
                 .insert(group.to_owned(), key_chain.key(group)?.clone());
         }
 
-        kdflist.keys.insert(
-            real_name,
-            create_key(
-                new_passphrase,
-                &mut new_chain,
-                old_entry.created,
-                Some(Utc::now()),
-            ),
-        );
+        let algorithm = algorithm.unwrap_or_else(|| {
+            if validate_kdf_algorithm(&old_entry.algorithm).is_ok() {
+                old_entry.algorithm.as_str()
+            } else {
+                DEFAULT_KDF_ALGORITHM
+            }
+        });
+        let mut new_entry = create_key_with_algorithm(
+            new_passphrase,
+            &mut new_chain,
+            old_entry.created,
+            Some(not_before(old_entry.created, Utc::now())),
+            algorithm,
+        )?;
+        // Fields written by a newer version may well still apply to the
+        // logical key, so carry them over.
+        new_entry.unknown = old_entry.unknown.clone();
+        kdflist.keys.insert(real_name, new_entry);
+        Ok(())
+    })
+}
+
+/// Recreates every entry in the key store with a fresh salt, and thus fresh
+/// XOR pads between its derived key and the internal keys of its groups.
+///
+/// This does NOT change the internal keys themselves, and so does nothing for
+/// data already encrypted under them; it only helps if the stored pads of an
+/// entry are suspected to have leaked while the internal keys have not.
+///
+/// Since each new pad requires re-running the KDF of that entry,
+/// `get_passphrase` is called with the name of every key to obtain its
+/// passphrase (at most once per key, even if the transaction is retried). If it
+/// fails, or returns a passphrase which does not belong to that key (which
+/// fails with `PassphraseNotForKey`), nothing is changed.
+///
+/// Timestamps, comments, KDF algorithms, and salt lengths of the entries are
+/// kept, except that `updated` is set to the current time.
+pub fn rewrap_keys<
+    S: Storage + ?Sized,
+    K: FnMut(&str) -> Result<Passphrase>,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    mut get_passphrase: K,
+    get_root_passphrase: P,
+) -> Result<()> {
+    let mut passphrases: BTreeMap<String, Passphrase> = BTreeMap::new();
+    let names = list_keys(storage)?.into_iter().map(|k| k.name).collect();
+
+    let edit = KdfEdit::new("rewrap-keys", names);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, root_key| {
+        let now = Utc::now();
+        let mut keys = BTreeMap::new();
+        for (name, entry) in &kdflist.keys {
+            if !passphrases.contains_key(name) {
+                passphrases.insert(name.clone(), get_passphrase(name)?);
+            }
+            let passphrase = &passphrases[name];
+
+            let mut chain = try_derive_key_single(passphrase, entry)
+                .ok_or_else(|| ErrorKind::PassphraseNotForKey(name.clone()))?;
+            root_key.chain(&chain);
+
+            let mut new_entry = create_key_full(
+                passphrase,
+                &mut chain,
+                entry.created,
+                Some(not_before(entry.created, now)),
+                &entry.algorithm,
+                entry.salt.len(),
+            )?;
+            new_entry.used = entry.used;
+            new_entry.comment = entry.comment.clone();
+            new_entry.unknown = entry.unknown.clone();
+            keys.insert(name.clone(), new_entry);
+        }
+
+        kdflist.keys = keys;
         Ok(())
     })
 }
 
+/// Returns `now`, or `created` if `now` is earlier.
+///
+/// Used when stamping an entry so that a system clock which has been set
+/// backwards cannot make an entry look like it was updated or used before it
+/// was created.
+fn not_before(created: DateTime<Utc>, now: DateTime<Utc>) -> DateTime<Utc> {
+    now.max(created)
+}
+
 /// Fetches the KDF list and uses `passphrase` to derive the key chain.
 pub fn derive_key_chain<S: Storage + ?Sized>(
     storage: &S,
@@ -359,13 +1122,41 @@ pub fn derive_key_chain<S: Storage + ?Sized>(
 ) -> Result<KeyChain> {
     let (kdflist, _, _) =
         get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
-    for entry in kdflist.keys.values() {
-        if let Some(key_chain) = try_derive_key_single(passphrase, entry) {
-            return Ok(key_chain);
-        }
-    }
+    derive_key_entry(&kdflist, passphrase).map(|(_, key_chain)| key_chain)
+}
 
-    Err(ErrorKind::PassphraseNotInKdfList.into())
+/// Finds the entry in `kdflist` matching `passphrase`, returning its name and
+/// the key chain derived from it.
+fn derive_key_entry(
+    kdflist: &KdfList,
+    passphrase: &[u8],
+) -> Result<(String, KeyChain)> {
+    let dir_ver_fmt = kdflist.dir_ver_fmt.unwrap_or(DIR_VER_FMT_LEGACY);
+    if !is_supported_dir_ver_fmt(dir_ver_fmt) {
+        return Err(ErrorKind::UnsupportedDirVerFormat(dir_ver_fmt).into());
+    }
+    let hash_variant = match kdflist.hash_variant {
+        None => HashVariant::default(),
+        Some(code) => HashVariant::from_code(code)
+            .ok_or(ErrorKind::UnsupportedHashVariant(code))?,
+    };
+    let cipher_width = match kdflist.cipher_width {
+        None => CipherWidth::default(),
+        Some(code) => CipherWidth::from_code(code)
+            .ok_or(ErrorKind::UnsupportedCipherWidth(code))?,
+    };
+
+    // Trying every key can take a while, so let an interrupted sync stop it.
+    let (name, mut key_chain) = try_derive_key_cancellable(
+        passphrase,
+        &kdflist.keys,
+        interrupt::is_interrupted,
+    )?
+    .ok_or(ErrorKind::PassphraseNotInKdfList)?;
+    key_chain.dir_ver_fmt = dir_ver_fmt;
+    key_chain.hash_variant = hash_variant;
+    key_chain.cipher_width = cipher_width;
+    Ok((name.to_owned(), key_chain))
 }
 
 /// Create a group with each given name on the key with the given passphrase.
@@ -374,7 +1165,7 @@ pub fn derive_key_chain<S: Storage + ?Sized>(
 pub fn create_group<
     S: Storage + ?Sized,
     IT: Iterator + Clone,
-    P: FnMut() -> Result<Vec<u8>>,
+    P: FnMut() -> Result<Passphrase>,
 >(
     storage: &S,
     passphrase: &[u8],
@@ -384,13 +1175,34 @@ pub fn create_group<
 where
     IT::Item: AsRef<str>,
 {
-    for name in names.clone() {
-        if name.as_ref().is_empty() {
+    create_group_with(storage, passphrase, names, false, get_root_passphrase)
+}
+
+/// Like `create_group`, but if `verify` is set, checks before writing the key
+/// store that `passphrase` still derives every group it did before.
+pub fn create_group_with<
+    S: Storage + ?Sized,
+    IT: Iterator + Clone,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    passphrase: &[u8],
+    names: IT,
+    verify: bool,
+    get_root_passphrase: P,
+) -> Result<()>
+where
+    IT::Item: AsRef<str>,
+{
+    for name in names.clone() {
+        if name.as_ref().is_empty() {
             return Err(ErrorKind::EmptyKeyGroupName.into());
         }
     }
 
-    edit_kdflist(storage, get_root_passphrase, |kdflist, root_key| {
+    let edit = KdfEdit::new("create-group", audit_names(names.clone()))
+        .verified(passphrase, verify);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, root_key| {
         for name in names.clone() {
             let name = name.as_ref();
             for (_, e) in &kdflist.keys {
@@ -427,18 +1239,47 @@ where
 pub fn assoc_group<
     S: Storage + ?Sized,
     IT: Iterator + Clone,
-    P: FnMut() -> Result<Vec<u8>>,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    src_passphrase: &[u8],
+    dst_passphrase: &[u8],
+    names: IT,
+    get_root_passphrase: P,
+) -> Result<()>
+where
+    IT::Item: AsRef<str>,
+{
+    assoc_group_with(
+        storage,
+        src_passphrase,
+        dst_passphrase,
+        names,
+        false,
+        get_root_passphrase,
+    )
+}
+
+/// Like `assoc_group`, but if `verify` is set, checks before writing the key
+/// store that `src_passphrase` still derives every group it did before.
+pub fn assoc_group_with<
+    S: Storage + ?Sized,
+    IT: Iterator + Clone,
+    P: FnMut() -> Result<Passphrase>,
 >(
     storage: &S,
     src_passphrase: &[u8],
     dst_passphrase: &[u8],
     names: IT,
+    verify: bool,
     get_root_passphrase: P,
 ) -> Result<()>
 where
     IT::Item: AsRef<str>,
 {
-    edit_kdflist(storage, get_root_passphrase, |kdflist, root_key| {
+    let edit = KdfEdit::new("assoc-group", audit_names(names.clone()))
+        .verified(src_passphrase, verify);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, root_key| {
         let src_chain = try_derive_key(src_passphrase, &kdflist.keys)
             .ok_or_else(|| ErrorKind::PassphraseNotInKdfList)?;
         root_key.chain(&src_chain);
@@ -470,20 +1311,88 @@ where
     })
 }
 
+/// Like `assoc_group`, but adds the groups to the entries corresponding to
+/// each of `dst_passphrases` at once, deriving the source key chain only once.
+///
+/// If `skip_existing` is false, it is an error for any destination entry to
+/// already be associated with one of the groups. Otherwise, such groups are
+/// simply left alone on that entry. Either way, nothing is changed unless all
+/// destination entries can be updated. `verify` is as for `assoc_group_with`.
+pub fn assoc_group_multi<
+    S: Storage + ?Sized,
+    IT: Iterator + Clone,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    src_passphrase: &[u8],
+    dst_passphrases: &[&[u8]],
+    names: IT,
+    skip_existing: bool,
+    verify: bool,
+    get_root_passphrase: P,
+) -> Result<()>
+where
+    IT::Item: AsRef<str>,
+{
+    let edit = KdfEdit::new("assoc-group", audit_names(names.clone()))
+        .verified(src_passphrase, verify);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, root_key| {
+        let src_chain = try_derive_key(src_passphrase, &kdflist.keys)
+            .ok_or_else(|| ErrorKind::PassphraseNotInKdfList)?;
+        root_key.chain(&src_chain);
+
+        'dsts: for dst_passphrase in dst_passphrases {
+            for (_, e) in &mut kdflist.keys {
+                if let Some(mut key_chain) =
+                    try_derive_key_single(dst_passphrase, e)
+                {
+                    root_key.chain(&key_chain);
+
+                    for name in names.clone() {
+                        let name = name.as_ref();
+                        if key_chain.keys.contains_key(name) {
+                            if skip_existing {
+                                continue;
+                            }
+                            return Err(ErrorKind::KeyAlreadyInGroup(
+                                name.to_owned(),
+                            )
+                            .into());
+                        }
+                        key_chain.keys.insert(
+                            name.to_owned(),
+                            src_chain.key(name)?.clone(),
+                        );
+                    }
+                    reassoc_keys(e, &mut key_chain);
+                    continue 'dsts;
+                }
+            }
+
+            return Err(ErrorKind::PassphraseNotInKdfList.into());
+        }
+
+        Ok(())
+    })
+}
+
 /// Disassociates the key named by `key` from all groups named in `names`.
 ///
 /// It is an error to disassociate a group not associated, to disassociate
 /// `everyone`, or to disassociate a group which has only one associated key.
+///
+/// On success, returns each group in `names`, in the order given, together with
+/// the keys still associated with it.
 pub fn disassoc_group<
     S: Storage + ?Sized,
     IT: Iterator + Clone,
-    P: FnMut() -> Result<Vec<u8>>,
+    P: FnMut() -> Result<Passphrase>,
 >(
     storage: &S,
     key: &str,
     names: IT,
     get_root_passphrase: P,
-) -> Result<()>
+) -> Result<Vec<GroupInfo>>
 where
     IT::Item: AsRef<str>,
 {
@@ -496,7 +1405,11 @@ where
         }
     }
 
-    edit_kdflist(storage, get_root_passphrase, |kdflist, _root_key| {
+    let mut audit = vec![key.to_owned()];
+    audit.extend(audit_names(names.clone()));
+
+    let edit = KdfEdit::new("disassoc-group", audit);
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, _root_key| {
         {
             let entry = kdflist
                 .keys
@@ -511,18 +1424,30 @@ where
             }
         }
 
+        let mut remaining = Vec::new();
         for name in names.clone() {
             let name = name.as_ref();
-            if !kdflist.keys.values().any(|e| e.groups.contains_key(name)) {
+            let holders: Vec<String> = kdflist
+                .keys
+                .iter()
+                .filter(|&(_, e)| e.groups.contains_key(name))
+                .map(|(k, _)| k.to_owned())
+                .collect();
+            if holders.is_empty() {
                 return Err(ErrorKind::WouldDisassocLastKeyFromGroup(
                     key.to_owned(),
                     name.to_owned(),
                 )
                 .into());
             }
+            remaining.push(GroupInfo {
+                name: name.to_owned(),
+                keys: holders,
+            });
         }
 
-        Ok(())
+        check_min_keys(kdflist)?;
+        Ok(remaining)
     })
 }
 
@@ -532,7 +1457,7 @@ where
 pub fn destroy_group<
     S: Storage + ?Sized,
     IT: Iterator + Clone,
-    P: FnMut() -> Result<Vec<u8>>,
+    P: FnMut() -> Result<Passphrase>,
 >(
     storage: &S,
     names: IT,
@@ -548,7 +1473,8 @@ where
         }
     }
 
-    edit_kdflist(storage, get_root_passphrase, |kdflist, _root_key| {
+    let edit = KdfEdit::new("destroy-group", audit_names(names.clone()));
+    edit_kdflist(storage, edit, get_root_passphrase, |kdflist, _root_key| {
         for name in names.clone() {
             let name = name.as_ref();
             let mut found = false;
@@ -574,653 +1500,2929 @@ pub struct KeyInfo {
     pub algorithm: String,
     pub created: DateTime<Utc>,
     pub updated: Option<DateTime<Utc>>,
+    pub used: Option<DateTime<Utc>>,
+    pub comment: Option<String>,
     pub groups: Vec<String>,
 }
 
+impl KeyInfo {
+    fn new(name: &str, e: &KdfEntry) -> Self {
+        KeyInfo {
+            name: name.to_owned(),
+            algorithm: e.algorithm.clone(),
+            created: e.created,
+            updated: e.updated,
+            used: e.used,
+            comment: e.comment.clone(),
+            groups: e.groups.keys().map(|s| s.to_owned()).collect(),
+        }
+    }
+
+    /// Returns whether the timestamps of this key are inconsistent, i.e., it
+    /// was updated or used before it was created.
+    ///
+    /// This usually means the system clock was wrong when one of them was
+    /// recorded, so they should not be trusted.
+    pub fn has_clock_anomaly(&self) -> bool {
+        self.updated.map_or(false, |t| t < self.created)
+            || self.used.map_or(false, |t| t < self.created)
+    }
+}
+
+/// The field by which `list_keys_sorted` orders keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeySort {
+    Name,
+    Created,
+    Updated,
+    Used,
+}
+
 /// Fetches the list of keys in the storage.
 ///
 /// If the key store has not been initialised, returns an empty vec.
 pub fn list_keys<S: Storage + ?Sized>(storage: &S) -> Result<Vec<KeyInfo>> {
+    let mut keys = Vec::new();
+    for_each_key(storage, |key| keys.push(key))?;
+    Ok(keys)
+}
+
+/// Invokes `f` with information on each key in the storage, in name order,
+/// without collecting them all into a vec as `list_keys` does.
+///
+/// If the key store has not been initialised, `f` is never called.
+pub fn for_each_key<S: Storage + ?Sized, F: FnMut(KeyInfo)>(
+    storage: &S,
+    mut f: F,
+) -> Result<()> {
     if let Some((kdflist, _, _)) = get_kdflist(storage)? {
-        Ok(kdflist
-            .keys
-            .iter()
-            .map(|(name, e)| KeyInfo {
-                name: name.clone(),
-                algorithm: e.algorithm.clone(),
-                created: e.created,
-                updated: e.updated,
-                groups: e.groups.keys().map(|s| s.to_owned()).collect(),
-            })
-            .collect())
-    } else {
-        Ok(vec![])
+        for (name, e) in &kdflist.keys {
+            f(KeyInfo::new(name, e));
+        }
     }
+    Ok(())
 }
 
-// These tests are going to be extremely slow on debug builds since they call
-// into the scrypt stuff.
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::server::local_storage::LocalStorage;
+/// Like `list_keys`, but orders the result by `sort`, in descending order if
+/// `descending` is true.
+///
+/// Keys with no value for the selected timestamp always sort last, regardless
+/// of direction. Ties are broken by name.
+pub fn list_keys_sorted<S: Storage + ?Sized>(
+    storage: &S,
+    sort: KeySort,
+    descending: bool,
+) -> Result<Vec<KeyInfo>> {
+    let mut keys = list_keys(storage)?;
+    // `list_keys` returns keys in name order, so a stable sort keeps ties
+    // ordered by name.
+    keys.sort_by(|a, b| {
+        let (a_time, b_time) = match sort {
+            KeySort::Name => {
+                return if descending {
+                    b.name.cmp(&a.name)
+                } else {
+                    a.name.cmp(&b.name)
+                };
+            }
+            KeySort::Created => (Some(a.created), Some(b.created)),
+            KeySort::Updated => (a.updated, b.updated),
+            KeySort::Used => (a.used, b.used),
+        };
 
-    fn no_prompt() -> Result<Vec<u8>> {
-        panic!("shouldn't prompt");
-    }
+        match (a_time, b_time) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(a), Some(b)) if descending => b.cmp(&a),
+            (Some(a), Some(b)) => a.cmp(&b),
+        }
+    });
+    Ok(keys)
+}
 
-    macro_rules! init {
-        ($storage:ident) => {
-            let dir = tempfile::Builder::new()
-                .prefix("keymgmt")
-                .tempdir()
-                .unwrap();
-            let $storage = LocalStorage::open(dir.path()).unwrap();
-        };
-    }
+/// Derives the key chain like `derive_key_chain`, and records the current time
+/// as the time the matching key was last used.
+///
+/// The time can only be recorded if the key is in the `root` group, since the
+/// key store cannot otherwise be written; for other keys this is equivalent to
+/// `derive_key_chain`.
+pub fn record_key_use<S: Storage + ?Sized>(
+    storage: &S,
+    passphrase: &[u8],
+) -> Result<KeyChain> {
+    record_key_use_throttled(storage, passphrase, Duration::zero())
+}
 
-    macro_rules! assert_err {
-        ($expected:pat, $actual:expr) => {
-            match $actual {
-                Ok(_) => panic!("Call succeeded unexpectedly"),
-                Err(Error($expected, _)) => {}
-                Err(e) => panic!("Error was not the expected error: {:?}", e),
-            }
-        };
-    }
+/// Like `record_key_use`, but leaves the key store alone if the recorded
+/// last-used time of the key is less than `min_interval` ago.
+///
+/// This avoids rewriting the key store on every unlock of a key which is used
+/// very frequently, e.g. by automated syncs. A `min_interval` of zero always
+/// records the time.
+pub fn record_key_use_throttled<S: Storage + ?Sized>(
+    storage: &S,
+    passphrase: &[u8],
+    min_interval: Duration,
+) -> Result<KeyChain> {
+    record_key_use_full(storage, passphrase, min_interval, Utc::now())
+        .map(|(_, key_chain)| key_chain)
+}
 
-    #[test]
-    fn empty() {
-        init!(storage);
-        assert_err!(
-            ErrorKind::KdfListNotExists,
-            add_key(&storage, b"a", b"b", "name", no_prompt)
-        );
-        assert_err!(
-            ErrorKind::KdfListNotExists,
-            change_key(&storage, b"a", b"b", None, false, no_prompt)
-        );
-        assert_err!(
-            ErrorKind::KdfListNotExists,
-            del_key(&storage, "name", no_prompt)
-        );
-        assert!(list_keys(&storage).unwrap().is_empty());
+/// Like `record_key_use_throttled`, but takes the current time as `now`
+/// rather than reading the system clock, and also returns information on the
+/// key which matched `passphrase`.
+///
+/// If `now` is before the creation time of the key, the creation time is
+/// recorded instead.
+fn record_key_use_full<S: Storage + ?Sized>(
+    storage: &S,
+    passphrase: &[u8],
+    min_interval: Duration,
+    now: DateTime<Utc>,
+) -> Result<(KeyInfo, KeyChain)> {
+    let (kdflist, _, _) =
+        get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
+    let (name, key_chain) = derive_key_entry(&kdflist, passphrase)?;
+    let mut info = KeyInfo::new(&name, &kdflist.keys[&name]);
+    let root = match key_chain.key(GROUP_ROOT) {
+        Ok(root) => root.clone(),
+        Err(_) => return Ok((info, key_chain)),
+    };
+
+    if Some(false) == kdflist.record_used {
+        return Ok((info, key_chain));
     }
 
-    #[test]
-    fn init_keys_adds_one_key_but_fails_if_already_init() {
-        init!(storage);
-
-        init_keys(&storage, b"hunter2", "name").unwrap();
-        assert_err!(
-            ErrorKind::KdfListAlreadyExists,
-            init_keys(&storage, b"hunter3", "name")
-        );
-        derive_key_chain(&storage, b"hunter2").unwrap();
-        assert_err!(
-            ErrorKind::PassphraseNotInKdfList,
-            derive_key_chain(&storage, b"hunter3")
-        );
+    if min_interval > Duration::zero()
+        && info.used.map_or(false, |used| {
+            now.signed_duration_since(used) < min_interval
+        })
+    {
+        return Ok((info, key_chain));
     }
 
-    #[test]
-    fn add_key_creates_new_key() {
-        init!(storage);
+    do_tx(storage, |tx| {
+        let (mut kdflist, old_ver, old_len) =
+            get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
+        if let Some(entry) = kdflist.keys.get_mut(&name) {
+            entry.used = Some(not_before(entry.created, now));
+            info = KeyInfo::new(&name, entry);
+        }
+        put_kdflist(storage, &kdflist, tx, Some((&old_ver, old_len)), &root)?;
+        Ok(())
+    })?;
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+    Ok((info, key_chain))
+}
 
-        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
-        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
-        assert_eq!(mk.keys, mk2.keys);
-    }
+/// The result of `probe`.
+#[derive(Clone, Debug)]
+pub struct ProbeResult {
+    /// Whether the key store has been initialised.
+    pub initialised: bool,
+    /// Whether the passphrase matched any key in the key store.
+    pub authenticated: bool,
+    /// How long it took to fetch the key store.
+    pub fetch_time: time::Duration,
+    /// How long it took to try the passphrase against the key store.
+    pub derive_time: time::Duration,
+}
 
-    #[test]
-    fn add_key_refuses_empty_name() {
-        init!(storage);
+/// Checks that `storage` can be read and that `passphrase` matches a key in
+/// it, without writing anything (in particular, the time the key was used is
+/// not recorded).
+///
+/// Failing to read the key store is reported as an error; an uninitialised
+/// store or an unknown passphrase is reported through the result.
+pub fn probe<S: Storage + ?Sized>(
+    storage: &S,
+    passphrase: &[u8],
+) -> Result<ProbeResult> {
+    let start = Instant::now();
+    let kdflist = get_kdflist(storage)?;
+    let fetch_time = start.elapsed();
+
+    let start = Instant::now();
+    let authenticated =
+        kdflist.as_ref().map_or(false, |&(ref kdflist, _, _)| {
+            try_derive_key(passphrase, &kdflist.keys).is_some()
+        });
+    let derive_time = start.elapsed();
+
+    Ok(ProbeResult {
+        initialised: kdflist.is_some(),
+        authenticated: authenticated,
+        fetch_time: fetch_time,
+        derive_time: derive_time,
+    })
+}
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        assert_err!(
-            ErrorKind::EmptyKeyName,
-            add_key(&storage, b"hunter2", b"hunter3", "", no_prompt)
-        );
+/// Fetches the list of keys in the storage which are associated with `group`.
+///
+/// Fails with `GroupNotInKdfList` if no key is in that group, including if the
+/// key store has not been initialised.
+pub fn keys_in_group<S: Storage + ?Sized>(
+    storage: &S,
+    group: &str,
+) -> Result<Vec<KeyInfo>> {
+    let keys: Vec<KeyInfo> = get_kdflist(storage)?
+        .map(|(kdflist, _, _)| {
+            kdflist
+                .keys
+                .iter()
+                .filter(|&(_, e)| e.groups.contains_key(group))
+                .map(|(name, e)| KeyInfo::new(name, e))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if keys.is_empty() {
+        Err(ErrorKind::GroupNotInKdfList(group.to_owned()).into())
+    } else {
+        Ok(keys)
     }
+}
 
-    #[test]
-    fn add_key_wont_overwrite_existing_key() {
-        init!(storage);
+/// A key group and the keys associated with it, as returned by `list_groups`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupInfo {
+    pub name: String,
+    /// The names of the keys in this group, in sorted order.
+    pub keys: Vec<String>,
+}
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        assert_err!(
-            ErrorKind::KeyNameAlreadyInUse(_),
-            add_key(&storage, b"hunter2", b"hunter3", "original", no_prompt)
-        );
+/// Fetches the list of groups in the storage, in name order, along with the
+/// keys associated with each.
+///
+/// The builtin `everyone` and `root` groups are always included. If the key
+/// store has not been initialised, returns an empty vec.
+pub fn list_groups<S: Storage + ?Sized>(storage: &S) -> Result<Vec<GroupInfo>> {
+    let kdflist = match get_kdflist(storage)? {
+        Some((kdflist, _, _)) => kdflist,
+        None => return Ok(vec![]),
+    };
+
+    let mut groups: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    groups.insert(GROUP_EVERYONE, vec![]);
+    groups.insert(GROUP_ROOT, vec![]);
+    // `kdflist.keys` is sorted by name, so each list of keys is too
+    for (name, entry) in &kdflist.keys {
+        for group in entry.groups.keys() {
+            groups.entry(group).or_default().push(name.clone());
+        }
     }
 
-    #[test]
-    fn add_key_bad_old_pw() {
-        init!(storage);
+    Ok(groups
+        .into_iter()
+        .map(|(name, keys)| GroupInfo {
+            name: name.to_owned(),
+            keys: keys,
+        })
+        .collect())
+}
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        assert_err!(
-            ErrorKind::PassphraseNotInKdfList,
-            add_key(&storage, b"plugh", b"xyzzy", "new", no_prompt)
-        );
+/// Returns the names, in sorted order, of the groups associated with some key
+/// in the storage but not with the key matching `passphrase`.
+///
+/// This lets a user find out which groups to ask to be added to. Only group
+/// names are returned; nothing is derived from the other keys.
+pub fn missing_groups<S: Storage + ?Sized>(
+    storage: &S,
+    passphrase: &[u8],
+) -> Result<Vec<String>> {
+    let (kdflist, _, _) =
+        get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
+    let (_, key_chain) = derive_key_entry(&kdflist, passphrase)?;
+
+    let mut missing = BTreeSet::new();
+    for entry in kdflist.keys.values() {
+        for group in entry.groups.keys() {
+            if !key_chain.keys.contains_key(group) {
+                missing.insert(group.to_owned());
+            }
+        }
     }
+    Ok(missing.into_iter().collect())
+}
 
-    #[test]
-    fn add_key_refuses_duplicate_pw() {
-        init!(storage);
+/// Returns the names, in sorted order, of the groups associated with the key
+/// matching `passphrase`.
+///
+/// This is purely introspective; in particular, it is not recorded as a use of
+/// the key by `record_key_use`.
+pub fn groups_for_passphrase<S: Storage + ?Sized>(
+    storage: &S,
+    passphrase: &[u8],
+) -> Result<Vec<String>> {
+    let (kdflist, _, _) =
+        get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
+    let (name, _) = derive_key_entry(&kdflist, passphrase)?;
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        assert_err!(
-            ErrorKind::PassphraseInKdfList,
-            add_key(&storage, b"hunter2", b"hunter2", "new", no_prompt)
-        );
-    }
+    Ok(kdflist.keys[&name].groups.keys().cloned().collect())
+}
 
-    #[test]
-    fn change_key_doesnt_need_key_name_if_only_one_key() {
-        init!(storage);
+/// Derives the key chain of each key named in `passphrases` with the paired
+/// passphrase and checks that they agree with each other, without writing
+/// anything.
+///
+/// Every key must hold the `everyone` group, and every group held by more than
+/// one of the keys must resolve to the same internal key for each of them.
+/// Keys not named in `passphrases` are not checked.
+///
+/// Fails with `KeyNotInKdfList` or `PassphraseNotForKey` if a key cannot be
+/// derived, and with `GroupKeyMissing` or `GroupKeyMismatch` for the first
+/// inconsistency found.
+pub fn full_consistency_check<S: Storage + ?Sized>(
+    storage: &S,
+    passphrases: &[(&str, &[u8])],
+) -> Result<()> {
+    let (kdflist, _, _) =
+        get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+    // Group name -> (first key holding it, internal key)
+    let mut seen: BTreeMap<String, (&str, InternalKey)> = BTreeMap::new();
+    for &(name, passphrase) in passphrases {
+        let entry = kdflist
+            .keys
+            .get(name)
+            .ok_or_else(|| ErrorKind::KeyNotInKdfList(name.to_owned()))?;
+        let key_chain = try_derive_key_single(passphrase, entry)
+            .ok_or_else(|| ErrorKind::PassphraseNotForKey(name.to_owned()))?;
 
-        change_key(&storage, b"hunter2", b"hunter3", None, false, no_prompt)
+        if !key_chain.keys.contains_key(GROUP_EVERYONE) {
+            return Err(ErrorKind::GroupKeyMissing(
+                GROUP_EVERYONE.to_owned(),
+                name.to_owned(),
+            )
+            .into());
+        }
+
+        for (group, key) in &key_chain.keys {
+            match seen.get(group) {
+                Some(&(first, ref first_key)) => {
+                    if first_key != key {
+                        return Err(ErrorKind::GroupKeyMismatch(
+                            group.to_owned(),
+                            first.to_owned(),
+                            name.to_owned(),
+                        )
+                        .into());
+                    }
+                }
+                None => {
+                    seen.insert(group.to_owned(), (name, key.clone()));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts the key store entries using each KDF algorithm, without deriving
+/// anything.
+///
+/// If the key store has not been initialised, returns an empty map.
+pub fn algorithm_histogram<S: Storage + ?Sized>(
+    storage: &S,
+) -> Result<BTreeMap<String, usize>> {
+    let mut histogram = BTreeMap::new();
+    if let Some((kdflist, _, _)) = get_kdflist(storage)? {
+        for entry in kdflist.keys.values() {
+            *histogram.entry(entry.algorithm.clone()).or_insert(0) += 1;
+        }
+    }
+    Ok(histogram)
+}
+
+/// A single key store entry together with its name, as produced by
+/// `export_key`.
+struct ExportedKey {
+    name: String,
+    entry: KdfEntry,
+}
+
+fourleaf_retrofit!(struct ExportedKey : {} {} {
+    |_context, this|
+    [1] name: String = &this.name,
+    [2] entry: KdfEntry = &this.entry,
+    { Ok(ExportedKey { name: name, entry: entry }) }
+});
+
+/// Serialises the key named `name` so that it can be added to another key
+/// store with `import_key`.
+pub fn export_key<S: Storage + ?Sized>(
+    storage: &S,
+    name: &str,
+) -> Result<Vec<u8>> {
+    let (kdflist, _, _) =
+        get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
+    let entry = kdflist
+        .keys
+        .get(name)
+        .ok_or_else(|| ErrorKind::KeyNotInKdfList(name.to_owned()))?;
+
+    Ok(fourleaf::to_vec(&ExportedKey {
+        name: name.to_owned(),
+        entry: entry.clone(),
+    })?)
+}
+
+/// Adds a key previously produced by `export_key` to this key store.
+///
+/// `passphrase` is the passphrase of the exported key. The key is only
+/// accepted if every group it shares with the `root` key chain of this store
+/// has the same internal key, since otherwise it could not be used to read
+/// anything here. Fails with `IncompatibleKeyImport` if that is not the case.
+///
+/// `get_root_passphrase` is only called once the checks which do not need it
+/// have passed, and at most once even if the transaction is retried.
+pub fn import_key<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
+    storage: &S,
+    data: &[u8],
+    passphrase: &[u8],
+    mut get_root_passphrase: P,
+) -> Result<()> {
+    let imported: ExportedKey =
+        fourleaf::from_slice_copy(data, &fourleaf::DeConfig::default())?;
+    if imported.name.is_empty() {
+        return Err(ErrorKind::EmptyKeyName.into());
+    }
+
+    let imported_chain = try_derive_key_single(passphrase, &imported.entry)
+        .ok_or("Passphrase does not match the imported key")?;
+
+    let mut root_chain: Option<KeyChain> = None;
+    let edit = KdfEdit::new("import-key", vec![imported.name.clone()]);
+    edit_kdflist(
+        storage,
+        edit,
+        // `root_key` is always set by the edit itself
+        || Err(ErrorKind::PassphraseNotInKdfList.into()),
+        |kdflist, root_key| {
+            if kdflist.keys.contains_key(&imported.name) {
+                return Err(ErrorKind::KeyNameAlreadyInUse(
+                    imported.name.clone(),
+                )
+                .into());
+            }
+
+            if root_chain.is_none() {
+                let root_passphrase = get_root_passphrase()?;
+                root_chain = Some(
+                    try_derive_key(&root_passphrase, &kdflist.keys)
+                        .ok_or(ErrorKind::PassphraseNotInKdfList)?,
+                );
+            }
+            let root_chain = root_chain.as_ref().unwrap();
+            root_key.chain(root_chain);
+
+            let incompatible = imported_chain.key(GROUP_EVERYONE).ok()
+                != root_chain.key(GROUP_EVERYONE).ok()
+                || imported_chain.keys.iter().any(|(group, key)| {
+                    root_chain.keys.get(group).map_or(false, |k| k != key)
+                });
+            if incompatible {
+                return Err(ErrorKind::IncompatibleKeyImport(
+                    imported.name.clone(),
+                )
+                .into());
+            }
+
+            if try_derive_key(passphrase, &kdflist.keys).is_some() {
+                return Err(ErrorKind::PassphraseInKdfList.into());
+            }
+
+            kdflist
+                .keys
+                .insert(imported.name.clone(), imported.entry.clone());
+            Ok(())
+        },
+    )
+}
+
+/// Magic bytes at the start of the output of `export_kdflist`.
+const KDFLIST_EXPORT_MAGIC: &[u8; 8] = b"ensynckl";
+/// The version of the header written by `export_kdflist`, following
+/// `KDFLIST_EXPORT_MAGIC` as a little-endian `u32`.
+const KDFLIST_EXPORT_VERSION: u32 = 1;
+
+fn encode_kdflist_export(kdflist: &KdfList) -> Result<Vec<u8>> {
+    let mut data = KDFLIST_EXPORT_MAGIC.to_vec();
+    data.extend_from_slice(&KDFLIST_EXPORT_VERSION.to_le_bytes());
+    data.extend_from_slice(&fourleaf::to_vec(kdflist)?);
+    Ok(data)
+}
+
+fn decode_kdflist_export(data: &[u8]) -> Result<KdfList> {
+    let header_len = KDFLIST_EXPORT_MAGIC.len() + 4;
+    if data.len() < header_len
+        || &data[..KDFLIST_EXPORT_MAGIC.len()] != KDFLIST_EXPORT_MAGIC
+    {
+        return Err(ErrorKind::NotKdfListExport.into());
+    }
+
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&data[KDFLIST_EXPORT_MAGIC.len()..header_len]);
+    let version = u32::from_le_bytes(version);
+    if version > KDFLIST_EXPORT_VERSION {
+        return Err(ErrorKind::UnsupportedKdfListExport(version).into());
+    }
+
+    let mut config = fourleaf::DeConfig::default();
+    config.max_blob = 16 * 1024 * 1024;
+    config.max_collect = 65536;
+    let kdflist: KdfList =
+        fourleaf::from_slice_copy(&data[header_len..], &config)?;
+    check_format_version(&kdflist)?;
+    Ok(kdflist)
+}
+
+/// Serialises the whole key store so that it can later be restored with
+/// `import_kdflist` or `restore_kdflist`.
+///
+/// The result contains only what is already stored unencrypted on the server,
+/// but is still only as secure as the weakest passphrase in it.
+pub fn export_kdflist<S: Storage + ?Sized>(storage: &S) -> Result<Vec<u8>> {
+    let (kdflist, _, _) =
+        get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
+    encode_kdflist_export(&kdflist)
+}
+
+/// Writes a key store previously produced by `export_kdflist` back to the
+/// server in its entirety, including its policy settings.
+///
+/// Unlike `import_kdflist`, this works when the store has no key store at all,
+/// e.g. because it was lost or deliberately removed. If there already is one,
+/// this fails with `KdfListAlreadyExists` unless `force` is true, in which
+/// case it is replaced, but its audit log is carried over rather than the one
+/// of the backup.
+///
+/// The root passphrase must be valid in the exported key store. When `force`
+/// is used to replace an existing key store, the root passphrase must also be
+/// valid in that one and derive the same `root` internal key from it, since
+/// otherwise the backup is of some other store; this fails with
+/// `IncompatibleKdfListImport` if that is not the case.
+pub fn restore_kdflist<
+    S: Storage + ?Sized,
+    P: FnMut() -> Result<Passphrase>,
+>(
+    storage: &S,
+    data: &[u8],
+    force: bool,
+    mut get_root_passphrase: P,
+) -> Result<()> {
+    let imported = decode_kdflist_export(data)?;
+    let root_passphrase = get_root_passphrase()?;
+    let root_chain = try_derive_key(&root_passphrase, &imported.keys)
+        .ok_or(ErrorKind::PassphraseNotInKdfList)?;
+    let root = root_chain.key(GROUP_ROOT)?;
+
+    do_tx(storage, |tx| {
+        let existing = get_kdflist(storage)?;
+        if existing.is_some() && !force {
+            return Err(ErrorKind::KdfListAlreadyExists.into());
+        }
+
+        let mut kdflist = imported.clone();
+        if let Some((ref live, _, _)) = existing {
+            match try_derive_key(&root_passphrase, &live.keys) {
+                Some(ref live_chain)
+                    if live_chain.key(GROUP_ROOT).ok() == Some(root) => {}
+                _ => return Err(ErrorKind::IncompatibleKdfListImport.into()),
+            }
+
+            kdflist.audit_head = live.audit_head;
+            kdflist.audit_tail = live.audit_tail.clone();
+        } else if storage.getdir(&DIRID_KEY_AUDIT)?.is_none() {
+            // The backup's log position refers to a log which no longer
+            // exists, so start a new one.
+            kdflist.audit_head = None;
+            kdflist.audit_tail = None;
+        }
+        append_audit_log(
+            storage,
+            tx,
+            &mut kdflist,
+            &AuditEntry::new(
+                "restore-kdflist",
+                audit_names(kdflist.keys.keys()),
+            ),
+            &audit_key(root),
+        )?;
+        put_kdflist(
+            storage,
+            &kdflist,
+            tx,
+            existing.as_ref().map(|&(_, ref ver, len)| (ver, len)),
+            root,
+        )?;
+        Ok(())
+    })
+}
+
+/// Restores the keys of a key store previously produced by `export_kdflist`.
+///
+/// If `merge` is false, the keys in the store are replaced wholesale by the
+/// imported keys. If `merge` is true, keys only present in the store are
+/// kept, and for keys present in both, the imported entry is used except that
+/// the later of the two `used` and `updated` timestamps is kept. Either way,
+/// the audit log and the policy settings of the live store are kept.
+///
+/// The root passphrase must be valid in both the live and the imported key
+/// store and derive the same internal keys from each, since otherwise the
+/// result could not read the data in this store. Fails with
+/// `IncompatibleKdfListImport` if that is not the case.
+pub fn import_kdflist<S: Storage + ?Sized, P: FnMut() -> Result<Passphrase>>(
+    storage: &S,
+    data: &[u8],
+    merge: bool,
+    mut get_root_passphrase: P,
+) -> Result<()> {
+    let imported = decode_kdflist_export(data)?;
+
+    let root_passphrase = get_root_passphrase()?;
+    let edit = KdfEdit::new(
+        if merge {
+            "import-kdflist-merge"
+        } else {
+            "import-kdflist"
+        },
+        audit_names(imported.keys.keys()),
+    );
+    edit_kdflist(
+        storage,
+        edit,
+        || Ok(root_passphrase.clone()),
+        |kdflist, root_key| {
+            let root_chain = try_derive_key(&root_passphrase, &kdflist.keys)
+                .ok_or(ErrorKind::PassphraseNotInKdfList)?;
+            root_key.chain(&root_chain);
+
+            let mut keys = if merge {
+                kdflist.keys.clone()
+            } else {
+                BTreeMap::new()
+            };
+            for (name, entry) in &imported.keys {
+                let mut entry = entry.clone();
+                if let Some(live) = keys.get(name) {
+                    entry.used = entry.used.max(live.used);
+                    entry.updated = entry.updated.max(live.updated);
+                }
+                keys.insert(name.clone(), entry);
+            }
+
+            match try_derive_key(&root_passphrase, &keys) {
+                Some(ref chain) if chain.keys == root_chain.keys => (),
+                _ => return Err(ErrorKind::IncompatibleKdfListImport.into()),
+            }
+
+            kdflist.keys = keys;
+            check_min_keys(kdflist)
+        },
+    )
+}
+
+/// Reads and verifies the key management audit log.
+///
+/// `passphrase` must correspond to a key in the `root` group. Fails with
+/// `AuditLogCorrupt` if any record has been altered, reordered, or removed.
+/// Returns an empty vec if the key store predates the audit log.
+pub fn read_audit_log<S: Storage + ?Sized>(
+    storage: &S,
+    passphrase: &[u8],
+) -> Result<Vec<AuditEntry>> {
+    fn corrupt(message: &str) -> Error {
+        ErrorKind::AuditLogCorrupt(message.to_owned()).into()
+    }
+
+    let (kdflist, _, _) =
+        get_kdflist(storage)?.ok_or(ErrorKind::KdfListNotExists)?;
+    let key_chain = try_derive_key(passphrase, &kdflist.keys)
+        .ok_or(ErrorKind::PassphraseNotInKdfList)?;
+    let key = audit_key(key_chain.key(GROUP_ROOT)?);
+
+    let data = match storage.getdir(&DIRID_KEY_AUDIT)? {
+        Some((_, data)) => data,
+        None if kdflist.audit_head.is_none() => return Ok(vec![]),
+        None => return Err(corrupt("audit log missing")),
+    };
+
+    let mut cleartext = Vec::new();
+    decrypt_whole_dir(&mut cleartext, &data[..], &key)?;
+
+    let mut entries = Vec::new();
+    let mut prev = UNKNOWN_HASH;
+    let mut rest = &cleartext[..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(corrupt("truncated record header"));
+        }
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&rest[..4]);
+        let len = u32::from_le_bytes(len) as usize;
+        let record_len = (4 + len + prev.len() + BLKSZ - 1) / BLKSZ * BLKSZ;
+        if rest.len() < record_len {
+            return Err(corrupt("truncated record"));
+        }
+
+        let entry_data = &rest[4..4 + len];
+        let mut mac = HashId::default();
+        mac.copy_from_slice(&rest[4 + len..4 + len + mac.len()]);
+        if mac != audit_mac(&prev, entry_data, &key) {
+            return Err(corrupt("record MAC mismatch"));
+        }
+        if rest[4 + len + mac.len()..record_len]
+            .iter()
+            .any(|&b| 0 != b)
+        {
+            return Err(corrupt("non-zero record padding"));
+        }
+
+        entries.push(
+            fourleaf::from_slice_copy(
+                entry_data,
+                &fourleaf::DeConfig::default(),
+            )
+            .map_err(|_| corrupt("malformed record"))?,
+        );
+        prev = mac;
+        rest = &rest[record_len..];
+    }
+
+    if Some(prev) != kdflist.audit_head {
+        return Err(corrupt("log does not end at the recorded head"));
+    }
+
+    Ok(entries)
+}
+
+// These tests are going to be extremely slow on debug builds since they call
+// into the scrypt stuff.
+#[cfg(test)]
+mod test {
+    use fourleaf::adapt::Copied;
+    use fourleaf::UnknownFields;
+
+    use super::*;
+    use crate::server::local_storage::LocalStorage;
+
+    fn no_prompt() -> Result<Passphrase> {
+        panic!("shouldn't prompt");
+    }
+
+    macro_rules! init {
+        ($storage:ident) => {
+            let dir = tempfile::Builder::new()
+                .prefix("keymgmt")
+                .tempdir()
+                .unwrap();
+            let $storage = LocalStorage::open(dir.path()).unwrap();
+        };
+    }
+
+    macro_rules! assert_err {
+        ($expected:pat, $actual:expr) => {
+            match $actual {
+                Ok(_) => panic!("Call succeeded unexpectedly"),
+                Err(Error($expected, _)) => {}
+                Err(e) => panic!("Error was not the expected error: {:?}", e),
+            }
+        };
+    }
+
+    #[test]
+    fn empty() {
+        init!(storage);
+        assert_err!(
+            ErrorKind::KdfListNotExists,
+            add_key(&storage, b"a", b"b", "name", no_prompt)
+        );
+        assert_err!(
+            ErrorKind::KdfListNotExists,
+            change_key(&storage, b"a", b"b", None, false, no_prompt)
+        );
+        assert_err!(
+            ErrorKind::KdfListNotExists,
+            del_key(&storage, "name", no_prompt)
+        );
+        assert!(list_keys(&storage).unwrap().is_empty());
+    }
+
+    #[test]
+    fn init_keys_adds_one_key_but_fails_if_already_init() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "name").unwrap();
+        assert_err!(
+            ErrorKind::KdfListAlreadyExists,
+            init_keys(&storage, b"hunter3", "name")
+        );
+        derive_key_chain(&storage, b"hunter2").unwrap();
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter3")
+        );
+    }
+
+    /// A field this version of Ensync does not know about.
+    struct Extra(u32);
+    fourleaf_retrofit!(struct Extra : {} {} {
+        |_context, this|
+        [99] extra: u32 = this.0,
+        { Ok(Extra(extra)) }
+    });
+
+    /// Collects all fields of whatever it is decoded from as unknown.
+    struct Unknown(UnknownFields<'static>);
+    fourleaf_retrofit!(struct Unknown : {} {} {
+        |_context, this|
+        (?) unknown: Copied<UnknownFields<'static>> = &this.0,
+        { Ok(Unknown(unknown.0)) }
+    });
+
+    #[test]
+    fn unknown_kdflist_fields_preserved() {
+        init!(storage);
+
+        let extra: UnknownFields<'static> =
+            fourleaf::from_slice_copy::<Unknown>(
+                &fourleaf::to_vec(&Extra(42)).unwrap(),
+                &fourleaf::DeConfig::default(),
+            )
+            .unwrap()
+            .0;
+        assert!(extra != UnknownFields::default());
+
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        let edit = KdfEdit::new("test", vec![]);
+        edit_kdflist(
+            &storage,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                kdflist.unknown = extra.clone();
+                kdflist.keys.get_mut("a").unwrap().unknown = extra.clone();
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        change_key(
+            &storage,
+            b"hunter2",
+            b"hunter4",
+            Some("a"),
+            false,
+            no_prompt,
+        )
+        .unwrap();
+
+        let (kdflist, _, _) = get_kdflist(&storage).unwrap().unwrap();
+        assert_eq!(extra, kdflist.unknown);
+        assert_eq!(extra, kdflist.keys["a"].unknown);
+        let Extra(value) = fourleaf::from_slice_copy(
+            &fourleaf::to_vec(&Unknown(kdflist.unknown.clone())).unwrap(),
+            &fourleaf::DeConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(42, value);
+    }
+
+    #[test]
+    fn kdflist_from_newer_version_rejected() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "name").unwrap();
+        assert_eq!(
+            Some(KDFLIST_FORMAT_VERSION),
+            get_kdflist(&storage).unwrap().unwrap().0.format_version
+        );
+
+        // Stores from before the field existed are version 1
+        let edit = KdfEdit::new("test", vec![]);
+        edit_kdflist(
+            &storage,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                kdflist.format_version = None;
+                Ok(())
+            },
+        )
+        .unwrap();
+        derive_key_chain(&storage, b"hunter2").unwrap();
+
+        let edit = KdfEdit::new("test", vec![]);
+        edit_kdflist(
+            &storage,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                kdflist.format_version = Some(KDFLIST_FORMAT_VERSION + 1);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_err!(
+            ErrorKind::KdfListFromNewerVersion(..),
+            derive_key_chain(&storage, b"hunter2")
+        );
+        assert_err!(
+            ErrorKind::KdfListFromNewerVersion(..),
+            add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt)
+        );
+    }
+
+    #[test]
+    fn add_key_creates_new_key() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+
+        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
+        assert_eq!(mk.keys, mk2.keys);
+    }
+
+    #[test]
+    fn add_key_refuses_empty_name() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::EmptyKeyName,
+            add_key(&storage, b"hunter2", b"hunter3", "", no_prompt)
+        );
+    }
+
+    #[test]
+    fn add_key_wont_overwrite_existing_key() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::KeyNameAlreadyInUse(_),
+            add_key(&storage, b"hunter2", b"hunter3", "original", no_prompt)
+        );
+    }
+
+    #[test]
+    fn add_key_bad_old_pw() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            add_key(&storage, b"plugh", b"xyzzy", "new", no_prompt)
+        );
+    }
+
+    #[test]
+    fn add_key_refuses_duplicate_pw() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::PassphraseInKdfList,
+            add_key(&storage, b"hunter2", b"hunter2", "new", no_prompt)
+        );
+    }
+
+    #[test]
+    fn change_key_doesnt_need_key_name_if_only_one_key() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+
+        change_key(&storage, b"hunter2", b"hunter3", None, false, no_prompt)
+            .unwrap();
+        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
+        assert_eq!(mk.keys, mk2.keys);
+
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter2")
+        );
+    }
+
+    #[test]
+    fn change_key_fails_if_no_name_but_multiple_keys() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+        assert_err!(
+            ErrorKind::AnonChangeKeyButMultipleKdfEntries,
+            change_key(
+                &storage, b"hunter3", b"hunter4", None, false, no_prompt
+            )
+        );
+    }
+
+    #[test]
+    fn change_key_keeps_or_overrides_algorithm() {
+        init!(storage);
+
+        init_keys_with(
+            &storage,
+            b"hunter2",
+            "original",
+            "scrypt-12-8-1",
+            None,
+            false,
+            true,
+        )
+        .unwrap();
+        let algorithm = |storage: &LocalStorage| {
+            list_keys(storage).unwrap()[0].algorithm.clone()
+        };
+
+        change_key(&storage, b"hunter2", b"hunter3", None, false, no_prompt)
+            .unwrap();
+        assert_eq!("scrypt-12-8-1", algorithm(&storage));
+
+        assert_err!(
+            ErrorKind::UnsupportedKdfAlgorithm(..),
+            change_key_with_algorithm(
+                &storage,
+                b"hunter3",
+                b"hunter4",
+                None,
+                false,
+                Some("plugh"),
+                no_prompt,
+            )
+        );
+
+        change_key_with_algorithm(
+            &storage,
+            b"hunter3",
+            b"hunter4",
+            None,
+            false,
+            Some("scrypt-14-8-1"),
+            no_prompt,
+        )
+        .unwrap();
+        assert_eq!("scrypt-14-8-1", algorithm(&storage));
+        derive_key_chain(&storage, b"hunter4").unwrap();
+    }
+
+    #[test]
+    fn interleaved_edits_both_apply() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+
+        let mut attempts = 0;
+        let edit = KdfEdit::new("outer", vec![]);
+        edit_kdflist(
+            &storage,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                attempts += 1;
+                if 1 == attempts {
+                    // Another edit commits while this one is in progress, so
+                    // this one must be rejected and retried.
+                    edit_kdflist(
+                        &storage,
+                        "inner",
+                        vec![],
+                        None,
+                        || Ok(b"hunter2".to_vec().into()),
+                        |kdflist, _| {
+                            kdflist.min_keys = Some(2);
+                            Ok(())
+                        },
+                    )?;
+                }
+                kdflist.keys.get_mut("a").unwrap().comment =
+                    Some("outer".to_owned());
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(2, attempts);
+        let (kdflist, _, _) = get_kdflist(&storage).unwrap().unwrap();
+        assert_eq!(Some(2), kdflist.min_keys);
+        assert_eq!(Some("outer"), kdflist.keys["a"].comment.as_deref());
+        assert_eq!(
+            vec!["inner", "outer"],
+            read_audit_log(&storage, b"hunter2")
+                .unwrap()
+                .iter()
+                .rev()
+                .take(2)
+                .rev()
+                .map(|e| e.operation.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rewrap_keys_changes_pads_but_not_internal_keys() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        // Give `b` a salt of non-default length, as imported keys can have
+        let edit = KdfEdit::new("test", vec![]);
+        edit_kdflist(&storage, edit, no_prompt, |kdflist, root_key| {
+            let mut chain = try_derive_key(b"hunter3", &kdflist.keys).unwrap();
+            root_key.chain(&chain);
+            let entry = kdflist.keys.get_mut("b").unwrap();
+            let new_entry = create_key_full(
+                b"hunter3",
+                &mut chain,
+                entry.created,
+                entry.updated,
+                &entry.algorithm,
+                16,
+            )?;
+            *entry = new_entry;
+            Ok(())
+        })
+        .unwrap();
+        let chain = derive_key_chain(&storage, b"hunter2").unwrap();
+        let (before, _, _) = get_kdflist(&storage).unwrap().unwrap();
+        assert_eq!(16, before.keys["b"].salt.len());
+
+        let passphrase = |name: &str| -> Result<Passphrase> {
+            Ok(match name {
+                "a" => b"hunter2".to_vec().into(),
+                "b" => b"hunter3".to_vec().into(),
+                _ => panic!("Unexpected key {}", name),
+            })
+        };
+
+        // A wrong passphrase for any key leaves everything unchanged
+        assert_err!(
+            ErrorKind::PassphraseNotForKey(..),
+            rewrap_keys(
+                &storage,
+                |name| if "b" == name {
+                    Ok(b"hunter2".to_vec().into())
+                } else {
+                    passphrase(name)
+                },
+                no_prompt,
+            )
+        );
+        assert_err!(
+            ErrorKind::KeyNotInKdfList(..),
+            rewrap_keys(
+                &storage,
+                |name| Err(ErrorKind::KeyNotInKdfList(name.to_owned()).into()),
+                no_prompt,
+            )
+        );
+        assert_eq!(before, get_kdflist(&storage).unwrap().unwrap().0);
+
+        rewrap_keys(&storage, passphrase, no_prompt).unwrap();
+
+        let (after, _, _) = get_kdflist(&storage).unwrap().unwrap();
+        for name in &["a", "b"] {
+            let (old, new) = (&before.keys[*name], &after.keys[*name]);
+            assert_ne!(old.salt, new.salt);
+            assert_eq!(old.salt.len(), new.salt.len());
+            assert_ne!(old.groups, new.groups);
+            assert_eq!(
+                old.groups.keys().collect::<Vec<_>>(),
+                new.groups.keys().collect::<Vec<_>>()
+            );
+            assert_eq!(old.created, new.created);
+            assert_eq!(old.algorithm, new.algorithm);
+        }
+        assert_eq!(
+            chain.keys,
+            derive_key_chain(&storage, b"hunter2").unwrap().keys
+        );
+        assert_eq!(
+            chain.keys,
+            derive_key_chain(&storage, b"hunter3").unwrap().keys
+        );
+    }
+
+    #[test]
+    fn change_key_without_name_uses_resolver() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+
+        let mut offered = Vec::new();
+        change_key_resolving(
+            &storage,
+            b"hunter3",
+            b"hunter4",
+            None,
+            false,
+            None,
+            Some(&mut |keys: &[KeyInfo]| {
+                offered = keys.iter().map(|k| k.name.clone()).collect();
+                Ok("new".to_owned())
+            }),
+            no_prompt,
+        )
+        .unwrap();
+
+        assert_eq!(vec!["new".to_owned(), "original".to_owned()], offered);
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter3")
+        );
+        derive_key_chain(&storage, b"hunter4").unwrap();
+        derive_key_chain(&storage, b"hunter2").unwrap();
+
+        // An error from the resolver aborts the change
+        assert_err!(
+            ErrorKind::KeyNotInKdfList(..),
+            change_key_resolving(
+                &storage,
+                b"hunter4",
+                b"hunter5",
+                None,
+                false,
+                None,
+                Some(&mut |_: &[KeyInfo]| Err(ErrorKind::KeyNotInKdfList(
+                    "nx".to_owned()
+                )
+                .into())),
+                no_prompt,
+            )
+        );
+        derive_key_chain(&storage, b"hunter4").unwrap();
+    }
+
+    #[test]
+    fn change_key_by_name() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+
+        change_key(
+            &storage,
+            b"hunter2",
+            b"hunter22",
+            Some("original"),
+            false,
+            no_prompt,
+        )
+        .unwrap();
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter2")
+        );
+        derive_key_chain(&storage, b"hunter22").unwrap();
+        derive_key_chain(&storage, b"hunter3").unwrap();
+
+        change_key(
+            &storage,
+            b"hunter3",
+            b"hunter33",
+            Some("new"),
+            false,
+            no_prompt,
+        )
+        .unwrap();
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter3")
+        );
+        derive_key_chain(&storage, b"hunter22").unwrap();
+        derive_key_chain(&storage, b"hunter33").unwrap();
+    }
+
+    #[test]
+    fn change_key_by_name_nx() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::KeyNotInKdfList(_),
+            change_key(
+                &storage,
+                b"hunter2",
+                b"hunter3",
+                Some("new"),
+                false,
+                no_prompt
+            )
+        );
+    }
+
+    #[test]
+    fn change_key_by_default_requires_corresponding_pw_and_name() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+
+        assert_err!(
+            ErrorKind::ChangeKeyWithPassphraseMismatch,
+            change_key(
+                &storage,
+                b"hunter2",
+                b"hunter33",
+                Some("new"),
+                false,
+                no_prompt
+            )
+        );
+    }
+
+    #[test]
+    fn change_key_allows_forcing_pw_name_mismatch() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+
+        change_key(
+            &storage,
+            b"hunter2",
+            b"hunter33",
+            Some("new"),
+            true,
+            no_prompt,
+        )
+        .unwrap();
+
+        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+        let mk2 = derive_key_chain(&storage, b"hunter33").unwrap();
+        assert_eq!(mk.keys, mk2.keys);
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter3")
+        );
+    }
+
+    #[test]
+    fn change_key_bad_old_pw() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            change_key(&storage, b"plugh", b"xyzzy", None, false, no_prompt)
+        );
+    }
+
+    #[test]
+    fn change_key_dupe_pw() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+
+        assert_err!(
+            ErrorKind::PassphraseInKdfList,
+            change_key(
+                &storage,
+                b"hunter3",
+                b"hunter2",
+                Some("new"),
+                false,
+                no_prompt
+            )
+        );
+    }
+
+    #[test]
+    fn change_key_other_doesnt_add_groups() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
+            .unwrap();
+
+        change_key(
+            &storage,
+            b"hunter2",
+            b"hunter33",
+            Some("new"),
+            true,
+            no_prompt,
+        )
+        .unwrap();
+
+        let mk2 = derive_key_chain(&storage, b"hunter33").unwrap();
+        assert_eq!(2, mk2.keys.len());
+    }
+
+    #[test]
+    fn change_key_other_fails_if_insufficient_groups() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+        create_group(&storage, b"hunter3", ["group"].iter(), no_prompt)
+            .unwrap();
+
+        assert_err!(
+            ErrorKind::KeyNotInGroups(..),
+            change_key(
+                &storage,
+                b"hunter2",
+                b"hunter33",
+                Some("new"),
+                true,
+                no_prompt
+            )
+        );
+    }
+
+    #[test]
+    fn change_key_other_reports_all_missing_groups() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+        create_group(&storage, b"hunter3", ["a", "b"].iter(), no_prompt)
+            .unwrap();
+
+        match change_key(
+            &storage,
+            b"hunter2",
+            b"hunter33",
+            Some("new"),
+            true,
+            no_prompt,
+        ) {
+            Err(Error(ErrorKind::KeyNotInGroups(names), _)) => {
+                assert_eq!(vec!["a".to_owned(), "b".to_owned()], names);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn del_key_wont_delete_last_key() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::WouldRemoveLastKdfEntry,
+            del_key(&storage, "original", no_prompt)
+        );
+    }
+
+    #[test]
+    fn del_key_wont_delete_last_key_in_group() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+        create_group(&storage, b"hunter3", ["group"].iter(), no_prompt)
+            .unwrap();
+        assert_err!(
+            ErrorKind::WouldDisassocLastKeyFromGroup(..),
+            del_key(&storage, "new", no_prompt)
+        );
+    }
+
+    #[test]
+    fn del_key_name_nx() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::KeyNotInKdfList(_),
+            del_key(&storage, "plugh", no_prompt)
+        );
+    }
+
+    #[test]
+    fn del_key_removes_named_key() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+
+        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+
+        del_key(&storage, "original", || Ok(b"hunter3".to_vec().into()))
+            .unwrap();
+
+        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
+        assert_eq!(mk.keys, mk2.keys);
+
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter2")
+        );
+    }
+
+    #[test]
+    fn rename_key_moves_entry() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "other", no_prompt).unwrap();
+        record_key_use(&storage, b"hunter3").unwrap();
+        let (before, _, _) = get_kdflist(&storage).unwrap().unwrap();
+
+        assert_err!(
+            ErrorKind::KeyNotInKdfList(_),
+            rename_key(&storage, "plugh", "xyzzy", no_prompt)
+        );
+        assert_err!(
+            ErrorKind::KeyNameAlreadyInUse(_),
+            rename_key(&storage, "other", "original", no_prompt)
+        );
+
+        rename_key(&storage, "other", "renamed", || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+
+        let (after, _, _) = get_kdflist(&storage).unwrap().unwrap();
+        assert!(!after.keys.contains_key("other"));
+        assert_eq!(before.keys["other"], after.keys["renamed"]);
+        assert!(after.keys["renamed"].used.is_some());
+        assert_eq!(
+            derive_key_chain(&storage, b"hunter2").unwrap().keys,
+            derive_key_chain(&storage, b"hunter3").unwrap().keys
+        );
+    }
+
+    #[test]
+    fn kdf_timestamps_updated() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        let list = list_keys(&storage).unwrap();
+        assert_eq!(1, list.len());
+        assert!(list[0].updated.is_none());
+
+        change_key(&storage, b"hunter2", b"hunter3", None, false, no_prompt)
+            .unwrap();
+        let list = list_keys(&storage).unwrap();
+        assert_eq!(1, list.len());
+        assert!(list[0].updated.is_some());
+    }
+
+    #[test]
+    fn create_group_already_exists() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::GroupNameAlreadyInUse(_),
+            create_group(&storage, b"hunter2", ["root"].iter(), no_prompt)
+        );
+    }
+
+    #[test]
+    fn create_group_empty_name() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::EmptyKeyGroupName,
+            create_group(&storage, b"hunter2", [""].iter(), no_prompt)
+        );
+    }
+
+    #[test]
+    fn create_group_success() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        create_group(
+            &storage,
+            b"hunter2",
+            ["users", "private"].iter(),
+            no_prompt,
+        )
+        .unwrap();
+
+        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+        assert_eq!(4, mk.keys.len());
+        assert!(mk.keys.contains_key("root"));
+        assert!(mk.keys.contains_key("everyone"));
+        assert!(mk.keys.contains_key("users"));
+        assert!(mk.keys.contains_key("private"));
+    }
+
+    #[test]
+    fn assoc_group_nx_group() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        assert_err!(
+            ErrorKind::KeyNotInGroup(..),
+            assoc_group(
+                &storage,
+                b"hunter2",
+                b"hunter3",
+                ["group"].iter(),
+                no_prompt
+            )
+        );
+    }
+
+    #[test]
+    fn assoc_group_success() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        create_group(
+            &storage,
+            b"hunter2",
+            ["users", "shared", "private"].iter(),
+            no_prompt,
+        )
+        .unwrap();
+        assoc_group(
+            &storage,
+            b"hunter2",
+            b"hunter3",
+            ["users", "shared"].iter(),
+            no_prompt,
+        )
+        .unwrap();
+
+        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
+        assert_eq!(5, mk.keys.len());
+        assert_eq!(4, mk2.keys.len());
+        assert_eq!(mk.keys["root"], mk2.keys["root"]);
+        assert_eq!(mk.keys["everyone"], mk2.keys["everyone"]);
+        assert_eq!(mk.keys["users"], mk2.keys["users"]);
+        assert_eq!(mk.keys["shared"], mk2.keys["shared"]);
+    }
+
+    #[test]
+    fn assoc_group_multi_success() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "third", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["users"].iter(), no_prompt)
             .unwrap();
+        assoc_group_multi(
+            &storage,
+            b"hunter2",
+            &[&b"hunter3"[..], &b"hunter4"[..]],
+            ["users"].iter(),
+            false,
+            false,
+            no_prompt,
+        )
+        .unwrap();
+
+        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
         let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
-        assert_eq!(mk.keys, mk2.keys);
+        let mk3 = derive_key_chain(&storage, b"hunter4").unwrap();
+        assert_eq!(mk.keys["users"], mk2.keys["users"]);
+        assert_eq!(mk.keys["users"], mk3.keys["users"]);
 
         assert_err!(
-            ErrorKind::PassphraseNotInKdfList,
-            derive_key_chain(&storage, b"hunter2")
+            ErrorKind::KeyAlreadyInGroup(_),
+            assoc_group_multi(
+                &storage,
+                b"hunter2",
+                &[&b"hunter3"[..]],
+                ["users"].iter(),
+                false,
+                false,
+                no_prompt,
+            )
+        );
+        assoc_group_multi(
+            &storage,
+            b"hunter2",
+            &[&b"hunter3"[..], &b"hunter4"[..]],
+            ["users"].iter(),
+            true,
+            true,
+            no_prompt,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn disassoc_group_refuses_everyone() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::CannotDisassocGroup(_),
+            disassoc_group(
+                &storage,
+                "original",
+                ["everyone"].iter(),
+                no_prompt
+            )
+        );
+    }
+
+    #[test]
+    fn disassoc_group_refuses_last_key() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
+            .unwrap();
+        assert_err!(
+            ErrorKind::WouldDisassocLastKeyFromGroup(..),
+            disassoc_group(&storage, "original", ["group"].iter(), no_prompt)
+        );
+    }
+
+    #[test]
+    fn disassoc_group_nx_group() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::KeyNotInGroup(..),
+            disassoc_group(&storage, "original", ["group"].iter(), no_prompt)
+        );
+    }
+
+    #[test]
+    fn disassoc_group_nx_key() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        assert_err!(
+            ErrorKind::KeyNotInKdfList(..),
+            disassoc_group(&storage, "plugh", ["root"].iter(), no_prompt)
+        );
+    }
+
+    #[test]
+    fn disassoc_group_success() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(
+            &storage,
+            b"hunter2",
+            b"hunter3",
+            ["group"].iter(),
+            no_prompt,
+        )
+        .unwrap();
+        disassoc_group(&storage, "original", ["group", "root"].iter(), || {
+            Ok(b"hunter3".to_vec().into())
+        })
+        .unwrap();
+
+        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
+        assert_eq!(1, mk.keys.len());
+        assert_eq!(mk2.keys["everyone"], mk.keys["everyone"]);
+    }
+
+    #[test]
+    fn disassoc_group_reports_remaining_holders() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "c", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(
+            &storage,
+            b"hunter2",
+            b"hunter3",
+            ["group"].iter(),
+            no_prompt,
+        )
+        .unwrap();
+        assoc_group(
+            &storage,
+            b"hunter2",
+            b"hunter4",
+            ["group"].iter(),
+            no_prompt,
+        )
+        .unwrap();
+
+        let remaining =
+            disassoc_group(&storage, "b", ["group", "root"].iter(), || {
+                Ok(b"hunter2".to_vec().into())
+            })
+            .unwrap();
+        assert_eq!(
+            vec![
+                GroupInfo {
+                    name: "group".to_owned(),
+                    keys: vec!["a".to_owned(), "c".to_owned()],
+                },
+                GroupInfo {
+                    name: "root".to_owned(),
+                    keys: vec!["a".to_owned(), "c".to_owned()],
+                },
+            ],
+            remaining
+        );
+    }
+
+    #[test]
+    fn keys_in_group_filters_by_group() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "third", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["a", "b"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(&storage, b"hunter2", b"hunter3", ["a"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(&storage, b"hunter2", b"hunter4", ["b"].iter(), no_prompt)
+            .unwrap();
+
+        let names = |group| {
+            keys_in_group(&storage, group)
+                .unwrap()
+                .into_iter()
+                .map(|k| k.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["original", "second"], names("a"));
+        assert_eq!(vec!["original", "third"], names("b"));
+        assert_eq!(vec!["original", "second", "third"], names("everyone"));
+
+        assert_err!(
+            ErrorKind::GroupNotInKdfList(..),
+            keys_in_group(&storage, "plugh")
         );
     }
 
     #[test]
-    fn change_key_fails_if_no_name_but_multiple_keys() {
+    fn algorithm_histogram_counts_entries() {
+        init!(storage);
+        assert!(algorithm_histogram(&storage).unwrap().is_empty());
+
+        init_keys_with(
+            &storage,
+            b"hunter2",
+            "original",
+            "scrypt-12-8-1",
+            None,
+            false,
+            true,
+        )
+        .unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "third", no_prompt).unwrap();
+
+        let histogram = algorithm_histogram(&storage).unwrap();
+        assert_eq!(2, histogram.len());
+        assert_eq!(Some(&1), histogram.get("scrypt-12-8-1"));
+        assert_eq!(Some(&2), histogram.get(DEFAULT_KDF_ALGORITHM));
+    }
+
+    #[test]
+    fn list_groups_inverts_key_groups() {
         init!(storage);
+        assert!(list_groups(&storage).unwrap().is_empty());
 
         init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
-        assert_err!(
-            ErrorKind::AnonChangeKeyButMultipleKdfEntries,
-            change_key(
-                &storage, b"hunter3", b"hunter4", None, false, no_prompt
-            )
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "third", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["a", "b"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(&storage, b"hunter2", b"hunter4", ["b"].iter(), no_prompt)
+            .unwrap();
+
+        let groups = list_groups(&storage).unwrap();
+        let all = vec!["original", "second", "third"];
+        assert_eq!(
+            vec![
+                ("a", vec!["original"]),
+                ("b", vec!["original", "third"]),
+                (GROUP_EVERYONE, all.clone()),
+                (GROUP_ROOT, all.clone()),
+            ],
+            groups
+                .iter()
+                .map(|g| (
+                    g.name.as_str(),
+                    g.keys.iter().map(String::as_str).collect::<Vec<_>>()
+                ))
+                .collect::<Vec<_>>()
         );
     }
 
     #[test]
-    fn change_key_by_name() {
+    fn missing_groups_reports_only_absent_groups() {
         init!(storage);
-
         init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
-
-        change_key(
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["a", "b", "c"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(
             &storage,
             b"hunter2",
-            b"hunter22",
-            Some("original"),
-            false,
+            b"hunter3",
+            ["a", "c"].iter(),
             no_prompt,
         )
         .unwrap();
+
+        assert_eq!(
+            vec!["b".to_owned()],
+            missing_groups(&storage, b"hunter3").unwrap()
+        );
+        assert!(missing_groups(&storage, b"hunter2").unwrap().is_empty());
         assert_err!(
             ErrorKind::PassphraseNotInKdfList,
-            derive_key_chain(&storage, b"hunter2")
+            missing_groups(&storage, b"plugh")
         );
-        derive_key_chain(&storage, b"hunter22").unwrap();
-        derive_key_chain(&storage, b"hunter3").unwrap();
+    }
 
-        change_key(
+    #[test]
+    fn groups_for_passphrase_lists_key_groups_without_writing() {
+        init!(storage);
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["c", "a", "b"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(
             &storage,
+            b"hunter2",
             b"hunter3",
-            b"hunter33",
-            Some("new"),
-            false,
+            ["c", "a"].iter(),
             no_prompt,
         )
         .unwrap();
+
+        let (_, ver_before, _) = get_kdflist(&storage).unwrap().unwrap();
+        let groups = groups_for_passphrase(&storage, b"hunter3").unwrap();
+        let mut sorted = groups.clone();
+        sorted.sort();
+        assert_eq!(sorted, groups);
+        assert!(groups.contains(&"a".to_owned()));
+        assert!(!groups.contains(&"b".to_owned()));
+        assert!(groups.contains(&"c".to_owned()));
+        assert!(groups.contains(&GROUP_EVERYONE.to_owned()));
+        assert!(groups_for_passphrase(&storage, b"hunter2")
+            .unwrap()
+            .contains(&"b".to_owned()));
         assert_err!(
             ErrorKind::PassphraseNotInKdfList,
-            derive_key_chain(&storage, b"hunter3")
+            groups_for_passphrase(&storage, b"plugh")
         );
-        derive_key_chain(&storage, b"hunter22").unwrap();
-        derive_key_chain(&storage, b"hunter33").unwrap();
+
+        let (_, ver_after, _) = get_kdflist(&storage).unwrap().unwrap();
+        assert_eq!(ver_before, ver_after);
     }
 
     #[test]
-    fn change_key_by_name_nx() {
+    fn full_consistency_check_detects_corruption() {
         init!(storage);
-
         init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "third", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["a", "b"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(&storage, b"hunter2", b"hunter3", ["a"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(&storage, b"hunter2", b"hunter4", ["b"].iter(), no_prompt)
+            .unwrap();
+
+        let all: &[(&str, &[u8])] = &[
+            ("original", &b"hunter2"[..]),
+            ("second", &b"hunter3"[..]),
+            ("third", &b"hunter4"[..]),
+        ];
+        full_consistency_check(&storage, all).unwrap();
+
         assert_err!(
-            ErrorKind::KeyNotInKdfList(_),
-            change_key(
+            ErrorKind::PassphraseNotForKey(..),
+            full_consistency_check(
                 &storage,
-                b"hunter2",
-                b"hunter3",
-                Some("new"),
-                false,
-                no_prompt
+                &[("original", &b"hunter2"[..]), ("second", &b"hunter4"[..])]
             )
         );
+        assert_err!(
+            ErrorKind::KeyNotInKdfList(..),
+            full_consistency_check(&storage, &[("plugh", &b"hunter2"[..])])
+        );
+
+        let edit = KdfEdit::new("test", vec![]);
+        edit_kdflist(
+            &storage,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                kdflist
+                    .keys
+                    .get_mut("second")
+                    .unwrap()
+                    .groups
+                    .get_mut("a")
+                    .unwrap()[0] ^= 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        match full_consistency_check(&storage, all) {
+            Err(Error(ErrorKind::GroupKeyMismatch(group, first, other), _)) => {
+                assert_eq!("a", group);
+                assert_eq!("original", first);
+                assert_eq!("second", other);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Keys which don't share the corrupted group still agree.
+        full_consistency_check(
+            &storage,
+            &[("original", &b"hunter2"[..]), ("third", &b"hunter4"[..])],
+        )
+        .unwrap();
     }
 
     #[test]
-    fn change_key_by_default_requires_corresponding_pw_and_name() {
+    fn destroy_group_refuses_builtins() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
-
         assert_err!(
-            ErrorKind::ChangeKeyWithPassphraseMismatch,
-            change_key(
-                &storage,
-                b"hunter2",
-                b"hunter33",
-                Some("new"),
-                false,
-                no_prompt
-            )
+            ErrorKind::CannotDestroyGroup(..),
+            destroy_group(&storage, ["everyone"].iter(), no_prompt)
+        );
+        assert_err!(
+            ErrorKind::CannotDestroyGroup(..),
+            destroy_group(&storage, ["root"].iter(), no_prompt)
         );
     }
 
     #[test]
-    fn change_key_allows_forcing_pw_name_mismatch() {
+    fn destroy_group_nx() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+        assert_err!(
+            ErrorKind::GroupNotInKdfList(..),
+            destroy_group(&storage, ["plugh"].iter(), no_prompt)
+        );
+    }
 
-        change_key(
-            &storage,
-            b"hunter2",
-            b"hunter33",
-            Some("new"),
-            true,
-            no_prompt,
-        )
+    #[test]
+    fn destroy_group_success() {
+        init!(storage);
+
+        init_keys(&storage, b"hunter2", "original").unwrap();
+        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
+            .unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        destroy_group(&storage, ["group"].iter(), || {
+            Ok(b"hunter2".to_vec().into())
+        })
         .unwrap();
 
         let mk = derive_key_chain(&storage, b"hunter2").unwrap();
-        let mk2 = derive_key_chain(&storage, b"hunter33").unwrap();
-        assert_eq!(mk.keys, mk2.keys);
-        assert_err!(
-            ErrorKind::PassphraseNotInKdfList,
-            derive_key_chain(&storage, b"hunter3")
-        );
+        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
+        assert_eq!(2, mk.keys.len());
+        assert_eq!(2, mk2.keys.len());
+        assert_eq!(mk2.keys["everyone"], mk.keys["everyone"]);
+        assert_eq!(mk2.keys["root"], mk.keys["root"]);
     }
 
     #[test]
-    fn change_key_bad_old_pw() {
+    fn audit_log_records_operations_and_detects_tampering() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["foo"].iter(), no_prompt).unwrap();
+        del_key(&storage, "second", || Ok(b"hunter2".to_vec().into())).unwrap();
+
+        let log = read_audit_log(&storage, b"hunter2").unwrap();
+        let ops: Vec<_> = log
+            .iter()
+            .map(|e| (e.operation.as_str(), e.names.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("init", vec!["original".to_owned()]),
+                ("add-key", vec!["second".to_owned()]),
+                ("create-group", vec!["foo".to_owned()]),
+                ("del-key", vec!["second".to_owned()]),
+            ],
+            ops
+        );
+
+        let key = audit_key(
+            derive_key_chain(&storage, b"hunter2")
+                .unwrap()
+                .key(GROUP_ROOT)
+                .unwrap(),
+        );
+        let (ver, mut data) =
+            storage.getdir(&DIRID_KEY_AUDIT).unwrap().unwrap();
+        let len = data.len();
+        let tail = get_kdflist(&storage).unwrap().unwrap().0.audit_tail;
+        assert_eq!(Some(len as u32), tail.map(|t| t.len));
+
+        data[len - BLKSZ - 1] ^= 1;
+        let new_ver = rand_hashid();
+        storage.start_tx(1).unwrap();
+        storage
+            .rmdir(1, &DIRID_KEY_AUDIT, &secret_dir_ver(&ver, &key), len as u32)
+            .unwrap();
+        storage
+            .mkdir(
+                1,
+                &DIRID_KEY_AUDIT,
+                &new_ver,
+                &secret_dir_ver(&new_ver, &key),
+                &data,
+            )
+            .unwrap();
+        assert!(storage.commit(1).unwrap());
 
         assert_err!(
-            ErrorKind::PassphraseNotInKdfList,
-            change_key(&storage, b"plugh", b"xyzzy", None, false, no_prompt)
+            ErrorKind::AuditLogCorrupt(..),
+            read_audit_log(&storage, b"hunter2")
         );
     }
 
     #[test]
-    fn change_key_dupe_pw() {
-        init!(storage);
+    fn export_and_import_key_between_stores_with_same_internal_keys() {
+        init!(src);
+        init!(dst);
+        init!(other);
+
+        init_keys(&src, b"hunter2", "original").unwrap();
+        add_key(&src, b"hunter2", b"hunter3", "shared", no_prompt).unwrap();
+
+        // Give `dst` the same internal keys by copying the key store over,
+        // then drop the shared key from it.
+        let root = derive_key_chain(&src, b"hunter2")
+            .unwrap()
+            .key(GROUP_ROOT)
+            .unwrap()
+            .clone();
+        let (ver, data) = src.getdir(&DIRID_KEYS).unwrap().unwrap();
+        dst.start_tx(1).unwrap();
+        dst.mkdir(1, &DIRID_KEYS, &ver, &secret_dir_ver(&ver, &key), &data)
+            .unwrap();
+        assert!(dst.commit(1).unwrap());
+        del_key(&dst, "shared", || Ok(b"hunter2".to_vec().into())).unwrap();
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&dst, b"hunter3")
+        );
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+        let exported = export_key(&src, "shared").unwrap();
+        let mut prompts = 0;
+        import_key(&dst, &exported, b"hunter3", || {
+            prompts += 1;
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        assert_eq!(1, prompts);
+        // A name clash is found without asking for the root passphrase
+        assert_err!(
+            ErrorKind::KeyNameAlreadyInUse(..),
+            import_key(&dst, &exported, b"hunter3", no_prompt)
+        );
+        assert_eq!(
+            derive_key_chain(&src, b"hunter3").unwrap().keys,
+            derive_key_chain(&dst, b"hunter3").unwrap().keys
+        );
 
+        init_keys(&other, b"hunter4", "original").unwrap();
         assert_err!(
-            ErrorKind::PassphraseInKdfList,
-            change_key(
-                &storage,
-                b"hunter3",
-                b"hunter2",
-                Some("new"),
-                false,
-                no_prompt
-            )
+            ErrorKind::IncompatibleKeyImport(..),
+            import_key(&other, &exported, b"hunter3", || Ok(b"hunter4"
+                .to_vec()
+                .into()))
         );
     }
 
     #[test]
-    fn change_key_other_doesnt_add_groups() {
+    fn list_keys_sorted_by_use_puts_least_recently_used_first() {
         init!(storage);
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
-        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
-            .unwrap();
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "c", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter5", "d", no_prompt).unwrap();
 
-        change_key(
+        let now = Utc::now();
+        let edit = KdfEdit::new("test", vec![]);
+        edit_kdflist(
             &storage,
-            b"hunter2",
-            b"hunter33",
-            Some("new"),
-            true,
-            no_prompt,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                kdflist.keys.get_mut("a").unwrap().used =
+                    Some(now - chrono::Duration::days(1));
+                kdflist.keys.get_mut("b").unwrap().used =
+                    Some(now - chrono::Duration::days(30));
+                kdflist.keys.get_mut("c").unwrap().used = Some(now);
+                Ok(())
+            },
         )
         .unwrap();
 
-        let mk2 = derive_key_chain(&storage, b"hunter33").unwrap();
-        assert_eq!(2, mk2.keys.len());
+        let names = |keys: Vec<KeyInfo>| {
+            keys.into_iter().map(|k| k.name).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["b", "a", "c", "d"],
+            names(list_keys_sorted(&storage, KeySort::Used, false).unwrap())
+        );
+        assert_eq!(
+            vec!["c", "a", "b", "d"],
+            names(list_keys_sorted(&storage, KeySort::Used, true).unwrap())
+        );
+        assert_eq!(
+            vec!["d", "c", "b", "a"],
+            names(list_keys_sorted(&storage, KeySort::Name, true).unwrap())
+        );
+
+        record_key_use(&storage, b"hunter5").unwrap();
+        assert_eq!(
+            vec!["d", "c", "a", "b"],
+            names(list_keys_sorted(&storage, KeySort::Used, true).unwrap())
+        );
     }
 
     #[test]
-    fn change_key_other_fails_if_insufficient_groups() {
+    fn import_kdflist_merge_keeps_later_timestamps() {
         init!(storage);
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
-        create_group(&storage, b"hunter3", ["group"].iter(), no_prompt)
-            .unwrap();
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+
+        let now = Utc::now();
+        let mut backup =
+            decode_kdflist_export(&export_kdflist(&storage).unwrap()).unwrap();
+        backup.keys.get_mut("a").unwrap().used = Some(now - Duration::days(10));
+        backup.keys.get_mut("b").unwrap().used = Some(now);
+        backup.keys.get_mut("b").unwrap().updated =
+            Some(now - Duration::days(10));
+        backup.keys.get_mut("b").unwrap().comment = Some("backup".to_owned());
+        let backup = encode_kdflist_export(&backup).unwrap();
+
+        add_key(&storage, b"hunter2", b"hunter4", "c", no_prompt).unwrap();
+        let edit = KdfEdit::new("test", vec![]);
+        edit_kdflist(
+            &storage,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                kdflist.keys.get_mut("a").unwrap().used = Some(now);
+                kdflist.keys.get_mut("b").unwrap().used =
+                    Some(now - Duration::days(10));
+                kdflist.keys.get_mut("b").unwrap().updated = Some(now);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        import_kdflist(&storage, &backup, true, || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
 
+        let (kdflist, _, _) = get_kdflist(&storage).unwrap().unwrap();
+        assert_eq!(Some(now), kdflist.keys["a"].used);
+        assert_eq!(Some(now), kdflist.keys["b"].used);
+        assert_eq!(Some(now), kdflist.keys["b"].updated);
+        assert_eq!(Some("backup"), kdflist.keys["b"].comment.as_deref());
+        assert!(kdflist.keys.contains_key("c"));
+
+        import_kdflist(&storage, &backup, false, || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        let (kdflist, _, _) = get_kdflist(&storage).unwrap().unwrap();
+        assert_eq!(Some(now), kdflist.keys["b"].used);
+        assert_eq!(Some(now - Duration::days(10)), kdflist.keys["a"].used);
+        assert!(!kdflist.keys.contains_key("c"));
+
+        init!(other);
+        init_keys(&other, b"hunter2", "a").unwrap();
         assert_err!(
-            ErrorKind::KeyNotInGroup(..),
-            change_key(
-                &storage,
-                b"hunter2",
-                b"hunter33",
-                Some("new"),
-                true,
-                no_prompt
-            )
+            ErrorKind::IncompatibleKdfListImport,
+            import_kdflist(&other, &backup, true, || Ok(b"hunter2"
+                .to_vec()
+                .into()))
         );
     }
 
     #[test]
-    fn del_key_wont_delete_last_key() {
+    fn restore_kdflist_round_trip() {
         init!(storage);
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        let chain = derive_key_chain(&storage, b"hunter3").unwrap();
+        let backup = export_kdflist(&storage).unwrap();
+
+        do_tx(&storage, |tx| {
+            let (_, ver, len) = get_kdflist(&storage)?.unwrap();
+            storage.rmdir(
+                tx,
+                &DIRID_KEYS,
+                &secret_dir_ver(&ver, chain.key(GROUP_ROOT)?),
+                len,
+            )?;
+            Ok(())
+        })
+        .unwrap();
         assert_err!(
-            ErrorKind::WouldRemoveLastKdfEntry,
-            del_key(&storage, "original", no_prompt)
+            ErrorKind::KdfListNotExists,
+            derive_key_chain(&storage, b"hunter3")
         );
-    }
 
-    #[test]
-    fn del_key_wont_delete_last_key_in_group() {
-        init!(storage);
+        restore_kdflist(&storage, &backup, false, || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        assert_eq!(
+            chain.keys,
+            derive_key_chain(&storage, b"hunter3").unwrap().keys
+        );
+        assert_eq!(
+            "restore-kdflist",
+            read_audit_log(&storage, b"hunter2")
+                .unwrap()
+                .last()
+                .unwrap()
+                .operation
+        );
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
-        create_group(&storage, b"hunter3", ["group"].iter(), no_prompt)
-            .unwrap();
         assert_err!(
-            ErrorKind::WouldDisassocLastKeyFromGroup(..),
-            del_key(&storage, "new", no_prompt)
+            ErrorKind::KdfListAlreadyExists,
+            restore_kdflist(&storage, &backup, false, || {
+                Ok(b"hunter2".to_vec().into())
+            })
+        );
+        add_key(&storage, b"hunter2", b"hunter4", "c", no_prompt).unwrap();
+        restore_kdflist(&storage, &backup, true, || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        assert_err!(
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter4")
+        );
+        read_audit_log(&storage, b"hunter2").unwrap();
+
+        assert_err!(
+            ErrorKind::NotKdfListExport,
+            restore_kdflist(&storage, &backup[4..], true, || {
+                Ok(b"hunter2".to_vec().into())
+            })
+        );
+        let mut newer = backup.clone();
+        newer[KDFLIST_EXPORT_MAGIC.len()] = 2;
+        assert_err!(
+            ErrorKind::UnsupportedKdfListExport(2),
+            restore_kdflist(&storage, &newer, true, || {
+                Ok(b"hunter2".to_vec().into())
+            })
         );
     }
 
     #[test]
-    fn del_key_name_nx() {
+    fn restore_kdflist_refuses_backup_of_other_store() {
         init!(storage);
+        init!(other);
+
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        // Same passphrase, but different internal keys
+        init_keys(&other, b"hunter2", "a").unwrap();
+        let backup = export_kdflist(&other).unwrap();
+
+        assert_err!(
+            ErrorKind::IncompatibleKdfListImport,
+            restore_kdflist(&storage, &backup, true, || {
+                Ok(b"hunter2".to_vec().into())
+            })
+        );
+        // The root passphrase of the backup must also be valid in the store
+        add_key(&other, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        let backup = export_kdflist(&other).unwrap();
+        assert_err!(
+            ErrorKind::IncompatibleKdfListImport,
+            restore_kdflist(&storage, &backup, true, || {
+                Ok(b"hunter3".to_vec().into())
+            })
+        );
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
+        derive_key_chain(&storage, b"hunter2").unwrap();
         assert_err!(
-            ErrorKind::KeyNotInKdfList(_),
-            del_key(&storage, "plugh", no_prompt)
+            ErrorKind::PassphraseNotInKdfList,
+            derive_key_chain(&storage, b"hunter3")
         );
     }
 
     #[test]
-    fn del_key_removes_named_key() {
+    fn record_key_use_throttled_skips_recent_updates() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
-
-        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
+        let ver =
+            |storage: &LocalStorage| get_kdflist(storage).unwrap().unwrap().1;
 
-        del_key(&storage, "original", || Ok((&b"hunter3"[..]).to_owned()))
+        let v0 = ver(&storage);
+        record_key_use_throttled(&storage, b"hunter2", Duration::hours(1))
             .unwrap();
+        let v1 = ver(&storage);
+        // Never used before, so the time is always recorded.
+        assert!(v0 != v1);
 
-        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
-        assert_eq!(mk.keys, mk2.keys);
+        record_key_use_throttled(&storage, b"hunter2", Duration::hours(1))
+            .unwrap();
+        assert_eq!(v1, ver(&storage));
 
-        assert_err!(
-            ErrorKind::PassphraseNotInKdfList,
-            derive_key_chain(&storage, b"hunter2")
-        );
+        record_key_use(&storage, b"hunter2").unwrap();
+        assert!(v1 != ver(&storage));
     }
 
     #[test]
-    fn kdf_timestamps_updated() {
+    fn record_key_use_disabled_by_policy() {
         init!(storage);
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        let list = list_keys(&storage).unwrap();
-        assert_eq!(1, list.len());
-        assert!(list[0].updated.is_none());
+        init_keys_with(
+            &storage,
+            b"hunter2",
+            "original",
+            DEFAULT_KDF_ALGORITHM,
+            None,
+            false,
+            false,
+        )
+        .unwrap();
 
-        change_key(&storage, b"hunter2", b"hunter3", None, false, no_prompt)
+        let ver =
+            |storage: &LocalStorage| get_kdflist(storage).unwrap().unwrap().1;
+        let v0 = ver(&storage);
+        record_key_use(&storage, b"hunter2").unwrap();
+        let (info, _) = record_key_use_full(
+            &storage,
+            b"hunter2",
+            Duration::zero(),
+            Utc::now(),
+        )
+        .unwrap();
+        assert_eq!(None, info.used);
+        assert_eq!(v0, ver(&storage));
+        assert_eq!(None, list_keys(&storage).unwrap()[0].used);
+    }
+
+    /// Creates an empty pseudo-root in `storage`, as opening a replica would.
+    fn fake_pseudo_root(storage: &LocalStorage) {
+        storage.start_tx(1).unwrap();
+        storage
+            .mkdir(1, &DIRID_PROOT, &UNKNOWN_HASH, &UNKNOWN_HASH, b"")
             .unwrap();
-        let list = list_keys(&storage).unwrap();
-        assert_eq!(1, list.len());
-        assert!(list[0].updated.is_some());
+        assert!(storage.commit(1).unwrap());
     }
 
     #[test]
-    fn create_group_already_exists() {
+    fn set_hash_variant_refuses_non_empty_store() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
+        set_hash_variant(&storage, HashVariant::Blake3, || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        assert_eq!(
+            HashVariant::Blake3,
+            derive_key_chain(&storage, b"hunter2").unwrap().hash_variant
+        );
+
+        fake_pseudo_root(&storage);
         assert_err!(
-            ErrorKind::GroupNameAlreadyInUse(_),
-            create_group(&storage, b"hunter2", ["root"].iter(), no_prompt)
+            ErrorKind::StoreNotEmpty,
+            set_hash_variant(&storage, HashVariant::Sha3_256, no_prompt)
+        );
+        assert_eq!(
+            HashVariant::Blake3,
+            derive_key_chain(&storage, b"hunter2").unwrap().hash_variant
         );
     }
 
     #[test]
-    fn create_group_empty_name() {
+    fn set_cipher_width_refuses_non_empty_store() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
+        set_cipher_width(&storage, CipherWidth::Aes256, || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        assert_eq!(
+            CipherWidth::Aes256,
+            derive_key_chain(&storage, b"hunter2").unwrap().cipher_width
+        );
+
+        fake_pseudo_root(&storage);
         assert_err!(
-            ErrorKind::EmptyKeyGroupName,
-            create_group(&storage, b"hunter2", [""].iter(), no_prompt)
+            ErrorKind::StoreNotEmpty,
+            set_cipher_width(&storage, CipherWidth::Aes128, no_prompt)
+        );
+        assert_eq!(
+            CipherWidth::Aes256,
+            derive_key_chain(&storage, b"hunter2").unwrap().cipher_width
         );
     }
 
     #[test]
-    fn create_group_success() {
+    fn probe_checks_passphrase_without_writing() {
         init!(storage);
 
+        let result = probe(&storage, b"hunter2").unwrap();
+        assert!(!result.initialised);
+        assert!(!result.authenticated);
+
         init_keys(&storage, b"hunter2", "original").unwrap();
-        create_group(
-            &storage,
-            b"hunter2",
-            ["users", "private"].iter(),
-            no_prompt,
-        )
-        .unwrap();
+        let ver =
+            |storage: &LocalStorage| get_kdflist(storage).unwrap().unwrap().1;
+        let v0 = ver(&storage);
 
-        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
-        assert_eq!(4, mk.keys.len());
-        assert!(mk.keys.contains_key("root"));
-        assert!(mk.keys.contains_key("everyone"));
-        assert!(mk.keys.contains_key("users"));
-        assert!(mk.keys.contains_key("private"));
+        let result = probe(&storage, b"hunter2").unwrap();
+        assert!(result.initialised);
+        assert!(result.authenticated);
+
+        let result = probe(&storage, b"hunter3").unwrap();
+        assert!(result.initialised);
+        assert!(!result.authenticated);
+
+        assert_eq!(v0, ver(&storage));
+        assert_eq!(None, list_keys(&storage).unwrap()[0].used);
     }
 
     #[test]
-    fn assoc_group_nx_group() {
+    fn record_key_use_full_returns_matched_key() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "new", no_prompt).unwrap();
+
+        let (info, chain) = record_key_use_full(
+            &storage,
+            b"hunter3",
+            Duration::zero(),
+            Utc::now(),
+        )
+        .unwrap();
+        assert_eq!("new", info.name);
+        assert!(info.used.is_some());
+        assert_eq!(
+            derive_key_chain(&storage, b"hunter3").unwrap().keys,
+            chain.keys
+        );
+
+        let listed = list_keys(&storage).unwrap();
+        let new = listed.iter().find(|k| "new" == k.name).unwrap();
+        assert_eq!(new.used, info.used);
+        assert_eq!(new.created, info.created);
+        assert_eq!(new.groups, info.groups);
+        let original = listed.iter().find(|k| "original" == k.name).unwrap();
+        assert_eq!(None, original.used);
+
         assert_err!(
-            ErrorKind::KeyNotInGroup(..),
-            assoc_group(
+            ErrorKind::PassphraseNotInKdfList,
+            record_key_use_full(
                 &storage,
-                b"hunter2",
-                b"hunter3",
-                ["group"].iter(),
-                no_prompt
+                b"hunter4",
+                Duration::zero(),
+                Utc::now()
             )
         );
     }
 
     #[test]
-    fn assoc_group_success() {
+    fn verified_edit_rolls_back_corruption() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
-        create_group(
-            &storage,
-            b"hunter2",
-            ["users", "shared", "private"].iter(),
-            no_prompt,
-        )
-        .unwrap();
-        assoc_group(
+        let chain = derive_key_chain(&storage, b"hunter2").unwrap();
+        let ver =
+            |storage: &LocalStorage| get_kdflist(storage).unwrap().unwrap().1;
+        let v0 = ver(&storage);
+
+        let edit = KdfEdit::new("test", vec![]).verified(b"hunter2", true);
+        assert_err!(
+            ErrorKind::KdfListVerificationFailed,
+            edit_kdflist(&storage, edit, no_prompt, |kdflist, _| {
+                let diff = kdflist
+                    .keys
+                    .get_mut("original")
+                    .unwrap()
+                    .groups
+                    .get_mut(GROUP_EVERYONE)
+                    .unwrap();
+                diff[0] ^= 1;
+                Ok(())
+            })
+        );
+        assert_eq!(v0, ver(&storage));
+        assert_eq!(
+            chain.keys,
+            derive_key_chain(&storage, b"hunter2").unwrap().keys
+        );
+
+        let edit = KdfEdit::new("test", vec![]).verified(b"hunter2", true);
+        edit_kdflist(
             &storage,
-            b"hunter2",
-            b"hunter3",
-            ["users", "shared"].iter(),
-            no_prompt,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                kdflist.keys.get_mut("original").unwrap().comment =
+                    Some("fine".to_owned());
+                Ok(())
+            },
         )
         .unwrap();
-
-        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
-        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
-        assert_eq!(5, mk.keys.len());
-        assert_eq!(4, mk2.keys.len());
-        assert_eq!(mk.keys["root"], mk2.keys["root"]);
-        assert_eq!(mk.keys["everyone"], mk2.keys["everyone"]);
-        assert_eq!(mk.keys["users"], mk2.keys["users"]);
-        assert_eq!(mk.keys["shared"], mk2.keys["shared"]);
+        assert!(v0 != ver(&storage));
     }
 
     #[test]
-    fn disassoc_group_refuses_everyone() {
+    fn verified_edit_rejects_lost_group() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
+        create_group(&storage, b"hunter2", ["foo"].iter(), no_prompt).unwrap();
+
+        let edit = KdfEdit::new("test", vec![]).verified(b"hunter2", true);
         assert_err!(
-            ErrorKind::CannotDisassocGroup(_),
-            disassoc_group(
-                &storage,
-                "original",
-                ["everyone"].iter(),
-                no_prompt
-            )
+            ErrorKind::KdfListVerificationFailed,
+            edit_kdflist(&storage, edit, no_prompt, |kdflist, _| {
+                kdflist
+                    .keys
+                    .get_mut("original")
+                    .unwrap()
+                    .groups
+                    .remove("foo");
+                Ok(())
+            })
         );
+        assert!(derive_key_chain(&storage, b"hunter2")
+            .unwrap()
+            .key("foo")
+            .is_ok());
     }
 
     #[test]
-    fn disassoc_group_refuses_last_key() {
+    fn backwards_clock_is_clamped_and_detected() {
         init!(storage);
 
         init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
-        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
+        let created = list_keys(&storage).unwrap()[0].created;
+
+        // A clock which has gone back to 1970
+        let epoch = created - Duration::days(365 * 50);
+        record_key_use_full(&storage, b"hunter2", Duration::zero(), epoch)
             .unwrap();
-        assert_err!(
-            ErrorKind::WouldDisassocLastKeyFromGroup(..),
-            disassoc_group(&storage, "original", ["group"].iter(), no_prompt)
-        );
+        let info = list_keys(&storage).unwrap().remove(0);
+        assert_eq!(Some(created), info.used);
+        assert!(!info.has_clock_anomaly());
+
+        // Entries written before clamping existed may still be inconsistent.
+        let edit = KdfEdit::new("test", vec![]);
+        edit_kdflist(
+            &storage,
+            edit,
+            || Ok(b"hunter2".to_vec().into()),
+            |kdflist, _| {
+                kdflist.keys.get_mut("original").unwrap().updated = Some(epoch);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert!(list_keys(&storage).unwrap()[0].has_clock_anomaly());
     }
 
     #[test]
-    fn disassoc_group_nx_group() {
+    fn min_keys_policy_prevents_deleting_below_minimum() {
         init!(storage);
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
+        init_keys(&storage, b"hunter2", "a").unwrap();
         assert_err!(
-            ErrorKind::KeyNotInGroup(..),
-            disassoc_group(&storage, "original", ["group"].iter(), no_prompt)
+            ErrorKind::MinKeyPolicyViolation(2),
+            set_min_keys(&storage, Some(2), || Ok(b"hunter2".to_vec().into()))
         );
-    }
 
-    #[test]
-    fn disassoc_group_nx_key() {
-        init!(storage);
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "c", no_prompt).unwrap();
+        set_min_keys(&storage, Some(2), || Ok(b"hunter2".to_vec().into()))
+            .unwrap();
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
+        del_key(&storage, "c", || Ok(b"hunter2".to_vec().into())).unwrap();
         assert_err!(
-            ErrorKind::KeyNotInKdfList(..),
-            disassoc_group(&storage, "plugh", ["root"].iter(), no_prompt)
+            ErrorKind::MinKeyPolicyViolation(2),
+            del_key(&storage, "b", || Ok(b"hunter2".to_vec().into()))
+        );
+        assert_err!(
+            ErrorKind::MinKeyPolicyViolation(2),
+            disassoc_group(&storage, "b", [GROUP_ROOT].iter(), || Ok(
+                b"hunter2".to_vec().into()
+            ))
         );
+        assert_eq!(2, list_keys(&storage).unwrap().len());
+
+        set_min_keys(&storage, None, || Ok(b"hunter2".to_vec().into()))
+            .unwrap();
+        del_key(&storage, "b", || Ok(b"hunter2".to_vec().into())).unwrap();
     }
 
     #[test]
-    fn disassoc_group_success() {
+    fn min_keys_policy_counts_root_group_separately() {
         init!(storage);
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
-        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "c", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter5", "d", no_prompt).unwrap();
+        create_group(&storage, b"hunter2", ["users"].iter(), no_prompt)
+            .unwrap();
+        assoc_group(&storage, b"hunter2", b"hunter3", ["users"].iter(), || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        disassoc_group(&storage, "c", [GROUP_ROOT].iter(), || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        disassoc_group(&storage, "d", [GROUP_ROOT].iter(), || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        set_min_keys(&storage, Some(2), || Ok(b"hunter2".to_vec().into()))
             .unwrap();
+
+        // Only `a` and `b` remain in `root`, so neither can go even though
+        // four keys remain overall.
+        assert_err!(
+            ErrorKind::MinKeyPolicyViolation(2),
+            del_key(&storage, "b", || Ok(b"hunter2".to_vec().into()))
+        );
+        // User groups are not subject to the minimum.
+        disassoc_group(&storage, "b", ["users"].iter(), || {
+            Ok(b"hunter2".to_vec().into())
+        })
+        .unwrap();
+        del_key(&storage, "c", || Ok(b"hunter2".to_vec().into())).unwrap();
+
         assoc_group(
             &storage,
             b"hunter2",
-            b"hunter3",
-            ["group"].iter(),
-            no_prompt,
+            b"hunter5",
+            [GROUP_ROOT].iter(),
+            || Ok(b"hunter2".to_vec().into()),
         )
         .unwrap();
-        disassoc_group(&storage, "original", ["group", "root"].iter(), || {
-            Ok((&b"hunter3"[..]).to_owned())
-        })
-        .unwrap();
-
-        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
-        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
-        assert_eq!(1, mk.keys.len());
-        assert_eq!(mk2.keys["everyone"], mk.keys["everyone"]);
+        del_key(&storage, "b", || Ok(b"hunter2".to_vec().into())).unwrap();
     }
 
     #[test]
-    fn destroy_group_refuses_builtins() {
+    fn weak_passphrases_assessed_and_refused() {
+        assert_eq!(PassphraseStrength::Empty, assess_passphrase(b""));
+        assert_eq!(PassphraseStrength::Empty, assess_passphrase(b" \t\n"));
+        assert_eq!(PassphraseStrength::TooShort, assess_passphrase(b"hunter2"));
+        assert_eq!(
+            PassphraseStrength::Acceptable,
+            assess_passphrase_with(b"hunter2", 4)
+        );
+        assert_eq!(
+            PassphraseStrength::LowEntropy,
+            assess_passphrase(b"aaaaaaaaaaaaaaaa")
+        );
+        assert_eq!(
+            PassphraseStrength::LowEntropy,
+            assess_passphrase(b"abcdefghijklmnop")
+        );
+        assert_eq!(
+            PassphraseStrength::Acceptable,
+            assess_passphrase(b"correct horse battery staple")
+        );
+
         init!(storage);
+        assert_err!(
+            ErrorKind::PassphraseEmpty,
+            init_keys(&storage, b"  ", "name")
+        );
+        assert_err!(
+            ErrorKind::PassphraseTooWeak(PassphraseStrength::TooShort),
+            init_keys_with(
+                &storage,
+                b"hunter2",
+                "name",
+                DEFAULT_KDF_ALGORITHM,
+                None,
+                true,
+                true,
+            )
+        );
+        assert!(storage.getdir(&DIRID_KEYS).unwrap().is_none());
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
+        init_keys(&storage, b"hunter2", "name").unwrap();
         assert_err!(
-            ErrorKind::CannotDestroyGroup(..),
-            destroy_group(&storage, ["everyone"].iter(), no_prompt)
+            ErrorKind::PassphraseEmpty,
+            add_key(&storage, b"hunter2", b"", "other", no_prompt)
         );
         assert_err!(
-            ErrorKind::CannotDestroyGroup(..),
-            destroy_group(&storage, ["root"].iter(), no_prompt)
+            ErrorKind::PassphraseEmpty,
+            change_key(&storage, b"hunter2", b"\n", None, false, no_prompt)
+        );
+        assert_err!(
+            ErrorKind::PassphraseTooWeak(PassphraseStrength::LowEntropy),
+            add_key_with(
+                &storage,
+                b"hunter2",
+                b"aaaaaaaaaaaaaaaa",
+                "other",
+                true,
+                no_prompt,
+            )
         );
+        add_key_with(
+            &storage,
+            b"hunter2",
+            b"correct horse battery staple",
+            "other",
+            true,
+            no_prompt,
+        )
+        .unwrap();
+        assert_eq!(2, list_keys(&storage).unwrap().len());
     }
 
     #[test]
-    fn destroy_group_nx() {
+    fn init_keys_with_algorithm_and_comment() {
         init!(storage);
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
         assert_err!(
-            ErrorKind::GroupNotInKdfList(..),
-            destroy_group(&storage, ["plugh"].iter(), no_prompt)
+            ErrorKind::UnsupportedKdfAlgorithm(..),
+            init_keys_with(
+                &storage,
+                b"hunter2",
+                "name",
+                "scrypt-1-1",
+                None,
+                false,
+                true,
+            )
         );
+        assert!(storage.getdir(&DIRID_KEYS).unwrap().is_none());
+
+        init_keys_with(
+            &storage,
+            b"hunter2",
+            "name",
+            "scrypt-10-4-1",
+            Some("ops team"),
+            false,
+        )
+        .unwrap();
+
+        let keys = list_keys(&storage).unwrap();
+        assert_eq!(1, keys.len());
+        assert_eq!("scrypt-10-4-1", keys[0].algorithm);
+        assert_eq!(Some("ops team".to_owned()), keys[0].comment);
+        derive_key_chain(&storage, b"hunter2").unwrap();
     }
 
     #[test]
-    fn destroy_group_success() {
+    fn for_each_key_visits_every_key_once() {
         init!(storage);
 
-        init_keys(&storage, b"hunter2", "original").unwrap();
-        create_group(&storage, b"hunter2", ["group"].iter(), no_prompt)
-            .unwrap();
-        add_key(&storage, b"hunter2", b"hunter3", "second", no_prompt).unwrap();
-        destroy_group(&storage, ["group"].iter(), || {
-            Ok((&b"hunter2"[..]).to_owned())
-        })
-        .unwrap();
+        let mut visited = Vec::new();
+        for_each_key(&storage, |k| visited.push(k.name.clone())).unwrap();
+        assert!(visited.is_empty());
 
-        let mk = derive_key_chain(&storage, b"hunter2").unwrap();
-        let mk2 = derive_key_chain(&storage, b"hunter3").unwrap();
-        assert_eq!(2, mk.keys.len());
-        assert_eq!(2, mk2.keys.len());
-        assert_eq!(mk2.keys["everyone"], mk.keys["everyone"]);
-        assert_eq!(mk2.keys["root"], mk.keys["root"]);
+        init_keys(&storage, b"hunter2", "a").unwrap();
+        add_key(&storage, b"hunter2", b"hunter3", "b", no_prompt).unwrap();
+        add_key(&storage, b"hunter2", b"hunter4", "c", no_prompt).unwrap();
+
+        for_each_key(&storage, |k| visited.push(k.name.clone())).unwrap();
+        assert_eq!(vec!["a", "b", "c"], visited);
     }
 }
@@ -609,6 +609,14 @@ impl Storage for LocalStorage {
         }
     }
 
+    fn hasobj(&self, id: &HashId) -> Result<bool> {
+        match fs::metadata(self.obj_path(id)) {
+            Ok(_) => Ok(true),
+            Err(ref ioe) if io::ErrorKind::NotFound == ioe.kind() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     fn check_dir_dirty(
         &self,
         id: &HashId,
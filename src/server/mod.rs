@@ -19,6 +19,7 @@
 mod crypt;
 mod dir;
 mod dir_config;
+pub mod estimate;
 pub mod keymgmt;
 mod local_storage;
 mod replica;
@@ -26,8 +27,11 @@ pub mod rpc;
 pub mod storage;
 mod transfer;
 
-pub use self::crypt::KeyChain;
-pub use self::dir::{DIRID_KEYS, DIRID_PROOT};
+pub use self::crypt::{
+    CipherWidth, KeyChain, DIR_VER_FMT_LEGACY, DIR_VER_FMT_MAC,
+    KDFLIST_FORMAT_VERSION,
+};
+pub use self::dir::{UploadStats, DIRID_KEYS, DIRID_PROOT};
 pub use self::local_storage::LocalStorage;
 pub use self::replica::ServerReplica;
 pub use self::rpc::RemoteStorage;
@@ -27,9 +27,11 @@ use std::u32;
 use flate2;
 use sqlite;
 
-use super::crypt::{encrypt_dir_ver, KeyChain};
+use super::crypt::{encrypt_dir_ver, is_supported_dir_ver_fmt, KeyChain};
 use super::dir::*;
+use super::keymgmt;
 use super::storage::*;
+use super::transfer;
 use crate::block_xfer::*;
 use crate::defs::*;
 use crate::errors::*;
@@ -121,11 +123,60 @@ impl<S: Storage + ?Sized + 'static> ServerReplica<S> {
         self.pseudo_root.clone()
     }
 
+    /// Returns the statistics on the file content this replica has uploaded
+    /// since the last call, or since it was opened.
+    pub fn take_upload_stats(&self) -> UploadStats {
+        self.pseudo_root.take_upload_stats()
+    }
+
     /// Returns the key chain being used by this replica.
     pub fn key_chain(&self) -> &Arc<KeyChain> {
         &self.key
     }
 
+    /// Upgrades the store to the directory version format `fmt`.
+    ///
+    /// The versions of every directory in the store are re-encoded, and then
+    /// the new format is recorded in the key store. Until then, clients still
+    /// using the old format fail to read the directories already converted
+    /// rather than misreading them; if the upgrade is interrupted, calling
+    /// this again picks up where it left off.
+    ///
+    /// The replica must not be used afterwards; a new one should be opened
+    /// with a key chain using the new format.
+    pub fn upgrade_dir_ver_fmt<P: FnMut() -> Result<Passphrase>>(
+        &self,
+        fmt: u32,
+        get_root_passphrase: P,
+    ) -> Result<()> {
+        if !is_supported_dir_ver_fmt(fmt) {
+            return Err(ErrorKind::UnsupportedDirVerFormat(fmt).into());
+        }
+
+        Dir::reencode_versions(self.pseudo_root.clone(), fmt)?;
+        keymgmt::record_dir_ver_fmt(&**self.storage(), fmt, get_root_passphrase)
+    }
+
+    /// Verifies each file in `lists` directly against the store, as with
+    /// `transfer::verify_files`, running up to `parallelism` verifications
+    /// concurrently.
+    ///
+    /// Returns one result per file, paired with its index into `lists`.
+    pub fn verify_files(
+        &self,
+        lists: &[BlockList],
+        parallelism: usize,
+    ) -> Result<Vec<(usize, Result<()>)>> {
+        Ok(transfer::verify_files(
+            &**self.storage(),
+            lists,
+            self.key.obj_hmac_secret()?,
+            self.key.cipher_width,
+            self.key.hash_variant,
+            parallelism,
+        ))
+    }
+
     fn storage(&self) -> &Arc<S> {
         &self.pseudo_root.storage
     }
@@ -310,6 +361,22 @@ impl<S: Storage + ?Sized + 'static> Replica for ServerReplica<S> {
         }
     }
 
+    fn transfer_with_progress(
+        &self,
+        dir: &Arc<Dir<S>>,
+        file: File,
+        block_size: Option<usize>,
+        progress: ProgressFn,
+    ) -> Result<Self::TransferOut> {
+        let size = match *file.1 {
+            FileData::Regular(_, size, _, _) => size,
+            _ => 0,
+        };
+        Ok(self
+            .transfer_with_block_size(dir, file, block_size)?
+            .map(|xfer| xfer.with_progress(size, progress)))
+    }
+
     fn prepare(&self, typ: PrepareType) -> Result<()> {
         let dir_filter = if let (true, Some(ws)) =
             (typ <= PrepareType::Watched, self.watcher.as_ref())
@@ -604,6 +671,77 @@ mod test {
         assert_eq!(file_data, actual_data);
     }
 
+    #[test]
+    fn read_range_of_server_file() {
+        init!(replica, root, key_chain);
+
+        let file_data = gen_file(65536);
+        let created = replica
+            .create(
+                &mut root,
+                File(
+                    &oss("fib"),
+                    &FileData::Regular(0o660, 65536, 0, UNKNOWN_HASH),
+                ),
+                Some(Box::new(Cursor::new(file_data.clone()))),
+            )
+            .unwrap();
+
+        let xfer = replica
+            .transfer(&root, File(&oss("fib"), &created))
+            .unwrap()
+            .unwrap();
+        assert_eq!(65536, xfer.blocks.size);
+        assert_eq!(Some(vec![1024; 64]), xfer.blocks.block_sizes);
+
+        let mut fetched = 0;
+        let mut actual_data = Vec::<u8>::new();
+        block_xfer::read_range_with(
+            key_chain.hash_variant,
+            &xfer.blocks,
+            1500,
+            3000,
+            &mut actual_data,
+            key_chain.obj_hmac_secret().unwrap(),
+            |h| {
+                fetched += 1;
+                xfer.fetch.fetch(h)
+            },
+        )
+        .unwrap();
+
+        assert_eq!(&file_data[1500..4500], &actual_data[..]);
+        assert_eq!(4, fetched);
+    }
+
+    #[test]
+    fn upload_stats_count_blocks_already_present() {
+        init!(replica, root);
+
+        let file_data = gen_file(3000);
+        for name in &["a", "b"] {
+            replica
+                .create(
+                    &mut root,
+                    File(
+                        &oss(name),
+                        &FileData::Regular(0o660, 3000, 0, UNKNOWN_HASH),
+                    ),
+                    Some(Box::new(Cursor::new(file_data.clone()))),
+                )
+                .unwrap();
+        }
+
+        let stats = replica.take_upload_stats();
+        assert_eq!(6, stats.blocks.blocks);
+        assert_eq!(6000, stats.blocks.bytes);
+        assert_eq!(0, stats.blocks.zero_blocks);
+        assert_eq!(3, stats.present_blocks);
+        assert_eq!(3000, stats.present_bytes);
+
+        assert_eq!(UploadStats::default(), replica.take_upload_stats());
+    }
+
     #[test]
     fn create_already_exists() {
         init!(replica, root);
@@ -1723,7 +1861,7 @@ mod test {
         }
     }
 
-    fn no_prompt() -> Result<Vec<u8>> {
+    fn no_prompt() -> Result<Passphrase> {
         panic!("shouldn't prompt for password")
     }
 
@@ -1823,6 +1961,97 @@ mod test {
         }
     }
 
+    #[test]
+    fn upgrade_dir_ver_fmt_keeps_dirs_readable() {
+        use crate::server::crypt::{DIR_VER_FMT_LEGACY, DIR_VER_FMT_MAC};
+        use crate::server::keymgmt;
+
+        let dir = tempfile::Builder::new()
+            .prefix("storage")
+            .tempdir()
+            .unwrap();
+        let sqlite_file = dir.path().join("state.sqlite");
+        let storage_dir = dir.path().join("storage");
+
+        let storage = Arc::new(LocalStorage::open(&storage_dir).unwrap());
+        keymgmt::init_keys(&*storage, b"hunter2", "original").unwrap();
+
+        {
+            let key_chain =
+                keymgmt::derive_key_chain(&*storage, b"hunter2").unwrap();
+            assert_eq!(DIR_VER_FMT_LEGACY, key_chain.dir_ver_fmt);
+
+            let replica = ServerReplica::new(
+                sqlite_file.to_str().unwrap(),
+                Arc::new(key_chain),
+                storage.clone(),
+                "r00t",
+                1024,
+                flate2::Compression::fast(),
+            )
+            .unwrap();
+            replica.create_root().unwrap();
+
+            let mut root = replica.root().unwrap();
+            replica
+                .create(
+                    &mut root,
+                    File(&oss("sub"), &FileData::Directory(0o700)),
+                    None,
+                )
+                .unwrap();
+            let mut subdir = replica.chdir(&root, &oss("sub")).unwrap();
+            replica
+                .create(
+                    &mut subdir,
+                    File(&oss("link"), &FileData::Symlink(oss("plugh"))),
+                    None,
+                )
+                .unwrap();
+
+            assert!(keymgmt::set_dir_ver_fmt(
+                &*storage,
+                DIR_VER_FMT_MAC,
+                no_prompt
+            )
+            .is_err());
+
+            // Simulate an earlier upgrade which was interrupted after
+            // converting only `sub`.
+            Dir::reencode_versions(subdir.clone(), DIR_VER_FMT_MAC).unwrap();
+            replica
+                .upgrade_dir_ver_fmt(DIR_VER_FMT_MAC, || {
+                    Ok(b"hunter2".to_vec().into())
+                })
+                .unwrap();
+        }
+
+        let key_chain =
+            keymgmt::derive_key_chain(&*storage, b"hunter2").unwrap();
+        assert_eq!(DIR_VER_FMT_MAC, key_chain.dir_ver_fmt);
+
+        let replica = ServerReplica::new(
+            sqlite_file.to_str().unwrap(),
+            Arc::new(key_chain),
+            storage.clone(),
+            "r00t",
+            1024,
+            flate2::Compression::fast(),
+        )
+        .unwrap();
+
+        let mut root = replica.root().unwrap();
+        let list = replica.list(&mut root).unwrap();
+        assert_eq!(1, list.len());
+        assert_eq!(oss("sub"), list[0].0);
+
+        let mut subdir = replica.chdir(&root, &oss("sub")).unwrap();
+        let list = replica.list(&mut subdir).unwrap();
+        assert_eq!(1, list.len());
+        assert_eq!(oss("link"), list[0].0);
+        assert_eq!(FileData::Symlink(oss("plugh")), list[0].1);
+    }
+
     #[test]
     fn write_protected_dirs_not_writable_by_non_group_members() {
         use crate::server::keymgmt;
@@ -29,7 +29,7 @@ use crate::errors::*;
 use crate::server::storage::*;
 
 pub const PROTOCOL_VERSION_MAJOR: u32 = 0;
-pub const PROTOCOL_VERSION_MINOR: u32 = 1;
+pub const PROTOCOL_VERSION_MINOR: u32 = 2;
 
 /// Identifies a client or server implementation.
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -233,6 +233,12 @@ pub enum Request {
     ///
     /// Since: 0.1
     Watchdir { id: HashId, ver: HashId, len: u32 },
+    /// `Storage::hasobj`
+    ///
+    /// Response: One `Done` | `NotFound` | `Error`
+    ///
+    /// Since: 0.2
+    HasObj(HashId),
 }
 
 fourleaf_retrofit!(enum Request : {} {} {
@@ -325,6 +331,10 @@ fourleaf_retrofit!(enum Request : {} {} {
         [3] len: u32 = len,
         { Ok(Request::Watchdir { id: id, ver: ver, len: len }) }
     },
+    [18] Request::HasObj(ref id) => {
+        [1] id: HashId = id,
+        { Ok(Request::HasObj(id)) }
+    },
 });
 
 /// Responses correspoinding to various `Request`s above.
@@ -578,6 +588,12 @@ pub fn run_server_rpc<S: Storage, R: Read, W: Write + Send + 'static>(
                 Err(err) => err!(err),
             },
 
+            Request::HasObj(id) => match storage.hasobj(&id) {
+                Ok(true) => RequestResponse::SyncResponse(Response::Done),
+                Ok(false) => RequestResponse::SyncResponse(Response::NotFound),
+                Err(err) => err!(err),
+            },
+
             Request::CheckDirDirty(ref id, ref ver, len) => {
                 none_or_fatal!(storage.check_dir_dirty(id, ver, len))
             }
@@ -911,6 +927,20 @@ impl Storage for RemoteStorage {
         })
     }
 
+    fn hasobj(&self, id: &HashId) -> Result<bool> {
+        // Older servers can only tell by sending the whole object
+        if self.protocol < (0, 2) {
+            return self.getobj(id).map(|o| o.is_some());
+        }
+
+        handle_response!(self, tryf!(self, self.send_single_sync_request(
+            Request::HasObj(*id)
+        )) => {
+            Response::Done => Ok(true),
+            Response::NotFound => Ok(false),
+        })
+    }
+
     fn check_dir_dirty(
         &self,
         id: &HashId,
@@ -53,6 +53,13 @@ pub trait Storage: Send + Sync {
     fn getdir(&self, id: &HashId) -> Result<Option<(HashId, Vec<u8>)>>;
     /// Returns the object with the given hash id if it exists.
     fn getobj(&self, id: &HashId) -> Result<Option<Vec<u8>>>;
+    /// Returns whether the object with the given hash id exists.
+    ///
+    /// The default implementation fetches the whole object; implementations
+    /// which can check for existence more cheaply should override it.
+    fn hasobj(&self, id: &HashId) -> Result<bool> {
+        self.getobj(id).map(|o| o.is_some())
+    }
 
     /// Check whether a directory with the given id, version, and length exists.
     ///
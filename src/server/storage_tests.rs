@@ -374,6 +374,20 @@ fn putobj_visible_after_commit() { // and possibly before commit
                &storage.getobj(&hashid(1)).unwrap().unwrap()[..]);
 }
 
+#[test]
+fn hasobj_reflects_committed_objects() {
+    init!(dir, storage);
+
+    assert!(!storage.hasobj(&hashid(1)).unwrap());
+
+    storage.start_tx(1).unwrap();
+    storage.putobj(1, &hashid(1), &hashid(1), b"hello world").unwrap();
+    assert!(storage.commit(1).unwrap());
+
+    assert!(storage.hasobj(&hashid(1)).unwrap());
+    assert!(!storage.hasobj(&hashid(2)).unwrap());
+}
+
 #[test]
 fn cleanup_doesnt_remove_object_with_link() {
     init!(dir, storage);
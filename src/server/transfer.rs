@@ -16,38 +16,403 @@
 // You should have received a copy of the GNU General Public License along with
 // Ensync. If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp;
 use std::io;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use flate2;
 
-use crate::block_xfer::BlockFetch;
+use crate::block_xfer::{
+    verify_blocks_with, BlockFetch, BlockList, HashVariant,
+};
 use crate::defs::HashId;
 use crate::errors::*;
-use crate::server::crypt::{decrypt_obj, xform_obj_id};
+use crate::server::crypt::{decrypt_obj_with, xform_obj_id, CipherWidth};
 use crate::server::storage::Storage;
 
 pub struct ServerTransferOut<S: Storage + ?Sized> {
     storage: Arc<S>,
+    cipher_width: CipherWidth,
 }
 
 impl<S: Storage + ?Sized> ServerTransferOut<S> {
-    pub fn new(storage: Arc<S>) -> Self {
-        ServerTransferOut { storage: storage }
+    pub fn new(storage: Arc<S>, cipher_width: CipherWidth) -> Self {
+        ServerTransferOut {
+            storage: storage,
+            cipher_width: cipher_width,
+        }
     }
 }
 
 impl<S: Storage + ?Sized> BlockFetch for ServerTransferOut<S> {
     fn fetch(&self, block: &HashId) -> Result<Box<dyn io::Read>> {
-        let ciphertext = self
-            .storage
-            .getobj(&xform_obj_id(block))?
-            .ok_or(ErrorKind::ServerContentDeleted)?;
-        let mut cleartext = Vec::<u8>::with_capacity(ciphertext.len() * 3 / 2);
-        decrypt_obj(&mut cleartext, &ciphertext[..], block)?;
-
-        Ok(Box::new(flate2::read::GzDecoder::new(io::Cursor::new(
-            cleartext,
-        ))))
+        fetch_block(&*self.storage, block, self.cipher_width)
+    }
+}
+
+/// Fetches, decrypts, and decompresses the block identified by `block` from
+/// `storage`.
+fn fetch_block<S: Storage + ?Sized>(
+    storage: &S,
+    block: &HashId,
+    cipher_width: CipherWidth,
+) -> Result<Box<dyn io::Read>> {
+    let ciphertext = storage
+        .getobj(&xform_obj_id(block))?
+        .ok_or(ErrorKind::ServerContentDeleted)?;
+    let mut cleartext = Vec::<u8>::with_capacity(ciphertext.len() * 3 / 2);
+    decrypt_obj_with(cipher_width, &mut cleartext, &ciphertext[..], block)?;
+
+    Ok(Box::new(flate2::read::GzDecoder::new(io::Cursor::new(
+        cleartext,
+    ))))
+}
+
+/// Verifies the file described by `blocklist` directly against `storage`,
+/// without writing its content anywhere.
+///
+/// Every block must exist, decrypt, and match its HMAC, and the block list
+/// must match its total HMAC. Fails with the error for the first bad block.
+/// `hash_variant` and `cipher_width` must be those of the store, as recorded
+/// in its key chain.
+pub fn verify_file<S: Storage + ?Sized>(
+    storage: &S,
+    blocklist: &BlockList,
+    secret: &[u8],
+    cipher_width: CipherWidth,
+    hash_variant: HashVariant,
+) -> Result<()> {
+    verify_blocks_with(
+        hash_variant,
+        blocklist,
+        |block| fetch_block(storage, block, cipher_width),
+        secret,
+    )
+}
+
+/// Verifies each file in `lists` as with `verify_file`, running up to
+/// `parallelism` verifications concurrently.
+///
+/// Returns one result per file, paired with its index into `lists` and in
+/// that order. As with `verify_file`, the error for a bad file is that of its
+/// first bad block.
+pub fn verify_files<S: Storage + ?Sized>(
+    storage: &S,
+    lists: &[BlockList],
+    secret: &[u8],
+    cipher_width: CipherWidth,
+    hash_variant: HashVariant,
+    parallelism: usize,
+) -> Vec<(usize, Result<()>)> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(lists.len()));
+    let nthreads = cmp::max(1, cmp::min(parallelism, lists.len()));
+
+    thread::scope(|scope| {
+        for _ in 0..nthreads {
+            scope.spawn(|| loop {
+                let ix = next.fetch_add(1, Ordering::Relaxed);
+                if ix >= lists.len() {
+                    break;
+                }
+
+                let result = verify_file(
+                    storage,
+                    &lists[ix],
+                    secret,
+                    cipher_width,
+                    hash_variant,
+                );
+                results.lock().unwrap().push((ix, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|&(ix, _)| ix);
+    results
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block_xfer::{stream_to_blocks, stream_to_blocks_with};
+    use crate::server::crypt::{
+        encrypt_obj_with, rand_hashid, CipherWidth, BLKSZ,
+    };
+    use crate::server::local_storage::LocalStorage;
+
+    fn upload<S: Storage>(
+        storage: &S,
+        text: &[u8],
+        secret: &[u8],
+        corrupt_block: Option<usize>,
+    ) -> BlockList {
+        upload_with(
+            storage,
+            HashVariant::default(),
+            text,
+            secret,
+            corrupt_block,
+        )
+    }
+
+    fn upload_with<S: Storage>(
+        storage: &S,
+        variant: HashVariant,
+        text: &[u8],
+        secret: &[u8],
+        corrupt_block: Option<usize>,
+    ) -> BlockList {
+        let mut ix = 0;
+        storage.start_tx(1).unwrap();
+        let blocklist =
+            stream_to_blocks_with(variant, text, 4, secret, |id, data| {
+                let data = if Some(ix) == corrupt_block {
+                    &b"XXXX"[..]
+                } else {
+                    data
+                };
+                ix += 1;
+
+                let mut ciphertext = Vec::new();
+                encrypt_obj_with(
+                    CipherWidth::default(),
+                    &mut ciphertext,
+                    flate2::read::GzEncoder::new(
+                        data,
+                        flate2::Compression::fast(),
+                    ),
+                    id,
+                )?;
+                storage.putobj(
+                    1,
+                    &xform_obj_id(id),
+                    &rand_hashid(),
+                    &ciphertext,
+                )
+            })
+            .unwrap();
+        assert!(storage.commit(1).unwrap());
+        blocklist
+    }
+
+    #[test]
+    fn verify_good_file() {
+        let dir = tempfile::Builder::new()
+            .prefix("transfer")
+            .tempdir()
+            .unwrap();
+        let storage = LocalStorage::open(dir.path()).unwrap();
+
+        let blocklist = upload(&storage, b"hello world", b"secret", None);
+        verify_file(
+            &storage,
+            &blocklist,
+            b"secret",
+            CipherWidth::Aes128,
+            HashVariant::default(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn verify_file_with_store_hash_variant() {
+        let dir = tempfile::Builder::new()
+            .prefix("transfer")
+            .tempdir()
+            .unwrap();
+        let storage = LocalStorage::open(dir.path()).unwrap();
+
+        let blocklist = upload_with(
+            &storage,
+            HashVariant::Blake3,
+            b"hello world",
+            b"secret",
+            None,
+        );
+        verify_file(
+            &storage,
+            &blocklist,
+            b"secret",
+            CipherWidth::Aes128,
+            HashVariant::Blake3,
+        )
+        .unwrap();
+        match verify_file(
+            &storage,
+            &blocklist,
+            b"secret",
+            CipherWidth::Aes128,
+            HashVariant::default(),
+        ) {
+            Err(Error(ErrorKind::HmacMismatch(..), _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn verify_files_concurrently() {
+        let dir = tempfile::Builder::new()
+            .prefix("transfer")
+            .tempdir()
+            .unwrap();
+        let storage = LocalStorage::open(dir.path()).unwrap();
+
+        let lists: Vec<BlockList> = (0..8)
+            .map(|ix| {
+                upload(
+                    &storage,
+                    format!("file number {}", ix).as_bytes(),
+                    b"secret",
+                    if 5 == ix { Some(2) } else { None },
+                )
+            })
+            .collect();
+
+        let results = verify_files(
+            &storage,
+            &lists,
+            b"secret",
+            CipherWidth::Aes128,
+            HashVariant::default(),
+            3,
+        );
+        assert_eq!(8, results.len());
+        for (ix, &(file, ref result)) in results.iter().enumerate() {
+            assert_eq!(ix, file);
+            match *result {
+                Ok(()) => assert!(5 != file),
+                Err(Error(ErrorKind::HmacMismatch("block", id, _), _)) => {
+                    assert_eq!(5, file);
+                    assert_eq!(lists[5].blocks[2], id);
+                }
+                Err(ref e) => panic!("Unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn verify_file_with_corrupted_block() {
+        let dir = tempfile::Builder::new()
+            .prefix("transfer")
+            .tempdir()
+            .unwrap();
+        let storage = LocalStorage::open(dir.path()).unwrap();
+
+        let blocklist = upload(&storage, b"hello world", b"secret", Some(1));
+        match verify_file(
+            &storage,
+            &blocklist,
+            b"secret",
+            CipherWidth::Aes128,
+            HashVariant::default(),
+        ) {
+            Err(Error(ErrorKind::HmacMismatch("block", id, _), _)) => {
+                assert_eq!(blocklist.blocks[1], id)
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    /// Uploads "hello world" in 4-byte blocks, passing the cleartext of the
+    /// second block through `tamper_cleartext` and its ciphertext through
+    /// `tamper_ciphertext`.
+    fn upload_tampered<S: Storage>(
+        storage: &S,
+        tamper_cleartext: impl Fn(&mut [u8]),
+        tamper_ciphertext: impl Fn(&mut [u8]),
+    ) -> BlockList {
+        let mut ix = 0;
+        storage.start_tx(1).unwrap();
+        let blocklist =
+            stream_to_blocks(&b"hello world"[..], 4, b"secret", |id, data| {
+                let mut data = data.to_vec();
+                if 1 == ix {
+                    tamper_cleartext(&mut data);
+                }
+                let mut ciphertext = Vec::new();
+                encrypt_obj_with(
+                    CipherWidth::default(),
+                    &mut ciphertext,
+                    flate2::read::GzEncoder::new(
+                        &data[..],
+                        flate2::Compression::fast(),
+                    ),
+                    id,
+                )?;
+                if 1 == ix {
+                    tamper_ciphertext(&mut ciphertext);
+                }
+                ix += 1;
+                storage.putobj(
+                    1,
+                    &xform_obj_id(id),
+                    &rand_hashid(),
+                    &ciphertext,
+                )
+            })
+            .unwrap();
+        assert!(storage.commit(1).unwrap());
+        blocklist
+    }
+
+    #[test]
+    fn verify_file_with_flipped_ciphertext_byte() {
+        let dir = tempfile::Builder::new()
+            .prefix("transfer")
+            .tempdir()
+            .unwrap();
+        let storage = LocalStorage::open(dir.path()).unwrap();
+
+        // Objects have no MAC of their own. Flipping a byte of the ciphertext
+        // after the first cipher block (so the gzip header survives) garbles
+        // the compressed data, which the gzip checksum notices before the
+        // block HMAC gets a chance to.
+        let blocklist = upload_tampered(
+            &storage,
+            |_| (),
+            |ciphertext| ciphertext[BLKSZ + 3] ^= 0x10,
+        );
+        match verify_file(
+            &storage,
+            &blocklist,
+            b"secret",
+            CipherWidth::Aes128,
+            HashVariant::default(),
+        ) {
+            Err(Error(ErrorKind::Io(ref e), _))
+                if io::ErrorKind::InvalidInput == e.kind() => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn verify_file_with_flipped_cleartext_byte() {
+        let dir = tempfile::Builder::new()
+            .prefix("transfer")
+            .tempdir()
+            .unwrap();
+        let storage = LocalStorage::open(dir.path()).unwrap();
+
+        // The gzip checksum is not keyed, so anyone able to produce valid
+        // framing gets past it. What cannot be forged is the block HMAC, which
+        // rejects even a single flipped bit of content.
+        let blocklist =
+            upload_tampered(&storage, |data| data[1] ^= 0x10, |_| ());
+        match verify_file(
+            &storage,
+            &blocklist,
+            b"secret",
+            CipherWidth::Aes128,
+            HashVariant::default(),
+        ) {
+            Err(Error(ErrorKind::HmacMismatch("block", id, _), _)) => {
+                assert_eq!(blocklist.blocks[1], id)
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
     }
 }